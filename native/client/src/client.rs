@@ -1,18 +1,33 @@
 use crate::{ClientError, ClientResult};
-use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use chacha20poly1305::aead::OsRng;
 use chrono::DateTime;
-use ed25519_dalek::{SigningKey, VerifyingKey};
-use proto::ApplicationMessage;
-use protocol::bundle::{create_prekey_bundle, sign_bundle};
+use ed25519_dalek::SigningKey;
+use migrations::{migrate, Migration, MigrationError};
+use prost::Message as _;
+use proto::application::AttachmentPointer as AttachmentPointerProto;
+use proto::service::UnsealedMessage as UnsealedMessageProto;
+use proto::{
+    ApplicationMessage, AttachmentPointer, DeviceMessage, MessageContents, PreKeys, RatchetMessage,
+    UnsealedMessage,
+};
+use protocol::aead::CipherSuite;
+use protocol::bundle::{
+    create_kem_pre_key, create_prekey_bundle, sign_bundle, sign_kem_pre_key, sign_pre_key,
+};
+use protocol::kem::{KemCiphertext, KemPublicKey, KemSecretKey};
+use protocol::ratchet::{Header, Session};
+use protocol::sealed_sender::{self, SealedMessage, SealedSenderError, SenderCertificate};
 use protocol::x3dh;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
+use rusqlite::types::Type;
 use rusqlite::{params, Connection};
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::info;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
-use x3dh::{SignedPreKey, SignedPreKeys};
+use x3dh::{PreKey, PreKeyBundle, SignedKemPreKey, SignedPreKey, SignedPreKeys};
+use zeroize::Zeroizing;
 
-fn time_now() -> u64 {
+pub(crate) fn time_now() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -22,56 +37,245 @@ fn time_now() -> u64 {
 #[derive(Clone, Copy, strum_macros::Display)]
 #[repr(u32)]
 enum KeyType {
-    Identity = 0,
+    /// An Ed25519 identity key from before identity keys were X25519 keys.
+    LegacyIdentity = 0,
     Pre = 1,
     OneTimePre = 2,
+    Identity = 3,
+    OneTimeKemPre = 4,
+    LastResortKemPre = 5,
+    LastResortPre = 6,
 }
 
+/// Sender certificates are refreshed when they expire within this many seconds.
+const SENDER_CERTIFICATE_REFRESH: u64 = 60 * 60;
+/// Signed pre keys are replaced once they are this many seconds old.
+const SIGNED_PRE_KEY_ROTATION: u64 = 7 * 24 * 60 * 60;
+/// Replaced signed pre keys are kept for this many seconds so that prekey messages that were
+/// sent before the rotation can still be decrypted.
+const SIGNED_PRE_KEY_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60;
+/// Ids of received messages are remembered for this many seconds, which is as long as the server
+/// keeps unacknowledged messages.
+const RECEIVED_MESSAGE_TTL: u64 = 30 * 24 * 60 * 60;
+
 pub struct X3DHClient {
     connection: tokio_rusqlite::Connection,
-    ik: SigningKey,
+    ik: IdentityKey,
+    sender_certificate: Mutex<Option<SenderCertificate>>,
+    cipher_suite: CipherSuite,
 }
 
+/// The name the client's schema is versioned by.
+const SCHEMA: &str = "client";
+
+/// The migrations of the client's schema, in order. The first migration creates the schema from
+/// before it was versioned. Migrations from before the schema was versioned check whether they
+/// are needed because unversioned databases may have any of them applied.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create tables",
+        apply: create_schema,
+    },
+    Migration {
+        description: "Add identity keys to users",
+        apply: migrate_users_table,
+    },
+    Migration {
+        description: "Add ids to keys",
+        apply: migrate_keys_table,
+    },
+    Migration {
+        description: "Create session and message tables",
+        apply: create_session_tables,
+    },
+    Migration {
+        description: "Add pre keys to sessions",
+        apply: |connection| {
+            connection.execute_batch(
+                "
+                ALTER TABLE sessions ADD COLUMN ek BLOB;
+                ALTER TABLE sessions ADD COLUMN spk_id INTEGER;
+                ALTER TABLE sessions ADD COLUMN opk_id INTEGER;
+                ALTER TABLE sessions ADD COLUMN kem_pre_key BLOB;
+                ALTER TABLE sessions ADD COLUMN kem_ciphertext BLOB;",
+            )
+        },
+    },
+    Migration {
+        description: "Save identity keys per device",
+        apply: |connection| {
+            connection.execute_batch(
+                "
+                CREATE TABLE identity_keys (
+                    username TEXT NOT NULL,
+                    identity_key BLOB NOT NULL,
+                    verified INTEGER NOT NULL DEFAULT 0,
+                    PRIMARY KEY (username, identity_key),
+                    FOREIGN KEY(username) REFERENCES users(username)
+                );
+                INSERT INTO identity_keys (username, identity_key, verified)
+                    SELECT username, identity_key, verified FROM users WHERE identity_key IS NOT NULL;",
+            )
+        },
+    },
+    Migration {
+        description: "Persist the last pre key id",
+        apply: |connection| {
+            connection.execute("CREATE TABLE pre_key_id (last_id INTEGER NOT NULL)", [])?;
+            connection.execute(
+                "INSERT INTO pre_key_id (last_id)
+                    SELECT COALESCE(MAX(id), 0) FROM keys WHERE key_type IN (?1, ?2, ?3)",
+                params![
+                    KeyType::Pre as u32,
+                    KeyType::OneTimePre as u32,
+                    KeyType::LastResortPre as u32
+                ],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "Keep the previous session with each peer",
+        apply: |connection| {
+            connection.execute_batch(
+                "
+                CREATE TABLE previous_sessions (
+                    peer_ik BLOB PRIMARY KEY,
+                    session BLOB NOT NULL,
+                    associated_data BLOB NOT NULL,
+                    ek BLOB
+                );",
+            )
+        },
+    },
+];
+
 #[tracing::instrument]
-fn create_tables(connection: &Connection) -> rusqlite::Result<()> {
+fn create_tables(connection: &Connection) -> Result<(), MigrationError> {
     connection.pragma_update(None, "journal_mode", "WAL")?;
     connection.pragma_update(None, "synchronous", "normal")?;
     connection.pragma_update(None, "foreign_keys", "on")?;
+    migrate(connection, SCHEMA, MIGRATIONS)?;
+    Ok(())
+}
+
+fn create_schema(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS keys (
+            public_key BLOB PRIMARY KEY,
+            private_key BLOB NOT NULL,
+            key_type INTEGER NOT NULL,
+            creation_time INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            username TEXT PRIMARY KEY,
+            created_at INTEGER NOT NULL,
+            name TEXT,
+            profile_pic BLOB
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            sender TEXT NOT NULL,
+            receiver TEXT NOT NULL,
+            creation_time INTEGER NOT NULL,
+            state INTEGER NOT NULL,
+            text TEXT,
+            FOREIGN KEY(sender) REFERENCES users(username),
+            FOREIGN KEY(receiver) REFERENCES users(username)
+        );",
+    )
+}
 
+fn create_session_tables(connection: &Connection) -> rusqlite::Result<()> {
     connection.execute_batch(
         "
-                    BEGIN;
-                    CREATE TABLE IF NOT EXISTS keys (
-                        public_key BLOB PRIMARY KEY,
-                        private_key BLOB NOT NULL,
-                        key_type INTEGER NOT NULL,
-                        creation_time INTEGER NOT NULL
-                    );
-                    CREATE TABLE IF NOT EXISTS users (
-                        username TEXT PRIMARY KEY,
-                        created_at INTEGER NOT NULL,
-                        name TEXT,
-                        profile_pic BLOB
-                    );
-                    CREATE TABLE IF NOT EXISTS messages (
-                        sender TEXT NOT NULL,
-                        receiver TEXT NOT NULL,
-                        creation_time INTEGER NOT NULL,
-                        state INTEGER NOT NULL,
-                        text TEXT,
-                        FOREIGN KEY(sender) REFERENCES users(username),
-                        FOREIGN KEY(receiver) REFERENCES users(username)
-                    );
-                    COMMIT;",
+        CREATE TABLE IF NOT EXISTS replay_cache (
+            pre_key BLOB NOT NULL,
+            sender_ik BLOB NOT NULL,
+            ek BLOB NOT NULL,
+            PRIMARY KEY(pre_key, sender_ik, ek),
+            FOREIGN KEY(pre_key) REFERENCES keys(public_key) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS outgoing_messages (
+            ratchet_key BLOB NOT NULL,
+            message_number INTEGER NOT NULL,
+            recipient_ik BLOB NOT NULL,
+            message_id INTEGER NOT NULL,
+            retries INTEGER NOT NULL,
+            PRIMARY KEY(ratchet_key, message_number)
+        );
+        CREATE TABLE IF NOT EXISTS retry_requests (
+            ratchet_key BLOB NOT NULL,
+            message_number INTEGER NOT NULL,
+            sender_ik BLOB NOT NULL,
+            time INTEGER NOT NULL,
+            PRIMARY KEY(ratchet_key, message_number)
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            peer_ik BLOB PRIMARY KEY,
+            session BLOB NOT NULL,
+            associated_data BLOB NOT NULL,
+            update_time INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS trusted_server_key (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            server_key BLOB NOT NULL,
+            creation_time INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS attachments (
+            message_id INTEGER PRIMARY KEY,
+            pointer BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS received_messages (
+            id INTEGER PRIMARY KEY,
+            time INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Adds the identity key columns to `users` tables that were created before safety numbers.
+fn migrate_users_table(connection: &Connection) -> rusqlite::Result<()> {
+    let migrated: bool = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('users') WHERE name = 'identity_key'",
+        [],
+        |row| row.get(0),
     )?;
+    if !migrated {
+        info!("Adding identity keys to users table.");
+        connection.execute_batch(
+            "
+            ALTER TABLE users ADD COLUMN identity_key BLOB;
+            ALTER TABLE users ADD COLUMN verified INTEGER NOT NULL DEFAULT 0;",
+        )?;
+    }
+    Ok(())
+}
 
+/// Adds ids to `keys` tables that were created before prekeys were identified by id.
+fn migrate_keys_table(connection: &Connection) -> rusqlite::Result<()> {
+    let migrated: bool = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('keys') WHERE name = 'id'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !migrated {
+        info!("Adding ids to keys table.");
+        connection.execute_batch(
+            "
+            ALTER TABLE keys ADD COLUMN id INTEGER;
+            UPDATE keys SET id = rowid;",
+        )?;
+    }
     Ok(())
 }
 
-fn insert_identity_key(identity_key: &SigningKey, connection: &Connection) -> rusqlite::Result<()> {
+fn insert_identity_key(
+    identity_key: &IdentityKey,
+    connection: &Connection,
+) -> rusqlite::Result<()> {
     connection.execute("INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, ?4)", params![
-            VerifyingKey::from(identity_key).to_bytes(),
-            identity_key.to_bytes(),
+            identity_key.public_key().to_bytes(),
+            identity_key.to_bytes().as_slice(),
             KeyType::Identity as u32,
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -81,7 +285,7 @@ fn insert_identity_key(identity_key: &SigningKey, connection: &Connection) -> ru
     Ok(())
 }
 
-fn load_identity_key(connection: &Connection) -> rusqlite::Result<Option<SigningKey>> {
+fn load_identity_key(connection: &Connection) -> rusqlite::Result<Option<IdentityKey>> {
     let key: Option<[u8; 32]> = match connection.query_row(
         "SELECT private_key FROM keys WHERE key_type = ?1",
         params![KeyType::Identity as u32],
@@ -91,7 +295,33 @@ fn load_identity_key(connection: &Connection) -> rusqlite::Result<Option<Signing
         Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
         Err(e) => Err(e),
     }?;
-    Ok(key.map(SigningKey::from))
+    Ok(key.map(IdentityKey::from))
+}
+
+/// Replaces a legacy Ed25519 identity key with its birationally equivalent X25519 key so that
+/// peers continue to recognize the identity.
+fn migrate_identity_key(connection: &Connection) -> rusqlite::Result<()> {
+    let legacy: [u8; 32] = match connection.query_row(
+        "SELECT private_key FROM keys WHERE key_type = ?1",
+        params![KeyType::LegacyIdentity as u32],
+        |row| row.get(0),
+    ) {
+        Ok(value) => value,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    info!("Migrating Ed25519 identity key to X25519.");
+    let identity_key = IdentityKey::from_ed25519(&SigningKey::from(legacy));
+    connection.execute(
+        "UPDATE keys SET public_key = ?1, private_key = ?2, key_type = ?3 WHERE key_type = ?4",
+        params![
+            identity_key.public_key().to_bytes(),
+            identity_key.to_bytes().as_slice(),
+            KeyType::Identity as u32,
+            KeyType::LegacyIdentity as u32
+        ],
+    )?;
+    Ok(())
 }
 
 fn load_pre_key(connection: &Connection) -> rusqlite::Result<Option<X25519StaticSecret>> {
@@ -107,38 +337,154 @@ fn load_pre_key(connection: &Connection) -> rusqlite::Result<Option<X25519Static
     Ok(key.map(X25519StaticSecret::from))
 }
 
+/// Reserves `count` consecutive ids for new X25519 pre keys and returns the first.
+/// Signed, one time and last resort pre keys share ids so that a message can't confuse them.
+/// Ids come from a persisted counter, so an id is never reused after its key is deleted.
+/// Fails with `QueryReturnedNoRows` once the ids run out.
+fn allocate_pre_key_ids(connection: &Connection, count: u32) -> rusqlite::Result<u32> {
+    connection.query_row(
+        "UPDATE pre_key_id SET last_id = last_id + ?1 WHERE last_id + ?1 <= ?2
+         RETURNING last_id - ?1 + 1",
+        params![count, u32::MAX],
+        |row| row.get(0),
+    )
+}
+
 fn insert_pre_keys(
-    keys: &[X25519StaticSecret],
+    keys: &[(X25519StaticSecret, PreKey)],
+    key_type: KeyType,
+    creation_time: u64,
+    connection: &Connection,
+) -> rusqlite::Result<()> {
+    let mut stmt = connection.prepare(
+            "INSERT INTO keys (public_key, private_key, key_type, creation_time, id) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    for (key, pre_key) in keys {
+        info!("Inserting pre key: {}", pre_key.id);
+        stmt.execute((
+            pre_key.pre_key.to_bytes(),
+            Zeroizing::new(key.to_bytes()).as_slice(),
+            key_type as u32,
+            creation_time,
+            pre_key.id,
+        ))?;
+    }
+    Ok(())
+}
+
+fn insert_kem_pre_keys(
+    keys: &[KemSecretKey],
     key_type: KeyType,
     connection: &Connection,
 ) -> rusqlite::Result<()> {
     let mut stmt = connection.prepare(
             "INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, ?4)")?;
     for key in keys {
-        let pre_key = X25519PublicKey::from(key).to_bytes();
-        #[allow(deprecated)]
-        let pubkey = base64::encode(pre_key);
-        info!("Inserting pre key: {pubkey}");
         stmt.execute((
-            pre_key,
-            key.to_bytes(),
+            key.public_key().to_bytes(),
+            key.to_bytes().as_slice(),
             key_type as u32,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            time_now(),
         ))?;
     }
     Ok(())
 }
 
+fn parse_kem_secret_key(key: Vec<u8>) -> rusqlite::Result<KemSecretKey> {
+    KemSecretKey::from_bytes(&key)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e)))
+}
+
+/// Loads the private key for a one time pre key or the last resort pre key with `id`, and whether
+/// it is a one time pre key.
+fn load_opk(connection: &Connection, id: u32) -> rusqlite::Result<(X25519StaticSecret, bool)> {
+    let (key, key_type): ([u8; 32], u32) = connection.query_row(
+        "SELECT private_key, key_type FROM keys WHERE id = ?1 AND key_type IN (?2, ?3)",
+        params![
+            id,
+            KeyType::OneTimePre as u32,
+            KeyType::LastResortPre as u32
+        ],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok((
+        X25519StaticSecret::from(key),
+        key_type == KeyType::OneTimePre as u32,
+    ))
+}
+
+/// Loads the private key for a one time or last resort KEM pre key, and whether it is a one time
+/// pre key.
+fn load_kem_pre_key(
+    connection: &Connection,
+    pre_key: &KemPublicKey,
+) -> rusqlite::Result<(KemSecretKey, bool)> {
+    let (key, key_type): (Vec<u8>, u32) = connection.query_row(
+        "SELECT private_key, key_type FROM keys WHERE public_key = ?1 AND key_type IN (?2, ?3)",
+        params![
+            pre_key.to_bytes(),
+            KeyType::OneTimeKemPre as u32,
+            KeyType::LastResortKemPre as u32
+        ],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok((
+        parse_kem_secret_key(key)?,
+        key_type == KeyType::OneTimeKemPre as u32,
+    ))
+}
+
+/// Deletes the one time pre keys that a prekey message used. Last resort pre keys are reused
+/// until they are replaced.
+/// Returns false if a one time pre key was already consumed by another message.
+fn wipe_one_time_pre_keys(
+    connection: &Connection,
+    opk_id: Option<u32>,
+    kem_pre_key: Option<&KemPublicKey>,
+) -> rusqlite::Result<bool> {
+    if let Some(id) = opk_id {
+        info!("Consuming one time pre key {id}");
+        if connection.execute(
+            "DELETE FROM keys WHERE key_type = ?1 AND id = ?2",
+            params![KeyType::OneTimePre as u32, id],
+        )? == 0
+        {
+            return Ok(false);
+        }
+    }
+    if let Some(pre_key) = kem_pre_key {
+        info!("Consuming one time KEM pre key.");
+        if connection.execute(
+            "DELETE FROM keys WHERE key_type = ?1 AND public_key = ?2",
+            params![KeyType::OneTimeKemPre as u32, pre_key.to_bytes()],
+        )? == 0
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn load_last_resort_kem_pre_key(connection: &Connection) -> rusqlite::Result<Option<KemSecretKey>> {
+    let key: Option<Vec<u8>> = match connection.query_row(
+        "SELECT private_key FROM keys WHERE key_type = ?1 ORDER BY creation_time DESC LIMIT 1",
+        params![KeyType::LastResortKemPre as u32],
+        |row| row.get(0),
+    ) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }?;
+    key.map(parse_kem_secret_key).transpose()
+}
+
 #[tracing::instrument]
-fn lazy_init_identity_key(connection: &Connection) -> rusqlite::Result<SigningKey> {
+fn lazy_init_identity_key(connection: &Connection) -> rusqlite::Result<IdentityKey> {
+    migrate_identity_key(connection)?;
     if let Some(ik) = load_identity_key(connection)? {
         return Ok(ik);
     }
     info!("Creating initial identity key.");
-    let identity_key = SigningKey::generate(&mut OsRng);
+    let identity_key = IdentityKey::generate();
     insert_identity_key(&identity_key, connection)?;
     Ok(identity_key)
 }
@@ -149,8 +495,83 @@ fn lazy_init_pre_key(connection: &Connection) -> rusqlite::Result<()> {
         return Ok(());
     }
     info!("Creating initial pre key.");
-    insert_pre_keys(&[X25519StaticSecret::random()], KeyType::Pre, connection)?;
-    Ok(())
+    insert_signed_pre_key(connection, time_now())
+}
+
+fn insert_signed_pre_key(connection: &Connection, creation_time: u64) -> rusqlite::Result<()> {
+    let key = X25519StaticSecret::random();
+    let pre_key = PreKey {
+        id: allocate_pre_key_ids(connection, 1)?,
+        pre_key: X25519PublicKey::from(&key),
+    };
+    insert_pre_keys(&[(key, pre_key)], KeyType::Pre, creation_time, connection)
+}
+
+/// Creates a new signed pre key if the current one is older than `SIGNED_PRE_KEY_ROTATION` and
+/// deletes signed pre keys that were replaced more than `SIGNED_PRE_KEY_GRACE_PERIOD` ago.
+/// Returns true if a new signed pre key was created.
+fn rotate_pre_key(connection: &Connection, now: u64) -> rusqlite::Result<bool> {
+    let created: u64 = connection.query_row(
+        "SELECT MAX(creation_time) FROM keys WHERE key_type = ?1",
+        params![KeyType::Pre as u32],
+        |row| row.get(0),
+    )?;
+    let rotate = now.saturating_sub(created) >= SIGNED_PRE_KEY_ROTATION;
+    if rotate {
+        info!("Rotating signed pre key.");
+        insert_signed_pre_key(connection, now)?;
+    }
+    // A key was replaced when the next key was created. Deleting a key also deletes its replay
+    // cache entries.
+    connection.execute(
+        "DELETE FROM keys WHERE key_type = ?1 AND id < (
+            SELECT MAX(id) FROM keys WHERE key_type = ?1 AND creation_time <= ?2
+        )",
+        params![
+            KeyType::Pre as u32,
+            now.saturating_sub(SIGNED_PRE_KEY_GRACE_PERIOD)
+        ],
+    )?;
+    Ok(rotate)
+}
+
+#[tracing::instrument]
+fn lazy_init_last_resort_kem_pre_key(connection: &Connection) -> rusqlite::Result<()> {
+    if load_last_resort_kem_pre_key(connection)?.is_some() {
+        return Ok(());
+    }
+    info!("Creating last resort KEM pre key.");
+    insert_kem_pre_keys(
+        &[KemSecretKey::generate()],
+        KeyType::LastResortKemPre,
+        connection,
+    )
+}
+
+/// Creates the pre key that the server hands out in place of a one time pre key once they run
+/// out. Unlike one time pre keys it is kept after it is used.
+#[tracing::instrument]
+fn lazy_init_last_resort_pre_key(connection: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM keys WHERE key_type = ?1)",
+        params![KeyType::LastResortPre as u32],
+        |row| row.get(0),
+    )?;
+    if exists {
+        return Ok(());
+    }
+    info!("Creating last resort pre key.");
+    let key = X25519StaticSecret::random();
+    let pre_key = PreKey {
+        id: allocate_pre_key_ids(connection, 1)?,
+        pre_key: X25519PublicKey::from(&key),
+    };
+    insert_pre_keys(
+        &[(key, pre_key)],
+        KeyType::LastResortPre,
+        time_now(),
+        connection,
+    )
 }
 
 #[allow(dead_code)]
@@ -162,6 +583,323 @@ fn opk_count(connection: &Connection) -> rusqlite::Result<u32> {
     )
 }
 
+/// Returns true if an initial message with this (pre_key, sender_ik, ek) tuple was already received.
+fn is_replay(
+    connection: &Connection,
+    pre_key: &X25519PublicKey,
+    sender_ik: &IdentityPublicKey,
+    ek: &X25519PublicKey,
+) -> rusqlite::Result<bool> {
+    connection
+        .prepare("SELECT 1 FROM replay_cache WHERE pre_key = ?1 AND sender_ik = ?2 AND ek = ?3")?
+        .exists(params![
+            pre_key.to_bytes(),
+            sender_ik.to_bytes(),
+            ek.to_bytes()
+        ])
+}
+
+/// Records a received prekey message in the replay cache.
+/// Entries are scoped to the signed pre key and are deleted along with it.
+/// Returns false if the message was already recorded.
+fn insert_replay(
+    connection: &Connection,
+    pre_key: &X25519PublicKey,
+    sender_ik: &IdentityPublicKey,
+    ek: &X25519PublicKey,
+) -> rusqlite::Result<bool> {
+    let affected = connection.execute(
+        "INSERT OR IGNORE INTO replay_cache (pre_key, sender_ik, ek) VALUES (?1, ?2, ?3)",
+        params![pre_key.to_bytes(), sender_ik.to_bytes(), ek.to_bytes()],
+    )?;
+    Ok(affected == 1)
+}
+
+/// Returns the session with `peer_ik` and the associated data of its messages.
+fn get_session(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+) -> rusqlite::Result<Option<(Session, Vec<u8>)>> {
+    match connection.query_row(
+        "SELECT session, associated_data FROM sessions WHERE peer_ik = ?1",
+        params![peer_ik.to_bytes()],
+        |row| {
+            let session: Zeroizing<Vec<u8>> = Zeroizing::new(row.get(0)?);
+            let session = bincode::deserialize(&session)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, e))?;
+            Ok((session, row.get(1)?))
+        },
+    ) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the session with `peer_ik` that the current session replaced, the associated data of
+/// its messages and the ephemeral key it was started with.
+fn get_previous_session(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+) -> rusqlite::Result<Option<(Session, Vec<u8>, X25519PublicKey)>> {
+    match connection.query_row(
+        "SELECT session, associated_data, ek FROM previous_sessions WHERE peer_ik = ?1",
+        params![peer_ik.to_bytes()],
+        |row| {
+            let session: Zeroizing<Vec<u8>> = Zeroizing::new(row.get(0)?);
+            let session = bincode::deserialize(&session)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, e))?;
+            let ek: [u8; 32] = row.get(2)?;
+            Ok((session, row.get(1)?, X25519PublicKey::from(ek)))
+        },
+    ) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Saves a new session with `peer_ik` that was started with the ephemeral key `ek`. The existing
+/// session becomes the previous session, so that a stale prekey message can't clobber a session
+/// the peer still uses.
+/// `pre_keys` are set for sessions that this device started. They are sent with every message
+/// until the peer replies so that the peer can start the session from any of them.
+fn insert_session(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+    session: &Session,
+    associated_data: &[u8],
+    ek: &X25519PublicKey,
+    pre_keys: Option<&PreKeys>,
+) -> rusqlite::Result<()> {
+    let session = Zeroizing::new(
+        bincode::serialize(session).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e))?,
+    );
+    let pqpk = pre_keys.and_then(|pre_keys| pre_keys.pqpk.as_ref());
+    connection.execute(
+        "INSERT OR REPLACE INTO previous_sessions (peer_ik, session, associated_data, ek) SELECT peer_ik, session, associated_data, ek FROM sessions WHERE peer_ik = ?1 AND ek IS NOT NULL",
+        params![peer_ik.to_bytes()],
+    )?;
+    connection.execute(
+        "INSERT OR REPLACE INTO sessions (peer_ik, session, associated_data, update_time, ek, spk_id, opk_id, kem_pre_key, kem_ciphertext) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            peer_ik.to_bytes(),
+            session.as_slice(),
+            associated_data,
+            time_now(),
+            ek.to_bytes(),
+            pre_keys.map(|pre_keys| pre_keys.spk_id),
+            pre_keys.and_then(|pre_keys| pre_keys.opk_id),
+            pqpk.map(|(pqpk, _)| pqpk.to_bytes()),
+            pqpk.map(|(_, ciphertext)| ciphertext.to_bytes()),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Saves the advanced state of the existing session with `peer_ik`.
+fn update_session(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+    session: &Session,
+    associated_data: &[u8],
+) -> rusqlite::Result<()> {
+    let session = Zeroizing::new(
+        bincode::serialize(session).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e))?,
+    );
+    connection.execute(
+        "UPDATE sessions SET session = ?2, associated_data = ?3, update_time = ?4 WHERE peer_ik = ?1",
+        params![peer_ik.to_bytes(), session.as_slice(), associated_data, time_now()],
+    )?;
+    Ok(())
+}
+
+/// Returns the pre keys to send with messages of the session with `peer_ik` if the peer hasn't
+/// replied to it yet.
+fn get_pending_pre_keys(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+) -> rusqlite::Result<Option<PreKeys>> {
+    let row = match connection.query_row(
+        "SELECT ek, spk_id, opk_id, kem_pre_key, kem_ciphertext FROM sessions WHERE peer_ik = ?1 AND spk_id IS NOT NULL",
+        params![peer_ik.to_bytes()],
+        |row| {
+            Ok((
+                row.get::<_, [u8; 32]>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, Option<Vec<u8>>>(3)?,
+                row.get::<_, Option<Vec<u8>>>(4)?,
+            ))
+        },
+    ) {
+        Ok(row) => row,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (ek, spk_id, opk_id, kem_pre_key, kem_ciphertext) = row;
+    let pqpk = match kem_pre_key.zip(kem_ciphertext) {
+        Some((pqpk, ciphertext)) => Some((
+            KemPublicKey::from_bytes(&pqpk).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(3, Type::Blob, Box::new(e))
+            })?,
+            KemCiphertext::from_bytes(&ciphertext).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(4, Type::Blob, Box::new(e))
+            })?,
+        )),
+        None => None,
+    };
+    Ok(Some(PreKeys {
+        ek: X25519PublicKey::from(ek),
+        spk_id,
+        opk_id,
+        pqpk,
+    }))
+}
+
+/// Stops sending pre keys with messages of the session with `peer_ik` once the peer has replied.
+fn acknowledge_session(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "UPDATE sessions SET spk_id = NULL, opk_id = NULL, kem_pre_key = NULL, kem_ciphertext = NULL WHERE peer_ik = ?1",
+        params![peer_ik.to_bytes()],
+    )?;
+    Ok(())
+}
+
+/// Returns true if the current or previous session with `peer_ik` was started with the ephemeral
+/// key `ek`.
+fn is_session_started_by(
+    connection: &Connection,
+    peer_ik: &IdentityPublicKey,
+    ek: &X25519PublicKey,
+) -> rusqlite::Result<bool> {
+    connection
+        .prepare(
+            "SELECT 1 FROM sessions WHERE peer_ik = ?1 AND ek = ?2
+                UNION SELECT 1 FROM previous_sessions WHERE peer_ik = ?1 AND ek = ?2",
+        )?
+        .exists(params![peer_ik.to_bytes(), ek.to_bytes()])
+}
+
+/// Returns the key that signs sender certificates, if one has been trusted.
+fn load_trusted_server_key(connection: &Connection) -> rusqlite::Result<Option<IdentityPublicKey>> {
+    match connection.query_row(
+        "SELECT server_key FROM trusted_server_key WHERE id = 0",
+        [],
+        |row| row.get::<_, [u8; 32]>(0),
+    ) {
+        Ok(key) => Ok(Some(IdentityPublicKey::from(key))),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Trusts `server_key` if no server key has been trusted yet.
+/// Returns the trusted server key.
+fn lazy_init_trusted_server_key(
+    connection: &Connection,
+    server_key: &IdentityPublicKey,
+) -> rusqlite::Result<IdentityPublicKey> {
+    connection.execute(
+        "INSERT OR IGNORE INTO trusted_server_key (id, server_key, creation_time) VALUES (0, ?1, ?2)",
+        params![server_key.to_bytes(), time_now()],
+    )?;
+    Ok(load_trusted_server_key(connection)?.unwrap())
+}
+
+/// The number of times a message is re-encrypted for a recipient device that failed to decrypt it.
+pub const MAX_MESSAGE_RETRIES: u32 = 3;
+/// The number of retry requests sent to a single sender device per hour.
+pub const MAX_RETRY_REQUESTS_PER_HOUR: u32 = 5;
+
+fn insert_outgoing(
+    connection: &Connection,
+    ratchet_key: &X25519PublicKey,
+    message_number: u32,
+    recipient_ik: &IdentityPublicKey,
+    message_id: MessageId,
+    retries: u32,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO outgoing_messages (ratchet_key, message_number, recipient_ik, message_id, retries) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![ratchet_key.to_bytes(), message_number, recipient_ik.to_bytes(), message_id, retries],
+    )?;
+    Ok(())
+}
+
+/// Returns the message and retry count for an outgoing message sent to `recipient_ik` with
+/// `ratchet_key` and `message_number`.
+/// Retries are counted per message and recipient so that naming the header of an earlier attempt
+/// doesn't reset the count.
+fn get_outgoing(
+    connection: &Connection,
+    ratchet_key: &X25519PublicKey,
+    message_number: u32,
+    recipient_ik: &IdentityPublicKey,
+) -> rusqlite::Result<Option<(MessageId, u32)>> {
+    match connection.query_row(
+        "SELECT message_id, (SELECT MAX(retries) FROM outgoing_messages AS attempts WHERE attempts.message_id = outgoing.message_id AND attempts.recipient_ik = outgoing.recipient_ik) FROM outgoing_messages AS outgoing WHERE ratchet_key = ?1 AND message_number = ?2 AND recipient_ik = ?3",
+        params![ratchet_key.to_bytes(), message_number, recipient_ik.to_bytes()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Records a retry request for the message sent by `sender_ik` with `ratchet_key` and
+/// `message_number`.
+/// Returns false if the request was already sent or `sender_ik` has exceeded its hourly limit.
+fn insert_retry_request(
+    connection: &mut Connection,
+    ratchet_key: &X25519PublicKey,
+    message_number: u32,
+    sender_ik: &IdentityPublicKey,
+) -> rusqlite::Result<bool> {
+    let tx = connection.transaction()?;
+    let recent: u32 = tx.query_row(
+        "SELECT COUNT(*) FROM retry_requests WHERE sender_ik = ?1 AND time > ?2",
+        params![sender_ik.to_bytes(), time_now().saturating_sub(60 * 60)],
+        |row| row.get(0),
+    )?;
+    if recent >= MAX_RETRY_REQUESTS_PER_HOUR {
+        return Ok(false);
+    }
+    let affected = tx.execute(
+        "INSERT OR IGNORE INTO retry_requests (ratchet_key, message_number, sender_ik, time) VALUES (?1, ?2, ?3, ?4)",
+        params![ratchet_key.to_bytes(), message_number, sender_ik.to_bytes(), time_now()],
+    )?;
+    tx.commit()?;
+    Ok(affected == 1)
+}
+
+/// Returns true if the message with the server assigned `id` was already received.
+fn is_received(connection: &Connection, id: u64) -> rusqlite::Result<bool> {
+    connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM received_messages WHERE id = ?1)",
+        params![id],
+        |row| row.get(0),
+    )
+}
+
+/// Records that the message with the server assigned `id` was received and forgets messages that
+/// were received more than `RECEIVED_MESSAGE_TTL` ago.
+fn insert_received(connection: &Connection, id: u64, now: u64) -> rusqlite::Result<()> {
+    connection.execute(
+        "DELETE FROM received_messages WHERE time < ?1",
+        params![now.saturating_sub(RECEIVED_MESSAGE_TTL)],
+    )?;
+    connection.execute(
+        "INSERT OR IGNORE INTO received_messages (id, time) VALUES (?1, ?2)",
+        params![id, now],
+    )?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, serde::Serialize, strum_macros::Display, strum_macros::FromRepr)]
 #[repr(u8)]
 pub enum MessageState {
@@ -213,14 +951,71 @@ pub fn add_user(
     Ok(())
 }
 
+/// Returns the identity keys saved for the devices of `username` and whether each was verified.
+fn get_identity_keys(
+    connection: &Connection,
+    username: &str,
+) -> rusqlite::Result<Vec<(IdentityPublicKey, bool)>> {
+    connection
+        .prepare(
+            "SELECT identity_key, verified FROM identity_keys WHERE username = ?1 ORDER BY identity_key",
+        )?
+        .query_map(params![username], |row| {
+            Ok((
+                IdentityPublicKey::from(row.get::<_, [u8; 32]>(0)?),
+                row.get(1)?,
+            ))
+        })?
+        .collect()
+}
+
+/// Saves the identity key of one of the devices of `username`. New keys are not verified.
+/// Returns true if it is a new key of a user whose other keys were already saved.
+fn save_identity_key(
+    connection: &Connection,
+    username: &str,
+    ik: &IdentityPublicKey,
+) -> rusqlite::Result<bool> {
+    add_user(connection, username, None, None)?;
+    let known = !get_identity_keys(connection, username)?.is_empty();
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO identity_keys (username, identity_key) VALUES (?1, ?2)",
+        params![username, ik.to_bytes()],
+    )? == 1;
+    Ok(known && inserted)
+}
+
+/// Replaces the saved identity keys of `username` with `iks`, which are marked as verified.
+fn set_verified(
+    connection: &Connection,
+    username: &str,
+    iks: &[IdentityPublicKey],
+) -> rusqlite::Result<()> {
+    add_user(connection, username, None, None)?;
+    connection.execute(
+        "DELETE FROM identity_keys WHERE username = ?1",
+        params![username],
+    )?;
+    for ik in iks {
+        connection.execute(
+            "INSERT OR REPLACE INTO identity_keys (username, identity_key, verified) VALUES (?1, ?2, 1)",
+            params![username, ik.to_bytes()],
+        )?;
+    }
+    Ok(())
+}
+
 pub fn set_received(
     connection: &Connection,
     our_username: &str,
     message: ApplicationMessage,
 ) -> rusqlite::Result<()> {
+    let MessageContents::Text(text) = message.contents else {
+        return Ok(());
+    };
     connection.execute("INSERT OR IGNORE INTO messages (sender, receiver, creation_time, state, text) VALUES ($1, $2, $3, $4, $5)", 
         params![
-            message.sender, our_username, time_now(), MessageState::Delivered as u8, message.text
+            message.sender, our_username, time_now(), MessageState::Delivered as u8, text
         ]
     )?;
     Ok(())
@@ -286,73 +1081,464 @@ fn get_conversations(connection: &Connection) -> rusqlite::Result<Vec<MessageMod
     message_iter.try_collect()
 }
 
-impl X3DHClient {
-    #[tracing::instrument(skip(connection))]
-    pub async fn new(connection: tokio_rusqlite::Connection) -> ClientResult<X3DHClient> {
-        let ik = connection
-            .call(|connection| {
-                create_tables(connection)?;
-                lazy_init_pre_key(connection)?;
-                Ok(lazy_init_identity_key(connection)?)
-            })
-            .await
+fn save_attachment(
+    connection: &Connection,
+    id: MessageId,
+    pointer: AttachmentPointer,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO attachments (message_id, pointer) VALUES (?1, ?2)",
+        params![id, AttachmentPointerProto::from(pointer).encode_to_vec()],
+    )?;
+    Ok(())
+}
+
+fn get_attachment(
+    connection: &Connection,
+    id: MessageId,
+) -> rusqlite::Result<Option<AttachmentPointer>> {
+    let pointer: Vec<u8> = match connection.query_row(
+        "SELECT pointer FROM attachments WHERE message_id = ?1",
+        params![id],
+        |row| row.get(0),
+    ) {
+        Ok(pointer) => pointer,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let pointer = AttachmentPointerProto::decode(&*pointer)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e)))?;
+    Ok(Some(pointer.try_into().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e))
+    })?))
+}
+
+impl X3DHClient {
+    #[tracing::instrument(skip(connection))]
+    pub async fn new(connection: tokio_rusqlite::Connection) -> ClientResult<X3DHClient> {
+        let ik = connection
+            .call(|connection| {
+                create_tables(connection).map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
+                lazy_init_pre_key(connection)?;
+                lazy_init_last_resort_kem_pre_key(connection)?;
+                lazy_init_last_resort_pre_key(connection)?;
+                Ok(lazy_init_identity_key(connection)?)
+            })
+            .await
             .map_err(ClientError::TokioSqlite)?;
 
-        let sqlite_client = X3DHClient { connection, ik };
+        let sqlite_client = X3DHClient {
+            connection,
+            ik,
+            sender_certificate: Mutex::new(None),
+            cipher_suite: CipherSuite::default(),
+        };
         Ok(sqlite_client)
     }
 
-    pub async fn fetch_wipe_opk(
-        &self,
-        one_time_prekey: X25519PublicKey,
-    ) -> ClientResult<X25519StaticSecret> {
-        #[allow(deprecated)]
-        let pubkey = base64::encode(one_time_prekey.to_bytes());
-        info!("Attempting to consume one time pre key '{pubkey}'",);
+    /// Encrypts outgoing messages with `suite`. Incoming messages are decrypted with whichever
+    /// suite their version tag selects.
+    pub fn with_cipher_suite(mut self, suite: CipherSuite) -> X3DHClient {
+        self.cipher_suite = suite;
+        self
+    }
+
+    pub fn get_ik(&self) -> &IdentityKey {
+        &self.ik
+    }
+
+    pub async fn get_pre_key(&self, id: u32) -> ClientResult<X25519StaticSecret> {
+        info!("Loading pre key: {id}");
         let key: [u8; 32] = self
             .connection
             .call(move |connection| {
                 Ok(connection.query_row(
-                    "DELETE from keys WHERE public_key=?1 RETURNING private_key",
-                    params![one_time_prekey.to_bytes()],
+                    "SELECT private_key FROM keys WHERE key_type = ?1 AND id = ?2",
+                    params![KeyType::Pre as u32, id],
                     |row| row.get(0),
                 )?)
             })
-            .await
-            .map_err(|_| ClientError::WipeOpk(pubkey))?;
+            .await?;
         Ok(X25519StaticSecret::from(key))
     }
 
-    pub fn get_ik(&self) -> SigningKey {
-        self.ik.clone()
+    /// Starts a new session with the device that published `bundle`, replacing any existing
+    /// session, and encrypts `plaintext` as its first message.
+    /// Bundles without a KEM pre key are rejected rather than falling back to X3DH.
+    pub async fn start_session(
+        &self,
+        bundle: PreKeyBundle,
+        plaintext: Vec<u8>,
+    ) -> ClientResult<DeviceMessage> {
+        // Every registered device publishes a last resort KEM pre key, so a bundle without one
+        // was stripped to downgrade the session to X3DH.
+        if bundle.pqpk.is_none() {
+            return Err(ClientError::MissingKemPreKey);
+        }
+        let (ek, kem_ciphertext, mut session) = x3dh::initiate_send_session(&bundle, &self.ik)?;
+        let ad = x3dh::associated_data(&self.ik.public_key(), &bundle.ik);
+        let (header, ciphertext) =
+            session.encrypt_with_suite(&plaintext, &ad, self.cipher_suite)?;
+        let recipient = bundle.ik;
+        let pre_keys = PreKeys {
+            ek,
+            spk_id: bundle.spk.id,
+            opk_id: bundle.opk.map(|opk| opk.id),
+            pqpk: bundle.pqpk.map(|pqpk| pqpk.pre_key).zip(kem_ciphertext),
+        };
+        {
+            let pre_keys = pre_keys.clone();
+            self.connection
+                .call(move |connection| {
+                    let tx = connection.transaction()?;
+                    insert_session(&tx, &recipient, &session, &ad, &ek, Some(&pre_keys))?;
+                    tx.commit()?;
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(DeviceMessage {
+            ik: self.ik.public_key(),
+            pre_keys: Some(pre_keys),
+            message: RatchetMessage { header, ciphertext },
+        })
     }
 
-    pub async fn get_pre_key(&self, pre_key: X25519PublicKey) -> ClientResult<X25519StaticSecret> {
-        #[allow(deprecated)]
-        let pubkey = base64::encode(pre_key.to_bytes());
-        info!("Loading pre key: {pubkey}");
-        let key: [u8; 32] = self.connection.call(move |connection| {
-            Ok(connection.query_row("SELECT private_key FROM keys WHERE public_key = ?1 ORDER BY creation_time DESC LIMIT 1",
-                params![pre_key.to_bytes()],
-                |row| row.get(0))?)
-        }).await?;
-        Ok(X25519StaticSecret::from(key))
+    /// Encrypts `plaintext` with the existing session with `recipient`.
+    /// Messages are prekey messages until `recipient` replies to the session.
+    /// Returns `None` if there is no session with `recipient`.
+    pub async fn encrypt_for_session(
+        &self,
+        recipient: IdentityPublicKey,
+        plaintext: Vec<u8>,
+    ) -> ClientResult<Option<DeviceMessage>> {
+        let suite = self.cipher_suite;
+        let encrypted = self
+            .connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                let Some((mut session, ad)) = get_session(&tx, &recipient)? else {
+                    return Ok(None);
+                };
+                let encrypted = match session.encrypt_with_suite(&plaintext, &ad, suite) {
+                    Ok(encrypted) => encrypted,
+                    Err(e) => return Ok(Some(Err(e))),
+                };
+                update_session(&tx, &recipient, &session, &ad)?;
+                let pre_keys = get_pending_pre_keys(&tx, &recipient)?;
+                tx.commit()?;
+                Ok(Some(Ok((encrypted, pre_keys))))
+            })
+            .await?;
+        Ok(encrypted
+            .transpose()?
+            .map(|((header, ciphertext), pre_keys)| DeviceMessage {
+                ik: self.ik.public_key(),
+                pre_keys,
+                message: RatchetMessage { header, ciphertext },
+            }))
+    }
+
+    /// Decrypts a message from another device, starting a new session if it is a prekey message.
+    pub async fn receive_message(&self, message: &DeviceMessage) -> ClientResult<Vec<u8>> {
+        match &message.pre_keys {
+            Some(pre_keys) => {
+                self.receive_pre_key_message(message.ik, pre_keys.clone(), &message.message)
+                    .await
+            }
+            None => {
+                self.receive_session_message(message.ik, message.message.clone())
+                    .await
+            }
+        }
     }
 
+    /// Runs the recipient side of X3DH (or PQXDH) and decrypts the first message of the new
+    /// session.
+    /// Prekey messages are only accepted once per signed pre key. Replays (including those that
+    /// did not use a one time pre key) are rejected with `ClientError::Replay`.
+    /// Later prekey messages of a session that was already started are decrypted with the
+    /// session.
+    async fn receive_pre_key_message(
+        &self,
+        sender_ik: IdentityPublicKey,
+        pre_keys: PreKeys,
+        message: &RatchetMessage,
+    ) -> ClientResult<Vec<u8>> {
+        let spk = self.get_pre_key(pre_keys.spk_id).await?;
+        let spk_public = X25519PublicKey::from(&spk);
+        let ek = pre_keys.ek;
+        let (replay, started) = self
+            .connection
+            .call(move |connection| {
+                Ok((
+                    is_replay(connection, &spk_public, &sender_ik, &ek)?,
+                    is_session_started_by(connection, &sender_ik, &ek)?,
+                ))
+            })
+            .await?;
+        if replay && started {
+            // The session can't decrypt messages it has already decrypted.
+            return match self
+                .receive_session_message(sender_ik, message.clone())
+                .await
+            {
+                Err(ClientError::Ratchet(_)) => Err(ClientError::Replay),
+                decrypted => decrypted,
+            };
+        }
+        if replay {
+            return Err(ClientError::Replay);
+        }
+
+        // One time pre keys are only deleted once the message decrypts, so that a forged message
+        // can't exhaust them.
+        let opk = if let Some(id) = pre_keys.opk_id {
+            Some(
+                self.connection
+                    .call(move |connection| Ok(load_opk(connection, id)?))
+                    .await
+                    .map_err(|_| ClientError::WipeOpk(id.to_string()))?,
+            )
+        } else {
+            None
+        };
+        let pqpk = if let Some((pqpk, kem_ciphertext)) = &pre_keys.pqpk {
+            info!("Loading KEM pre key.");
+            let pqpk = pqpk.clone();
+            Some((
+                self.connection
+                    .call(move |connection| Ok(load_kem_pre_key(connection, &pqpk)?))
+                    .await?,
+                kem_ciphertext,
+            ))
+        } else {
+            None
+        };
+        let wiped_opk_id = pre_keys
+            .opk_id
+            .filter(|_| opk.as_ref().is_some_and(|(_, one_time)| *one_time));
+        let wiped_kem_pre_key = pre_keys
+            .pqpk
+            .as_ref()
+            .filter(|_| pqpk.as_ref().is_some_and(|((_, one_time), _)| *one_time))
+            .map(|(pqpk, _)| pqpk.clone());
+        let mut session = x3dh::initiate_recv_session(
+            &self.ik,
+            &spk,
+            &sender_ik,
+            pre_keys.ek,
+            opk.map(|(opk, _)| opk),
+            pqpk.as_ref().map(|((pqpk, _), ct)| (pqpk, *ct)),
+        );
+        let ad = x3dh::associated_data(&sender_ik, &self.ik.public_key());
+        let decrypted = session.decrypt(&message.header, &message.ciphertext, &ad)?;
+
+        if !self
+            .connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                if !insert_replay(&tx, &spk_public, &sender_ik, &ek)?
+                    || !wipe_one_time_pre_keys(&tx, wiped_opk_id, wiped_kem_pre_key.as_ref())?
+                {
+                    return Ok(false);
+                }
+                insert_session(&tx, &sender_ik, &session, &ad, &ek, None)?;
+                tx.commit()?;
+                Ok(true)
+            })
+            .await?
+        {
+            return Err(ClientError::Replay);
+        }
+        Ok(decrypted)
+    }
+
+    /// Decrypts a message with the existing session with `sender_ik`.
+    /// A message from the peer means that it has started the session, so later messages no longer
+    /// carry pre keys.
+    /// Messages that only the previous session decrypts make it the current session again, since
+    /// the peer is still using it.
+    async fn receive_session_message(
+        &self,
+        sender_ik: IdentityPublicKey,
+        message: RatchetMessage,
+    ) -> ClientResult<Vec<u8>> {
+        let decrypted = self
+            .connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                let Some((mut session, ad)) = get_session(&tx, &sender_ik)? else {
+                    return Ok(None);
+                };
+                let decrypted = session.decrypt(&message.header, &message.ciphertext, &ad);
+                if decrypted.is_ok() {
+                    update_session(&tx, &sender_ik, &session, &ad)?;
+                    acknowledge_session(&tx, &sender_ik)?;
+                    tx.commit()?;
+                    return Ok(Some(decrypted));
+                }
+                if let Some((mut previous, ad, ek)) = get_previous_session(&tx, &sender_ik)? {
+                    if let Ok(decrypted) =
+                        previous.decrypt(&message.header, &message.ciphertext, &ad)
+                    {
+                        insert_session(&tx, &sender_ik, &previous, &ad, &ek, None)?;
+                        tx.commit()?;
+                        return Ok(Some(Ok(decrypted)));
+                    }
+                }
+                Ok(Some(decrypted))
+            })
+            .await?;
+        Ok(decrypted.ok_or(ClientError::NoSession)??)
+    }
+
+    /// Saves the identity key of one of the devices of `username`.
+    /// Returns true if it is a new key of a user whose other keys were already saved.
+    pub async fn save_identity_key(
+        &self,
+        username: String,
+        ik: IdentityPublicKey,
+    ) -> ClientResult<bool> {
+        Ok(self
+            .connection
+            .call(move |connection| Ok(save_identity_key(connection, &username, &ik)?))
+            .await?)
+    }
+
+    /// Returns true if the identity keys of all of the saved devices of `username` were verified.
+    pub async fn is_verified(&self, username: String) -> ClientResult<bool> {
+        let keys = self
+            .connection
+            .call(move |connection| Ok(get_identity_keys(connection, &username)?))
+            .await?;
+        Ok(!keys.is_empty() && keys.iter().all(|(_, verified)| *verified))
+    }
+
+    /// Marks `iks` as the verified identity keys of `username`, forgetting any other saved keys.
+    pub async fn set_verified(
+        &self,
+        username: String,
+        iks: Vec<IdentityPublicKey>,
+    ) -> ClientResult<()> {
+        Ok(self
+            .connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                set_verified(&tx, &username, &iks)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await?)
+    }
+
+    /// Trusts `server_key` to sign sender certificates on first use.
+    /// Fails if a different server key was already trusted.
+    pub async fn trust_server_key(&self, server_key: IdentityPublicKey) -> ClientResult<()> {
+        let trusted = self
+            .connection
+            .call(move |connection| Ok(lazy_init_trusted_server_key(connection, &server_key)?))
+            .await?;
+        if trusted != server_key {
+            return Err(ClientError::UntrustedServerKey);
+        }
+        Ok(())
+    }
+
+    async fn get_trusted_server_key(&self) -> ClientResult<IdentityPublicKey> {
+        self.connection
+            .call(|connection| Ok(load_trusted_server_key(connection)?))
+            .await?
+            .ok_or(ClientError::UntrustedServerKey)
+    }
+
+    /// Returns the cached sender certificate unless it is about to expire.
+    pub fn get_sender_certificate(&self) -> Option<SenderCertificate> {
+        self.sender_certificate
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|certificate| certificate.expiration > time_now() + SENDER_CERTIFICATE_REFRESH)
+    }
+
+    /// Validates and caches a sender certificate for this device.
+    pub async fn set_sender_certificate(&self, certificate: SenderCertificate) -> ClientResult<()> {
+        let trust_root = self.get_trusted_server_key().await?;
+        certificate.validate(&trust_root, time_now())?;
+        if certificate.sender != self.ik.public_key() {
+            return Err(SealedSenderError::SenderMismatch.into());
+        }
+        *self.sender_certificate.lock().unwrap() = Some(certificate);
+        Ok(())
+    }
+
+    /// Hides the sender of `message` from the server.
+    pub fn seal(
+        &self,
+        recipient: &IdentityPublicKey,
+        message: DeviceMessage,
+        certificate: SenderCertificate,
+    ) -> Result<SealedMessage, SealedSenderError> {
+        let contents = UnsealedMessageProto::from(UnsealedMessage {
+            certificate,
+            message,
+        })
+        .encode_to_vec();
+        sealed_sender::seal(&self.ik, recipient, &contents)
+    }
+
+    /// Decrypts a sealed message and verifies that its sender certificate was signed by the
+    /// trusted server key and names the sender.
+    pub async fn unseal(&self, message: &SealedMessage) -> ClientResult<DeviceMessage> {
+        let (sender, contents) = sealed_sender::unseal(&self.ik, message)?;
+        let UnsealedMessage {
+            certificate,
+            message,
+        } = UnsealedMessageProto::decode(&*contents)?.try_into()?;
+        let trust_root = self.get_trusted_server_key().await?;
+        certificate.validate(&trust_root, time_now())?;
+        if certificate.sender != sender || message.ik != sender {
+            return Err(SealedSenderError::SenderMismatch.into());
+        }
+        Ok(message)
+    }
+
+    /// Replaces the signed pre key once it is old enough. See `rotate_pre_key`.
+    /// Returns true if the signed pre key was replaced.
+    #[tracing::instrument(skip(self))]
+    pub async fn rotate_spk(&self) -> ClientResult<bool> {
+        Ok(self
+            .connection
+            .call(|connection| {
+                let tx = connection.transaction()?;
+                let rotated = rotate_pre_key(&tx, time_now())?;
+                tx.commit()?;
+                Ok(rotated)
+            })
+            .await?)
+    }
+
+    /// Signs the current signed pre key.
     #[tracing::instrument(skip(self))]
     pub async fn get_spk(&self) -> ClientResult<SignedPreKey> {
         let ik = self.ik.clone();
         self.connection
             .call(move |connection| {
-                let pre_key = load_pre_key(connection)?.unwrap();
-                let pubkey = base64.encode(pre_key.to_bytes());
-                info!("Signing pre key: {pubkey}");
+                let (id, pre_key, created): (u32, [u8; 32], u64) = connection.query_row(
+                    "SELECT id, public_key, creation_time FROM keys WHERE key_type = ?1 ORDER BY id DESC LIMIT 1",
+                    params![KeyType::Pre as u32],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?;
+                let pre_key = PreKey {
+                    id,
+                    pre_key: X25519PublicKey::from(pre_key),
+                };
+                info!("Signing pre key: {id}");
                 Ok(SignedPreKey {
-                    pre_key: X25519PublicKey::from(&pre_key),
-                    signature: sign_bundle(
-                        &ik,
-                        &[(pre_key.clone(), X25519PublicKey::from(&pre_key))],
-                    ),
+                    id,
+                    created,
+                    pre_key: pre_key.pre_key,
+                    signature: sign_pre_key(&ik, &pre_key, created),
                 })
             })
             .await
@@ -364,24 +1550,83 @@ impl X3DHClient {
         if num_keys != 0 {
             info!("Creating {num_keys} one time pre keys!");
         }
-        let opks = create_prekey_bundle(&self.ik, num_keys);
-        let pre_keys = opks.bundle.iter().map(|(_, _pub)| *_pub).collect();
-        let persisted_pre_keys: Vec<X25519StaticSecret> =
-            opks.bundle.into_iter().map(|opk| opk.0).collect();
+        let ik = self.ik.clone();
+        Ok(self
+            .connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                let opks = match create_prekey_bundle(
+                    &ik,
+                    allocate_pre_key_ids(&tx, num_keys)?,
+                    num_keys,
+                    time_now(),
+                ) {
+                    Ok(opks) => opks,
+                    Err(e) => return Ok(Err(e)),
+                };
+                insert_pre_keys(&opks.bundle, KeyType::OneTimePre, opks.created, &tx)?;
+                tx.commit()?;
+                Ok(Ok(opks.signed_pre_keys()))
+            })
+            .await??)
+    }
+
+    /// Signs the last resort pre key as a bundle of one key.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_last_resort_pre_key(&self) -> ClientResult<SignedPreKeys> {
+        let ik = self.ik.clone();
+        self.connection
+            .call(move |connection| {
+                let (id, pre_key, created): (u32, [u8; 32], u64) = connection.query_row(
+                    "SELECT id, public_key, creation_time FROM keys WHERE key_type = ?1",
+                    params![KeyType::LastResortPre as u32],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?;
+                let pre_keys = vec![PreKey {
+                    id,
+                    pre_key: X25519PublicKey::from(pre_key),
+                }];
+                let signature = sign_bundle(&ik, created, &pre_keys);
+                Ok(SignedPreKeys {
+                    created,
+                    pre_keys,
+                    signature,
+                })
+            })
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Signs the current last resort KEM pre key.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_last_resort_kem_pre_key(&self) -> ClientResult<SignedKemPreKey> {
+        let pre_key = self
+            .connection
+            .call(|connection| Ok(load_last_resort_kem_pre_key(connection)?))
+            .await?
+            .ok_or(ClientError::GetPreKey(rusqlite::Error::QueryReturnedNoRows))?
+            .public_key();
+        let signature = sign_kem_pre_key(&self.ik, &pre_key);
+        Ok(SignedKemPreKey { pre_key, signature })
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn create_kem_pre_keys(&self, num_keys: u32) -> ClientResult<Vec<SignedKemPreKey>> {
+        if num_keys != 0 {
+            info!("Creating {num_keys} one time KEM pre keys!");
+        }
+        let (persisted_pre_keys, pre_keys): (Vec<_>, Vec<_>) =
+            (0..num_keys).map(|_| create_kem_pre_key(&self.ik)).unzip();
         self.connection
             .call(move |connection| {
-                Ok(insert_pre_keys(
+                Ok(insert_kem_pre_keys(
                     &persisted_pre_keys,
-                    KeyType::OneTimePre,
+                    KeyType::OneTimeKemPre,
                     connection,
                 )?)
             })
             .await?;
-
-        Ok(SignedPreKeys {
-            pre_keys,
-            signature: opks.signature,
-        })
+        Ok(pre_keys)
     }
 
     pub async fn persist_message(
@@ -403,6 +1648,36 @@ impl X3DHClient {
             .map_err(ClientError::TokioSqlite)
     }
 
+    /// Persists a message received from `sender` together with its attachment.
+    /// The server assigned `server_id` is recorded in the same transaction so that a redelivered
+    /// message is never persisted twice.
+    pub async fn persist_received_message(
+        &self,
+        server_id: Option<u64>,
+        sender: String,
+        receiver: String,
+        message: String,
+        attachment: Option<AttachmentPointer>,
+    ) -> ClientResult<MessageId> {
+        self.connection
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                add_user(&tx, &sender, None, None)?;
+                add_user(&tx, &receiver, None, None)?;
+                let id = persist_state(&tx, &sender, &receiver, &message, MessageState::Delivered)?;
+                if let Some(attachment) = attachment {
+                    save_attachment(&tx, id, attachment)?;
+                }
+                if let Some(server_id) = server_id {
+                    insert_received(&tx, server_id, time_now())?;
+                }
+                tx.commit()?;
+                Ok(id)
+            })
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
     pub async fn persist_message_state(
         &self,
         message_id: MessageId,
@@ -414,6 +1689,112 @@ impl X3DHClient {
             .map_err(ClientError::TokioSqlite)
     }
 
+    /// Attaches `pointer` to the message with `message_id`.
+    pub async fn persist_attachment(
+        &self,
+        message_id: MessageId,
+        pointer: AttachmentPointer,
+    ) -> ClientResult<()> {
+        self.connection
+            .call(move |connection| Ok(save_attachment(connection, message_id, pointer)?))
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Returns the attachment of the message with `message_id`, if it has one.
+    pub async fn get_attachment(
+        &self,
+        message_id: MessageId,
+    ) -> ClientResult<Option<AttachmentPointer>> {
+        self.connection
+            .call(move |connection| Ok(get_attachment(connection, message_id)?))
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Remembers which message was sent to `recipient_ik` with the ratchet header `header` so
+    /// that it can be resent if the recipient requests a retry.
+    pub async fn persist_outgoing(
+        &self,
+        header: Header,
+        recipient_ik: IdentityPublicKey,
+        message_id: MessageId,
+        retries: u32,
+    ) -> ClientResult<()> {
+        self.connection
+            .call(move |connection| {
+                Ok(insert_outgoing(
+                    connection,
+                    &header.ratchet_key,
+                    header.message_number,
+                    &recipient_ik,
+                    message_id,
+                    retries,
+                )?)
+            })
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Returns the message to resend and its retry count if `recipient_ik` may retry the message
+    /// sent with `ratchet_key` and `message_number`.
+    pub async fn get_retry(
+        &self,
+        ratchet_key: X25519PublicKey,
+        message_number: u32,
+        recipient_ik: IdentityPublicKey,
+    ) -> ClientResult<Option<(MessageId, u32)>> {
+        let outgoing = self
+            .connection
+            .call(move |connection| {
+                Ok(get_outgoing(
+                    connection,
+                    &ratchet_key,
+                    message_number,
+                    &recipient_ik,
+                )?)
+            })
+            .await?;
+        Ok(outgoing.filter(|(_, retries)| *retries < MAX_MESSAGE_RETRIES))
+    }
+
+    /// Returns true if a retry request may be sent for the message from `sender_ik` with the
+    /// ratchet header `header`.
+    pub async fn allow_retry_request(
+        &self,
+        header: Header,
+        sender_ik: IdentityPublicKey,
+    ) -> ClientResult<bool> {
+        self.connection
+            .call(move |connection| {
+                Ok(insert_retry_request(
+                    connection,
+                    &header.ratchet_key,
+                    header.message_number,
+                    &sender_ik,
+                )?)
+            })
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Returns true if the message with the server assigned `id` was already received.
+    pub async fn is_message_received(&self, id: u64) -> ClientResult<bool> {
+        self.connection
+            .call(move |connection| Ok(is_received(connection, id)?))
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
+    /// Records that the message with the server assigned `id` was received so that it is
+    /// ignored if the server delivers it again.
+    pub async fn mark_message_received(&self, id: u64) -> ClientResult<()> {
+        self.connection
+            .call(move |connection| Ok(insert_received(connection, id, time_now())?))
+            .await
+            .map_err(ClientError::TokioSqlite)
+    }
+
     pub async fn get_message(&self, id: MessageId) -> ClientResult<MessageModel> {
         self.connection
             .call(move |connection| Ok(get_message(connection, id)?))
@@ -434,6 +1815,9 @@ mod tests {
     use crate::client::*;
     use anyhow::anyhow;
     use anyhow::Result;
+    use proto::service::{Message as MessageProto, MessageType};
+    use protocol::bundle::{verify_bundle, verify_pre_key};
+    use protocol::secret::SecretKey;
     use rusqlite::Connection;
 
     #[test]
@@ -466,6 +1850,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn migrate_legacy_identity_key() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        create_tables(&connection)?;
+        let legacy = SigningKey::generate(&mut chacha20poly1305::aead::OsRng);
+        connection.execute(
+            "INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                legacy.verifying_key().to_bytes(),
+                legacy.to_bytes(),
+                KeyType::LegacyIdentity as u32,
+                time_now()
+            ],
+        )?;
+
+        let key = lazy_init_identity_key(&connection)?;
+        assert_eq!(key, IdentityKey::from_ed25519(&legacy));
+        assert_eq!(
+            key.public_key(),
+            IdentityPublicKey::from_ed25519(&legacy.verifying_key())
+        );
+        assert_eq!(load_identity_key(&connection)?, Some(key));
+        Ok(())
+    }
+
     #[test]
     fn init_pre_key() -> Result<()> {
         let connection = Connection::open_in_memory()?;
@@ -484,12 +1893,930 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn migrate_users_table_keeps_users() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(
+            "CREATE TABLE users (
+                username TEXT PRIMARY KEY,
+                created_at INTEGER NOT NULL,
+                name TEXT,
+                profile_pic BLOB
+            );",
+        )?;
+        add_user(&connection, "alice", None, None)?;
+        create_tables(&connection)?;
+        create_tables(&connection)?;
+        assert_eq!(
+            migrations::version(&connection, SCHEMA)?,
+            MIGRATIONS.len() as u32
+        );
+
+        let alice = IdentityKey::generate().public_key();
+        assert_eq!(get_identity_keys(&connection, "alice")?, vec![]);
+        assert!(!save_identity_key(&connection, "alice", &alice)?);
+        assert_eq!(
+            get_identity_keys(&connection, "alice")?,
+            vec![(alice, false)]
+        );
+        Ok(())
+    }
+
+    fn signed_pre_key_ids(connection: &Connection) -> rusqlite::Result<Vec<u32>> {
+        connection
+            .prepare("SELECT id FROM keys WHERE key_type = ?1 ORDER BY id")?
+            .query_map(params![KeyType::Pre as u32], |row| row.get(0))?
+            .collect()
+    }
+
+    #[test]
+    fn rotate_pre_key_keeps_replaced_keys_for_grace_period() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        create_tables(&connection)?;
+        insert_signed_pre_key(&connection, 1000)?;
+
+        assert!(!rotate_pre_key(&connection, 1000)?);
+        assert!(rotate_pre_key(&connection, 1000 + SIGNED_PRE_KEY_ROTATION)?);
+        assert!(!rotate_pre_key(
+            &connection,
+            1000 + SIGNED_PRE_KEY_ROTATION
+        )?);
+        assert_eq!(signed_pre_key_ids(&connection)?, [1, 2]);
+
+        // The first key was replaced at the rotation and is deleted after the grace period.
+        let now = 1000 + SIGNED_PRE_KEY_ROTATION + SIGNED_PRE_KEY_GRACE_PERIOD;
+        assert!(rotate_pre_key(&connection, now - 1)?);
+        assert_eq!(signed_pre_key_ids(&connection)?, [1, 2, 3]);
+        assert!(!rotate_pre_key(&connection, now)?);
+        assert_eq!(signed_pre_key_ids(&connection)?, [2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_keys_table_assigns_ids() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(
+            "CREATE TABLE keys (
+                public_key BLOB PRIMARY KEY,
+                private_key BLOB NOT NULL,
+                key_type INTEGER NOT NULL,
+                creation_time INTEGER NOT NULL
+            );",
+        )?;
+        let opks = [X25519StaticSecret::random(), X25519StaticSecret::random()];
+        for opk in &opks {
+            connection.execute(
+                "INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    X25519PublicKey::from(opk).to_bytes(),
+                    opk.to_bytes(),
+                    KeyType::OneTimePre as u32,
+                    time_now()
+                ],
+            )?;
+        }
+        create_tables(&connection)?;
+        create_tables(&connection)?;
+        assert_eq!(
+            migrations::version(&connection, SCHEMA)?,
+            MIGRATIONS.len() as u32
+        );
+
+        let ids: Vec<u32> = connection
+            .prepare("SELECT id FROM keys ORDER BY rowid")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(allocate_pre_key_ids(&connection, 1)?, 3);
+        Ok(())
+    }
+
+    /// Inserts a user and a one time prekey the way the client at `version` would have.
+    fn insert_fixture(
+        connection: &Connection,
+        version: usize,
+        opk: &X25519StaticSecret,
+        ik: &IdentityPublicKey,
+    ) -> rusqlite::Result<()> {
+        if version < 2 {
+            connection.execute(
+                "INSERT INTO users (username, created_at) VALUES ('alice', 0)",
+                [],
+            )?;
+        } else if version < 6 {
+            connection.execute(
+                "INSERT INTO users (username, created_at, identity_key, verified) VALUES ('alice', 0, ?1, 1)",
+                params![ik.to_bytes()],
+            )?;
+        } else {
+            connection.execute(
+                "INSERT INTO users (username, created_at) VALUES ('alice', 0)",
+                [],
+            )?;
+            connection.execute(
+                "INSERT INTO identity_keys (username, identity_key, verified) VALUES ('alice', ?1, 1)",
+                params![ik.to_bytes()],
+            )?;
+        }
+        let (public_key, private_key) = (X25519PublicKey::from(opk).to_bytes(), opk.to_bytes());
+        let key_type = KeyType::OneTimePre as u32;
+        if version < 3 {
+            connection.execute(
+                "INSERT INTO keys (public_key, private_key, key_type, creation_time) VALUES (?1, ?2, ?3, 0)",
+                params![public_key, private_key, key_type],
+            )?;
+        } else {
+            connection.execute(
+                "INSERT INTO keys (public_key, private_key, key_type, creation_time, id) VALUES (?1, ?2, ?3, 0, 1)",
+                params![public_key, private_key, key_type],
+            )?;
+        }
+        if version >= 7 {
+            connection.execute("UPDATE pre_key_id SET last_id = 1", [])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pre_key_ids_not_reused() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        create_tables(&connection)?;
+        let ik = IdentityKey::generate();
+        let first = allocate_pre_key_ids(&connection, 2)?;
+        let opks = create_prekey_bundle(&ik, first, 2, time_now())?;
+        insert_pre_keys(&opks.bundle, KeyType::OneTimePre, opks.created, &connection)?;
+
+        // The one time pre key with the highest id was used.
+        assert!(wipe_one_time_pre_keys(&connection, Some(first + 1), None)?);
+        assert_eq!(allocate_pre_key_ids(&connection, 1)?, first + 2);
+
+        connection.execute("UPDATE pre_key_id SET last_id = ?1", params![u32::MAX - 1])?;
+        assert_eq!(allocate_pre_key_ids(&connection, 1)?, u32::MAX);
+        assert!(allocate_pre_key_ids(&connection, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_from_every_version() -> Result<()> {
+        for version in 1..=MIGRATIONS.len() {
+            let connection = Connection::open_in_memory()?;
+            migrate(&connection, SCHEMA, &MIGRATIONS[..version])?;
+            let opk = X25519StaticSecret::random();
+            let alice = IdentityKey::generate().public_key();
+            insert_fixture(&connection, version, &opk, &alice)?;
+
+            create_tables(&connection)?;
+            assert_eq!(
+                migrations::version(&connection, SCHEMA)?,
+                MIGRATIONS.len() as u32
+            );
+            if version < 2 {
+                assert_eq!(get_identity_keys(&connection, "alice")?, vec![]);
+            } else {
+                assert_eq!(
+                    get_identity_keys(&connection, "alice")?,
+                    vec![(alice, true)]
+                );
+            }
+            assert!(!save_identity_key(&connection, "alice", &alice)?);
+            let (id, private_key): (u32, [u8; 32]) = connection.query_row(
+                "SELECT id, private_key FROM keys WHERE key_type = ?1",
+                params![KeyType::OneTimePre as u32],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            assert_eq!((id, private_key), (1, opk.to_bytes()));
+            assert_eq!(allocate_pre_key_ids(&connection, 1)?, 2);
+            insert_received(&connection, 1, time_now())?;
+            assert!(is_received(&connection, 1)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn received_messages_expire() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        create_tables(&connection)?;
+        assert!(!is_received(&connection, 1)?);
+        insert_received(&connection, 1, 1000)?;
+        insert_received(&connection, 1, 1000)?;
+        assert!(is_received(&connection, 1)?);
+        assert!(!is_received(&connection, 2)?);
+
+        insert_received(&connection, 2, 1001 + RECEIVED_MESSAGE_TTL)?;
+        assert!(!is_received(&connection, 1)?);
+        assert!(is_received(&connection, 2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn identity_keys_saved_per_device() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        create_tables(&connection)?;
+        let phone = IdentityKey::generate().public_key();
+        let laptop = IdentityKey::generate().public_key();
+        let revoked = IdentityKey::generate().public_key();
+        let sorted = |mut keys: Vec<(IdentityPublicKey, bool)>| {
+            keys.sort_by_key(|(key, _)| key.to_bytes());
+            keys
+        };
+
+        assert!(!save_identity_key(&connection, "alice", &phone)?);
+        assert!(save_identity_key(&connection, "alice", &revoked)?);
+        set_verified(&connection, "alice", &[phone, laptop])?;
+        assert_eq!(
+            get_identity_keys(&connection, "alice")?,
+            sorted(vec![(phone, true), (laptop, true)])
+        );
+
+        // Messages from each device don't replace the keys of the others.
+        assert!(!save_identity_key(&connection, "alice", &phone)?);
+        assert!(!save_identity_key(&connection, "alice", &laptop)?);
+        assert_eq!(
+            get_identity_keys(&connection, "alice")?,
+            sorted(vec![(phone, true), (laptop, true)])
+        );
+
+        assert!(save_identity_key(&connection, "alice", &revoked)?);
+        assert_eq!(
+            get_identity_keys(&connection, "alice")?,
+            sorted(vec![(phone, true), (laptop, true), (revoked, false)])
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn fetch_pre_key() -> Result<()> {
         let connection = tokio_rusqlite::Connection::open_in_memory().await?;
         let client = X3DHClient::new(connection).await?;
         let spk = client.get_spk().await?;
-        client.get_pre_key(spk.pre_key).await?;
+        assert_eq!(
+            X25519PublicKey::from(&client.get_pre_key(spk.id).await?),
+            spk.pre_key
+        );
+        verify_pre_key(&client.get_ik().public_key(), &spk)?;
+        Ok(())
+    }
+
+    async fn new_client() -> Result<X3DHClient> {
+        Ok(X3DHClient::new(tokio_rusqlite::Connection::open_in_memory().await?).await?)
+    }
+
+    async fn send_initial_message(
+        sender: &X3DHClient,
+        recipient: &X3DHClient,
+        opk: Option<PreKey>,
+    ) -> Result<DeviceMessage> {
+        let bundle = PreKeyBundle {
+            ik: recipient.get_ik().public_key(),
+            opk,
+            spk: recipient.get_spk().await?,
+            pqpk: Some(recipient.get_last_resort_kem_pre_key().await?),
+        };
+        Ok(sender.start_session(bundle, b"Hello Bob!".to_vec()).await?)
+    }
+
+    #[tokio::test]
+    async fn pqxdh_initial_message_consumes_one_time_kem_pre_key() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let pqpk = bob.create_kem_pre_keys(1).await?.remove(0);
+        let bundle = PreKeyBundle {
+            ik: bob.get_ik().public_key(),
+            opk: None,
+            spk: bob.get_spk().await?,
+            pqpk: Some(pqpk.clone()),
+        };
+        let message = alice.start_session(bundle, b"Hello Bob!".to_vec()).await?;
+        assert!(message
+            .pre_keys
+            .as_ref()
+            .is_some_and(|pre_keys| pre_keys.pqpk.is_some()));
+
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        assert!(bob
+            .connection
+            .call(move |connection| Ok(load_kem_pre_key(connection, &pqpk.pre_key)?))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn configured_cipher_suite_encrypts_messages() -> Result<()> {
+        let alice = new_client()
+            .await?
+            .with_cipher_suite(CipherSuite::Aes256GcmSiv);
+        let bob = new_client().await?;
+        let message = send_initial_message(&alice, &bob, None).await?;
+        assert_eq!(
+            message.message.ciphertext[0],
+            CipherSuite::Aes256GcmSiv.version_tag()
+        );
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+
+        let message = alice
+            .encrypt_for_session(bob.get_ik().public_key(), b"Hello again!".to_vec())
+            .await?
+            .ok_or(anyhow!("missing session"))?;
+        assert_eq!(
+            message.message.ciphertext[0],
+            CipherSuite::Aes256GcmSiv.version_tag()
+        );
+        assert_eq!(bob.receive_message(&message).await?, b"Hello again!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bundle_without_kem_pre_key_rejected() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let bundle = PreKeyBundle {
+            ik: bob.get_ik().public_key(),
+            opk: None,
+            spk: bob.get_spk().await?,
+            pqpk: None,
+        };
+        assert!(matches!(
+            alice.start_session(bundle, b"Hello Bob!".to_vec()).await,
+            Err(ClientError::MissingKemPreKey)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_pre_key_message_keeps_one_time_pre_keys() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let opk = bob.create_opks(1).await?.pre_keys.remove(0);
+        let pqpk = bob.create_kem_pre_keys(1).await?.remove(0);
+        let bundle = PreKeyBundle {
+            ik: bob.get_ik().public_key(),
+            opk: Some(opk),
+            spk: bob.get_spk().await?,
+            pqpk: Some(pqpk),
+        };
+        let message = alice.start_session(bundle, b"Hello Bob!".to_vec()).await?;
+        let mut forged = message.clone();
+        forged.message.ciphertext[0] ^= 0xFF;
+        assert!(bob.receive_message(&forged).await.is_err());
+
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pqxdh_last_resort_kem_pre_key_reused() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let pqpk = bob.get_last_resort_kem_pre_key().await?;
+        for _ in 0..2 {
+            let bundle = PreKeyBundle {
+                ik: bob.get_ik().public_key(),
+                opk: None,
+                spk: bob.get_spk().await?,
+                pqpk: Some(pqpk.clone()),
+            };
+            let message = alice.start_session(bundle, b"Hello Bob!".to_vec()).await?;
+            assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_resort_pre_key_reused() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let last_resort = bob.get_last_resort_pre_key().await?;
+        verify_bundle(&bob.get_ik().public_key(), &last_resort)?;
+        assert_eq!(last_resort.pre_keys.len(), 1);
+        for _ in 0..2 {
+            let message = send_initial_message(&alice, &bob, Some(last_resort.pre_keys[0])).await?;
+            assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_initial_message_rejected() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let message = send_initial_message(&alice, &bob, None).await?;
+
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        assert!(matches!(
+            bob.receive_message(&message).await,
+            Err(ClientError::Replay)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_initial_message_opk_rejected() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let opk = bob.create_opks(1).await?.pre_keys[0];
+        let message = send_initial_message(&alice, &bob, Some(opk)).await?;
+
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        assert!(matches!(
+            bob.receive_message(&message).await,
+            Err(ClientError::Replay)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn distinct_initial_messages_accepted() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        for _ in 0..2 {
+            let message = send_initial_message(&alice, &bob, None).await?;
+            assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn initial_message_to_rotated_pre_key_accepted() -> Result<()> {
+        let conn = tokio_rusqlite::Connection::open_in_memory().await?;
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let old_spk = bob.get_spk().await?;
+        let message = send_initial_message(&new_client().await?, &bob, None).await?;
+
+        assert!(!bob.rotate_spk().await?);
+        conn.call(|connection| {
+            Ok(rotate_pre_key(
+                connection,
+                time_now() + SIGNED_PRE_KEY_ROTATION,
+            )?)
+        })
+        .await?;
+        let spk = bob.get_spk().await?;
+        assert_ne!(spk.id, old_spk.id);
+        assert!(spk.created > old_spk.created);
+
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_cache_scoped_to_pre_key() -> Result<()> {
+        let conn = tokio_rusqlite::Connection::open_in_memory().await?;
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let message = send_initial_message(&new_client().await?, &bob, None).await?;
+        bob.receive_message(&message).await?;
+
+        let spk_id = message.pre_keys.ok_or(anyhow!("no pre keys"))?.spk_id;
+        let remaining: u32 = conn
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM keys WHERE key_type = ?1 AND id = ?2",
+                    params![KeyType::Pre as u32, spk_id],
+                )?;
+                Ok(connection
+                    .query_row("SELECT COUNT(*) FROM replay_cache", [], |row| row.get(0))?)
+            })
+            .await?;
+        assert_eq!(remaining, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_reused_after_initial_message() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let bob_ik = bob.get_ik().public_key();
+        let alice_ik = alice.get_ik().public_key();
+        assert!(alice
+            .encrypt_for_session(bob_ik, b"Hello Bob!".to_vec())
+            .await?
+            .is_none());
+
+        let opk = bob.create_opks(1).await?.pre_keys[0];
+        let message = send_initial_message(&alice, &bob, Some(opk)).await?;
+        bob.receive_message(&message).await?;
+
+        let message = alice
+            .encrypt_for_session(bob_ik, b"Still there?".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert!(message.pre_keys.is_some());
+        assert_eq!(bob.receive_message(&message).await?, b"Still there?");
+
+        let reply = bob
+            .encrypt_for_session(alice_ik, b"Hello Alice!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert!(reply.pre_keys.is_none());
+        assert_eq!(alice.receive_message(&reply).await?, b"Hello Alice!");
+
+        let message = alice
+            .encrypt_for_session(bob_ik, b"Bye Bob!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert!(message.pre_keys.is_none());
+        assert_eq!(bob.receive_message(&message).await?, b"Bye Bob!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_started_by_later_prekey_message() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let bob_ik = bob.get_ik().public_key();
+        let opk = bob.create_opks(1).await?.pre_keys[0];
+        let lost = send_initial_message(&alice, &bob, Some(opk)).await?;
+        let message = alice
+            .encrypt_for_session(bob_ik, b"Still there?".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+
+        assert_eq!(bob.receive_message(&message).await?, b"Still there?");
+        assert!(matches!(
+            bob.receive_message(&message).await,
+            Err(ClientError::Replay)
+        ));
+        // The first message can still be decrypted if it arrives late.
+        assert_eq!(bob.receive_message(&lost).await?, b"Hello Bob!");
+        Ok(())
+    }
+
+    /// Starts a session from `sender` to `recipient` that `recipient` has replied to, so that
+    /// messages of the session are no longer prekey messages.
+    async fn start_acknowledged_session(sender: &X3DHClient, recipient: &X3DHClient) -> Result<()> {
+        let message = send_initial_message(sender, recipient, None).await?;
+        recipient.receive_message(&message).await?;
+        let reply = recipient
+            .encrypt_for_session(sender.get_ik().public_key(), b"Hello Alice!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        sender.receive_message(&reply).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_pre_key_message_keeps_session() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let alice_ik = alice.get_ik().public_key();
+        let bob_ik = bob.get_ik().public_key();
+        let message = send_initial_message(&alice, &bob, None).await?;
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        let reply = bob
+            .encrypt_for_session(alice_ik, b"Hello Alice!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert_eq!(alice.receive_message(&reply).await?, b"Hello Alice!");
+
+        assert!(matches!(
+            bob.receive_message(&message).await,
+            Err(ClientError::Replay)
+        ));
+        let message = alice
+            .encrypt_for_session(bob_ik, b"Still there?".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert_eq!(bob.receive_message(&message).await?, b"Still there?");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_pre_key_message_keeps_session() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let alice_ik = alice.get_ik().public_key();
+        let bob_ik = bob.get_ik().public_key();
+        // Bob's session is delivered after Bob has accepted the session that Alice started.
+        let stale = send_initial_message(&bob, &alice, None).await?;
+        start_acknowledged_session(&alice, &bob).await?;
+        assert_eq!(alice.receive_message(&stale).await?, b"Hello Bob!");
+        assert!(matches!(
+            alice.receive_message(&stale).await,
+            Err(ClientError::Replay)
+        ));
+
+        let message = bob
+            .encrypt_for_session(alice_ik, b"Still there?".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert!(message.pre_keys.is_none());
+        assert_eq!(alice.receive_message(&message).await?, b"Still there?");
+        let reply = alice
+            .encrypt_for_session(bob_ik, b"Yes!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        assert!(reply.pre_keys.is_none());
+        assert_eq!(bob.receive_message(&reply).await?, b"Yes!");
+        Ok(())
+    }
+
+    /// Serializes `message` the way clients from before messages had types did.
+    fn untyped(message: DeviceMessage) -> Result<DeviceMessage> {
+        let mut proto = MessageProto::from(message);
+        proto.set_type(MessageType::Unspecified);
+        Ok(proto.try_into()?)
+    }
+
+    #[tokio::test]
+    async fn untyped_messages_accepted() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let alice_ik = alice.get_ik().public_key();
+        let message = untyped(send_initial_message(&alice, &bob, None).await?)?;
+        assert!(message.pre_keys.is_some());
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+
+        let reply = bob
+            .encrypt_for_session(alice_ik, b"Hello Alice!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        let reply = untyped(reply)?;
+        assert!(reply.pre_keys.is_none());
+        assert_eq!(alice.receive_message(&reply).await?, b"Hello Alice!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_message_without_session_rejected() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let carol = new_client().await?;
+        start_acknowledged_session(&alice, &bob).await?;
+        let message = alice
+            .encrypt_for_session(bob.get_ik().public_key(), b"Hello Bob!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+
+        assert!(matches!(
+            carol.receive_message(&message).await,
+            Err(ClientError::NoSession)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_session_message_preserves_session() -> Result<()> {
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let bob_ik = bob.get_ik().public_key();
+        start_acknowledged_session(&alice, &bob).await?;
+
+        let mut message = alice
+            .encrypt_for_session(bob_ik, b"Hello Bob!".to_vec())
+            .await?
+            .ok_or(anyhow!("no session"))?;
+        let ciphertext = message.message.ciphertext.clone();
+        message.message.ciphertext = b"invalid ciphertext".to_vec();
+        assert!(matches!(
+            bob.receive_message(&message).await,
+            Err(ClientError::Ratchet(_))
+        ));
+
+        message.message.ciphertext = ciphertext;
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        Ok(())
+    }
+
+    fn random_header() -> Header {
+        Header {
+            ratchet_key: X25519PublicKey::from(&X25519StaticSecret::random()),
+            message_number: 0,
+            chain_length: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_limited_per_message() -> Result<()> {
+        let alice = X3DHClient::new(tokio_rusqlite::Connection::open_in_memory().await?).await?;
+        let bob_ik = IdentityKey::generate().public_key();
+        let id = alice
+            .persist_message(
+                "alice".into(),
+                "bob".into(),
+                "hi".into(),
+                MessageState::Sent,
+            )
+            .await?;
+
+        let mut header = random_header();
+        alice.persist_outgoing(header, bob_ik, id, 0).await?;
+        for retries in 0..MAX_MESSAGE_RETRIES {
+            assert_eq!(
+                alice.get_retry(header.ratchet_key, 0, bob_ik).await?,
+                Some((id, retries))
+            );
+            header = random_header();
+            alice
+                .persist_outgoing(header, bob_ik, id, retries + 1)
+                .await?;
+        }
+        assert_eq!(alice.get_retry(header.ratchet_key, 0, bob_ik).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_of_first_attempt_limited() -> Result<()> {
+        let alice = X3DHClient::new(tokio_rusqlite::Connection::open_in_memory().await?).await?;
+        let bob_ik = IdentityKey::generate().public_key();
+        let id = alice
+            .persist_message(
+                "alice".into(),
+                "bob".into(),
+                "hi".into(),
+                MessageState::Sent,
+            )
+            .await?;
+
+        // Every retry request names the header of the first attempt.
+        let header = random_header();
+        alice.persist_outgoing(header, bob_ik, id, 0).await?;
+        for _ in 0..MAX_MESSAGE_RETRIES {
+            let (message_id, retries) = alice
+                .get_retry(header.ratchet_key, 0, bob_ik)
+                .await?
+                .ok_or(anyhow!("retry ignored"))?;
+            alice
+                .persist_outgoing(random_header(), bob_ik, message_id, retries + 1)
+                .await?;
+        }
+        assert_eq!(alice.get_retry(header.ratchet_key, 0, bob_ik).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_requires_original_recipient() -> Result<()> {
+        let alice = X3DHClient::new(tokio_rusqlite::Connection::open_in_memory().await?).await?;
+        let bob_ik = IdentityKey::generate().public_key();
+        let mallory_ik = IdentityKey::generate().public_key();
+        let header = random_header();
+        alice.persist_outgoing(header, bob_ik, 1, 0).await?;
+
+        assert_eq!(
+            alice.get_retry(header.ratchet_key, 0, mallory_ik).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_requests_rate_limited() -> Result<()> {
+        let bob = X3DHClient::new(tokio_rusqlite::Connection::open_in_memory().await?).await?;
+        let alice_ik = IdentityKey::generate().public_key();
+
+        let header = random_header();
+        assert!(bob.allow_retry_request(header, alice_ik).await?);
+        assert!(!bob.allow_retry_request(header, alice_ik).await?);
+
+        for _ in 1..MAX_RETRY_REQUESTS_PER_HOUR {
+            assert!(bob.allow_retry_request(random_header(), alice_ik).await?);
+        }
+        let header = random_header();
+        assert!(!bob.allow_retry_request(header, alice_ik).await?);
+
+        let carol_ik = IdentityKey::generate().public_key();
+        assert!(bob.allow_retry_request(header, carol_ik).await?);
+        Ok(())
+    }
+
+    fn issue_certificate(server_key: &IdentityKey, sender: &X3DHClient) -> SenderCertificate {
+        SenderCertificate::issue(
+            server_key,
+            sender.get_ik().public_key(),
+            time_now() + 24 * 60 * 60,
+        )
+    }
+
+    #[tokio::test]
+    async fn sealed_message_reveals_sender() -> Result<()> {
+        let server_key = IdentityKey::generate();
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        alice.trust_server_key(server_key.public_key()).await?;
+        bob.trust_server_key(server_key.public_key()).await?;
+        alice
+            .set_sender_certificate(issue_certificate(&server_key, &alice))
+            .await?;
+        let certificate = alice
+            .get_sender_certificate()
+            .ok_or(anyhow!("no certificate"))?;
+
+        let message = send_initial_message(&alice, &bob, None).await?;
+        let sealed = alice.seal(&bob.get_ik().public_key(), message, certificate)?;
+        let message = bob.unseal(&sealed).await?;
+        assert_eq!(message.ik, alice.get_ik().public_key());
+        assert_eq!(bob.receive_message(&message).await?, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sealed_message_requires_trusted_certificate() -> Result<()> {
+        let server_key = IdentityKey::generate();
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let message = send_initial_message(&alice, &bob, None).await?;
+        let sealed = alice.seal(
+            &bob.get_ik().public_key(),
+            message,
+            issue_certificate(&server_key, &alice),
+        )?;
+        assert!(matches!(
+            bob.unseal(&sealed).await,
+            Err(ClientError::UntrustedServerKey)
+        ));
+
+        bob.trust_server_key(IdentityKey::generate().public_key())
+            .await?;
+        assert!(matches!(
+            bob.unseal(&sealed).await,
+            Err(ClientError::SealedSender(
+                SealedSenderError::CertificateSignature
+            ))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sealed_message_certificate_must_match_sender() -> Result<()> {
+        let server_key = IdentityKey::generate();
+        let alice = new_client().await?;
+        let bob = new_client().await?;
+        let eve = new_client().await?;
+        bob.trust_server_key(server_key.public_key()).await?;
+
+        let message = send_initial_message(&eve, &bob, None).await?;
+        let sealed = eve.seal(
+            &bob.get_ik().public_key(),
+            message,
+            issue_certificate(&server_key, &alice),
+        )?;
+        assert!(matches!(
+            bob.unseal(&sealed).await,
+            Err(ClientError::SealedSender(SealedSenderError::SenderMismatch))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trusted_server_key_pinned() -> Result<()> {
+        let server_key = IdentityKey::generate().public_key();
+        let client = new_client().await?;
+        client.trust_server_key(server_key).await?;
+        client.trust_server_key(server_key).await?;
+        assert!(matches!(
+            client
+                .trust_server_key(IdentityKey::generate().public_key())
+                .await,
+            Err(ClientError::UntrustedServerKey)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_attachment() -> Result<()> {
+        let client = new_client().await?;
+        let pointer = AttachmentPointer {
+            digest: [1; 32],
+            key: SecretKey::from([2; 32]),
+            size: 3,
+            content_type: String::from("image/png"),
+            caption: None,
+        };
+        let id = client
+            .persist_message(
+                String::from("alice"),
+                String::from("bob"),
+                String::new(),
+                MessageState::Delivered,
+            )
+            .await?;
+        assert_eq!(client.get_attachment(id).await?, None);
+        client.persist_attachment(id, pointer.clone()).await?;
+        assert_eq!(client.get_attachment(id).await?, Some(pointer));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_received_message_records_id() -> Result<()> {
+        let client = new_client().await?;
+        let pointer = AttachmentPointer {
+            digest: [1; 32],
+            key: SecretKey::from([2; 32]),
+            size: 3,
+            content_type: String::from("image/png"),
+            caption: None,
+        };
+        assert!(!client.is_message_received(7).await?);
+        let id = client
+            .persist_received_message(
+                Some(7),
+                String::from("alice"),
+                String::from("bob"),
+                String::new(),
+                Some(pointer.clone()),
+            )
+            .await?;
+        assert!(client.is_message_received(7).await?);
+        assert_eq!(client.get_attachment(id).await?, Some(pointer));
         Ok(())
     }
 
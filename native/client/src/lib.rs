@@ -1,39 +1,54 @@
 #![feature(trivial_bounds)]
 #![feature(iterator_try_collect)]
-use anyhow::Context;
-use async_stream::{stream, try_stream};
+use async_stream::try_stream;
 use blake2::{Blake2b, Digest};
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
 pub use client::X3DHClient;
 use client::{MessageState};
-use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
 use prost::Message as _;
 use proto::application::Message as ApplicationMessageProto;
-use proto::application::RatchetMessage as RatchetProto;
 use proto::gossamer::gossamer_service_client::GossamerServiceClient;
 use proto::gossamer::{ActionRequest, GetLedgerRequest, Ledger as LedgerProto, SignedMessage};
 use proto::service::brongnal_service_client::BrongnalServiceClient;
 use proto::service::{
+    AcknowledgeMessagesRequest, ChallengeRequest, DownloadAttachmentRequest,
     Message as MessageProto, PreKeyBundleRequest, RegisterPreKeyBundleRequest,
-    RetrieveMessagesRequest, SendMessageRequest,
+    RetrieveMessagesRequest, SendMessageRequest, SenderCertificateRequest, UploadAttachmentRequest,
 };
-use proto::{parse_verifying_key, ApplicationMessage, RatchetMessage};
-use protocol::x3dh::{
-    initiate_recv, initiate_send, Message as X3DHMessage, PreKeyBundle, X3DHError,
+use proto::{
+    parse_identity_key, ApplicationMessage, AttachmentPointer, DeviceMessage, Envelope,
+    MessageContents,
 };
+use protocol::attachment::{
+    blob_len, decrypt_attachment, encrypt_attachment, AttachmentError, EncryptedAttachment,
+};
+use protocol::auth::{
+    acknowledgement_contents, SignedRequest, ACKNOWLEDGE_MESSAGES_PREFIX, PRE_KEY_REQUEST_PREFIX,
+    RETRIEVE_MESSAGES_PREFIX, UPLOAD_ATTACHMENT_PREFIX,
+};
+use protocol::bundle::BundleError;
+use protocol::fingerprint::{Fingerprint, FingerprintError};
+use protocol::ratchet::{Header as RatchetHeader, RatchetError};
+use protocol::sealed_sender::{SealedSenderError, SenderCertificate};
+use protocol::x3dh::{PreKeyBundle, X3DHError};
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
 use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::sync::Arc;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 use thiserror::Error;
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::SendError;
-use tokio_stream::Stream;
-use tokio_stream::StreamExt;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use tonic::transport::Channel;
 use tonic::{Request, Streaming};
 use tracing::{error, info, warn};
+use x25519_dalek::PublicKey as X25519PublicKey;
 
-use crate::client::MessageModel;
+use crate::client::{time_now, MessageModel};
 
 pub mod client;
 
@@ -41,6 +56,15 @@ type BrongnalClient = BrongnalServiceClient<Channel>;
 type GossamerClient = GossamerServiceClient<Channel>;
 type ClientResult<T> = Result<T, ClientError>;
 
+/// Attachments are uploaded in chunks of this size.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// How often a running client checks whether its signed pre key is due for rotation.
+const PRE_KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The number of chunks of an attachment that are buffered between the network and the cipher.
+const ATTACHMENT_CHANNEL_CHUNKS: usize = 4;
+/// The most redelivered messages that are acknowledged in one request.
+const MAX_ACKNOWLEDGEMENT_BATCH: usize = 100;
+
 #[derive(Error, Debug)]
 pub enum ClientError {
     #[error("failed to load identity key")]
@@ -57,52 +81,34 @@ pub enum ClientError {
     WipeOpk(String),
     #[error("failed to retrieve pre key")]
     GetPreKey(rusqlite::Error),
+    #[error("initial message was already received")]
+    Replay,
+    #[error("no session with peer")]
+    NoSession,
     #[error("grpc error: {0}")]
     Grpc(#[from] tonic::Status),
     #[error("send decrypted message error: {0}")]
     Send(#[from] SendError<ApplicationMessageProto>),
     #[error("x3dh error: {0}")]
     X3DH(#[from] X3DHError),
+    #[error("ratchet error: {0}")]
+    Ratchet(#[from] RatchetError),
     #[error("decode error: {0}")]
     Decode(#[from] prost::DecodeError),
-}
-
-#[allow(dead_code)]
-struct SessionKeys<T> {
-    session_keys: HashMap<T, [u8; 32]>,
-}
-
-#[allow(dead_code)]
-impl<Identity: Eq + std::hash::Hash> SessionKeys<Identity> {
-    fn set_session_key(&mut self, recipient_identity: Identity, secret_key: &[u8; 32]) {
-        self.session_keys.insert(recipient_identity, *secret_key);
-    }
-
-    fn get_encryption_key(
-        &mut self,
-        recipient_identity: &Identity,
-    ) -> anyhow::Result<ChaCha20Poly1305> {
-        let key = self
-            .session_keys
-            .get(recipient_identity)
-            .context("Session key not found.")?;
-        Ok(ChaCha20Poly1305::new_from_slice(key).unwrap())
-    }
-
-    fn destroy_session_key(&mut self, peer: &Identity) {
-        self.session_keys.remove(peer);
-    }
-}
-
-fn into_message_stream(
-    mut stream: Streaming<MessageProto>,
-) -> impl Stream<Item = ClientResult<X3DHMessage>> {
-    try_stream! {
-        while let Some(message) = stream.message().await? {
-            let message: X3DHMessage = message.try_into()?;
-            yield message;
-        }
-    }
+    #[error("sealed sender error: {0}")]
+    SealedSender(#[from] SealedSenderError),
+    #[error("sender certificate is not signed by the trusted server key")]
+    UntrustedServerKey,
+    #[error("fingerprint error: {0}")]
+    Fingerprint(#[from] FingerprintError),
+    #[error("no identity key for user: {0}")]
+    UnknownIdentityKey(String),
+    #[error("attachment error: {0}")]
+    Attachment(#[from] AttachmentError),
+    #[error("pre key bundle has no KEM pre key")]
+    MissingKemPreKey,
+    #[error("bundle error: {0}")]
+    Bundle(#[from] BundleError),
 }
 
 #[derive(Clone)]
@@ -114,10 +120,10 @@ pub struct User {
 }
 
 pub trait Ledger: Send + Sync {
-    fn validate_username(&self, username: &str, ik: &VerifyingKey) -> bool;
+    fn validate_username(&self, username: &str, ik: &IdentityPublicKey) -> bool;
 }
 
-struct HashLedger(HashMap<Vec<u8>, Vec<VerifyingKey>>);
+struct HashLedger(HashMap<Vec<u8>, Vec<IdentityPublicKey>>);
 
 impl From<LedgerProto> for HashLedger {
     fn from(ledger: LedgerProto) -> Self {
@@ -130,7 +136,7 @@ impl From<LedgerProto> for HashLedger {
                         user.provider.unwrap(),
                         user.public_keys
                             .into_iter()
-                            .map(|key| parse_verifying_key(&key).unwrap())
+                            .map(|key| parse_identity_key(&key).unwrap())
                             .collect(),
                     )
                 })
@@ -140,7 +146,7 @@ impl From<LedgerProto> for HashLedger {
 }
 
 impl Ledger for HashLedger {
-    fn validate_username(&self, username: &str, ik: &VerifyingKey) -> bool {
+    fn validate_username(&self, username: &str, ik: &IdentityPublicKey) -> bool {
         let provider =
             Blake2b::<blake2::digest::typenum::U32>::digest(username.as_bytes()).to_vec();
         match self.0.get(&provider) {
@@ -152,68 +158,240 @@ impl Ledger for HashLedger {
 
 pub struct MessageSubscriber {
     stream: Streaming<MessageProto>,
-    ik: SigningKey,
+    handler: MessageHandler,
+}
+
+struct MessageHandler {
+    brongnal: BrongnalClient,
     x3dh: Arc<X3DHClient>,
     ledger: Box<dyn Ledger>,
     username: String,
 }
 
 impl MessageSubscriber {
-    pub fn into_stream(self) -> impl Stream<Item = ClientResult<MessageModel>> {
+    /// Messages are acknowledged once they are handled, whether or not they could be decrypted,
+    /// so that the server deletes them. Redelivered messages are acknowledged without being
+    /// handled again, in batches of up to `MAX_ACKNOWLEDGEMENT_BATCH` ids.
+    pub fn into_stream(mut self) -> impl Stream<Item = ClientResult<MessageModel>> {
         try_stream! {
-            let messages = into_message_stream(self.stream);
-            tokio::pin!(messages);
-            while let Some(message) = messages.next().await {
-                if let Err(e) = message {
-                    warn!("Message was not validly serialized: {e}");
-                    continue;
-                }
-                let message = message.unwrap();
-                let res = {
-                    let opk = if let Some(opk) = message.opk {
-                        Some(self.x3dh.fetch_wipe_opk(opk).await?)
-                    } else {
-                        None
-                    };
-                    // TODO: Caller must delete the session keys with the peer on an error.
-                    let (_sk, decrypted) = initiate_recv(
-                        &self.ik,
-                        &self.x3dh.get_pre_key(message.pre_key).await?,
-                        &message.ik,
-                        message.ek,
-                        opk,
-                        &message.ciphertext,
-                    )?;
-                    // TODO: Handle the ratchet header.
-                    let ratchet_message: RatchetMessage = RatchetProto::decode(&*decrypted)?.try_into()?;
-                    if !self.ledger.validate_username(&ratchet_message.message.sender, &message.ik) {
-                        warn!("Message failed username validation. Claimed sender: {}", &ratchet_message.message.sender);
-                        continue;
+            let mut redelivered = Vec::new();
+            while let Some(message) = self.next_message(&mut redelivered).await? {
+                let Some(id) = message.id else {
+                    if let Some(decrypted) = self.handler.receive(message, None).await {
+                        yield decrypted;
                     }
-                    let application_message = ratchet_message.message;
-                    let ApplicationMessage {
-                        sender,
-                        text
-                    } = application_message;
-                    let _id = self.x3dh.persist_message(sender.clone(), self.username.clone(), text.clone(), MessageState::Delivered).await?;
-                    Ok::<MessageModel, ClientError>(MessageModel {
-                        sender,
-                        receiver: self.username.clone(),
-                        db_recv_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
-                        state: MessageState::Delivered,
-                        text,
-                    })
+                    continue;
                 };
-                match res {
-                    Ok(decrypted) => yield decrypted,
-                    Err(e) => {
-                        error!("Failed to decrypt message: {e}");
+                if self.handler.x3dh.is_message_received(id).await? {
+                    info!("Ignoring redelivered message {id}.");
+                    redelivered.push(id);
+                    if redelivered.len() >= MAX_ACKNOWLEDGEMENT_BATCH {
+                        self.handler.acknowledge(std::mem::take(&mut redelivered)).await;
                     }
+                    continue;
+                }
+                let decrypted = self.handler.receive(message, Some(id)).await;
+                if decrypted.is_none() {
+                    // Messages that are not persisted are still only handled once.
+                    self.handler.x3dh.mark_message_received(id).await?;
+                }
+                redelivered.push(id);
+                self.handler.acknowledge(std::mem::take(&mut redelivered)).await;
+                if let Some(decrypted) = decrypted {
+                    yield decrypted;
                 }
             }
+            self.handler.acknowledge(redelivered).await;
             warn!("Server terminated message stream.");
         }
     }
+
+    /// Returns the next message from the server, first acknowledging the `redelivered` messages if
+    /// no message is ready yet.
+    async fn next_message(
+        &mut self,
+        redelivered: &mut Vec<u64>,
+    ) -> Result<Option<MessageProto>, tonic::Status> {
+        if !redelivered.is_empty() {
+            tokio::select! {
+                biased;
+                message = self.stream.message() => return message,
+                () = std::future::ready(()) => {
+                    self.handler.acknowledge(std::mem::take(redelivered)).await;
+                }
+            }
+        }
+        self.stream.message().await
+    }
+}
+
+impl MessageHandler {
+    /// Unseals, decrypts and persists a message from the server, recording its server assigned
+    /// `id` with it.
+    /// Returns `None` if the message is invalid or not displayed to the user.
+    async fn receive(&self, message: MessageProto, id: Option<u64>) -> Option<MessageModel> {
+        let envelope: Envelope = match message.try_into() {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Message was not validly serialized: {e}");
+                return None;
+            }
+        };
+        let message = match self.open_envelope(envelope).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to unseal message: {e}");
+                return None;
+            }
+        };
+        match self.handle_message(message, id).await {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                error!("Failed to decrypt message: {e}");
+                None
+            }
+        }
+    }
+
+    /// Asks the server to delete received messages from the mailbox.
+    /// Messages that fail to be acknowledged are redelivered and acknowledged again later.
+    async fn acknowledge(&self, ids: Vec<u64>) {
+        if ids.is_empty() {
+            return;
+        }
+        if let Err(e) = acknowledge_messages(&mut self.brongnal.clone(), &self.x3dh, ids).await {
+            warn!("Failed to acknowledge messages: {e}");
+        }
+    }
+
+    /// Reveals the sender of a sealed message.
+    /// The claimed username is still validated against the ledger by `handle_message`.
+    async fn open_envelope(&self, envelope: Envelope) -> ClientResult<DeviceMessage> {
+        match envelope {
+            Envelope::Sealed(sealed) => self.x3dh.unseal(&sealed).await,
+            Envelope::Device(message) => Ok(*message),
+        }
+    }
+
+    /// Decrypts and persists a message.
+    /// Returns `None` for valid messages that are not displayed to the user.
+    async fn handle_message(
+        &self,
+        message: DeviceMessage,
+        server_id: Option<u64>,
+    ) -> ClientResult<Option<MessageModel>> {
+        let decrypted = match self.x3dh.receive_message(&message).await {
+            Ok(decrypted) => decrypted,
+            Err(ClientError::Replay) => return Err(ClientError::Replay),
+            Err(e) => {
+                if let Err(e) = self.request_retry(&message).await {
+                    error!("Failed to request retry: {e}");
+                }
+                return Err(e);
+            }
+        };
+        let ApplicationMessage { sender, contents } =
+            ApplicationMessageProto::decode(&*decrypted)?.try_into()?;
+        if !self.ledger.validate_username(&sender, &message.ik) {
+            warn!("Message failed username validation. Claimed sender: {sender}");
+            return Ok(None);
+        }
+        if self
+            .x3dh
+            .save_identity_key(sender.clone(), message.ik)
+            .await?
+        {
+            warn!("New identity key for {sender}.");
+        }
+        let (text, attachment) = match contents {
+            MessageContents::Text(text) => (text, None),
+            MessageContents::Attachment(pointer) => {
+                (pointer.caption.clone().unwrap_or_default(), Some(pointer))
+            }
+            MessageContents::RetryRequest {
+                ratchet_key,
+                message_number,
+            } => {
+                self.handle_retry_request(ratchet_key, message_number, message.ik)
+                    .await?;
+                return Ok(None);
+            }
+        };
+        self.x3dh
+            .persist_received_message(
+                server_id,
+                sender.clone(),
+                self.username.clone(),
+                text.clone(),
+                attachment,
+            )
+            .await?;
+        Ok(Some(MessageModel {
+            sender,
+            receiver: self.username.clone(),
+            db_recv_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            state: MessageState::Delivered,
+            text,
+        }))
+    }
+
+    /// Asks the sender of a message that failed to decrypt to resend it.
+    async fn request_retry(&self, message: &DeviceMessage) -> ClientResult<()> {
+        let header = message.message.header;
+        if !self.x3dh.allow_retry_request(header, message.ik).await? {
+            warn!("Not requesting a retry: already requested or sender exceeded retry limit.");
+            return Ok(());
+        }
+        info!("Requesting retry of message.");
+        let retry_request = ApplicationMessage {
+            sender: self.username.clone(),
+            contents: MessageContents::RetryRequest {
+                ratchet_key: header.ratchet_key,
+                message_number: header.message_number,
+            },
+        };
+        send_to_device(
+            &mut self.brongnal.clone(),
+            &self.x3dh,
+            message.ik,
+            retry_request,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Re-encrypts the message sent with `ratchet_key` and `message_number` for `requester`
+    /// using a fresh prekey bundle.
+    async fn handle_retry_request(
+        &self,
+        ratchet_key: X25519PublicKey,
+        message_number: u32,
+        requester: IdentityPublicKey,
+    ) -> ClientResult<()> {
+        let Some((message_id, retries)) = self
+            .x3dh
+            .get_retry(ratchet_key, message_number, requester)
+            .await?
+        else {
+            warn!("Ignoring retry request for unknown message or exhausted retries.");
+            return Ok(());
+        };
+        info!("Resending message {message_id}. Attempt {}.", retries + 1);
+        let text = self.x3dh.get_message(message_id).await?.text;
+        let attachment = self.x3dh.get_attachment(message_id).await?;
+        let message = ApplicationMessage {
+            sender: self.username.clone(),
+            contents: message_contents(text, attachment),
+        };
+        let header =
+            send_to_device(&mut self.brongnal.clone(), &self.x3dh, requester, message).await?;
+        self.x3dh
+            .persist_outgoing(header, requester, message_id, retries + 1)
+            .await
+    }
 }
 
 impl User {
@@ -247,54 +425,140 @@ impl User {
         Ok(())
     }
 
+    /// Rotates the signed pre key on a timer for as long as the returned task runs.
+    /// A rotated key is uploaded to the server, and retried on the next tick if that fails.
+    pub fn spawn_pre_key_rotation(&self) -> JoinHandle<()> {
+        let mut brongnal = self.brongnal.clone();
+        let x3dh = self.x3dh.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRE_KEY_ROTATION_INTERVAL);
+            let mut pending_upload = false;
+            loop {
+                interval.tick().await;
+                match x3dh.rotate_spk().await {
+                    Ok(rotated) => pending_upload |= rotated,
+                    Err(e) => warn!("Failed to rotate signed pre key: {e}"),
+                }
+                if !pending_upload {
+                    continue;
+                }
+                match upload_spk(&mut brongnal, &x3dh).await {
+                    Ok(()) => {
+                        info!("Uploaded rotated signed pre key.");
+                        pending_upload = false;
+                    }
+                    Err(e) => warn!("Failed to upload rotated signed pre key: {e}"),
+                }
+            }
+        })
+    }
+
     pub async fn get_messages(&self) -> ClientResult<MessageSubscriber> {
         let mut brongnal = self.brongnal.clone();
         let mut gossamer = self.gossamer.clone();
         let ik = self.x3dh.get_ik();
+        // Trusts the server key that signs the sender certificates of sealed messages.
+        get_sender_certificate(&mut brongnal, &self.x3dh).await?;
+        let nonce = brongnal
+            .get_challenge(ChallengeRequest {
+                identity_key: Some(ik.public_key().encode()),
+            })
+            .await?
+            .into_inner()
+            .nonce
+            .unwrap_or_default();
+        let proof = SignedRequest::sign(ik, RETRIEVE_MESSAGES_PREFIX, &nonce, time_now());
         let stream = brongnal
             .retrieve_messages(RetrieveMessagesRequest {
-                identity_key: Some(ik.verifying_key().as_bytes().to_vec()),
+                identity_key: Some(ik.public_key().encode()),
+                nonce: Some(nonce),
+                proof: Some(proof.into()),
             })
             .await?
             .into_inner();
         let ledger: Box<HashLedger> = Box::new(get_ledger(&mut gossamer).await?.into());
         Ok(MessageSubscriber {
             stream,
-            ik,
-            x3dh: self.x3dh.clone(),
-            ledger,
-            username: self.username.clone(),
+            handler: MessageHandler {
+                brongnal: self.brongnal.clone(),
+                x3dh: self.x3dh.clone(),
+                ledger,
+                username: self.username.clone(),
+            },
         })
     }
 
     pub async fn send_message(&self, peer_username: String, message: String) -> ClientResult<i64> {
+        self.send(peer_username, message, None).await
+    }
+
+    /// Encrypts and uploads `attachment` chunk by chunk, then sends a pointer to it to
+    /// `peer_username`.
+    pub async fn send_attachment(
+        &self,
+        peer_username: String,
+        attachment: Vec<u8>,
+        content_type: String,
+        caption: Option<String>,
+    ) -> ClientResult<i64> {
         let mut brongnal = self.brongnal.clone();
-        let mut gossamer = self.gossamer.clone();
-        // TODO: Create Ratchet Header
-        let ratchet_message = RatchetMessage {
-            header: None,
-            message: ApplicationMessage {
-                sender: self.username.clone(),
-                text: message.clone(),
-            },
+        let (sender, receiver) = mpsc::channel(ATTACHMENT_CHANNEL_CHUNKS);
+        let encrypt = tokio::task::spawn_blocking(move || {
+            encrypt_attachment(
+                attachment.as_slice(),
+                ChunkWriter {
+                    sender,
+                    buffer: Vec::with_capacity(UPLOAD_CHUNK_SIZE),
+                },
+            )
+        });
+        let uploaded_digest = upload_attachment(
+            &mut brongnal,
+            self.x3dh.get_ik(),
+            ReceiverStream::new(receiver),
+        )
+        .await?;
+        let EncryptedAttachment { key, digest, size } = encrypt
+            .await
+            .map_err(|e| AttachmentError::Io(io::Error::other(e)))?
+            .map_err(AttachmentError::Io)?;
+        if uploaded_digest != digest {
+            return Err(AttachmentError::Digest.into());
+        }
+        let pointer = AttachmentPointer {
+            digest,
+            key,
+            size,
+            content_type,
+            caption: caption.clone(),
         };
-        let ik = self.x3dh.get_ik();
+        self.send(peer_username, caption.unwrap_or_default(), Some(pointer))
+            .await
+    }
+
+    /// Sends `message`, or `attachment` with `message` as its caption.
+    async fn send(
+        &self,
+        peer_username: String,
+        message: String,
+        attachment: Option<AttachmentPointer>,
+    ) -> ClientResult<i64> {
+        let mut brongnal = self.brongnal.clone();
+        let mut gossamer = self.gossamer.clone();
+        let plaintext = ApplicationMessageProto::from(ApplicationMessage {
+            sender: self.username.clone(),
+            contents: message_contents(message.clone(), attachment.clone()),
+        })
+        .encode_to_vec();
         let keys = get_keys(&mut gossamer, &peer_username).await?;
-        let mut bundles = Vec::with_capacity(keys.len());
-        for key in keys {
-            let recipient = key.as_bytes().to_vec();
-            let request = Request::new(PreKeyBundleRequest {
-                identity_key: Some(recipient),
-            });
-            bundles.push(
-                brongnal
-                    .request_pre_keys(request)
-                    .await?
-                    .into_inner()
-                    .try_into()?,
-            );
+        let mut messages = Vec::with_capacity(keys.len());
+        for recipient in keys {
+            match encrypt_for_device(&mut brongnal, &self.x3dh, recipient, plaintext.clone()).await
+            {
+                Ok(message) => messages.push((recipient, message)),
+                Err(e) => error!("Failed to encrypt message for device: {e}"),
+            }
         }
-        let requests = send_message_requests(bundles, ik, ratchet_message);
 
         let row_id = self
             .x3dh
@@ -305,7 +569,22 @@ impl User {
                 MessageState::Sending,
             )
             .await?;
-        brongnal.send_message(Request::new(requests)).await?;
+        if let Some(attachment) = attachment {
+            self.x3dh.persist_attachment(row_id, attachment).await?;
+        }
+        for (recipient, message) in &messages {
+            self.x3dh
+                .persist_outgoing(message.message.header, *recipient, row_id, 0)
+                .await?;
+        }
+        let certificate = get_sender_certificate(&mut brongnal, &self.x3dh).await?;
+        let requests = messages
+            .into_iter()
+            .map(|message| send_message_request(&self.x3dh, &certificate, message))
+            .collect::<Result<Vec<_>, _>>()?;
+        brongnal
+            .send_message(Request::new(tokio_stream::iter(requests)))
+            .await?;
         self.x3dh
             .persist_message_state(row_id, MessageState::Sent)
             .await?;
@@ -316,6 +595,112 @@ impl User {
         self.x3dh.get_message(id).await
     }
 
+    /// Returns the attachment of the message with `id`, if it has one.
+    pub async fn get_attachment(&self, id: i64) -> ClientResult<Option<AttachmentPointer>> {
+        self.x3dh.get_attachment(id).await
+    }
+
+    /// Downloads an attachment and decrypts it as it arrives.
+    /// The plaintext is only returned if the blob matches the size and digest of `pointer`.
+    pub async fn download_attachment(&self, pointer: AttachmentPointer) -> ClientResult<Vec<u8>> {
+        let mut stream = self
+            .brongnal
+            .clone()
+            .download_attachment(DownloadAttachmentRequest {
+                digest: Some(pointer.digest.to_vec()),
+            })
+            .await?
+            .into_inner();
+        let mut next = stream.message().await?;
+        // A blob of the wrong size is rejected before any of it is decrypted.
+        if next.as_ref().and_then(|response| response.size) != Some(blob_len(pointer.size)) {
+            return Err(AttachmentError::Size.into());
+        }
+
+        let (sender, receiver) = mpsc::channel(ATTACHMENT_CHANNEL_CHUNKS);
+        let decrypt = tokio::task::spawn_blocking(move || {
+            let mut plaintext = Vec::new();
+            decrypt_attachment(
+                &pointer.key,
+                &pointer.digest,
+                pointer.size,
+                ChunkReader {
+                    receiver,
+                    chunk: Vec::new(),
+                    position: 0,
+                },
+                &mut plaintext,
+            )
+            .map(|_| plaintext)
+        });
+        while let Some(response) = next {
+            // Decryption stops reading once it has the whole blob or fails.
+            if sender.send(response.chunk.unwrap_or_default()).await.is_err() {
+                break;
+            }
+            next = stream.message().await?;
+        }
+        drop(sender);
+        Ok(decrypt
+            .await
+            .map_err(|e| AttachmentError::Io(io::Error::other(e)))??)
+    }
+
+    /// Returns the safety number and scannable fingerprint of the conversation with
+    /// `peer_username`, which cover the identity keys of all of both users' devices in the ledger.
+    pub async fn get_fingerprint(&self, peer_username: String) -> ClientResult<Fingerprint> {
+        let (iks, peer_iks) = self.get_ledger_key_sets(&peer_username).await?;
+        Ok(Fingerprint::from_key_sets(
+            &self.username,
+            &iks,
+            &peer_username,
+            &peer_iks,
+        ))
+    }
+
+    /// Compares a fingerprint scanned from the peer's device and marks the identity keys of the
+    /// peer's devices as verified if they match.
+    pub async fn verify_fingerprint(
+        &self,
+        peer_username: String,
+        scanned: Vec<u8>,
+    ) -> ClientResult<bool> {
+        let (iks, peer_iks) = self.get_ledger_key_sets(&peer_username).await?;
+        let fingerprint =
+            Fingerprint::from_key_sets(&self.username, &iks, &peer_username, &peer_iks);
+        if !fingerprint.scannable.compare(&scanned)? {
+            warn!("Scanned fingerprint of {peer_username} does not match.");
+            return Ok(false);
+        }
+        self.x3dh.set_verified(peer_username, peer_iks).await?;
+        Ok(true)
+    }
+
+    /// Returns true if the identity keys of all of the devices of `peer_username` were verified
+    /// and no new device has messaged us since.
+    pub async fn is_verified(&self, peer_username: String) -> ClientResult<bool> {
+        self.x3dh.is_verified(peer_username).await
+    }
+
+    /// Returns the identity keys in the ledger of our devices and of the devices of
+    /// `peer_username`.
+    async fn get_ledger_key_sets(
+        &self,
+        peer_username: &str,
+    ) -> ClientResult<(Vec<IdentityPublicKey>, Vec<IdentityPublicKey>)> {
+        let mut gossamer = self.gossamer.clone();
+        let ledger = get_ledger(&mut gossamer).await?;
+        let iks = ledger_keys(&ledger, &self.username);
+        if iks.is_empty() {
+            return Err(ClientError::UnknownIdentityKey(self.username.clone()));
+        }
+        let peer_iks = ledger_keys(&ledger, peer_username);
+        if peer_iks.is_empty() {
+            return Err(ClientError::UnknownIdentityKey(peer_username.to_owned()));
+        }
+        Ok((iks, peer_iks))
+    }
+
     pub async fn get_message_history(&self) -> ClientResult<Vec<MessageModel>> {
         self.x3dh.get_messages().await
     }
@@ -323,7 +708,7 @@ impl User {
 
 async fn register_username(
     stub: &mut GossamerClient,
-    ik: SigningKey,
+    ik: &IdentityKey,
     name: String,
 ) -> ClientResult<()> {
     info!("Registering {name}!");
@@ -331,7 +716,7 @@ async fn register_username(
 
     let message = protocol::gossamer::Message {
         provider,
-        public_key: ik.verifying_key(),
+        public_key: ik.public_key(),
         action: protocol::gossamer::Action::AppendKey,
     };
     let message: proto::gossamer::Message = message.into();
@@ -340,7 +725,7 @@ async fn register_username(
 
     let signed_message = SignedMessage {
         contents: Some(contents),
-        identity_key: Some(ik.verifying_key().as_bytes().to_vec()),
+        identity_key: Some(ik.public_key().encode()),
         signature: Some(signature.to_vec()),
     };
     let request = Request::new(ActionRequest {
@@ -350,68 +735,285 @@ async fn register_username(
     Ok(())
 }
 
+/// Replaces the signed pre key that the server hands out with our current one.
+async fn upload_spk(stub: &mut BrongnalClient, x3dh_client: &X3DHClient) -> ClientResult<()> {
+    let request = Request::new(RegisterPreKeyBundleRequest {
+        identity_key: Some(x3dh_client.get_ik().public_key().encode()),
+        signed_pre_key: Some(x3dh_client.get_spk().await?.into()),
+        one_time_key_bundle: Some(x3dh_client.create_opks(0).await?.into()),
+        last_resort_pre_key: None,
+        fcm_token: None,
+        one_time_kem_pre_keys: Vec::new(),
+        last_resort_kem_pre_key: None,
+    });
+    stub.register_pre_key_bundle(request).await?;
+    Ok(())
+}
+
 async fn register_device(
     stub: &mut BrongnalClient,
     x3dh_client: &X3DHClient,
     fcm_token: Option<String>,
 ) -> ClientResult<()> {
-    let ik = x3dh_client.get_ik().verifying_key().as_bytes().to_vec();
+    let ik = x3dh_client.get_ik().public_key().encode();
     #[allow(deprecated)]
     let ik_str = base64::encode(&ik);
     info!("Registering {ik_str}!",);
+    if x3dh_client.rotate_spk().await? {
+        info!("Rotated signed pre key.");
+    }
 
     let request = Request::new(RegisterPreKeyBundleRequest {
         identity_key: Some(ik.clone()),
         signed_pre_key: Some(x3dh_client.get_spk().await?.into()),
         one_time_key_bundle: Some(x3dh_client.create_opks(0).await?.into()),
+        last_resort_pre_key: Some(x3dh_client.get_last_resort_pre_key().await?.into()),
         fcm_token,
+        one_time_kem_pre_keys: Vec::new(),
+        last_resort_kem_pre_key: Some(x3dh_client.get_last_resort_kem_pre_key().await?.into()),
     });
     let res = stub.register_pre_key_bundle(request).await?.into_inner();
-    info!("Registered. {} keys remaining!", res.num_keys());
-    if res.num_keys() < 100 {
-        info!("Adding 100 keys!");
+    info!(
+        "Registered. {} keys and {} KEM keys remaining!",
+        res.num_keys(),
+        res.num_kem_keys()
+    );
+    if res.num_keys() < 100 || res.num_kem_keys() < 100 {
+        let num_keys = if res.num_keys() < 100 { 100 } else { 0 };
+        let num_kem_keys = if res.num_kem_keys() < 100 { 100 } else { 0 };
+        info!("Adding {num_keys} keys and {num_kem_keys} KEM keys!");
         let request = Request::new(RegisterPreKeyBundleRequest {
             identity_key: Some(ik),
             signed_pre_key: Some(x3dh_client.get_spk().await?.into()),
-            one_time_key_bundle: Some(x3dh_client.create_opks(100).await?.into()),
+            one_time_key_bundle: Some(x3dh_client.create_opks(num_keys).await?.into()),
+            last_resort_pre_key: None,
             fcm_token: None,
+            one_time_kem_pre_keys: x3dh_client
+                .create_kem_pre_keys(num_kem_keys)
+                .await?
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            last_resort_kem_pre_key: None,
         });
         stub.register_pre_key_bundle(request).await?.into_inner();
     }
     Ok(())
 }
 
-fn send_message_requests(
-    bundles: Vec<PreKeyBundle>,
-    ik: SigningKey,
-    message: RatchetMessage,
-) -> impl Stream<Item = SendMessageRequest> {
-    let message: RatchetProto = message.into();
-    stream! {
-        for bundle in bundles {
-            let recipient_identity_key = Some(bundle.ik.as_bytes().to_vec());
-            let (_sk, x3dh_message) = match initiate_send(
-                bundle,
-                &ik,
-                &message.encode_to_vec(),
-            ) {
-                Ok((sk, message)) => (sk, message),
-                Err(e) => {
-                    error!("Failed to x3dh::initiate_send: {e}");
-                    continue
-                },
-            };
+/// Encrypts `plaintext` for `recipient` with the existing session.
+/// A fresh prekey bundle is only requested if there is no session or the session is broken.
+async fn encrypt_for_device(
+    stub: &mut BrongnalClient,
+    x3dh: &X3DHClient,
+    recipient: IdentityPublicKey,
+    plaintext: Vec<u8>,
+) -> ClientResult<DeviceMessage> {
+    match x3dh.encrypt_for_session(recipient, plaintext.clone()).await {
+        Ok(Some(message)) => return Ok(message),
+        Ok(None) => {}
+        Err(e) => warn!("Starting a new session to replace a broken session: {e}"),
+    }
+    start_session(stub, x3dh, recipient, plaintext).await
+}
+
+/// Requests a prekey bundle for `recipient` and starts a new session with its first message.
+async fn start_session(
+    stub: &mut BrongnalClient,
+    x3dh: &X3DHClient,
+    recipient: IdentityPublicKey,
+    plaintext: Vec<u8>,
+) -> ClientResult<DeviceMessage> {
+    let requester = SignedRequest::sign(
+        x3dh.get_ik(),
+        PRE_KEY_REQUEST_PREFIX,
+        &recipient.encode(),
+        time_now(),
+    );
+    let bundle: PreKeyBundle = stub
+        .request_pre_keys(Request::new(PreKeyBundleRequest {
+            identity_key: Some(recipient.encode()),
+            requester: Some(requester.into()),
+        }))
+        .await?
+        .into_inner()
+        .try_into()?;
+    x3dh.start_session(bundle, plaintext).await
+}
+
+async fn acknowledge_messages(
+    stub: &mut BrongnalClient,
+    x3dh: &X3DHClient,
+    ids: Vec<u64>,
+) -> ClientResult<()> {
+    let ik = x3dh.get_ik();
+    let proof = SignedRequest::sign(
+        ik,
+        ACKNOWLEDGE_MESSAGES_PREFIX,
+        &acknowledgement_contents(&ids),
+        time_now(),
+    );
+    stub.acknowledge_messages(Request::new(AcknowledgeMessagesRequest {
+        identity_key: Some(ik.public_key().encode()),
+        message_ids: ids,
+        proof: Some(proof.into()),
+    }))
+    .await?;
+    Ok(())
+}
+
+/// Returns a sender certificate for this device, requesting a new one if the cached
+/// certificate is about to expire.
+/// The server key that signs the certificate is trusted on first use.
+async fn get_sender_certificate(
+    stub: &mut BrongnalClient,
+    x3dh: &X3DHClient,
+) -> ClientResult<SenderCertificate> {
+    if let Some(certificate) = x3dh.get_sender_certificate() {
+        return Ok(certificate);
+    }
+    info!("Requesting sender certificate.");
+    let response = stub
+        .get_sender_certificate(Request::new(SenderCertificateRequest {
+            identity_key: Some(x3dh.get_ik().public_key().encode()),
+        }))
+        .await?
+        .into_inner();
+    let server_key = parse_identity_key(response.server_key()).map_err(|e| {
+        ClientError::Grpc(tonic::Status::invalid_argument(format!(
+            "Invalid server_key: {e}"
+        )))
+    })?;
+    x3dh.trust_server_key(server_key).await?;
+    let certificate: SenderCertificate = response
+        .certificate
+        .ok_or(tonic::Status::invalid_argument(
+            "SenderCertificateResponse missing certificate.",
+        ))?
+        .try_into()?;
+    x3dh.set_sender_certificate(certificate.clone()).await?;
+    Ok(certificate)
+}
+
+/// Seals `message` so that only `recipient` learns who sent it.
+fn send_message_request(
+    x3dh: &X3DHClient,
+    certificate: &SenderCertificate,
+    (recipient, message): (IdentityPublicKey, DeviceMessage),
+) -> Result<SendMessageRequest, SealedSenderError> {
+    let sealed = x3dh.seal(&recipient, message, certificate.clone())?;
+    Ok(SendMessageRequest {
+        recipient_identity_key: Some(recipient.encode()),
+        message: Some(sealed.into()),
+    })
+}
 
-            info!("Sending message:{message:?}\n{x3dh_message}\n");
+/// Encrypts `message` for a single device with a fresh prekey bundle and sends it.
+/// Returns the ratchet header of the sent message.
+async fn send_to_device(
+    stub: &mut BrongnalClient,
+    x3dh: &X3DHClient,
+    recipient: IdentityPublicKey,
+    message: ApplicationMessage,
+) -> ClientResult<RatchetHeader> {
+    let plaintext = ApplicationMessageProto::from(message).encode_to_vec();
+    let message = start_session(stub, x3dh, recipient, plaintext).await?;
+    let header = message.message.header;
+    let certificate = get_sender_certificate(stub, x3dh).await?;
+    let request = send_message_request(x3dh, &certificate, (recipient, message))?;
+    stub.send_message(Request::new(tokio_stream::iter([request])))
+        .await?;
+    Ok(header)
+}
 
-            yield SendMessageRequest {
-                recipient_identity_key,
-                message: Some(x3dh_message.into()),
+/// A text message, or an attachment captioned with `text`.
+fn message_contents(text: String, attachment: Option<AttachmentPointer>) -> MessageContents {
+    match attachment {
+        Some(attachment) => MessageContents::Attachment(attachment),
+        None => MessageContents::Text(text),
+    }
+}
+
+/// Sends what is written to it over `sender` in chunks of `UPLOAD_CHUNK_SIZE`, so that an
+/// attachment is uploaded while it is encrypted.
+struct ChunkWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(UPLOAD_CHUNK_SIZE));
+        self.sender
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upload stopped"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(UPLOAD_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == UPLOAD_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+/// Reads the chunks of a downloaded attachment as they arrive over `receiver`.
+struct ChunkReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            let Some(chunk) = self.receiver.blocking_recv() else {
+                return Ok(0);
             };
+            self.chunk = chunk;
+            self.position = 0;
         }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
     }
 }
 
+/// Uploads the chunks of an encrypted attachment as they are produced.
+/// Returns the digest the server stored it under.
+async fn upload_attachment(
+    stub: &mut BrongnalClient,
+    ik: &IdentityKey,
+    chunks: impl Stream<Item = Vec<u8>> + Send + 'static,
+) -> ClientResult<[u8; 32]> {
+    let mut proof = Some(SignedRequest::sign(ik, UPLOAD_ATTACHMENT_PREFIX, &[], time_now()).into());
+    let requests = chunks.map(move |chunk| UploadAttachmentRequest {
+        chunk: Some(chunk),
+        proof: proof.take(),
+    });
+    let response = stub
+        .upload_attachment(Request::new(requests))
+        .await?
+        .into_inner();
+    response.digest().try_into().map_err(|_| {
+        ClientError::Grpc(tonic::Status::invalid_argument(
+            "UploadAttachmentResponse has invalid digest.",
+        ))
+    })
+}
+
 async fn get_ledger(stub: &mut GossamerClient) -> ClientResult<LedgerProto> {
     let request = Request::new(GetLedgerRequest {});
     let ledger = stub.get_ledger(request).await?.into_inner();
@@ -421,19 +1023,22 @@ async fn get_ledger(stub: &mut GossamerClient) -> ClientResult<LedgerProto> {
 async fn get_keys(
     stub: &mut GossamerClient,
     peer_username: &str,
-) -> ClientResult<Vec<VerifyingKey>> {
-    let recipient_user_id =
-        Blake2b::<blake2::digest::typenum::U32>::digest(peer_username.as_bytes()).to_vec();
+) -> ClientResult<Vec<IdentityPublicKey>> {
     let ledger = get_ledger(stub).await?;
-    Ok(ledger
+    Ok(ledger_keys(&ledger, peer_username))
+}
+
+fn ledger_keys(ledger: &LedgerProto, username: &str) -> Vec<IdentityPublicKey> {
+    let user_id = Blake2b::<blake2::digest::typenum::U32>::digest(username.as_bytes()).to_vec();
+    ledger
         .users
-        .into_iter()
-        .filter(|user| user.provider() == recipient_user_id)
+        .iter()
+        .filter(|user| user.provider() == user_id)
         .flat_map(|user| {
             user.public_keys
-                .into_iter()
-                .map(|key| parse_verifying_key(&key).unwrap())
+                .iter()
+                .map(|key| parse_identity_key(key).unwrap())
                 .collect::<Vec<_>>()
         })
-        .collect::<Vec<_>>())
+        .collect::<Vec<_>>()
 }
@@ -3,6 +3,7 @@ use client::client::MessageModel;
 use client::{User, X3DHClient};
 use nom::character::complete::{alphanumeric1, multispace1};
 use nom::IResult;
+use protocol::aead::CipherSuite;
 use std::io::stdin;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -52,16 +53,28 @@ async fn main() -> Result<()> {
         .with(filter)
         .try_init()?;
 
+    let cipher_suite = match env::var("BRONGNAL_CIPHER_SUITE").as_deref() {
+        Ok("chacha20-poly1305") | Err(_) => CipherSuite::ChaCha20Poly1305,
+        Ok("aes-256-gcm-siv") => CipherSuite::Aes256GcmSiv,
+        Ok("committing-chacha20-poly1305") => CipherSuite::CommittingChaCha20Poly1305,
+        Ok(suite) => anyhow::bail!("unknown cipher suite: {suite}"),
+    };
+
     let xdg_dirs = xdg::BaseDirectories::with_prefix("brongnal")?;
     let db_path = xdg_dirs.place_data_file(format!("{}_keys.sqlite", name))?;
     let connection = Connection::open(db_path).await?;
-    let client = Arc::new(X3DHClient::new(connection.clone()).await?);
+    let client = Arc::new(
+        X3DHClient::new(connection.clone())
+            .await?
+            .with_cipher_suite(cipher_suite),
+    );
     let ik = client.get_ik();
 
     #[allow(deprecated)]
-    let ik_str = base64::encode(ik.verifying_key().as_bytes());
+    let ik_str = base64::encode(ik.public_key());
     info!("Registering {name} with key={ik_str} at {addr}");
     let user = User::new(addr, client, name.clone())?;
+    user.spawn_pre_key_rotation();
     let history = user.get_message_history().await.unwrap();
     for message in history {
         println!("{message}");
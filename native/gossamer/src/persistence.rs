@@ -1,7 +1,9 @@
-use ed25519_dalek::VerifyingKey;
+use migrations::{Migration, migrate};
 use prost::Message;
 use proto::gossamer::SignedMessage;
+use protocol::xeddsa::IdentityPublicKey;
 use rusqlite::params;
+use rusqlite::types::Type;
 use std::collections::HashMap;
 use tokio_rusqlite::{Connection, Result};
 use tracing::{info, instrument};
@@ -9,33 +11,80 @@ use tracing::{info, instrument};
 #[derive(Clone)]
 pub struct GossamerStorage(Connection);
 
+/// The name Gossamer's schema is versioned by.
+const SCHEMA: &str = "gossamer";
+
+/// The migrations of Gossamer's schema, in order.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create tables",
+        apply: create_tables,
+    },
+    Migration {
+        description: "Encode legacy Ed25519 keys as X25519 keys",
+        apply: migrate_legacy_keys,
+    },
+];
+
+fn create_tables(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS gossamer_providers (
+            provider BLOB PRIMARY KEY
+        );
+        CREATE TABLE IF NOT EXISTS gossamer_keys (
+            public_key BLOB PRIMARY KEY,
+            provider BLOB NOT NULL,
+            FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS gossamer_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            provider BLOB NOT NULL,
+            signed_message BLOB NOT NULL,
+            FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+        );",
+    )
+}
+
+/// Keys appended while identity keys were Ed25519 keys are stored as their 32 byte encoding.
+/// They are replaced by the encoding of the birationally equivalent X25519 key, which is
+/// what their devices now present.
+fn migrate_legacy_keys(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let legacy: Vec<Vec<u8>> = connection
+        .prepare("SELECT public_key FROM gossamer_keys WHERE length(public_key) = 32")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    if legacy.is_empty() {
+        return Ok(());
+    }
+    info!("Encoding {} legacy keys as X25519 keys.", legacy.len());
+    for key in legacy {
+        let encoded = IdentityPublicKey::decode(&key)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e)))?
+            .encode();
+        // A key that was appended again after it was converted is already encoded.
+        connection.execute(
+            "UPDATE OR IGNORE gossamer_keys SET public_key = ?1 WHERE public_key = ?2",
+            params![encoded, key],
+        )?;
+        connection.execute(
+            "DELETE FROM gossamer_keys WHERE public_key = ?1",
+            params![key],
+        )?;
+    }
+    Ok(())
+}
+
 impl GossamerStorage {
     pub async fn new(connection: Connection) -> Result<Self> {
-        info!("Creating SQlite Tables for Gossamer Service.");
+        info!("Migrating SQlite Tables for Gossamer Service.");
         connection
             .call(|connection| {
                 connection.pragma_update(None, "journal_mode", "WAL")?;
                 connection.pragma_update(None, "synchronous", "normal")?;
                 connection.pragma_update(None, "foreign_keys", "on")?;
-                connection.execute_batch(
-                    "
-                    BEGIN;
-                    CREATE TABLE IF NOT EXISTS gossamer_providers (
-                        provider BLOB PRIMARY KEY
-                    );
-                    CREATE TABLE IF NOT EXISTS gossamer_keys (
-                        public_key BLOB PRIMARY KEY,
-                        provider BLOB NOT NULL,
-                        FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
-                    );
-                    CREATE TABLE IF NOT EXISTS gossamer_messages (
-                        id INTEGER PRIMARY KEY AUTOINCREMENT,
-                        provider BLOB NOT NULL,
-                        signed_message BLOB NOT NULL,
-                        FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
-                    );
-                    COMMIT;",
-                )?;
+                migrate(connection, SCHEMA, MIGRATIONS)
+                    .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
                 Ok(())
             })
             .await?;
@@ -53,7 +102,11 @@ impl GossamerStorage {
     /// Returns `true` if the key was successfully added, or `false` if that specific key is already
     /// registered for the provider. Note that a single provider can have multiple associated keys.
     #[instrument(skip(self))]
-    pub async fn append_key(&self, provider: Vec<u8>, public_key: VerifyingKey) -> Result<bool> {
+    pub async fn append_key(
+        &self,
+        provider: Vec<u8>,
+        public_key: IdentityPublicKey,
+    ) -> Result<bool> {
         self.0
             .call(move |connection| {
                 let tx = connection.transaction()?;
@@ -63,7 +116,7 @@ impl GossamerStorage {
                 )?;
                 let affected = tx.execute(
                     "INSERT OR IGNORE INTO gossamer_keys (public_key, provider) VALUES (?1, ?2)",
-                    params![public_key.as_bytes(), provider],
+                    params![public_key.encode(), provider],
                 )?;
                 tx.commit()?;
                 Ok(affected == 1)
@@ -73,13 +126,13 @@ impl GossamerStorage {
 
     /// Returns `true` if the specific public key is currently associated with the given provider hash.
     #[instrument(skip(self))]
-    pub async fn has_key(&self, provider: Vec<u8>, public_key: VerifyingKey) -> Result<bool> {
+    pub async fn has_key(&self, provider: Vec<u8>, public_key: IdentityPublicKey) -> Result<bool> {
         self.0
             .call(move |connection| {
                 let mut statement = connection.prepare(
                     "SELECT 1 FROM gossamer_keys WHERE provider = ?1 AND public_key = ?2",
                 )?;
-                let exists = statement.exists(params![provider, public_key.as_bytes()])?;
+                let exists = statement.exists(params![provider, public_key.encode()])?;
                 Ok(exists)
             })
             .await
@@ -87,12 +140,12 @@ impl GossamerStorage {
 
     /// Returns the provider hash associated with a given public key, if it exists.
     #[instrument(skip(self))]
-    pub async fn get_key_provider(&self, public_key: VerifyingKey) -> Result<Option<Vec<u8>>> {
+    pub async fn get_key_provider(&self, public_key: IdentityPublicKey) -> Result<Option<Vec<u8>>> {
         self.0
             .call(move |connection| {
                 let mut statement = connection
                     .prepare("SELECT provider FROM gossamer_keys WHERE public_key = ?1")?;
-                let mut rows = statement.query([public_key.as_bytes()])?;
+                let mut rows = statement.query([public_key.encode()])?;
                 if let Some(row) = rows.next()? {
                     let provider: Vec<u8> = row.get(0)?;
                     Ok(Some(provider))
@@ -122,12 +175,16 @@ impl GossamerStorage {
     /// Returns `true` if the key was found and removed, or `false` if no such key exists for the provider.
     /// Revoking the last key of a provider does not delete the provider identity itself.
     #[instrument(skip(self))]
-    pub async fn revoke_key(&self, provider: Vec<u8>, public_key: VerifyingKey) -> Result<bool> {
+    pub async fn revoke_key(
+        &self,
+        provider: Vec<u8>,
+        public_key: IdentityPublicKey,
+    ) -> Result<bool> {
         self.0
             .call(move |connection| {
                 let affected = connection.execute(
                     "DELETE FROM gossamer_keys WHERE provider = ?1 AND public_key = ?2",
-                    params![provider, public_key.as_bytes()],
+                    params![provider, public_key.encode()],
                 )?;
                 Ok(affected == 1)
             })
@@ -158,7 +215,7 @@ impl GossamerStorage {
 
     /// Retrieves the entire ledger of all providers and their respective identity keys, grouped by provider.
     #[instrument(skip(self))]
-    pub async fn get_ledger(&self) -> Result<HashMap<Vec<u8>, Vec<VerifyingKey>>> {
+    pub async fn get_ledger(&self) -> Result<HashMap<Vec<u8>, Vec<IdentityPublicKey>>> {
         self.0
             .call(|connection| {
                 let mut statement =
@@ -166,17 +223,17 @@ impl GossamerStorage {
                 let rows = statement.query_map([], |row| {
                     let provider: Vec<u8> = row.get(0)?;
                     let key_bytes: Vec<u8> = row.get(1)?;
-                    let key = VerifyingKey::try_from(key_bytes.as_slice()).map_err(|_| {
+                    let key = IdentityPublicKey::decode(&key_bytes).map_err(|_| {
                         rusqlite::Error::InvalidColumnType(
                             1,
-                            "invalid ed25519 key".into(),
+                            "invalid identity key".into(),
                             rusqlite::types::Type::Blob,
                         )
                     })?;
                     Ok((provider, key))
                 })?;
 
-                let mut ledger: HashMap<Vec<u8>, Vec<VerifyingKey>> = HashMap::new();
+                let mut ledger: HashMap<Vec<u8>, Vec<IdentityPublicKey>> = HashMap::new();
                 for row in rows {
                     let (provider, key) = row?;
                     ledger.entry(provider).or_default().push(key);
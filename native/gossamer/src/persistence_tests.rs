@@ -1,7 +1,6 @@
 use super::*;
-use ed25519_dalek::SigningKey;
 use proto::gossamer::SignedMessage;
-use rand_core::OsRng;
+use protocol::xeddsa::IdentityKey;
 
 async fn setup_db() -> GossamerStorage {
     let conn = Connection::open_in_memory().await.unwrap();
@@ -19,7 +18,7 @@ async fn test_schema_initialization() {
 async fn test_append_key_success() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
 
     let appended = db.append_key(provider.clone(), key).await.unwrap();
     assert!(appended);
@@ -33,7 +32,7 @@ async fn test_append_key_success() {
 async fn test_append_key_idempotency() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
 
     db.append_key(provider.clone(), key).await.unwrap();
     let appended_second_time = db.append_key(provider.clone(), key).await.unwrap();
@@ -46,8 +45,8 @@ async fn test_append_key_idempotency() {
 async fn test_append_multiple_keys_for_same_provider() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key1 = SigningKey::generate(&mut OsRng).verifying_key();
-    let key2 = SigningKey::generate(&mut OsRng).verifying_key();
+    let key1 = IdentityKey::generate().public_key();
+    let key2 = IdentityKey::generate().public_key();
 
     db.append_key(provider.clone(), key1).await.unwrap();
     db.append_key(provider.clone(), key2).await.unwrap();
@@ -60,7 +59,7 @@ async fn test_append_multiple_keys_for_same_provider() {
 async fn test_has_key_authorized() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
     db.append_key(provider.clone(), key).await.unwrap();
 
     assert!(db.has_key(provider, key).await.unwrap());
@@ -70,8 +69,8 @@ async fn test_has_key_authorized() {
 async fn test_has_key_unauthorized() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key1 = SigningKey::generate(&mut OsRng).verifying_key();
-    let key2 = SigningKey::generate(&mut OsRng).verifying_key();
+    let key1 = IdentityKey::generate().public_key();
+    let key2 = IdentityKey::generate().public_key();
     db.append_key(provider, key1).await.unwrap();
 
     let other_provider = b"bob".to_vec();
@@ -82,7 +81,7 @@ async fn test_has_key_unauthorized() {
 async fn test_get_key_provider_mapping() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
     db.append_key(provider.clone(), key).await.unwrap();
 
     let found_provider = db.get_key_provider(key).await.unwrap();
@@ -93,7 +92,7 @@ async fn test_get_key_provider_mapping() {
 async fn test_revoke_key_success() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
     db.append_key(provider.clone(), key).await.unwrap();
 
     let revoked = db.revoke_key(provider.clone(), key).await.unwrap();
@@ -106,7 +105,7 @@ async fn test_revoke_key_success() {
 async fn test_revoke_key_not_found() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
 
     let revoked = db.revoke_key(provider, key).await.unwrap();
     assert!(!revoked);
@@ -116,7 +115,7 @@ async fn test_revoke_key_not_found() {
 async fn test_append_message_fk_constraint_success() {
     let db = setup_db().await;
     let provider = b"alice".to_vec();
-    let key = SigningKey::generate(&mut OsRng).verifying_key();
+    let key = IdentityKey::generate().public_key();
     db.append_key(provider.clone(), key).await.unwrap();
 
     let msg = SignedMessage {
@@ -148,9 +147,9 @@ async fn test_get_ledger_grouping() {
     let db = setup_db().await;
     let alice = b"alice".to_vec();
     let bob = b"bob".to_vec();
-    let key_a1 = SigningKey::generate(&mut OsRng).verifying_key();
-    let key_a2 = SigningKey::generate(&mut OsRng).verifying_key();
-    let key_b1 = SigningKey::generate(&mut OsRng).verifying_key();
+    let key_a1 = IdentityKey::generate().public_key();
+    let key_a2 = IdentityKey::generate().public_key();
+    let key_b1 = IdentityKey::generate().public_key();
 
     db.append_key(alice.clone(), key_a1).await.unwrap();
     db.append_key(alice.clone(), key_a2).await.unwrap();
@@ -161,3 +160,99 @@ async fn test_get_ledger_grouping() {
     assert_eq!(ledger.get(&alice).unwrap().len(), 2);
     assert_eq!(ledger.get(&bob).unwrap().len(), 1);
 }
+
+#[tokio::test]
+async fn test_upgrade_unversioned_database() {
+    let conn = Connection::open_in_memory().await.unwrap();
+    let key = IdentityKey::generate().public_key();
+    let encoded = key.encode();
+    // Tables created before the schema was versioned.
+    conn.call(move |connection| {
+        connection.execute_batch(
+            "CREATE TABLE gossamer_providers (
+                provider BLOB PRIMARY KEY
+            );
+            CREATE TABLE gossamer_keys (
+                public_key BLOB PRIMARY KEY,
+                provider BLOB NOT NULL,
+                FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+            );
+            CREATE TABLE gossamer_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                provider BLOB NOT NULL,
+                signed_message BLOB NOT NULL,
+                FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+            );
+            INSERT INTO gossamer_providers (provider) VALUES (x'616c696365');",
+        )?;
+        connection.execute(
+            "INSERT INTO gossamer_keys (public_key, provider) VALUES (?1, x'616c696365')",
+            params![encoded],
+        )?;
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    let db = GossamerStorage::new(conn.clone()).await.unwrap();
+    let version = conn
+        .call(|connection| Ok(migrations::version(connection, SCHEMA)?))
+        .await
+        .unwrap();
+    assert_eq!(version, MIGRATIONS.len() as u32);
+    assert!(db.has_key(b"alice".to_vec(), key).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_migrate_legacy_keys() {
+    let conn = Connection::open_in_memory().await.unwrap();
+    let legacy = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng).verifying_key();
+    let other = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng).verifying_key();
+    // Ed25519 keys appended before identity keys were X25519 keys.
+    conn.call(move |connection| {
+        connection.execute_batch(
+            "CREATE TABLE gossamer_providers (
+                provider BLOB PRIMARY KEY
+            );
+            CREATE TABLE gossamer_keys (
+                public_key BLOB PRIMARY KEY,
+                provider BLOB NOT NULL,
+                FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+            );
+            CREATE TABLE gossamer_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                provider BLOB NOT NULL,
+                signed_message BLOB NOT NULL,
+                FOREIGN KEY(provider) REFERENCES gossamer_providers(provider) ON DELETE CASCADE
+            );
+            INSERT INTO gossamer_providers (provider) VALUES (x'616c696365');
+            INSERT INTO gossamer_providers (provider) VALUES (x'626f62');",
+        )?;
+        connection.execute(
+            "INSERT INTO gossamer_keys (public_key, provider) VALUES (?1, x'616c696365')",
+            params![legacy.as_bytes()],
+        )?;
+        connection.execute(
+            "INSERT INTO gossamer_keys (public_key, provider) VALUES (?1, x'626f62')",
+            params![other.as_bytes()],
+        )?;
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    let db = GossamerStorage::new(conn).await.unwrap();
+    let key = IdentityPublicKey::from_ed25519(&legacy);
+    let other = IdentityPublicKey::from_ed25519(&other);
+    assert!(db.has_key(b"alice".to_vec(), key).await.unwrap());
+    assert_eq!(
+        db.get_key_provider(key).await.unwrap(),
+        Some(b"alice".to_vec())
+    );
+    assert_eq!(
+        db.get_ledger().await.unwrap()[&b"bob".to_vec()],
+        vec![other]
+    );
+    assert!(db.revoke_key(b"alice".to_vec(), key).await.unwrap());
+    assert!(!db.has_key(b"alice".to_vec(), key).await.unwrap());
+}
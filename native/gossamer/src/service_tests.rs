@@ -1,10 +1,9 @@
 use super::*;
 use crate::persistence::GossamerStorage;
 use crate::service::Service;
-use ed25519_dalek::{Signer, SigningKey};
 use prost::Message;
 use proto::gossamer::{ActionRequest, GetLedgerRequest, SignedMessage};
-use rand_core::OsRng;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
 use tokio_rusqlite::Connection;
 use tonic::Request;
 
@@ -15,14 +14,14 @@ async fn setup_service() -> Service {
 }
 
 fn create_signed_action(
-    signer: &SigningKey,
+    signer: &IdentityKey,
     provider: Vec<u8>,
     new_key: Vec<u8>,
     action: protocol::gossamer::Action,
 ) -> SignedMessage {
     let message = protocol::gossamer::Message {
         provider,
-        public_key: ed25519_dalek::VerifyingKey::try_from(new_key.as_slice()).unwrap(),
+        public_key: IdentityPublicKey::decode(&new_key).unwrap(),
         action,
     };
 
@@ -33,20 +32,20 @@ fn create_signed_action(
     SignedMessage {
         contents: Some(encoded),
         signature: Some(signature.to_vec()),
-        identity_key: Some(signer.verifying_key().to_bytes().to_vec()),
+        identity_key: Some(signer.public_key().encode()),
     }
 }
 
 #[tokio::test]
 async fn test_action_claim_new_provider_success() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
 
     // New user claims 'alice' using their own key
     let action = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -61,14 +60,14 @@ async fn test_action_claim_new_provider_success() {
 #[tokio::test]
 async fn test_action_claim_denied_if_not_self_signed() {
     let service = setup_service().await;
-    let attacker_key = SigningKey::generate(&mut OsRng);
-    let victim_key = SigningKey::generate(&mut OsRng);
+    let attacker_key = IdentityKey::generate();
+    let victim_key = IdentityKey::generate();
 
     // Attacker tries to claim 'victim' identity for the victims key, but signs with attackers key
     let action = create_signed_action(
         &attacker_key,
         b"victim".to_vec(),
-        victim_key.verifying_key().to_bytes().to_vec(),
+        victim_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -84,14 +83,14 @@ async fn test_action_claim_denied_if_not_self_signed() {
 #[tokio::test]
 async fn test_action_append_key_success_with_existing_auth() {
     let service = setup_service().await;
-    let alice_key1 = SigningKey::generate(&mut OsRng);
-    let alice_key2 = SigningKey::generate(&mut OsRng);
+    let alice_key1 = IdentityKey::generate();
+    let alice_key2 = IdentityKey::generate();
 
     // 1. Initial claim
     let claim = create_signed_action(
         &alice_key1,
         b"alice".to_vec(),
-        alice_key1.verifying_key().to_bytes().to_vec(),
+        alice_key1.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -105,7 +104,7 @@ async fn test_action_append_key_success_with_existing_auth() {
     let add_key = create_signed_action(
         &alice_key1,
         b"alice".to_vec(),
-        alice_key2.verifying_key().to_bytes().to_vec(),
+        alice_key2.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -120,13 +119,13 @@ async fn test_action_append_key_success_with_existing_auth() {
 #[tokio::test]
 async fn test_action_key_theft_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
 
     // 1. Alice claims 'alice'
     let alice_claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -140,7 +139,7 @@ async fn test_action_key_theft_denied() {
     let bob_theft_claim = create_signed_action(
         &alice_key,
         b"bob".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -160,15 +159,15 @@ async fn test_action_key_theft_denied() {
 #[tokio::test]
 async fn test_action_append_key_unauthorized_signer_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
-    let attacker_key = SigningKey::generate(&mut OsRng);
-    let new_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
+    let attacker_key = IdentityKey::generate();
+    let new_key = IdentityKey::generate();
 
     // 1. Alice claims 'alice'
     let alice_claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -182,7 +181,7 @@ async fn test_action_append_key_unauthorized_signer_denied() {
     let attack_add = create_signed_action(
         &attacker_key,
         b"alice".to_vec(),
-        new_key.verifying_key().to_bytes().to_vec(),
+        new_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -198,14 +197,14 @@ async fn test_action_append_key_unauthorized_signer_denied() {
 #[tokio::test]
 async fn test_action_existing_user_theft_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
-    let bob_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
+    let bob_key = IdentityKey::generate();
 
     // 1. Alice claims 'alice'
     let alice_claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -219,7 +218,7 @@ async fn test_action_existing_user_theft_denied() {
     let bob_claim = create_signed_action(
         &bob_key,
         b"bob".to_vec(),
-        bob_key.verifying_key().to_bytes().to_vec(),
+        bob_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -233,7 +232,7 @@ async fn test_action_existing_user_theft_denied() {
     let theft_append = create_signed_action(
         &bob_key,
         b"bob".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -253,13 +252,13 @@ async fn test_action_existing_user_theft_denied() {
 #[tokio::test]
 async fn test_action_revoke_key_success() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
 
     // 1. Claim
     let claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -273,7 +272,7 @@ async fn test_action_revoke_key_success() {
     let revoke = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::RevokeKey,
     );
 
@@ -288,14 +287,14 @@ async fn test_action_revoke_key_success() {
 #[tokio::test]
 async fn test_action_revoke_key_unauthorized_signer_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
-    let attacker_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
+    let attacker_key = IdentityKey::generate();
 
     // 1. Alice claims 'alice'
     let alice_claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -309,7 +308,7 @@ async fn test_action_revoke_key_unauthorized_signer_denied() {
     let attack_revoke = create_signed_action(
         &attacker_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::RevokeKey,
     );
 
@@ -325,14 +324,14 @@ async fn test_action_revoke_key_unauthorized_signer_denied() {
 #[tokio::test]
 async fn test_action_revoke_nonexistent_key_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
-    let fake_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
+    let fake_key = IdentityKey::generate();
 
     // 1. Alice claims 'alice'
     let alice_claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
@@ -346,7 +345,7 @@ async fn test_action_revoke_nonexistent_key_denied() {
     let bad_revoke = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        fake_key.verifying_key().to_bytes().to_vec(),
+        fake_key.public_key().encode(),
         protocol::gossamer::Action::RevokeKey,
     );
 
@@ -362,12 +361,12 @@ async fn test_action_revoke_nonexistent_key_denied() {
 #[tokio::test]
 async fn test_action_invalid_signature_denied() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
 
     let mut action = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
 
@@ -390,12 +389,12 @@ async fn test_action_invalid_signature_denied() {
 #[tokio::test]
 async fn test_get_ledger_mapping() {
     let service = setup_service().await;
-    let alice_key = SigningKey::generate(&mut OsRng);
+    let alice_key = IdentityKey::generate();
 
     let claim = create_signed_action(
         &alice_key,
         b"alice".to_vec(),
-        alice_key.verifying_key().to_bytes().to_vec(),
+        alice_key.public_key().encode(),
         protocol::gossamer::Action::AppendKey,
     );
     service
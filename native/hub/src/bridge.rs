@@ -84,6 +84,7 @@ pub async fn start_hub(
         let mut state_user = STATE.user.lock().await;
         *state_user = Some(user.clone());
 
+        user.spawn_pre_key_rotation();
         tokio::spawn(async move {
             let mut user = user;
             if let Err(e) = user.register(fcm_token).await {
@@ -2,11 +2,17 @@ use proto::gossamer::gossamer_service_server::{GossamerService, GossamerServiceS
 use proto::gossamer::{ActionRequest, ActionResponse, GetLedgerRequest, Ledger, User as UserProto};
 use proto::service::brongnal_service_server::{BrongnalService, BrongnalServiceServer};
 use proto::service::{
-    Message as MessageProto, RegisterPreKeyBundleResponse, PreKeyBundle, PreKeyBundleRequest,
+    AcknowledgeMessagesRequest, AcknowledgeMessagesResponse, ChallengeRequest, ChallengeResponse, Message as MessageProto, RegisterPreKeyBundleResponse, PreKeyBundle, PreKeyBundleRequest,
     RegisterPreKeyBundleRequest, RetrieveMessagesRequest, SendMessageRequest, SendMessageResponse,
+    SenderCertificateRequest, SenderCertificateResponse, UploadAttachmentRequest,
+    UploadAttachmentResponse, DownloadAttachmentRequest, DownloadAttachmentResponse,
 };
+use protocol::attachment::digest;
+use protocol::sealed_sender::SenderCertificate;
+use protocol::xeddsa::IdentityKey;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tonic::{transport::Server, Request, Response, Status};
 use tokio_stream::wrappers::ReceiverStream;
 use prost::Message;
@@ -15,11 +21,22 @@ use prost::Message;
 struct InnerState {
     users: HashMap<Vec<u8>, UserProto>,
     messages: HashMap<Vec<u8>, Vec<MessageProto>>,
+    attachments: HashMap<Vec<u8>, Vec<u8>>,
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct MockBackend {
     state: Arc<Mutex<InnerState>>,
+    server_key: Arc<IdentityKey>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        MockBackend {
+            state: Arc::default(),
+            server_key: Arc::new(IdentityKey::generate()),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -57,7 +74,8 @@ impl BrongnalService for MockBackend {
         _request: Request<RegisterPreKeyBundleRequest>,
     ) -> Result<Response<RegisterPreKeyBundleResponse>, Status> {
         Ok(Response::new(RegisterPreKeyBundleResponse { 
-            num_keys: Some(100)
+            num_keys: Some(100),
+            num_kem_keys: Some(100),
         }))
     }
 
@@ -72,8 +90,12 @@ impl BrongnalService for MockBackend {
             signed_pre_key: Some(proto::service::SignedPreKey {
                 pre_key: Some(vec![0u8; 32]),
                 signature: Some(vec![1u8; 64]),
+                id: Some(1),
+                created: Some(0),
             }),
             one_time_key: Some(vec![2u8; 32]),
+            one_time_key_id: Some(2),
+            kem_pre_key: None,
         }))
     }
 
@@ -91,6 +113,22 @@ impl BrongnalService for MockBackend {
         Ok(Response::new(SendMessageResponse {}))
     }
 
+    async fn get_challenge(
+        &self,
+        _request: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>, Status> {
+        // The mock does not check proofs of possession.
+        Ok(Response::new(ChallengeResponse { nonce: Some(vec![0; 32]) }))
+    }
+
+    async fn acknowledge_messages(
+        &self,
+        _request: Request<AcknowledgeMessagesRequest>,
+    ) -> Result<Response<AcknowledgeMessagesResponse>, Status> {
+        // The mock removes messages from the mailbox as they are delivered.
+        Ok(Response::new(AcknowledgeMessagesResponse {}))
+    }
+
     type RetrieveMessagesStream = ReceiverStream<Result<MessageProto, Status>>;
 
     async fn retrieve_messages(
@@ -120,6 +158,58 @@ impl BrongnalService for MockBackend {
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn get_sender_certificate(
+        &self,
+        request: Request<SenderCertificateRequest>,
+    ) -> Result<Response<SenderCertificateResponse>, Status> {
+        let req = request.into_inner();
+        let ik = proto::parse_identity_key(req.identity_key())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let expiration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 24 * 60 * 60;
+        let certificate = SenderCertificate::issue(&self.server_key, ik, expiration);
+        Ok(Response::new(SenderCertificateResponse {
+            certificate: Some(certificate.into()),
+            server_key: Some(self.server_key.public_key().encode()),
+        }))
+    }
+
+    async fn upload_attachment(
+        &self,
+        request: Request<tonic::Streaming<UploadAttachmentRequest>>,
+    ) -> Result<Response<UploadAttachmentResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut blob = Vec::new();
+        while let Some(req) = stream.message().await? {
+            blob.extend(req.chunk.ok_or(Status::invalid_argument("missing chunk"))?);
+        }
+        let digest = digest(&blob).to_vec();
+        self.state.lock().unwrap().attachments.insert(digest.clone(), blob);
+        Ok(Response::new(UploadAttachmentResponse { digest: Some(digest) }))
+    }
+
+    type DownloadAttachmentStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<DownloadAttachmentResponse, Status>>>;
+
+    async fn download_attachment(
+        &self,
+        request: Request<DownloadAttachmentRequest>,
+    ) -> Result<Response<Self::DownloadAttachmentStream>, Status> {
+        let req = request.into_inner();
+        let blob = self
+            .state
+            .lock()
+            .unwrap()
+            .attachments
+            .get(req.digest())
+            .cloned()
+            .ok_or(Status::not_found("attachment not found"))?;
+        let size = Some(blob.len() as u64);
+        Ok(Response::new(tokio_stream::iter(vec![Ok(DownloadAttachmentResponse {
+            chunk: Some(blob),
+            size,
+        })])))
+    }
 }
 
 /// Binds to the address and returns the listener. 
@@ -0,0 +1,185 @@
+use rusqlite::{params, Connection, Transaction, TransactionBehavior};
+use thiserror::Error;
+use tracing::info;
+
+/*
+    Schema Versions
+    Each schema that lives in a database, like the server's or Gossamer's, records the number of
+    migrations that have been applied to it in the schema_version table. Schemas are versioned by
+    name rather than with PRAGMA user_version because the server and Gossamer share a database.
+    A migration and the version it leads to are committed in one transaction, so a migration that
+    fails leaves the database at the previous version.
+*/
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("{schema} schema is at version {version} but only {latest} migrations are known")]
+    UnknownVersion {
+        schema: String,
+        version: u32,
+        latest: u32,
+    },
+}
+
+/// A change to a schema. The version a migration leads to is its position in the list of
+/// migrations plus one.
+pub struct Migration {
+    pub description: &'static str,
+    /// Runs inside of a transaction, so it must not begin or commit one.
+    pub apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+/// Returns the number of migrations that have been applied to `schema`.
+pub fn version(connection: &Connection, schema: &str) -> rusqlite::Result<u32> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            schema TEXT PRIMARY KEY,
+            version INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    match connection.query_row(
+        "SELECT version FROM schema_version WHERE schema = ?1",
+        params![schema],
+        |row| row.get(0),
+    ) {
+        Ok(version) => Ok(version),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the migrations of `schema` that haven't been applied yet, in order.
+/// Returns the version the schema is at.
+pub fn migrate(
+    connection: &Connection,
+    schema: &str,
+    migrations: &[Migration],
+) -> Result<u32, MigrationError> {
+    let latest = migrations.len() as u32;
+    loop {
+        // Taking the write lock before reading the version keeps two processes from applying the
+        // same migration.
+        let tx = Transaction::new_unchecked(connection, TransactionBehavior::Immediate)?;
+        let version = version(&tx, schema)?;
+        if version > latest {
+            return Err(MigrationError::UnknownVersion {
+                schema: schema.to_owned(),
+                version,
+                latest,
+            });
+        }
+        if version == latest {
+            return Ok(version);
+        }
+        let migration = &migrations[version as usize];
+        info!(
+            "Migrating {schema} schema to version {}: {}.",
+            version + 1,
+            migration.description
+        );
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (schema, version) VALUES (?1, ?2)
+            ON CONFLICT(schema) DO UPDATE SET version = excluded.version",
+            params![schema, version + 1],
+        )?;
+        tx.commit()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use anyhow::Result;
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            description: "Create a",
+            apply: |connection| connection.execute_batch("CREATE TABLE a (x INTEGER);"),
+        },
+        Migration {
+            description: "Create b",
+            apply: |connection| connection.execute_batch("CREATE TABLE b (x INTEGER);"),
+        },
+    ];
+
+    fn tables(connection: &Connection) -> rusqlite::Result<Vec<String>> {
+        connection
+            .prepare("SELECT name FROM sqlite_schema WHERE name IN ('a', 'b', 'c') ORDER BY name")?
+            .query_map([], |row| row.get(0))?
+            .collect()
+    }
+
+    #[test]
+    fn migrations_applied_once() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        assert_eq!(version(&connection, "test")?, 0);
+        assert_eq!(migrate(&connection, "test", MIGRATIONS)?, 2);
+        assert_eq!(migrate(&connection, "test", MIGRATIONS)?, 2);
+        assert_eq!(version(&connection, "test")?, 2);
+        assert_eq!(tables(&connection)?, ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_from_older_version() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        assert_eq!(migrate(&connection, "test", &MIGRATIONS[..1])?, 1);
+        assert_eq!(tables(&connection)?, ["a"]);
+        assert_eq!(migrate(&connection, "test", MIGRATIONS)?, 2);
+        assert_eq!(tables(&connection)?, ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        let failing = [Migration {
+            description: "Create c and fail",
+            apply: |connection| {
+                connection
+                    .execute_batch("CREATE TABLE c (x INTEGER); INSERT INTO missing VALUES (1);")
+            },
+        }];
+        assert!(matches!(
+            migrate(&connection, "test", &failing),
+            Err(MigrationError::Sqlite(_))
+        ));
+        assert_eq!(version(&connection, "test")?, 0);
+        assert!(tables(&connection)?.is_empty());
+
+        // The database can still be migrated.
+        assert_eq!(migrate(&connection, "test", MIGRATIONS)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_version_rejected() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        migrate(&connection, "test", MIGRATIONS)?;
+        assert!(matches!(
+            migrate(&connection, "test", &MIGRATIONS[..1]),
+            Err(MigrationError::UnknownVersion {
+                version: 2,
+                latest: 1,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn schemas_versioned_independently() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        migrate(&connection, "first", &MIGRATIONS[..1])?;
+        assert_eq!(version(&connection, "first")?, 1);
+        assert_eq!(version(&connection, "second")?, 0);
+        migrate(&connection, "second", &MIGRATIONS[1..])?;
+        assert_eq!(version(&connection, "second")?, 1);
+        assert_eq!(tables(&connection)?, ["a", "b"]);
+        Ok(())
+    }
+}
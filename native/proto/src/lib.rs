@@ -1,32 +1,48 @@
 use application::contents::ContentType;
-use application::{Contents, Sender};
-use ed25519_dalek::{Signature, VerifyingKey};
+use application::AttachmentPointer as AttachmentPointerProto;
+use application::{Contents, RetryRequest, Sender};
+use ed25519_dalek::Signature;
 use prost::Message as _;
+use protocol::auth::SignedRequest;
 use protocol::gossamer::Message;
 use protocol::gossamer::SignedMessage as GossamerSignedMessage;
-use protocol::x3dh::Message as X3DHMessage;
+use protocol::kem::{KemCiphertext, KemError, KemPublicKey};
+use protocol::ratchet::Header as RatchetHeader;
+use protocol::sealed_sender::{SealedMessage, SenderCertificate};
+use protocol::secret::SecretKey;
+use protocol::x3dh::PreKey;
 use protocol::x3dh::PreKeyBundle;
+use protocol::x3dh::SignedKemPreKey;
 use protocol::x3dh::SignedPreKey;
 use protocol::x3dh::SignedPreKeys;
+use protocol::xeddsa::{IdentityPublicKey, KeyEncodingError};
 use service::Message as MessageProto;
+use service::MessageType;
+use service::PreKey as PreKeyProto;
 use service::PreKeyBundle as PreKeyBundleProto;
+use service::SealedSenderMessage as SealedSenderMessageProto;
+use service::SenderCertificate as SenderCertificateProto;
+use service::SignedKemPreKey as SignedKemPreKeyProto;
 use service::SignedPreKey as SignedPreKeyProto;
 use service::SignedPreKeys as SignedPreKeysProto;
+use service::SignedRequest as SignedRequestProto;
+use service::UnsealedMessage as UnsealedMessageProto;
 use thiserror::Error;
 use tonic::Status;
 use x25519_dalek::PublicKey as X25519PublicKey;
 
 #[derive(Error, Debug)]
 pub enum KeyError {
-    #[error("Key was not a valid ED25519 point.")]
-    InvalidEd25519Key,
+    #[error("Key was not a valid identity key: {0}")]
+    InvalidIdentityKey(#[from] KeyEncodingError),
     #[error("Key was not a valid X25519 point.")]
     InvalidX25519Key,
+    #[error("Key was not a valid KEM key: {0}")]
+    InvalidKemKey(#[from] KemError),
 }
 
-pub fn parse_verifying_key(key: &[u8]) -> Result<VerifyingKey, KeyError> {
-    VerifyingKey::from_bytes(&key.try_into().map_err(|_| KeyError::InvalidEd25519Key)?)
-        .map_err(|_| KeyError::InvalidEd25519Key)
+pub fn parse_identity_key(key: &[u8]) -> Result<IdentityPublicKey, KeyError> {
+    Ok(IdentityPublicKey::decode(key)?)
 }
 
 pub fn parse_x25519_public_key(key: &[u8]) -> Result<X25519PublicKey, KeyError> {
@@ -34,6 +50,10 @@ pub fn parse_x25519_public_key(key: &[u8]) -> Result<X25519PublicKey, KeyError>
     Ok(X25519PublicKey::from(key))
 }
 
+pub fn parse_kem_public_key(key: &[u8]) -> Result<KemPublicKey, KeyError> {
+    Ok(KemPublicKey::from_bytes(key)?)
+}
+
 pub mod gossamer {
     tonic::include_proto!("gossamer.v1");
 }
@@ -53,23 +73,66 @@ impl From<SignedPreKey> for SignedPreKeyProto {
         SignedPreKeyProto {
             pre_key: Some(val.pre_key.to_bytes().to_vec()),
             signature: Some(val.signature.to_vec()),
+            id: Some(val.id),
+            created: Some(val.created),
+        }
+    }
+}
+
+impl From<PreKey> for PreKeyProto {
+    fn from(val: PreKey) -> Self {
+        PreKeyProto {
+            id: Some(val.id),
+            pre_key: Some(val.pre_key.to_bytes().to_vec()),
         }
     }
 }
 
+impl TryFrom<PreKeyProto> for PreKey {
+    type Error = tonic::Status;
+
+    fn try_from(value: PreKeyProto) -> Result<Self, Self::Error> {
+        let pre_key = parse_x25519_public_key(value.pre_key())
+            .map_err(|e| Status::invalid_argument(format!("Invalid PreKey: {e}")))?;
+        let id = value
+            .id
+            .ok_or(Status::invalid_argument("PreKey missing id."))?;
+        Ok(PreKey { id, pre_key })
+    }
+}
+
 impl From<SignedPreKeys> for SignedPreKeysProto {
     fn from(val: SignedPreKeys) -> Self {
         SignedPreKeysProto {
-            pre_keys: val
-                .pre_keys
-                .into_iter()
-                .map(|key| key.to_bytes().to_vec())
-                .collect(),
+            pre_keys: val.pre_keys.into_iter().map(Into::into).collect(),
+            created: Some(val.created),
             signature: Some(val.signature.to_vec()),
         }
     }
 }
 
+impl TryFrom<SignedPreKeysProto> for SignedPreKeys {
+    type Error = tonic::Status;
+
+    fn try_from(value: SignedPreKeysProto) -> Result<Self, Self::Error> {
+        let signature = Signature::from_slice(value.signature())
+            .map_err(|_| Status::invalid_argument("Pre Key bundle has an invalid Signature"))?;
+        let created = value
+            .created
+            .ok_or(Status::invalid_argument("Pre Key bundle missing created."))?;
+        let pre_keys = value
+            .pre_keys
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<_, _>>()?;
+        Ok(SignedPreKeys {
+            created,
+            pre_keys,
+            signature,
+        })
+    }
+}
+
 impl TryFrom<SignedPreKeyProto> for SignedPreKey {
     type Error = tonic::Status;
 
@@ -80,52 +143,270 @@ impl TryFrom<SignedPreKeyProto> for SignedPreKey {
             .map_err(|e| Status::invalid_argument(format!("Invalid SignedPreKey: {e}")))?;
         let signature = Signature::from_slice(signature)
             .map_err(|_| Status::invalid_argument("Pre Key has an invalid X25519 Signature"))?;
-        Ok(SignedPreKey { pre_key, signature })
+        let id = value
+            .id
+            .ok_or(Status::invalid_argument("SignedPreKey missing id."))?;
+        let created = value
+            .created
+            .ok_or(Status::invalid_argument("SignedPreKey missing created."))?;
+        Ok(SignedPreKey {
+            id,
+            created,
+            pre_key,
+            signature,
+        })
+    }
+}
+
+impl From<SignedKemPreKey> for SignedKemPreKeyProto {
+    fn from(val: SignedKemPreKey) -> Self {
+        SignedKemPreKeyProto {
+            pre_key: Some(val.pre_key.to_bytes()),
+            signature: Some(val.signature.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<SignedKemPreKeyProto> for SignedKemPreKey {
+    type Error = tonic::Status;
+
+    fn try_from(value: SignedKemPreKeyProto) -> Result<Self, Self::Error> {
+        let pre_key = parse_kem_public_key(value.pre_key())
+            .map_err(|e| Status::invalid_argument(format!("Invalid SignedKemPreKey: {e}")))?;
+        let signature = Signature::from_slice(value.signature())
+            .map_err(|_| Status::invalid_argument("KEM Pre Key has an invalid Signature"))?;
+        Ok(SignedKemPreKey { pre_key, signature })
     }
 }
 
-impl TryFrom<MessageProto> for X3DHMessage {
+impl TryFrom<MessageProto> for DeviceMessage {
     type Error = tonic::Status;
 
     fn try_from(value: MessageProto) -> Result<Self, Self::Error> {
-        let sender_ik = parse_verifying_key(value.sender_identity_key())
+        if value.r#type() == MessageType::SealedSender {
+            return Err(Status::invalid_argument(
+                "Sealed sender messages must be unsealed.",
+            ));
+        }
+        let ik = parse_identity_key(value.sender_identity_key())
             .map_err(|e| Status::invalid_argument(format!("Invalid sender_identity_key: {e}")))?;
 
-        let ek = parse_x25519_public_key(value.ephemeral_key())
-            .map_err(|e| Status::invalid_argument(format!("Invalid ephemeral_key: {e}")))?;
-
-        let pre_key = parse_x25519_public_key(value.pre_key())
-            .map_err(|e| Status::invalid_argument(format!("Invalid pre: {e}")))?;
+        let message_type = match value.r#type() {
+            // Clients from before messages had types only set the pre key fields of prekey
+            // messages.
+            MessageType::Unspecified
+                if value.ephemeral_key.is_some() || value.pre_key_id.is_some() =>
+            {
+                MessageType::PreKey
+            }
+            MessageType::Unspecified => MessageType::Session,
+            message_type => message_type,
+        };
+        let pre_keys = match message_type {
+            MessageType::PreKey => {
+                let ek = parse_x25519_public_key(value.ephemeral_key())
+                    .map_err(|e| Status::invalid_argument(format!("Invalid ephemeral_key: {e}")))?;
+
+                let spk_id = value
+                    .pre_key_id
+                    .ok_or(Status::invalid_argument("Message missing pre_key_id."))?;
+
+                let pqpk = match (value.kem_pre_key, value.kem_ciphertext) {
+                    (Some(pqpk), Some(ciphertext)) => Some((
+                        parse_kem_public_key(&pqpk).map_err(|e| {
+                            Status::invalid_argument(format!("Invalid kem_pre_key: {e}"))
+                        })?,
+                        KemCiphertext::from_bytes(&ciphertext).map_err(|e| {
+                            Status::invalid_argument(format!("Invalid kem_ciphertext: {e}"))
+                        })?,
+                    )),
+                    (None, None) => None,
+                    _ => {
+                        return Err(Status::invalid_argument(
+                            "kem_pre_key and kem_ciphertext must be set together.",
+                        ))
+                    }
+                };
+                Some(PreKeys {
+                    ek,
+                    spk_id,
+                    opk_id: value.one_time_key_id,
+                    pqpk,
+                })
+            }
+            MessageType::Session | MessageType::SealedSender | MessageType::Unspecified => None,
+        };
+        let ciphertext = value
+            .ciphertext
+            .ok_or(Status::invalid_argument("request missing ciphertext"))?;
+        let message = application::RatchetMessage::decode(&*ciphertext)
+            .map_err(|e| Status::invalid_argument(format!("Invalid RatchetMessage: {e}")))?
+            .try_into()?;
+        Ok(DeviceMessage {
+            ik,
+            pre_keys,
+            message,
+        })
+    }
+}
 
-        let opk = if let Some(opk) = value.one_time_key {
-            Some(
-                parse_x25519_public_key(&opk)
-                    .map_err(|e| Status::invalid_argument(format!("Invalid one_time_key: {e}")))?,
-            )
-        } else {
-            None
+impl From<DeviceMessage> for MessageProto {
+    fn from(val: DeviceMessage) -> Self {
+        let message: application::RatchetMessage = val.message.into();
+        let mut proto = MessageProto {
+            sender_identity_key: Some(val.ik.encode()),
+            ciphertext: Some(message.encode_to_vec()),
+            ..Default::default()
         };
-        Ok(X3DHMessage {
-            ik: sender_ik,
+        match val.pre_keys {
+            Some(PreKeys {
+                ek,
+                spk_id,
+                opk_id,
+                pqpk,
+            }) => {
+                proto.set_type(MessageType::PreKey);
+                proto.ephemeral_key = Some(ek.to_bytes().to_vec());
+                proto.pre_key_id = Some(spk_id);
+                proto.one_time_key_id = opk_id;
+                if let Some((pqpk, ciphertext)) = pqpk {
+                    proto.kem_pre_key = Some(pqpk.to_bytes());
+                    proto.kem_ciphertext = Some(ciphertext.to_bytes());
+                }
+            }
+            None => proto.set_type(MessageType::Session),
+        }
+        proto
+    }
+}
+
+impl TryFrom<MessageProto> for Envelope {
+    type Error = tonic::Status;
+
+    fn try_from(value: MessageProto) -> Result<Self, Self::Error> {
+        if value.r#type() != MessageType::SealedSender {
+            return Ok(Envelope::Device(Box::new(value.try_into()?)));
+        }
+        let sealed = value
+            .sealed_sender
+            .ok_or(Status::invalid_argument("Message missing sealed_sender."))?;
+        let ek = parse_x25519_public_key(sealed.ephemeral_key())
+            .map_err(|e| Status::invalid_argument(format!("Invalid ephemeral_key: {e}")))?;
+        let encrypted_static = sealed.encrypted_static.ok_or(Status::invalid_argument(
+            "Sealed sender missing encrypted_static.",
+        ))?;
+        let ciphertext = sealed.ciphertext.ok_or(Status::invalid_argument(
+            "Sealed sender missing ciphertext.",
+        ))?;
+        Ok(Envelope::Sealed(SealedMessage {
             ek,
-            pre_key,
-            opk,
-            ciphertext: value
-                .ciphertext
-                .ok_or(Status::invalid_argument("request missing ciphertext"))?
-                .to_vec(),
+            encrypted_static,
+            ciphertext,
+        }))
+    }
+}
+
+impl From<SealedMessage> for MessageProto {
+    fn from(val: SealedMessage) -> Self {
+        let mut proto = MessageProto {
+            sealed_sender: Some(SealedSenderMessageProto {
+                ephemeral_key: Some(val.ek.to_bytes().to_vec()),
+                encrypted_static: Some(val.encrypted_static),
+                ciphertext: Some(val.ciphertext),
+            }),
+            ..Default::default()
+        };
+        proto.set_type(MessageType::SealedSender);
+        proto
+    }
+}
+
+impl From<SenderCertificate> for SenderCertificateProto {
+    fn from(val: SenderCertificate) -> Self {
+        SenderCertificateProto {
+            sender_identity_key: Some(val.sender.encode()),
+            expiration: Some(val.expiration),
+            signature: Some(val.signature.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<SenderCertificateProto> for SenderCertificate {
+    type Error = tonic::Status;
+
+    fn try_from(value: SenderCertificateProto) -> Result<Self, Self::Error> {
+        let sender = parse_identity_key(value.sender_identity_key()).map_err(|e| {
+            Status::invalid_argument(format!("SenderCertificate invalid sender: {e}"))
+        })?;
+        let expiration = value.expiration.ok_or(Status::invalid_argument(
+            "SenderCertificate missing expiration.",
+        ))?;
+        let signature = Signature::from_slice(value.signature())
+            .map_err(|_| Status::invalid_argument("SenderCertificate has an invalid Signature"))?;
+        Ok(SenderCertificate {
+            sender,
+            expiration,
+            signature,
         })
     }
 }
 
-impl From<X3DHMessage> for MessageProto {
-    fn from(val: X3DHMessage) -> Self {
-        MessageProto {
-            sender_identity_key: Some(val.ik.to_bytes().to_vec()),
-            ephemeral_key: Some(val.ek.to_bytes().to_vec()),
-            pre_key: Some(val.pre_key.to_bytes().to_vec()),
-            one_time_key: val.opk.map(|opk| opk.to_bytes().to_vec()),
-            ciphertext: Some(val.ciphertext),
+impl From<SignedRequest> for SignedRequestProto {
+    fn from(val: SignedRequest) -> Self {
+        SignedRequestProto {
+            identity_key: Some(val.identity_key.encode()),
+            timestamp: Some(val.timestamp),
+            signature: Some(val.signature.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<SignedRequestProto> for SignedRequest {
+    type Error = tonic::Status;
+
+    fn try_from(value: SignedRequestProto) -> Result<Self, Self::Error> {
+        let identity_key = parse_identity_key(value.identity_key()).map_err(|e| {
+            Status::invalid_argument(format!("SignedRequest invalid identity_key: {e}"))
+        })?;
+        let timestamp = value
+            .timestamp
+            .ok_or(Status::invalid_argument("SignedRequest missing timestamp."))?;
+        let signature = Signature::from_slice(value.signature())
+            .map_err(|_| Status::invalid_argument("SignedRequest has an invalid Signature"))?;
+        Ok(SignedRequest {
+            identity_key,
+            timestamp,
+            signature,
+        })
+    }
+}
+
+impl TryFrom<UnsealedMessageProto> for UnsealedMessage {
+    type Error = tonic::Status;
+
+    fn try_from(value: UnsealedMessageProto) -> Result<Self, Self::Error> {
+        let certificate = value
+            .certificate
+            .ok_or(Status::invalid_argument(
+                "UnsealedMessage missing certificate.",
+            ))?
+            .try_into()?;
+        let message = value
+            .message
+            .ok_or(Status::invalid_argument("UnsealedMessage missing message."))?
+            .try_into()?;
+        Ok(UnsealedMessage {
+            certificate,
+            message,
+        })
+    }
+}
+
+impl From<UnsealedMessage> for UnsealedMessageProto {
+    fn from(val: UnsealedMessage) -> Self {
+        UnsealedMessageProto {
+            certificate: Some(val.certificate.into()),
+            message: Some(val.message.into()),
         }
     }
 }
@@ -134,16 +415,21 @@ impl TryInto<PreKeyBundle> for PreKeyBundleProto {
     type Error = tonic::Status;
 
     fn try_into(self) -> Result<PreKeyBundle, Self::Error> {
-        let ik = parse_verifying_key(self.identity_key())
+        let ik = parse_identity_key(self.identity_key())
             .map_err(|_| Status::invalid_argument("PreKeyBundle invalid identity_key"))?;
 
-        let opk = if let Some(opk) = self.one_time_key {
-            Some(
-                parse_x25519_public_key(&opk)
+        let opk = match (self.one_time_key, self.one_time_key_id) {
+            (Some(opk), Some(id)) => Some(PreKey {
+                id,
+                pre_key: parse_x25519_public_key(&opk)
                     .map_err(|e| Status::invalid_argument(format!("Invalid one_time_key: {e}")))?,
-            )
-        } else {
-            None
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "one_time_key and one_time_key_id must be set together.",
+                ))
+            }
         };
 
         let spk = self
@@ -151,7 +437,9 @@ impl TryInto<PreKeyBundle> for PreKeyBundleProto {
             .ok_or(Status::invalid_argument("PreKeyBundle missing spk."))?
             .try_into()?;
 
-        Ok(PreKeyBundle { ik, opk, spk })
+        let pqpk = self.kem_pre_key.map(TryInto::try_into).transpose()?;
+
+        Ok(PreKeyBundle { ik, opk, spk, pqpk })
     }
 }
 
@@ -159,7 +447,7 @@ impl TryInto<Message> for gossamer::Message {
     type Error = tonic::Status;
 
     fn try_into(self) -> Result<Message, Self::Error> {
-        let public_key = parse_verifying_key(self.public_key()).map_err(|e| {
+        let public_key = parse_identity_key(self.public_key()).map_err(|e| {
             Status::invalid_argument(format!("AppendKey has invalid public_key: {e}"))
         })?;
 
@@ -189,7 +477,7 @@ impl From<Message> for gossamer::Message {
     ) -> Self {
         Self {
             provider: Some(provider),
-            public_key: Some(public_key.as_bytes().to_vec()),
+            public_key: Some(public_key.encode()),
             action: Some(action as i32),
         }
     }
@@ -201,14 +489,14 @@ impl TryInto<GossamerSignedMessage> for gossamer::SignedMessage {
         let signature = Signature::from_slice(self.signature()).map_err(|_| {
             Status::invalid_argument("SignedMessage has an invalid X25519 Signature")
         })?;
-        let identity_key = parse_verifying_key(self.identity_key()).map_err(|e| {
+        let identity_key = parse_identity_key(self.identity_key()).map_err(|e| {
             Status::invalid_argument(format!(
                 "SignedMessage has invalid sender_identity_key: {e}"
             ))
         })?;
         let contents = self.contents();
         identity_key
-            .verify_strict(contents, &signature)
+            .verify(contents, &signature)
             .map_err(|_| Status::unauthenticated("SignedMessage signature invalid."))?;
 
         let message = gossamer::Message::decode(contents)
@@ -234,26 +522,118 @@ impl From<GossamerSignedMessage> for gossamer::SignedMessage {
         Self {
             contents: Some(contents.encode_to_vec()),
             signature: Some(val.signature.to_vec()),
-            identity_key: Some(val.identity_key.as_bytes().to_vec()),
+            identity_key: Some(val.identity_key.encode()),
         }
     }
 }
 
-pub struct RatchetHeader {
-    ratchet_key: X25519PublicKey,
-    message_number: u32,
-    chain_length: u32,
+/// The X3DH keys that a prekey message uses to start a new session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreKeys {
+    pub ek: X25519PublicKey,
+    /// The id of the recipient's signed prekey.
+    pub spk_id: u32,
+    /// The id of the recipient's one time prekey, if one was used.
+    pub opk_id: Option<u32>,
+    /// The recipient's KEM prekey and the ciphertext encapsulated to it. Only set for PQXDH.
+    pub pqpk: Option<(KemPublicKey, KemCiphertext)>,
+}
+
+/// A message sent from one device to another.
+#[derive(Clone, Debug)]
+pub struct DeviceMessage {
+    pub ik: IdentityPublicKey,
+    /// Only set on prekey messages, which start a new session with the recipient.
+    pub pre_keys: Option<PreKeys>,
+    pub message: RatchetMessage,
 }
 
+/// A message as delivered by the server.
+#[derive(Clone, Debug)]
+pub enum Envelope {
+    /// Only the recipient can learn the sender, by unsealing the message.
+    Sealed(SealedMessage),
+    Device(Box<DeviceMessage>),
+}
+
+/// The contents of a `SealedMessage`.
+#[derive(Clone, Debug)]
+pub struct UnsealedMessage {
+    pub certificate: SenderCertificate,
+    pub message: DeviceMessage,
+}
+
+/// A serialized `ApplicationMessage` encrypted by a Double Ratchet session.
+#[derive(Clone, Debug)]
 pub struct RatchetMessage {
-    pub header: Option<RatchetHeader>,
-    pub message: ApplicationMessage,
+    pub header: RatchetHeader,
+    pub ciphertext: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ApplicationMessage {
     pub sender: String,
-    pub text: String,
+    pub contents: MessageContents,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageContents {
+    Text(String),
+    /// Asks the sender to resend the message with the ratchet key and message number.
+    RetryRequest {
+        ratchet_key: X25519PublicKey,
+        message_number: u32,
+    },
+    Attachment(AttachmentPointer),
+}
+
+/// Where to download an encrypted attachment and how to decrypt it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachmentPointer {
+    pub digest: [u8; 32],
+    pub key: SecretKey,
+    /// The length of the plaintext.
+    pub size: u64,
+    pub content_type: String,
+    pub caption: Option<String>,
+}
+
+impl TryFrom<AttachmentPointerProto> for AttachmentPointer {
+    type Error = tonic::Status;
+    fn try_from(value: AttachmentPointerProto) -> Result<Self, Self::Error> {
+        let digest = value
+            .digest()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("AttachmentPointer has invalid digest."))?;
+        let key = <[u8; 32]>::try_from(value.key())
+            .map(SecretKey::from)
+            .map_err(|_| Status::invalid_argument("AttachmentPointer has invalid key."))?;
+        let size = value
+            .size
+            .ok_or(Status::invalid_argument("AttachmentPointer missing size."))?;
+        let content_type = value.content_type.ok_or(Status::invalid_argument(
+            "AttachmentPointer missing content_type.",
+        ))?;
+        Ok(AttachmentPointer {
+            digest,
+            key,
+            size,
+            content_type,
+            caption: value.caption,
+        })
+    }
+}
+
+impl From<AttachmentPointer> for AttachmentPointerProto {
+    fn from(val: AttachmentPointer) -> Self {
+        Self {
+            digest: Some(val.digest.to_vec()),
+            key: Some(val.key.as_bytes().to_vec()),
+            size: Some(val.size),
+            content_type: Some(val.content_type),
+            caption: val.caption,
+        }
+    }
 }
 
 impl TryInto<RatchetHeader> for application::ratchet_message::Header {
@@ -304,11 +684,29 @@ impl TryInto<ApplicationMessage> for application::Message {
             .ok_or(Status::invalid_argument(
                 "ApplicationMessage missing contents.",
             ))?;
-        let text = match contents {
-            ContentType::Text(text) => text,
-            _ => return Err(Status::unimplemented("Only text is supported.")),
+        let contents = match contents {
+            ContentType::Text(text) => MessageContents::Text(text),
+            ContentType::RetryRequest(retry_request) => {
+                let ratchet_key =
+                    parse_x25519_public_key(retry_request.ratchet_key()).map_err(|e| {
+                        Status::invalid_argument(format!("RetryRequest invalid ratchet_key: {e}"))
+                    })?;
+                let message_number =
+                    retry_request
+                        .message_number
+                        .ok_or(Status::invalid_argument(
+                            "RetryRequest missing message_number.",
+                        ))?;
+                MessageContents::RetryRequest {
+                    ratchet_key,
+                    message_number,
+                }
+            }
+            ContentType::Attachment(attachment) => {
+                MessageContents::Attachment(attachment.try_into()?)
+            }
         };
-        Ok(ApplicationMessage { sender, text })
+        Ok(ApplicationMessage { sender, contents })
     }
 }
 
@@ -319,7 +717,19 @@ impl From<ApplicationMessage> for application::Message {
                 username: Some(val.sender),
             }),
             contents: Some(Contents {
-                content_type: Some(ContentType::Text(val.text)),
+                content_type: Some(match val.contents {
+                    MessageContents::Text(text) => ContentType::Text(text),
+                    MessageContents::RetryRequest {
+                        ratchet_key,
+                        message_number,
+                    } => ContentType::RetryRequest(RetryRequest {
+                        ratchet_key: Some(ratchet_key.to_bytes().to_vec()),
+                        message_number: Some(message_number),
+                    }),
+                    MessageContents::Attachment(attachment) => {
+                        ContentType::Attachment(attachment.into())
+                    }
+                }),
             }),
         }
     }
@@ -328,20 +738,22 @@ impl From<ApplicationMessage> for application::Message {
 impl TryInto<RatchetMessage> for application::RatchetMessage {
     type Error = tonic::Status;
     fn try_into(self) -> Result<RatchetMessage, Self::Error> {
-        let header = self.header.map(|header| header.try_into()).transpose()?;
-        let message = self
-            .message
-            .ok_or(Status::invalid_argument("RatchetMessage missing message."))?
+        let header = self
+            .header
+            .ok_or(Status::invalid_argument("RatchetMessage missing header."))?
             .try_into()?;
-        Ok(RatchetMessage { header, message })
+        let ciphertext = self.ciphertext.ok_or(Status::invalid_argument(
+            "RatchetMessage missing ciphertext.",
+        ))?;
+        Ok(RatchetMessage { header, ciphertext })
     }
 }
 
 impl From<RatchetMessage> for application::RatchetMessage {
     fn from(val: RatchetMessage) -> Self {
         Self {
-            header: val.header.map(|header| header.into()),
-            message: Some(val.message.into()),
+            header: Some(val.header.into()),
+            ciphertext: Some(val.ciphertext),
         }
     }
 }
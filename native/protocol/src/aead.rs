@@ -1,12 +1,26 @@
+use aes_gcm_siv::Aes256GcmSiv;
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, OsRng, Payload},
-    ChaCha20Poly1305, Nonce,
+    aead::{rand_core::CryptoRngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305,
 };
+use hkdf::Hkdf;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
 use thiserror::Error;
+use zeroize::Zeroizing;
 
 const NONCE_LEN: usize = 12;
-const VERSION_TAG: u8 = 1;
+/// ChaCha20Poly1305 ciphertexts from before padding, which do not authenticate the tag. Decrypt
+/// only.
+const UNPADDED_VERSION_TAG: u8 = 1;
+const CHACHA20_POLY1305_VERSION_TAG: u8 = 2;
+const AES_256_GCM_SIV_VERSION_TAG: u8 = 3;
+const COMMITTING_CHACHA20_POLY1305_VERSION_TAG: u8 = 4;
+const COMMITMENT_LEN: usize = 32;
+/// Padded plaintexts are at least this long so that short messages all look the same.
+const MIN_PADDED_LEN: usize = 128;
+const PADDING_MARKER: u8 = 0x80;
 
 #[derive(Error, Debug, Serialize, Deserialize, PartialEq)]
 pub enum AeadError {
@@ -18,35 +32,219 @@ pub enum AeadError {
     Tag(u8),
     #[error("Invalid Ciphertext")]
     InvalidCiphertext,
+    #[error("Invalid Padding")]
+    Padding,
+    #[error("Key commitment did not match.")]
+    Commitment,
 }
 
-pub fn encrypt_data(payload: Payload, cipher: &ChaCha20Poly1305) -> Result<Vec<u8>, AeadError> {
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let ciphertext = cipher
-        .encrypt(&nonce, payload)
-        .map_err(|_| AeadError::Allocate)?;
+/*
+    Padmé - See https://petsymposium.org/popets/2019/popets-2019-0056.pdf
+    Rounds a length L up so that only O(log log L) bits of it are revealed. The padding overhead is
+    at most 12%.
+    E = floor(log2(L))
+    S = floor(log2(E)) + 1
+    The last E - S bits of the padded length are zero.
+*/
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let mask = (1usize << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+// pad(M) = M || 0x80 || 0x00...
+// The marker makes the padding unambiguous even if M ends in zero bytes.
+fn pad(msg: &[u8]) -> Vec<u8> {
+    let padded_len = padme((msg.len() + 1).max(MIN_PADDED_LEN));
+    let mut padded = Vec::with_capacity(padded_len);
+    padded.extend_from_slice(msg);
+    padded.push(PADDING_MARKER);
+    padded.resize(padded_len, 0);
+    padded
+}
 
-    Ok([vec![VERSION_TAG], nonce.to_vec(), ciphertext].concat())
+fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, AeadError> {
+    let marker = padded
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .ok_or(AeadError::Padding)?;
+    if padded[marker] != PADDING_MARKER {
+        return Err(AeadError::Padding);
+    }
+    padded.truncate(marker);
+    Ok(padded)
 }
 
-pub fn decrypt_data(
-    ciphertext: &[u8],
-    aad: &[u8],
-    cipher: &ChaCha20Poly1305,
+/*
+    Cipher suites
+    The version tag that prefixes a ciphertext selects its cipher suite, so suites can be added
+    without breaking old ciphertexts. Every suite uses a 32-byte key and a 12-byte nonce.
+    ciphertext = tag || nonce || AEAD(K, nonce, pad(M), tag || AD)
+    The tag is authenticated as part of the associated data, so relabelling a ciphertext as
+    another suite, or as the legacy unpadded format, fails to decrypt.
+    Decoders that predate a suite reject its tag with `AeadError::Tag`.
+*/
+
+/// The AEAD algorithms that a ciphertext's version tag can select.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    #[default]
+    ChaCha20Poly1305,
+    /// Nonce-misuse resistant. A repeated nonce only reveals whether two plaintexts are equal.
+    Aes256GcmSiv,
+    /// ChaCha20Poly1305 with a commitment to the key, so that a ciphertext only decrypts under
+    /// the key it was encrypted with.
+    CommittingChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    /// The version tag that prefixes ciphertexts of this suite.
+    pub fn version_tag(self) -> u8 {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => CHACHA20_POLY1305_VERSION_TAG,
+            CipherSuite::Aes256GcmSiv => AES_256_GCM_SIV_VERSION_TAG,
+            CipherSuite::CommittingChaCha20Poly1305 => COMMITTING_CHACHA20_POLY1305_VERSION_TAG,
+        }
+    }
+
+    fn encrypt(self, key: &[u8; 32], nonce: &[u8; NONCE_LEN], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::CommittingChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into())
+                    .encrypt(nonce.into(), payload)
+                    .ok()
+            }
+            CipherSuite::Aes256GcmSiv => Aes256GcmSiv::new(key.into())
+                .encrypt(nonce.into(), payload)
+                .ok(),
+        }
+    }
+
+    fn decrypt(self, key: &[u8; 32], nonce: &[u8; NONCE_LEN], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::CommittingChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into())
+                    .decrypt(nonce.into(), payload)
+                    .ok()
+            }
+            CipherSuite::Aes256GcmSiv => Aes256GcmSiv::new(key.into())
+                .decrypt(nonce.into(), payload)
+                .ok(),
+        }
+    }
+}
+
+/*
+    Key commitment
+    ChaCha20Poly1305 is not key-committing: a ciphertext can be crafted that decrypts under two
+    different keys. The committing suite encrypts under a key derived from K and prefixes the
+    ciphertext with a commitment to K, which the recipient checks before decrypting.
+    PRK = HKDF-Extract(salt = nonce, IKM = K)
+    commitment = HKDF-Expand(PRK, "BrongnalKeyCommitment", 32)
+    K' = HKDF-Expand(PRK, "BrongnalCommittedKey", 32)
+    ciphertext = tag || nonce || commitment || AEAD(K', nonce, pad(M), tag || AD)
+*/
+fn commit(key: &[u8; 32], nonce: &[u8; NONCE_LEN]) -> ([u8; COMMITMENT_LEN], Zeroizing<[u8; 32]>) {
+    let hk = Hkdf::<Sha256>::new(Some(nonce), key);
+    let mut commitment = [0u8; COMMITMENT_LEN];
+    hk.expand(b"BrongnalKeyCommitment", &mut commitment)
+        .unwrap();
+    let mut committed_key = Zeroizing::new([0u8; 32]);
+    hk.expand(b"BrongnalCommittedKey", committed_key.as_mut())
+        .unwrap();
+    (commitment, committed_key)
+}
+
+/// Pads and encrypts `payload.msg` with the default cipher suite.
+/// Returns `tag || nonce || ciphertext`.
+pub fn encrypt_data(payload: Payload, key: &[u8; 32]) -> Result<Vec<u8>, AeadError> {
+    encrypt_data_with_rng(payload, key, CipherSuite::default(), &mut OsRng)
+}
+
+/// `encrypt_data` with `suite` and the nonce drawn from `rng`.
+pub fn encrypt_data_with_rng(
+    payload: Payload,
+    key: &[u8; 32],
+    suite: CipherSuite,
+    rng: &mut impl CryptoRngCore,
 ) -> Result<Vec<u8>, AeadError> {
+    let tag = suite.version_tag();
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+    let (commitment, key) = match suite {
+        CipherSuite::CommittingChaCha20Poly1305 => {
+            let (commitment, key) = commit(key, &nonce);
+            (commitment.to_vec(), key)
+        }
+        _ => (Vec::new(), Zeroizing::new(*key)),
+    };
+    let ciphertext = suite
+        .encrypt(
+            &key,
+            &nonce,
+            Payload {
+                msg: &pad(payload.msg),
+                aad: &[&[tag], payload.aad].concat(),
+            },
+        )
+        .ok_or(AeadError::Allocate)?;
+
+    Ok([vec![tag], nonce.to_vec(), commitment, ciphertext].concat())
+}
+
+/// Decrypts a ciphertext from `encrypt_data` with the cipher suite selected by its version tag.
+pub fn decrypt_data(ciphertext: &[u8], aad: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, AeadError> {
     if ciphertext.len() < NONCE_LEN + 1 {
         return Err(AeadError::InvalidCiphertext);
     }
 
-    if ciphertext[0] != VERSION_TAG {
-        return Err(AeadError::Tag(ciphertext[0]));
-    }
+    let tag = ciphertext[0];
+    let (suite, legacy) = match tag {
+        UNPADDED_VERSION_TAG => (CipherSuite::ChaCha20Poly1305, true),
+        CHACHA20_POLY1305_VERSION_TAG => (CipherSuite::ChaCha20Poly1305, false),
+        AES_256_GCM_SIV_VERSION_TAG => (CipherSuite::Aes256GcmSiv, false),
+        COMMITTING_CHACHA20_POLY1305_VERSION_TAG => {
+            (CipherSuite::CommittingChaCha20Poly1305, false)
+        }
+        _ => return Err(AeadError::Tag(tag)),
+    };
 
-    let nonce_bytes = &ciphertext[1..(NONCE_LEN + 1)];
-    let msg = &ciphertext[(NONCE_LEN + 1)..];
-    cipher
-        .decrypt(Nonce::from_slice(nonce_bytes), Payload { msg, aad })
-        .map_err(|_| AeadError::Decrypt)
+    let nonce = ciphertext[1..(NONCE_LEN + 1)].try_into().unwrap();
+    let mut msg = &ciphertext[(NONCE_LEN + 1)..];
+    let key = match suite {
+        CipherSuite::CommittingChaCha20Poly1305 => {
+            if msg.len() < COMMITMENT_LEN {
+                return Err(AeadError::InvalidCiphertext);
+            }
+            let (commitment, key) = commit(key, nonce);
+            if !bool::from(commitment.ct_eq(&msg[..COMMITMENT_LEN])) {
+                return Err(AeadError::Commitment);
+            }
+            msg = &msg[COMMITMENT_LEN..];
+            key
+        }
+        _ => Zeroizing::new(*key),
+    };
+    let bound_aad = [&[tag], aad].concat();
+    let plaintext = suite
+        .decrypt(
+            &key,
+            nonce,
+            Payload {
+                msg,
+                aad: if legacy { aad } else { &bound_aad },
+            },
+        )
+        .ok_or(AeadError::Decrypt)?;
+    if legacy {
+        Ok(plaintext)
+    } else {
+        unpad(plaintext)
+    }
 }
 
 #[cfg(test)]
@@ -54,27 +252,65 @@ mod tests {
     use crate::aead::*;
     use anyhow::Result;
     use ary::ary;
-    use chacha20poly1305::KeyInit;
+    use chacha20poly1305::aead::rand_core::RngCore;
+
+    const SUITES: [CipherSuite; 3] = [
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::Aes256GcmSiv,
+        CipherSuite::CommittingChaCha20Poly1305,
+    ];
+
+    fn generate_key() -> [u8; 32] {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    fn encrypt(msg: &[u8], aad: &[u8], key: &[u8; 32], suite: CipherSuite) -> Result<Vec<u8>> {
+        Ok(encrypt_data_with_rng(
+            Payload { msg, aad },
+            key,
+            suite,
+            &mut OsRng,
+        )?)
+    }
+
+    /// Encrypts `msg` as is with ChaCha20Poly1305 under `tag` and `aad`.
+    fn encrypt_raw(msg: &[u8], aad: &[u8], key: &[u8; 32], tag: u8) -> Result<Vec<u8>> {
+        let nonce = [7u8; NONCE_LEN];
+        let ciphertext = CipherSuite::ChaCha20Poly1305
+            .encrypt(key, &nonce, Payload { msg, aad })
+            .ok_or(AeadError::Allocate)?;
+        Ok([vec![tag], nonce.to_vec(), ciphertext].concat())
+    }
 
     #[test]
     fn aead_roundtrip_success() -> Result<()> {
         let msg = b"Hello I am a plaintext.";
-        let aad = &[];
-        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
-
-        let ciphertext = encrypt_data(Payload { msg, aad }, &cipher)?;
-        let decrypted_data = decrypt_data(&ciphertext, aad, &cipher)?;
+        let aad = b"associated data";
+        let key = generate_key();
 
-        assert_eq!(&msg[..], &decrypted_data);
+        for suite in SUITES {
+            let ciphertext = encrypt(msg, aad, &key, suite)?;
+            assert_eq!(ciphertext[0], suite.version_tag());
+            assert_eq!(&decrypt_data(&ciphertext, aad, &key)?, msg);
+        }
+        let ciphertext = encrypt_data(Payload { msg, aad }, &key)?;
+        assert_eq!(ciphertext[0], CHACHA20_POLY1305_VERSION_TAG);
+        assert_eq!(&decrypt_data(&ciphertext, aad, &key)?, msg);
         Ok(())
     }
 
     #[test]
     fn invalid_ciphertext() {
-        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let key = generate_key();
 
         assert_eq!(
-            decrypt_data(&ary![VERSION_TAG, in b"123456789"], &[], &cipher),
+            decrypt_data(
+                &ary![CHACHA20_POLY1305_VERSION_TAG, in b"123456789"],
+                &[],
+                &key
+            ),
             Err(AeadError::InvalidCiphertext)
         );
     }
@@ -82,30 +318,176 @@ mod tests {
     #[test]
     fn invalid_tag() -> Result<()> {
         let msg = b"Hello I am a plaintext.";
-        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let key = generate_key();
+
+        let mut ciphertext = encrypt_data(Payload { msg, aad: &[] }, &key)?;
+        for tag in [0, 5, 0xFF] {
+            *ciphertext.first_mut().unwrap() = tag;
+            assert_eq!(
+                decrypt_data(&ciphertext, &[], &key),
+                Err(AeadError::Tag(tag))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cross_suite_rejected() -> Result<()> {
+        let msg = b"Hello I am a plaintext.";
+        let key = generate_key();
 
-        let mut ciphertext = encrypt_data(Payload { msg, aad: &[] }, &cipher)?;
-        *ciphertext.first_mut().unwrap() = 0;
+        for suite in SUITES {
+            for other in SUITES.into_iter().filter(|other| *other != suite) {
+                let mut ciphertext = encrypt(msg, &[], &key, suite)?;
+                ciphertext[0] = other.version_tag();
+                assert!(matches!(
+                    decrypt_data(&ciphertext, &[], &key),
+                    Err(AeadError::Decrypt | AeadError::Commitment)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn committing_rejects_other_keys() -> Result<()> {
+        let msg = b"Hello I am a plaintext.";
+        let key = generate_key();
+        let ciphertext = encrypt(msg, &[], &key, CipherSuite::CommittingChaCha20Poly1305)?;
+        assert_eq!(
+            ciphertext.len(),
+            1 + NONCE_LEN + COMMITMENT_LEN + MIN_PADDED_LEN + 16
+        );
 
         assert_eq!(
-            decrypt_data(&ciphertext, &[], &cipher),
-            Err(AeadError::Tag(0))
+            decrypt_data(&ciphertext, &[], &generate_key()),
+            Err(AeadError::Commitment)
+        );
+        let mut tampered = ciphertext.clone();
+        tampered[1 + NONCE_LEN] ^= 1;
+        assert_eq!(
+            decrypt_data(&tampered, &[], &key),
+            Err(AeadError::Commitment)
+        );
+        assert_eq!(
+            decrypt_data(&ciphertext[..1 + NONCE_LEN + 8], &[], &key),
+            Err(AeadError::InvalidCiphertext)
         );
         Ok(())
     }
 
+    #[test]
+    fn downgrade_rejected() -> Result<()> {
+        let msg = b"Hello I am a plaintext.";
+        let key = generate_key();
+
+        // The legacy format shares ChaCha20Poly1305 with the current one but does not
+        // authenticate the tag, so relabelling must still fail.
+        for suite in SUITES {
+            let mut ciphertext = encrypt(msg, &[], &key, suite)?;
+            ciphertext[0] = UNPADDED_VERSION_TAG;
+            assert_eq!(
+                decrypt_data(&ciphertext, &[], &key),
+                Err(AeadError::Decrypt)
+            );
+        }
+
+        let mut legacy = encrypt_raw(&pad(msg), &[], &key, UNPADDED_VERSION_TAG)?;
+        legacy[0] = CHACHA20_POLY1305_VERSION_TAG;
+        assert_eq!(decrypt_data(&legacy, &[], &key), Err(AeadError::Decrypt));
+        Ok(())
+    }
+
+    #[test]
+    fn padded_roundtrip() -> Result<()> {
+        let key = generate_key();
+        for suite in SUITES {
+            for msg in [
+                &b""[..],
+                b"\x80",
+                b"ends in zeros\0\0",
+                &[0x80; MIN_PADDED_LEN],
+            ] {
+                let ciphertext = encrypt(msg, &[], &key, suite)?;
+                assert_eq!(decrypt_data(&ciphertext, &[], &key)?, msg);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn padding_hides_length() -> Result<()> {
+        let key = generate_key();
+        let encrypted_len = |len: usize| -> Result<usize> {
+            Ok(encrypt_data(
+                Payload {
+                    msg: &vec![0u8; len],
+                    aad: &[],
+                },
+                &key,
+            )?
+            .len())
+        };
+        assert_eq!(encrypted_len(1)?, encrypted_len(MIN_PADDED_LEN - 1)?);
+        assert_eq!(encrypted_len(1000)?, encrypted_len(1020)?);
+        assert!(encrypted_len(10000)? - 10000 < 10000 / 8 + 64);
+        Ok(())
+    }
+
+    #[test]
+    fn padme_lengths() {
+        assert_eq!(padme(0), 0);
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(1000), 1024);
+        for len in 1..5000 {
+            let padded = padme(len);
+            assert!(padded >= len && padded - len <= len / 8);
+        }
+    }
+
+    #[test]
+    fn unpadded_ciphertext_decrypts() -> Result<()> {
+        let msg = b"Hello I am a plaintext.";
+        let key = generate_key();
+
+        let unpadded = encrypt_raw(msg, &[], &key, UNPADDED_VERSION_TAG)?;
+        assert_eq!(decrypt_data(&unpadded, &[], &key)?, msg);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_padding() -> Result<()> {
+        let key = generate_key();
+        for padded in [&b"no marker"[..], &[0; 16], &[]] {
+            let tag = CHACHA20_POLY1305_VERSION_TAG;
+            let ciphertext = encrypt_raw(padded, &[tag], &key, tag)?;
+            assert_eq!(
+                decrypt_data(&ciphertext, &[], &key),
+                Err(AeadError::Padding)
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn decryption_failure() -> Result<()> {
         let msg = b"Hello I am a plaintext.";
         let aad = &[];
-        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let key = generate_key();
 
-        let mut ciphertext = encrypt_data(Payload { msg, aad }, &cipher)?;
-        *ciphertext.last_mut().unwrap() = 0;
-        assert_eq!(
-            decrypt_data(&ciphertext, aad, &cipher),
-            Err(AeadError::Decrypt),
-        );
+        for suite in SUITES {
+            let mut ciphertext = encrypt(msg, aad, &key, suite)?;
+            *ciphertext.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                decrypt_data(&ciphertext, aad, &key),
+                Err(AeadError::Decrypt),
+            );
+            assert!(matches!(
+                decrypt_data(&encrypt(msg, aad, &key, suite)?, aad, &generate_key()),
+                Err(AeadError::Decrypt | AeadError::Commitment),
+            ));
+        }
         Ok(())
     }
 }
@@ -0,0 +1,235 @@
+use crate::secret::SecretKey;
+use crate::stream::{encrypted_len, DecryptReader, EncryptWriter};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/*
+    Attachments
+    Attachments are too large to send through the mailbox, so they are encrypted under a random
+    key and uploaded to the server as opaque blobs. Messages only carry a pointer to the blob.
+    K = 32 random bytes
+    blob = STREAM(K, plaintext)
+    digest = SHA-256(blob)
+    pointer = (digest, K)
+    The server stores blobs by their digest. The recipient checks the length and digest of the
+    blob it downloads, and discards the plaintext if either doesn't match.
+*/
+
+#[derive(Error, Debug)]
+pub enum AttachmentError {
+    #[error("Attachment io failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Attachment did not match its digest.")]
+    Digest,
+    #[error("Attachment did not match its size.")]
+    Size,
+}
+
+/// The secrets a recipient needs to find and decrypt an uploaded blob.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncryptedAttachment {
+    pub key: SecretKey,
+    pub digest: [u8; 32],
+    /// The length of the plaintext.
+    pub size: u64,
+}
+
+/// Returns the digest that identifies `blob`.
+pub fn digest(blob: &[u8]) -> [u8; 32] {
+    Sha256::digest(blob).into()
+}
+
+/// Returns the length of the blob that an attachment of `size` bytes is encrypted into.
+pub fn blob_len(size: u64) -> u64 {
+    encrypted_len(size)
+}
+
+struct DigestReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypts `plaintext` into `blob` under a new random key.
+pub fn encrypt_attachment<R: Read, W: Write>(
+    mut plaintext: R,
+    blob: W,
+) -> io::Result<EncryptedAttachment> {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let key = SecretKey::from(key);
+    let mut writer = EncryptWriter::new(
+        key.as_bytes(),
+        DigestWriter {
+            inner: blob,
+            hasher: Sha256::new(),
+        },
+    )?;
+    let size = io::copy(&mut plaintext, &mut writer)?;
+    let digest = writer.finish()?.hasher.finalize().into();
+    Ok(EncryptedAttachment { key, digest, size })
+}
+
+/// Decrypts `blob` into `plaintext` as it is read, and checks it against `digest` and the `size`
+/// of the plaintext. No more than the expected length of the blob is read.
+/// Plaintext is written before the checks finish, so it must be discarded if this fails.
+pub fn decrypt_attachment<R: Read, W: Write>(
+    key: &SecretKey,
+    digest: &[u8; 32],
+    size: u64,
+    blob: R,
+    mut plaintext: W,
+) -> Result<u64, AttachmentError> {
+    let expected_len = blob_len(size);
+    let mut blob = DigestReader {
+        inner: blob.take(expected_len + 1),
+        hasher: Sha256::new(),
+        len: 0,
+    };
+    let decrypted = DecryptReader::new(key.as_bytes(), &mut blob)
+        .and_then(|mut reader| io::copy(&mut reader, &mut plaintext));
+    // The digest covers the whole blob, including anything after a chunk that failed.
+    io::copy(&mut blob, &mut io::sink())?;
+    if <[u8; 32]>::from(blob.hasher.finalize()) != *digest {
+        return Err(AttachmentError::Digest);
+    }
+    if blob.len != expected_len || decrypted? != size {
+        return Err(AttachmentError::Size);
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attachment::*;
+    use crate::stream::CHUNK_SIZE;
+    use anyhow::Result;
+
+    #[test]
+    fn attachment_roundtrip() -> Result<()> {
+        let image: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        let mut blob = Vec::new();
+        let attachment = encrypt_attachment(image.as_slice(), &mut blob)?;
+        assert_eq!(attachment.size, image.len() as u64);
+        assert_eq!(attachment.digest, digest(&blob));
+
+        assert_eq!(blob.len() as u64, blob_len(attachment.size));
+
+        let mut plaintext = Vec::new();
+        let size = decrypt_attachment(
+            &attachment.key,
+            &attachment.digest,
+            attachment.size,
+            blob.as_slice(),
+            &mut plaintext,
+        )?;
+        assert_eq!(size, attachment.size);
+        assert_eq!(plaintext, image);
+        Ok(())
+    }
+
+    #[test]
+    fn blob_len_matches_encryption() -> Result<()> {
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+        ] {
+            let mut blob = Vec::new();
+            encrypt_attachment(vec![0; len].as_slice(), &mut blob)?;
+            assert_eq!(blob.len() as u64, blob_len(len as u64));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn attachment_size_mismatch_fails() -> Result<()> {
+        let mut blob = Vec::new();
+        let attachment = encrypt_attachment(&b"Hello"[..], &mut blob)?;
+        for size in [attachment.size - 1, attachment.size + 1] {
+            assert!(matches!(
+                decrypt_attachment(
+                    &attachment.key,
+                    &attachment.digest,
+                    size,
+                    blob.as_slice(),
+                    Vec::new()
+                ),
+                Err(AttachmentError::Size)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn attachment_keys_are_random() -> Result<()> {
+        let first = encrypt_attachment(&b"Hello"[..], Vec::new())?;
+        let second = encrypt_attachment(&b"Hello"[..], Vec::new())?;
+        assert_ne!(first.key, second.key);
+        assert_ne!(first.digest, second.digest);
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_attachment_fails() -> Result<()> {
+        let mut blob = Vec::new();
+        let attachment = encrypt_attachment(&b"Hello"[..], &mut blob)?;
+
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt_attachment(
+                &attachment.key,
+                &attachment.digest,
+                attachment.size,
+                tampered.as_slice(),
+                Vec::new()
+            ),
+            Err(AttachmentError::Digest)
+        ));
+
+        // A blob that matches its digest still has to decrypt.
+        assert!(matches!(
+            decrypt_attachment(
+                &SecretKey::from([0; 32]),
+                &attachment.digest,
+                attachment.size,
+                blob.as_slice(),
+                Vec::new()
+            ),
+            Err(AttachmentError::Io(_))
+        ));
+        Ok(())
+    }
+}
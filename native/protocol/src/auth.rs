@@ -0,0 +1,146 @@
+use crate::xeddsa::{IdentityKey, IdentityPublicKey};
+use ed25519_dalek::Signature;
+use thiserror::Error;
+
+/*
+    Request Authentication
+    A device proves to the server that it holds the identity key it makes a request as by signing
+    the request with that key. The signature covers a prefix that names the kind of request, the
+    time the request was made, and the contents of the request, so that it can't be reused for a
+    different request or replayed once it is stale.
+*/
+
+pub const PRE_KEY_REQUEST_PREFIX: &[u8] = b"BrongnalPreKeyRequest";
+pub const RETRIEVE_MESSAGES_PREFIX: &[u8] = b"BrongnalRetrieveMessages";
+pub const ACKNOWLEDGE_MESSAGES_PREFIX: &[u8] = b"BrongnalAcknowledgeMessages";
+pub const UPLOAD_ATTACHMENT_PREFIX: &[u8] = b"BrongnalUploadAttachment";
+/// How far in seconds a request's timestamp may be from the verifier's clock.
+pub const MAX_REQUEST_SKEW: u64 = 5 * 60;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Request signature failed to validate.")]
+    Signature,
+    #[error("Request timestamp is too far from the current time.")]
+    Stale,
+}
+
+/// A request signed by the identity key of the device that made it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedRequest {
+    pub identity_key: IdentityPublicKey,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+
+// prefix || Encode(IK) || big endian timestamp || contents
+fn request_contents(
+    prefix: &[u8],
+    identity_key: &IdentityPublicKey,
+    timestamp: u64,
+    contents: &[u8],
+) -> Vec<u8> {
+    [
+        prefix,
+        &identity_key.encode(),
+        &timestamp.to_be_bytes(),
+        contents,
+    ]
+    .concat()
+}
+
+/// The contents of a request to acknowledge the messages with `ids`.
+pub fn acknowledgement_contents(ids: &[u64]) -> Vec<u8> {
+    ids.iter().flat_map(|id| id.to_be_bytes()).collect()
+}
+
+impl SignedRequest {
+    pub fn sign(
+        identity_key: &IdentityKey,
+        prefix: &[u8],
+        contents: &[u8],
+        timestamp: u64,
+    ) -> SignedRequest {
+        let public_key = identity_key.public_key();
+        let signature =
+            identity_key.sign(&request_contents(prefix, &public_key, timestamp, contents));
+        SignedRequest {
+            identity_key: public_key,
+            timestamp,
+            signature,
+        }
+    }
+
+    /// Checks that the request for `contents` was signed by `identity_key` within
+    /// `MAX_REQUEST_SKEW` of `now`.
+    pub fn verify(&self, prefix: &[u8], contents: &[u8], now: u64) -> Result<(), AuthError> {
+        self.identity_key
+            .verify(
+                &request_contents(prefix, &self.identity_key, self.timestamp, contents),
+                &self.signature,
+            )
+            .map_err(|_| AuthError::Signature)?;
+        if now.abs_diff(self.timestamp) > MAX_REQUEST_SKEW {
+            return Err(AuthError::Stale);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+    use anyhow::Result;
+
+    #[test]
+    fn sign_verify() -> Result<()> {
+        let key = IdentityKey::generate();
+        let request = SignedRequest::sign(&key, PRE_KEY_REQUEST_PREFIX, b"bob", 1000);
+        assert_eq!(request.identity_key, key.public_key());
+        request.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1000)?;
+
+        assert_eq!(
+            request.verify(PRE_KEY_REQUEST_PREFIX, b"eve", 1000),
+            Err(AuthError::Signature)
+        );
+        assert_eq!(
+            request.verify(b"BrongnalOtherRequest", b"bob", 1000),
+            Err(AuthError::Signature)
+        );
+        let forged = SignedRequest {
+            identity_key: IdentityKey::generate().public_key(),
+            ..request.clone()
+        };
+        assert_eq!(
+            forged.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1000),
+            Err(AuthError::Signature)
+        );
+        let retimed = SignedRequest {
+            timestamp: 1001,
+            ..request
+        };
+        assert_eq!(
+            retimed.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1000),
+            Err(AuthError::Signature)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stale_request_rejected() -> Result<()> {
+        let key = IdentityKey::generate();
+        let request = SignedRequest::sign(&key, PRE_KEY_REQUEST_PREFIX, b"bob", 1000);
+        request.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1000 + MAX_REQUEST_SKEW)?;
+        request.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1000 - MAX_REQUEST_SKEW)?;
+        assert_eq!(
+            request.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 1001 + MAX_REQUEST_SKEW),
+            Err(AuthError::Stale)
+        );
+        assert_eq!(
+            request.verify(PRE_KEY_REQUEST_PREFIX, b"bob", 999 - MAX_REQUEST_SKEW),
+            Err(AuthError::Stale)
+        );
+        Ok(())
+    }
+}
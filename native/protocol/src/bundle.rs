@@ -1,79 +1,296 @@
+use crate::kem::{KemPublicKey, KemSecretKey};
+use crate::x3dh::{PreKey, SignedKemPreKey, SignedPreKey, SignedPreKeys};
+use crate::xeddsa::{IdentityKey, IdentityPublicKey};
 use blake2::{Blake2b512, Digest};
-use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use chacha20poly1305::aead::{rand_core::CryptoRngCore, OsRng};
+use ed25519_dalek::Signature;
+use thiserror::Error;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 
-pub fn sign_bundle(
-    signing_key: &SigningKey,
-    key_pairs: &[(X25519StaticSecret, X25519PublicKey)],
-) -> Signature {
+#[derive(Error, Debug, PartialEq)]
+pub enum BundleError {
+    #[error("{num_keys} pre key ids starting at {first_id} overflow.")]
+    IdOverflow { first_id: u32, num_keys: u32 },
+}
+
+// Signed prekeys and one time prekey bundles sign the ids and creation time of their keys so that
+// recipients can look keys up by id and tell how old they are.
+// The digests are prefixed so that a signature over one kind of prekey can't be passed off as
+// the other.
+fn pre_key_digest(prefix: &[u8], created: u64, pre_keys: &[PreKey]) -> Vec<u8> {
     let mut hasher = Blake2b512::new();
-    hasher.update(key_pairs.len().to_be_bytes());
-    for key_pair in key_pairs {
-        hasher.update(key_pair.1.as_bytes());
+    hasher.update(prefix);
+    hasher.update(created.to_be_bytes());
+    hasher.update((pre_keys.len() as u64).to_be_bytes());
+    for pre_key in pre_keys {
+        hasher.update(pre_key.id.to_be_bytes());
+        hasher.update(pre_key.pre_key.as_bytes());
     }
-    signing_key.sign(&hasher.finalize())
+    hasher.finalize().to_vec()
+}
+
+fn signed_pre_key_digest(pre_key: &PreKey, created: u64) -> Vec<u8> {
+    pre_key_digest(b"SPK", created, &[*pre_key])
+}
+
+fn bundle_digest(created: u64, pre_keys: &[PreKey]) -> Vec<u8> {
+    pre_key_digest(b"OPK", created, pre_keys)
+}
+
+/// Signs a bundle of one time prekeys created at `created` seconds since the Unix epoch.
+pub fn sign_bundle(signing_key: &IdentityKey, created: u64, pre_keys: &[PreKey]) -> Signature {
+    signing_key.sign(&bundle_digest(created, pre_keys))
 }
 
 pub fn verify_bundle(
-    verifying_key: &VerifyingKey,
-    public_keys: &[X25519PublicKey],
-    signature: &Signature,
+    verifying_key: &IdentityPublicKey,
+    bundle: &SignedPreKeys,
 ) -> Result<(), ed25519_dalek::ed25519::Error> {
+    verifying_key.verify(
+        &bundle_digest(bundle.created, &bundle.pre_keys),
+        &bundle.signature,
+    )
+}
+
+/// Signs a signed prekey created at `created` seconds since the Unix epoch.
+pub fn sign_pre_key(signing_key: &IdentityKey, pre_key: &PreKey, created: u64) -> Signature {
+    signing_key.sign(&signed_pre_key_digest(pre_key, created))
+}
+
+pub fn verify_pre_key(
+    verifying_key: &IdentityPublicKey,
+    pre_key: &SignedPreKey,
+) -> Result<(), ed25519_dalek::ed25519::Error> {
+    verifying_key.verify(
+        &signed_pre_key_digest(&pre_key.public(), pre_key.created),
+        &pre_key.signature,
+    )
+}
+
+pub fn create_signed_pre_key(
+    signing_key: &IdentityKey,
+    id: u32,
+    created: u64,
+) -> (X25519StaticSecret, SignedPreKey) {
+    create_signed_pre_key_with_rng(signing_key, id, created, &mut OsRng)
+}
+
+/// `create_signed_pre_key` with the prekey and then the signature nonce drawn from `rng`.
+pub fn create_signed_pre_key_with_rng(
+    signing_key: &IdentityKey,
+    id: u32,
+    created: u64,
+    rng: &mut impl CryptoRngCore,
+) -> (X25519StaticSecret, SignedPreKey) {
+    let secret_key = X25519StaticSecret::random_from_rng(&mut *rng);
+    let pre_key = PreKey {
+        id,
+        pre_key: X25519PublicKey::from(&secret_key),
+    };
+    let signature = signing_key.sign_with_rng(&signed_pre_key_digest(&pre_key, created), rng);
+    (
+        secret_key,
+        SignedPreKey {
+            id,
+            created,
+            pre_key: pre_key.pre_key,
+            signature,
+        },
+    )
+}
+
+/// KEM prekeys are vended one at a time, so each is signed individually.
+/// The digest is prefixed to distinguish it from a bundle of X25519 prekeys.
+fn kem_pre_key_digest(pre_key: &KemPublicKey) -> Vec<u8> {
     let mut hasher = Blake2b512::new();
-    hasher.update(public_keys.len().to_be_bytes());
-    for public_key in public_keys {
-        hasher.update(public_key.as_bytes());
-    }
-    verifying_key.verify_strict(&hasher.finalize(), signature)
+    hasher.update(b"KEM");
+    hasher.update(pre_key.to_bytes());
+    hasher.finalize().to_vec()
+}
+
+pub fn sign_kem_pre_key(signing_key: &IdentityKey, pre_key: &KemPublicKey) -> Signature {
+    signing_key.sign(&kem_pre_key_digest(pre_key))
+}
+
+pub fn verify_kem_pre_key(
+    verifying_key: &IdentityPublicKey,
+    pre_key: &SignedKemPreKey,
+) -> Result<(), ed25519_dalek::ed25519::Error> {
+    verifying_key.verify(&kem_pre_key_digest(&pre_key.pre_key), &pre_key.signature)
+}
+
+pub fn create_kem_pre_key(signing_key: &IdentityKey) -> (KemSecretKey, SignedKemPreKey) {
+    create_kem_pre_key_with_rng(signing_key, &mut OsRng)
+}
+
+/// `create_kem_pre_key` with the key seed and then the signature nonce drawn from `rng`.
+pub fn create_kem_pre_key_with_rng(
+    signing_key: &IdentityKey,
+    rng: &mut impl CryptoRngCore,
+) -> (KemSecretKey, SignedKemPreKey) {
+    let secret_key = KemSecretKey::generate_with_rng(rng);
+    let pre_key = secret_key.public_key();
+    let signature = signing_key.sign_with_rng(&kem_pre_key_digest(&pre_key), rng);
+    (secret_key, SignedKemPreKey { pre_key, signature })
 }
 
 pub struct X3DHPreKeyBundle {
-    pub bundle: Vec<(X25519StaticSecret, X25519PublicKey)>,
+    pub bundle: Vec<(X25519StaticSecret, PreKey)>,
+    pub created: u64,
     pub signature: Signature,
 }
 
-pub fn create_prekey_bundle(signing_key: &SigningKey, num_keys: u32) -> X3DHPreKeyBundle {
-    let bundle: Vec<_> = (0..num_keys)
-        .map(|_| {
-            let pkey = X25519StaticSecret::random();
-            let pubkey = X25519PublicKey::from(&pkey);
-            (pkey, pubkey)
+impl X3DHPreKeyBundle {
+    /// The public half of the bundle to upload.
+    pub fn signed_pre_keys(&self) -> SignedPreKeys {
+        SignedPreKeys {
+            created: self.created,
+            pre_keys: self.bundle.iter().map(|(_, pre_key)| *pre_key).collect(),
+            signature: self.signature,
+        }
+    }
+}
+
+/// Creates `num_keys` one time prekeys with ids counting up from `first_id`.
+/// Fails if the ids would overflow.
+pub fn create_prekey_bundle(
+    signing_key: &IdentityKey,
+    first_id: u32,
+    num_keys: u32,
+    created: u64,
+) -> Result<X3DHPreKeyBundle, BundleError> {
+    create_prekey_bundle_with_rng(signing_key, first_id, num_keys, created, &mut OsRng)
+}
+
+/// `create_prekey_bundle` with each prekey and then the signature nonce drawn from `rng`.
+pub fn create_prekey_bundle_with_rng(
+    signing_key: &IdentityKey,
+    first_id: u32,
+    num_keys: u32,
+    created: u64,
+    rng: &mut impl CryptoRngCore,
+) -> Result<X3DHPreKeyBundle, BundleError> {
+    let end = first_id
+        .checked_add(num_keys)
+        .ok_or(BundleError::IdOverflow { first_id, num_keys })?;
+    let bundle: Vec<_> = (first_id..end)
+        .map(|id| {
+            let pkey = X25519StaticSecret::random_from_rng(&mut *rng);
+            let pre_key = PreKey {
+                id,
+                pre_key: X25519PublicKey::from(&pkey),
+            };
+            (pkey, pre_key)
         })
         .collect();
-    let signature = sign_bundle(signing_key, &bundle);
-    X3DHPreKeyBundle { signature, bundle }
+    let pre_keys: Vec<_> = bundle.iter().map(|(_, pre_key)| *pre_key).collect();
+    let signature = signing_key.sign_with_rng(&bundle_digest(created, &pre_keys), rng);
+    Ok(X3DHPreKeyBundle {
+        bundle,
+        created,
+        signature,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::bundle::*;
     use anyhow::Result;
-    use chacha20poly1305::aead::OsRng;
 
     #[test]
     fn create_verify_bundle_success() -> Result<()> {
-        let key = SigningKey::generate(&mut OsRng);
+        let key = IdentityKey::generate();
         for bundle_size in [0, 1, 4] {
-            let signed_bundle = create_prekey_bundle(&key, bundle_size);
-            let bundle_keys: Vec<X25519PublicKey> = signed_bundle
-                .bundle
-                .into_iter()
-                .map(|pair| pair.1)
-                .collect();
-            verify_bundle(
-                &VerifyingKey::from(&key),
-                &bundle_keys,
-                &signed_bundle.signature,
-            )?;
-
-            let other_key = SigningKey::generate(&mut OsRng);
-            assert!(verify_bundle(
-                &VerifyingKey::from(&other_key),
-                &bundle_keys,
-                &signed_bundle.signature,
-            )
-            .is_err());
+            let signed_bundle = create_prekey_bundle(&key, 7, bundle_size, 1000)?.signed_pre_keys();
+            assert!(signed_bundle
+                .pre_keys
+                .iter()
+                .map(|key| key.id)
+                .eq(7..7 + bundle_size));
+            verify_bundle(&key.public_key(), &signed_bundle)?;
+
+            let other_key = IdentityKey::generate();
+            assert!(verify_bundle(&other_key.public_key(), &signed_bundle).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_ids_overflow() -> Result<()> {
+        let key = IdentityKey::generate();
+        let signed_bundle = create_prekey_bundle(&key, u32::MAX - 2, 2, 1000)?.signed_pre_keys();
+        assert_eq!(signed_bundle.pre_keys[1].id, u32::MAX - 1);
+        assert_eq!(
+            create_prekey_bundle(&key, u32::MAX - 1, 2, 1000).err(),
+            Some(BundleError::IdOverflow {
+                first_id: u32::MAX - 1,
+                num_keys: 2
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_signature_covers_ids_and_time() -> Result<()> {
+        let key = IdentityKey::generate();
+        let signed_bundle = create_prekey_bundle(&key, 1, 2, 1000)?.signed_pre_keys();
+
+        let mut changed_id = signed_bundle.clone();
+        changed_id.pre_keys[0].id = 3;
+        assert!(verify_bundle(&key.public_key(), &changed_id).is_err());
+        let mut changed_time = signed_bundle.clone();
+        changed_time.created = 2000;
+        assert!(verify_bundle(&key.public_key(), &changed_time).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_verify_signed_pre_key() -> Result<()> {
+        let key = IdentityKey::generate();
+        let (secret_key, spk) = create_signed_pre_key(&key, 5, 1000);
+        assert_eq!(X25519PublicKey::from(&secret_key), spk.pre_key);
+        verify_pre_key(&key.public_key(), &spk)?;
+        assert!(verify_pre_key(&IdentityKey::generate().public_key(), &spk).is_err());
+
+        for forged in [
+            SignedPreKey {
+                id: 6,
+                ..spk.clone()
+            },
+            SignedPreKey {
+                created: 2000,
+                ..spk.clone()
+            },
+        ] {
+            assert!(verify_pre_key(&key.public_key(), &forged).is_err());
         }
+
+        // A one time prekey bundle of one key does not verify as a signed prekey.
+        let bundle = create_prekey_bundle(&key, 5, 1, 1000)?;
+        let forged = SignedPreKey {
+            id: 5,
+            created: 1000,
+            pre_key: bundle.bundle[0].1.pre_key,
+            signature: bundle.signature,
+        };
+        assert!(verify_pre_key(&key.public_key(), &forged).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_verify_kem_pre_key() -> Result<()> {
+        let key = IdentityKey::generate();
+        let (secret_key, pre_key) = create_kem_pre_key(&key);
+        assert_eq!(secret_key.public_key(), pre_key.pre_key);
+        verify_kem_pre_key(&key.public_key(), &pre_key)?;
+        assert!(verify_kem_pre_key(&IdentityKey::generate().public_key(), &pre_key).is_err());
+
+        let (_, other) = create_kem_pre_key(&key);
+        let forged = SignedKemPreKey {
+            pre_key: other.pre_key,
+            signature: pre_key.signature,
+        };
+        assert!(verify_kem_pre_key(&key.public_key(), &forged).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,252 @@
+use crate::xeddsa::IdentityPublicKey;
+use sha2::{Digest, Sha512};
+use thiserror::Error;
+
+/*
+    Safety Numbers - See https://signal.org/blog/safety-number-updates/
+    Two users compare safety numbers out of band to check that the identity keys they were given
+    by the Gossamer ledger really belong to each other.
+    Each user's fingerprint is an iterated hash of the identity keys of all of their devices and
+    their username:
+        keys = Encode(IK1) || ... || Encode(IKn), sorted by encoding
+        hash = SHA-512(version || keys || username)
+        repeat ITERATIONS times: hash = SHA-512(hash || keys)
+    A user with a single device has the fingerprint of their only identity key.
+    The safety number is the digits of both fingerprints with the lower one first, so that both
+    users see the same number. The scannable fingerprint carries both fingerprints from the point
+    of view of the user displaying it.
+*/
+
+/// The version of the fingerprint format.
+pub const FINGERPRINT_VERSION: u8 = 0;
+const ITERATIONS: usize = 5200;
+const FINGERPRINT_LEN: usize = 32;
+const SCANNABLE_LEN: usize = 1 + 2 * FINGERPRINT_LEN;
+/// The number of digits in a safety number.
+pub const SAFETY_NUMBER_LEN: usize = 60;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FingerprintError {
+    #[error("Unknown fingerprint version: `{0}`")]
+    Version(u8),
+    #[error("Fingerprint had an invalid length.")]
+    Length,
+}
+
+/// The fingerprints of a conversation between a local and a remote user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    /// A number that both users can read to each other.
+    pub safety_number: String,
+    /// A payload to show as a QR code for the remote user to scan.
+    pub scannable: ScannableFingerprint,
+}
+
+/// The binary form of a `Fingerprint` encoded as `version || local || remote`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannableFingerprint {
+    local: [u8; FINGERPRINT_LEN],
+    remote: [u8; FINGERPRINT_LEN],
+}
+
+fn hash_identity(username: &str, iks: &[IdentityPublicKey]) -> [u8; 64] {
+    let mut keys: Vec<Vec<u8>> = iks.iter().map(IdentityPublicKey::encode).collect();
+    keys.sort();
+    let key = keys.concat();
+    let mut hash: [u8; 64] = Sha512::new()
+        .chain_update([0, FINGERPRINT_VERSION])
+        .chain_update(&key)
+        .chain_update(username.as_bytes())
+        .finalize()
+        .into();
+    for _ in 0..ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(&key)
+            .finalize()
+            .into();
+    }
+    hash
+}
+
+// Each 5 byte chunk of the first 30 bytes is a big endian number that is reduced to 5 digits.
+fn display_digits(hash: &[u8; 64]) -> String {
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            format!("{:05}", value % 100000)
+        })
+        .collect()
+}
+
+impl Fingerprint {
+    pub fn new(
+        local_username: &str,
+        local_ik: &IdentityPublicKey,
+        remote_username: &str,
+        remote_ik: &IdentityPublicKey,
+    ) -> Fingerprint {
+        Fingerprint::from_key_sets(
+            local_username,
+            std::slice::from_ref(local_ik),
+            remote_username,
+            std::slice::from_ref(remote_ik),
+        )
+    }
+
+    /// The fingerprint of a conversation between users with any number of devices.
+    /// The order of the identity keys does not matter.
+    pub fn from_key_sets(
+        local_username: &str,
+        local_iks: &[IdentityPublicKey],
+        remote_username: &str,
+        remote_iks: &[IdentityPublicKey],
+    ) -> Fingerprint {
+        let local = hash_identity(local_username, local_iks);
+        let remote = hash_identity(remote_username, remote_iks);
+        let (local_digits, remote_digits) = (display_digits(&local), display_digits(&remote));
+        let safety_number = if local_digits <= remote_digits {
+            local_digits + &remote_digits
+        } else {
+            remote_digits + &local_digits
+        };
+        Fingerprint {
+            safety_number,
+            scannable: ScannableFingerprint {
+                local: local[..FINGERPRINT_LEN].try_into().unwrap(),
+                remote: remote[..FINGERPRINT_LEN].try_into().unwrap(),
+            },
+        }
+    }
+}
+
+impl ScannableFingerprint {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [[FINGERPRINT_VERSION].as_slice(), &self.local, &self.remote].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ScannableFingerprint, FingerprintError> {
+        if bytes.is_empty() {
+            return Err(FingerprintError::Length);
+        }
+        if bytes[0] != FINGERPRINT_VERSION {
+            return Err(FingerprintError::Version(bytes[0]));
+        }
+        if bytes.len() != SCANNABLE_LEN {
+            return Err(FingerprintError::Length);
+        }
+        Ok(ScannableFingerprint {
+            local: bytes[1..1 + FINGERPRINT_LEN].try_into().unwrap(),
+            remote: bytes[1 + FINGERPRINT_LEN..].try_into().unwrap(),
+        })
+    }
+
+    /// Returns true if `scanned` was displayed by the remote user, which means both users have
+    /// the same identity keys for each other.
+    pub fn compare(&self, scanned: &[u8]) -> Result<bool, FingerprintError> {
+        let scanned = ScannableFingerprint::from_bytes(scanned)?;
+        Ok(scanned.local == self.remote && scanned.remote == self.local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fingerprint::*;
+    use crate::xeddsa::IdentityKey;
+    use anyhow::Result;
+
+    #[test]
+    fn safety_numbers_match() {
+        let alice = IdentityKey::generate().public_key();
+        let bob = IdentityKey::generate().public_key();
+        let alice_fingerprint = Fingerprint::new("alice", &alice, "bob", &bob);
+        let bob_fingerprint = Fingerprint::new("bob", &bob, "alice", &alice);
+
+        assert_eq!(
+            alice_fingerprint.safety_number,
+            bob_fingerprint.safety_number
+        );
+        assert_eq!(alice_fingerprint.safety_number.len(), SAFETY_NUMBER_LEN);
+        assert!(alice_fingerprint
+            .safety_number
+            .chars()
+            .all(|c| c.is_ascii_digit()));
+        assert_eq!(
+            Fingerprint::new("alice", &alice, "bob", &bob),
+            alice_fingerprint
+        );
+    }
+
+    #[test]
+    fn safety_number_changes_with_key() {
+        let alice = IdentityKey::generate().public_key();
+        let bob = IdentityKey::generate().public_key();
+        let mallory = IdentityKey::generate().public_key();
+
+        assert_ne!(
+            Fingerprint::new("alice", &alice, "bob", &bob).safety_number,
+            Fingerprint::new("alice", &alice, "bob", &mallory).safety_number
+        );
+    }
+
+    #[test]
+    fn safety_number_covers_every_device() {
+        let alice = IdentityKey::generate().public_key();
+        let bob = IdentityKey::generate().public_key();
+        let bob_laptop = IdentityKey::generate().public_key();
+
+        assert_eq!(
+            Fingerprint::from_key_sets("alice", &[alice], "bob", &[bob]),
+            Fingerprint::new("alice", &alice, "bob", &bob)
+        );
+        assert_eq!(
+            Fingerprint::from_key_sets("alice", &[alice], "bob", &[bob, bob_laptop]),
+            Fingerprint::from_key_sets("alice", &[alice], "bob", &[bob_laptop, bob])
+        );
+        assert_ne!(
+            Fingerprint::from_key_sets("alice", &[alice], "bob", &[bob, bob_laptop]).safety_number,
+            Fingerprint::new("alice", &alice, "bob", &bob).safety_number
+        );
+    }
+
+    #[test]
+    fn scannable_fingerprints_match() -> Result<()> {
+        let alice = IdentityKey::generate().public_key();
+        let bob = IdentityKey::generate().public_key();
+        let mallory = IdentityKey::generate().public_key();
+        let alice_fingerprint = Fingerprint::new("alice", &alice, "bob", &bob).scannable;
+        let bob_fingerprint = Fingerprint::new("bob", &bob, "alice", &alice).scannable;
+        let mitm_fingerprint = Fingerprint::new("bob", &bob, "alice", &mallory).scannable;
+
+        assert!(alice_fingerprint.compare(&bob_fingerprint.to_bytes())?);
+        assert!(bob_fingerprint.compare(&alice_fingerprint.to_bytes())?);
+        assert!(!alice_fingerprint.compare(&alice_fingerprint.to_bytes())?);
+        assert!(!alice_fingerprint.compare(&mitm_fingerprint.to_bytes())?);
+        Ok(())
+    }
+
+    #[test]
+    fn scannable_fingerprint_encoding() -> Result<()> {
+        let alice = IdentityKey::generate().public_key();
+        let bob = IdentityKey::generate().public_key();
+        let fingerprint = Fingerprint::new("alice", &alice, "bob", &bob).scannable;
+        let bytes = fingerprint.to_bytes();
+        assert_eq!(ScannableFingerprint::from_bytes(&bytes)?, fingerprint);
+
+        let mut unknown = bytes.clone();
+        unknown[0] = 0xFF;
+        assert_eq!(
+            fingerprint.compare(&unknown),
+            Err(FingerprintError::Version(0xFF))
+        );
+        assert_eq!(
+            fingerprint.compare(&bytes[..20]),
+            Err(FingerprintError::Length)
+        );
+        assert_eq!(fingerprint.compare(&[]), Err(FingerprintError::Length));
+        Ok(())
+    }
+}
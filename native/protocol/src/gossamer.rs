@@ -1,4 +1,5 @@
-use ed25519_dalek::{Signature, VerifyingKey};
+use crate::xeddsa::IdentityPublicKey;
+use ed25519_dalek::Signature;
 use num_enum::TryFromPrimitive;
 
 #[repr(i32)]
@@ -10,12 +11,12 @@ pub enum Action {
 
 pub struct Message {
     pub provider: Vec<u8>,
-    pub public_key: VerifyingKey,
+    pub public_key: IdentityPublicKey,
     pub action: Action,
 }
 
 pub struct SignedMessage {
     pub message: Message,
     pub signature: Signature,
-    pub identity_key: VerifyingKey,
+    pub identity_key: IdentityPublicKey,
 }
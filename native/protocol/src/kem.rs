@@ -0,0 +1,200 @@
+use crate::secret::SecretKey;
+use chacha20poly1305::aead::{rand_core::CryptoRngCore, OsRng};
+use ml_kem::kem::{Decapsulate, DecapsulationKey, Encapsulate, EncapsulationKey};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem1024, MlKem1024Params};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/*
+    ML-KEM - See https://csrc.nist.gov/pubs/fips/203/final
+    PQXDH uses ML-KEM-1024 (formerly CRYSTALS-Kyber-1024) as its post-quantum KEM.
+    Encaps(PK) - Returns a ciphertext CT and a shared secret SS.
+    Decaps(SK, CT) - Returns the shared secret SS from CT.
+*/
+
+#[derive(Error, Debug, PartialEq)]
+pub enum KemError {
+    #[error("KEM key or ciphertext had an invalid length.")]
+    Length,
+}
+
+/// An ML-KEM-1024 decapsulation key.
+#[derive(Clone)]
+pub struct KemSecretKey(DecapsulationKey<MlKem1024Params>);
+
+/// An ML-KEM-1024 encapsulation key.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct KemPublicKey(EncapsulationKey<MlKem1024Params>);
+
+/// An ML-KEM-1024 ciphertext that encapsulates a shared secret to a `KemPublicKey`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct KemCiphertext(Ciphertext<MlKem1024>);
+
+impl KemSecretKey {
+    pub fn generate() -> KemSecretKey {
+        KemSecretKey::generate_with_rng(&mut OsRng)
+    }
+
+    /// Draws the 64-byte seed d || z of the key from `rng`.
+    pub fn generate_with_rng(rng: &mut impl CryptoRngCore) -> KemSecretKey {
+        let (dk, _) = MlKem1024::generate(rng);
+        KemSecretKey(dk)
+    }
+
+    pub fn public_key(&self) -> KemPublicKey {
+        KemPublicKey(self.0.encapsulation_key().clone())
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.0.as_bytes().to_vec())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KemSecretKey, KemError> {
+        let encoded: Encoded<DecapsulationKey<MlKem1024Params>> =
+            bytes.try_into().map_err(|_| KemError::Length)?;
+        Ok(KemSecretKey(DecapsulationKey::from_bytes(&encoded)))
+    }
+
+    pub fn decapsulate(&self, ciphertext: &KemCiphertext) -> SecretKey {
+        // ML-KEM decapsulation uses implicit rejection, so it cannot fail.
+        SecretKey::from(<[u8; 32]>::from(self.0.decapsulate(&ciphertext.0).unwrap()))
+    }
+}
+
+impl PartialEq for KemSecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_bytes()
+            .as_slice()
+            .ct_eq(other.to_bytes().as_slice())
+            .into()
+    }
+}
+
+impl Eq for KemSecretKey {}
+
+impl std::fmt::Debug for KemSecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("KemSecretKey")
+            .field(&self.public_key())
+            .finish()
+    }
+}
+
+impl KemPublicKey {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KemPublicKey, KemError> {
+        let encoded: Encoded<EncapsulationKey<MlKem1024Params>> =
+            bytes.try_into().map_err(|_| KemError::Length)?;
+        Ok(KemPublicKey(EncapsulationKey::from_bytes(&encoded)))
+    }
+
+    /// Returns the ciphertext to send to the owner of the key and the shared secret.
+    pub fn encapsulate(&self) -> (KemCiphertext, SecretKey) {
+        self.encapsulate_with_rng(&mut OsRng)
+    }
+
+    /// Draws the 32-byte message m that is encapsulated from `rng`.
+    pub fn encapsulate_with_rng(&self, rng: &mut impl CryptoRngCore) -> (KemCiphertext, SecretKey) {
+        let (ciphertext, shared_secret) = self.0.encapsulate(rng).unwrap();
+        (
+            KemCiphertext(ciphertext),
+            SecretKey::from(<[u8; 32]>::from(shared_secret)),
+        )
+    }
+}
+
+#[allow(deprecated)]
+impl std::fmt::Debug for KemPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("KemPublicKey")
+            .field(&base64::encode(&self.to_bytes()[..16]))
+            .finish()
+    }
+}
+
+impl TryFrom<Vec<u8>> for KemPublicKey {
+    type Error = KemError;
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        KemPublicKey::from_bytes(&bytes)
+    }
+}
+
+impl From<KemPublicKey> for Vec<u8> {
+    fn from(key: KemPublicKey) -> Self {
+        key.to_bytes()
+    }
+}
+
+impl KemCiphertext {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KemCiphertext, KemError> {
+        Ok(KemCiphertext(
+            bytes.try_into().map_err(|_| KemError::Length)?,
+        ))
+    }
+}
+
+#[allow(deprecated)]
+impl std::fmt::Debug for KemCiphertext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("KemCiphertext")
+            .field(&base64::encode(&self.0[..16]))
+            .finish()
+    }
+}
+
+impl TryFrom<Vec<u8>> for KemCiphertext {
+    type Error = KemError;
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        KemCiphertext::from_bytes(&bytes)
+    }
+}
+
+impl From<KemCiphertext> for Vec<u8> {
+    fn from(ciphertext: KemCiphertext) -> Self {
+        ciphertext.to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kem::*;
+    use anyhow::Result;
+
+    #[test]
+    fn encapsulate_decapsulate() {
+        let secret_key = KemSecretKey::generate();
+        let (ciphertext, shared_secret) = secret_key.public_key().encapsulate();
+        assert_eq!(secret_key.decapsulate(&ciphertext), shared_secret);
+        assert_ne!(
+            KemSecretKey::generate().decapsulate(&ciphertext),
+            shared_secret
+        );
+    }
+
+    #[test]
+    fn encode_decode() -> Result<()> {
+        let secret_key = KemSecretKey::generate();
+        let (ciphertext, shared_secret) = secret_key.public_key().encapsulate();
+
+        let secret_key = KemSecretKey::from_bytes(&secret_key.to_bytes())?;
+        let public_key = KemPublicKey::from_bytes(&secret_key.public_key().to_bytes())?;
+        let ciphertext = KemCiphertext::from_bytes(&ciphertext.to_bytes())?;
+        assert_eq!(public_key, secret_key.public_key());
+        assert_eq!(secret_key.decapsulate(&ciphertext), shared_secret);
+
+        assert_eq!(KemPublicKey::from_bytes(&[0; 32]), Err(KemError::Length));
+        assert_eq!(KemCiphertext::from_bytes(&[0; 32]), Err(KemError::Length));
+        Ok(())
+    }
+}
@@ -1,5 +1,14 @@
-mod aead;
+pub mod aead;
+pub mod attachment;
+pub mod auth;
 pub mod bundle;
+pub mod fingerprint;
 pub mod gossamer;
+pub mod kem;
 pub mod ratchet;
+pub mod sealed_sender;
+pub mod secret;
+pub mod sender_keys;
+pub mod stream;
 pub mod x3dh;
+pub mod xeddsa;
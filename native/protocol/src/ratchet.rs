@@ -1,32 +1,94 @@
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError, CipherSuite};
+use crate::secret::SecretKey;
 use blake2::{Blake2b512, Digest};
-use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::{OsRng, Payload};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use thiserror::Error;
 use x25519_dalek::{
-    EphemeralSecret as X25519EphemeralSecret, PublicKey as X25519PublicKey, SharedSecret,
+    PublicKey as X25519PublicKey, SharedSecret, StaticSecret as X25519StaticSecret,
 };
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
+/*
+    Glossary - See https://signal.org/docs/specifications/doubleratchet/
+    DHs - The sending (self) ratchet key pair.
+    DHr - The remote party's current ratchet public key.
+    RK - Root Key. Mixed with each new DH output to derive fresh chain keys.
+    CKs, CKr - Sending and receiving chain keys.
+    Ns, Nr - Message numbers for sending and receiving.
+    PN - Number of messages in the previous sending chain.
+    MKSKIPPED - Message keys of skipped messages, indexed by ratchet public key and message number.
+*/
+
+/// The maximum number of message keys that can be skipped in a single chain.
+/// This bounds the work (and storage) a malicious sender can force on a recipient.
+pub const MAX_SKIP: u32 = 1000;
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 struct RootKey([u8; 32]);
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 struct ChainKey([u8; 32]);
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 struct MessageKey([u8; 32]);
 
+#[derive(Error, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RatchetError {
+    #[error("Session cannot send until it has received a message.")]
+    NoSendingChain,
+    #[error("Session has no receiving chain for this ratchet key.")]
+    NoReceivingChain,
+    #[error("Message skipped too many keys.")]
+    TooManySkipped,
+    #[error("Aead routine failed.")]
+    Aead(#[from] AeadError),
+}
+
+/// The header sent in the clear alongside every Double Ratchet ciphertext.
+/// * `ratchet_key` is the sender's current ratchet public key.
+/// * `message_number` is the message's index in the sending chain (Ns).
+/// * `chain_length` is the number of messages in the previous sending chain (PN).
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Header {
+    pub ratchet_key: X25519PublicKey,
+    pub message_number: u32,
+    pub chain_length: u32,
+}
+
+impl Header {
+    // CONCAT(ad, header): Encodes a message header into a parseable byte sequence, prepends the ad byte sequence, and returns the result.
+    fn concat(&self, ad: &[u8]) -> Vec<u8> {
+        [
+            ad,
+            self.ratchet_key.as_bytes(),
+            &self.message_number.to_be_bytes(),
+            &self.chain_length.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
 // GENERATE_DH(): Returns a new Diffie-Hellman key pair.
-fn generate_dh() -> X25519EphemeralSecret {
-    X25519EphemeralSecret::random_from_rng(OsRng)
+// A `StaticSecret` is used because the sending key pair is used for many DH calculations until the next ratchet step.
+fn generate_dh() -> X25519StaticSecret {
+    X25519StaticSecret::random_from_rng(OsRng)
 }
 
 // DH(dh_pair, dh_pub): Returns the output from the Diffie-Hellman calculation between the private key from the DH key pair dh_pair and the DH public key dh_pub. If the DH function rejects invalid public keys, then this function may raise an exception which terminates processing.
-fn dh(dh_pair: X25519EphemeralSecret, dh_pub: &X25519PublicKey) -> SharedSecret {
+fn dh(dh_pair: &X25519StaticSecret, dh_pub: &X25519PublicKey) -> SharedSecret {
     dh_pair.diffie_hellman(dh_pub)
 }
 
 impl RootKey {
     // KDF_RK(rk, dh_out): Returns a pair (32-byte root key, 32-byte chain key) as the output of applying a KDF keyed by a 32-byte root key rk to a Diffie-Hellman output dh_out.
-    fn kdf_rk(self, _dh_out: SharedSecret) -> (RootKey, ChainKey) {
-        let digest = Blake2b512::new()
-            .chain_update(b"RootKeyConstant")
-            .chain_update(self.0)
-            .finalize();
-        // TODO - figure out how to avoid the copies here?
-        let (l, r) = digest.split_at(32);
+    // This uses HKDF with SHA-256, rk as the salt, dh_out as the input key material, and an application-specific info string.
+    fn kdf_rk(&self, dh_out: SharedSecret) -> (RootKey, ChainKey) {
+        let hk = Hkdf::<Sha256>::new(Some(&self.0), dh_out.as_bytes());
+        let mut okm = Zeroizing::new([0u8; 64]);
+        hk.expand(b"BrongnalRatchet", okm.as_mut()).unwrap();
+        let (l, r) = okm.split_at(32);
         (
             RootKey(l.try_into().unwrap()),
             ChainKey(r.try_into().unwrap()),
@@ -37,12 +99,14 @@ impl RootKey {
 // Symmetric Ratchet
 impl ChainKey {
     // KDF_CK(ck): Returns a pair (32-byte chain key, 32-byte message key) as the output of applying a KDF keyed by a 32-byte chain key ck to some constant.
-    fn kdf_ck(self) -> (Self, MessageKey) {
-        let digest = Blake2b512::new()
-            .chain_update(b"ChainKeyConstant?")
-            .chain_update(self.0)
-            .finalize();
-        // TODO - figure out how to avoid the copies here?
+    fn kdf_ck(&self) -> (Self, MessageKey) {
+        let digest: Zeroizing<[u8; 64]> = Zeroizing::new(
+            Blake2b512::new()
+                .chain_update(b"ChainKeyConstant?")
+                .chain_update(self.0)
+                .finalize()
+                .into(),
+        );
         let (l, r) = digest.split_at(32);
         (
             ChainKey(l.try_into().unwrap()),
@@ -51,5 +115,292 @@ impl ChainKey {
     }
 }
 
-// TODO(https://github.com/brongan/brongnal/issues/7) - Implement ratcheting.
-// https://signal.org/docs/specifications/doubleratchet/
+/// The state of one party in a Double Ratchet session.
+/// Sessions are seeded from the X3DH shared secret SK:
+/// * the X3DH initiator (Alice) calls `Session::new_sender` with Bob's signed prekey.
+/// * the X3DH recipient (Bob) calls `Session::new_receiver` with his signed prekey pair.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    dhs: X25519StaticSecret,
+    dhr: Option<X25519PublicKey>,
+    rk: RootKey,
+    cks: Option<ChainKey>,
+    ckr: Option<ChainKey>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    mkskipped: HashMap<([u8; 32], u32), MessageKey>,
+}
+
+impl Session {
+    // RatchetInitAlice(state, SK, bob_dh_public_key)
+    /// Initializes the session for the party that sent the X3DH initial message.
+    /// `remote_ratchet_key` is the recipient's signed prekey that was used in X3DH.
+    pub fn new_sender(sk: SecretKey, remote_ratchet_key: X25519PublicKey) -> Session {
+        let dhs = generate_dh();
+        let (rk, cks) = RootKey(*sk.as_bytes()).kdf_rk(dh(&dhs, &remote_ratchet_key));
+        Session {
+            dhs,
+            dhr: Some(remote_ratchet_key),
+            rk,
+            cks: Some(cks),
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            mkskipped: HashMap::new(),
+        }
+    }
+
+    // RatchetInitBob(state, SK, bob_dh_key_pair)
+    /// Initializes the session for the party that received the X3DH initial message.
+    /// `ratchet_key` is the signed prekey that the sender used in X3DH.
+    pub fn new_receiver(sk: SecretKey, ratchet_key: X25519StaticSecret) -> Session {
+        Session {
+            dhs: ratchet_key,
+            dhr: None,
+            rk: RootKey(*sk.as_bytes()),
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            mkskipped: HashMap::new(),
+        }
+    }
+
+    /// The current public key of our sending ratchet.
+    pub fn ratchet_key(&self) -> X25519PublicKey {
+        X25519PublicKey::from(&self.dhs)
+    }
+
+    // RatchetEncrypt(state, plaintext, AD)
+    /// Encrypts `plaintext` with the next sending message key.
+    /// `ad` is authenticated along with the returned header.
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        ad: &[u8],
+    ) -> Result<(Header, Vec<u8>), RatchetError> {
+        self.encrypt_with_suite(plaintext, ad, CipherSuite::default())
+    }
+
+    /// `encrypt` with `suite`. The recipient decrypts with whichever suite the ciphertext's
+    /// version tag selects, so the suite can differ from message to message.
+    pub fn encrypt_with_suite(
+        &mut self,
+        plaintext: &[u8],
+        ad: &[u8],
+        suite: CipherSuite,
+    ) -> Result<(Header, Vec<u8>), RatchetError> {
+        let (cks, mk) = self
+            .cks
+            .as_ref()
+            .ok_or(RatchetError::NoSendingChain)?
+            .kdf_ck();
+        let header = Header {
+            ratchet_key: self.ratchet_key(),
+            message_number: self.ns,
+            chain_length: self.pn,
+        };
+        let ciphertext = encrypt_data_with_rng(
+            Payload {
+                msg: plaintext,
+                aad: &header.concat(ad),
+            },
+            &mk.0,
+            suite,
+            &mut OsRng,
+        )?;
+        self.cks = Some(cks);
+        self.ns += 1;
+        Ok((header, ciphertext))
+    }
+
+    // RatchetDecrypt(state, header, ciphertext, AD)
+    /// Decrypts a message, performing a DH ratchet step if the header carries a new ratchet key.
+    /// If any step fails the session is left unmodified.
+    pub fn decrypt(
+        &mut self,
+        header: &Header,
+        ciphertext: &[u8],
+        ad: &[u8],
+    ) -> Result<Vec<u8>, RatchetError> {
+        let aad = header.concat(ad);
+        let skipped = (header.ratchet_key.to_bytes(), header.message_number);
+        if let Some(mk) = self.mkskipped.get(&skipped) {
+            let plaintext = decrypt_data(ciphertext, &aad, &mk.0)?;
+            self.mkskipped.remove(&skipped);
+            return Ok(plaintext);
+        }
+
+        // Changes are made to a copy of the state which is only committed on success.
+        let mut state = self.clone();
+        if state.dhr != Some(header.ratchet_key) {
+            state.skip_message_keys(header.chain_length)?;
+            state.dh_ratchet(header);
+        }
+        state.skip_message_keys(header.message_number)?;
+        let (ckr, mk) = state
+            .ckr
+            .as_ref()
+            .ok_or(RatchetError::NoReceivingChain)?
+            .kdf_ck();
+        let plaintext = decrypt_data(ciphertext, &aad, &mk.0)?;
+        state.ckr = Some(ckr);
+        state.nr += 1;
+        *self = state;
+        Ok(plaintext)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), RatchetError> {
+        if self.nr.saturating_add(MAX_SKIP) < until {
+            return Err(RatchetError::TooManySkipped);
+        }
+        if let (Some(dhr), Some(mut ckr)) = (self.dhr, self.ckr.clone()) {
+            while self.nr < until {
+                let (next, mk) = ckr.kdf_ck();
+                self.mkskipped.insert((dhr.to_bytes(), self.nr), mk);
+                ckr = next;
+                self.nr += 1;
+            }
+            self.ckr = Some(ckr);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &Header) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = Some(header.ratchet_key);
+        let (rk, ckr) = self.rk.kdf_rk(dh(&self.dhs, &header.ratchet_key));
+        self.dhs = generate_dh();
+        let (rk, cks) = rk.kdf_rk(dh(&self.dhs, &header.ratchet_key));
+        self.rk = rk;
+        self.ckr = Some(ckr);
+        self.cks = Some(cks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ratchet::*;
+    use anyhow::Result;
+
+    const AD: &[u8] = b"alice ik || bob ik";
+
+    fn create_sessions() -> (Session, Session) {
+        let sk = SecretKey::from([7u8; 32]);
+        let bob_spk = X25519StaticSecret::random_from_rng(OsRng);
+        let alice = Session::new_sender(sk.clone(), X25519PublicKey::from(&bob_spk));
+        let bob = Session::new_receiver(sk, bob_spk);
+        (alice, bob)
+    }
+
+    #[test]
+    fn ratchet_roundtrip() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+
+        let (header, ciphertext) = alice.encrypt(b"Hello Bob!", AD)?;
+        assert_eq!(bob.decrypt(&header, &ciphertext, AD)?, b"Hello Bob!");
+
+        let (header, ciphertext) = bob.encrypt(b"Hello Alice!", AD)?;
+        assert_eq!(alice.decrypt(&header, &ciphertext, AD)?, b"Hello Alice!");
+        Ok(())
+    }
+
+    #[test]
+    fn receiver_cannot_send_first() {
+        let (_alice, mut bob) = create_sessions();
+        assert_eq!(
+            bob.encrypt(b"Hello Alice!", AD).err(),
+            Some(RatchetError::NoSendingChain)
+        );
+    }
+
+    #[test]
+    fn ratchet_rotates_keys() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let mut alice_keys = vec![alice.ratchet_key()];
+        for i in 0..3 {
+            let (header, ciphertext) = alice.encrypt(&[i], AD)?;
+            assert_eq!(bob.decrypt(&header, &ciphertext, AD)?, [i]);
+            let (header, ciphertext) = bob.encrypt(&[i], AD)?;
+            assert_eq!(alice.decrypt(&header, &ciphertext, AD)?, [i]);
+            alice_keys.push(alice.ratchet_key());
+        }
+        alice_keys.dedup();
+        assert_eq!(alice_keys.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn ratchet_roundtrip_aes_256_gcm_siv() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let (header, ciphertext) =
+            alice.encrypt_with_suite(b"Hello Bob!", AD, CipherSuite::Aes256GcmSiv)?;
+        assert_eq!(ciphertext[0], CipherSuite::Aes256GcmSiv.version_tag());
+        assert_eq!(bob.decrypt(&header, &ciphertext, AD)?, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_order_messages() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let messages = (0..4u8)
+            .map(|i| alice.encrypt(&[i], AD))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(bob.decrypt(&messages[2].0, &messages[2].1, AD)?, [2]);
+        assert_eq!(bob.decrypt(&messages[0].0, &messages[0].1, AD)?, [0]);
+
+        // Bob replies, ratcheting Alice forward, before the stragglers arrive.
+        let (header, ciphertext) = bob.encrypt(b"ack", AD)?;
+        assert_eq!(alice.decrypt(&header, &ciphertext, AD)?, b"ack");
+        let (header, ciphertext) = alice.encrypt(&[4], AD)?;
+        assert_eq!(bob.decrypt(&header, &ciphertext, AD)?, [4]);
+
+        assert_eq!(bob.decrypt(&messages[3].0, &messages[3].1, AD)?, [3]);
+        assert_eq!(bob.decrypt(&messages[1].0, &messages[1].1, AD)?, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_message_fails() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let (header, ciphertext) = alice.encrypt(b"Hello Bob!", AD)?;
+        bob.decrypt(&header, &ciphertext, AD)?;
+        assert!(bob.decrypt(&header, &ciphertext, AD).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_skipped() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let (mut header, ciphertext) = alice.encrypt(b"Hello Bob!", AD)?;
+        header.message_number = MAX_SKIP + 1;
+        assert_eq!(
+            bob.decrypt(&header, &ciphertext, AD),
+            Err(RatchetError::TooManySkipped)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_decrypt_preserves_state() -> Result<()> {
+        let (mut alice, mut bob) = create_sessions();
+        let (header, ciphertext) = alice.encrypt(b"Hello Bob!", AD)?;
+
+        assert_eq!(
+            bob.decrypt(&header, &ciphertext, b"wrong associated data"),
+            Err(RatchetError::Aead(AeadError::Decrypt))
+        );
+        let mut tampered = header;
+        tampered.chain_length += 1;
+        assert!(bob.decrypt(&tampered, &ciphertext, AD).is_err());
+
+        assert_eq!(bob.decrypt(&header, &ciphertext, AD)?, b"Hello Bob!");
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use crate::aead::{decrypt_data, encrypt_data, AeadError};
+use crate::xeddsa::{IdentityKey, IdentityPublicKey};
+use chacha20poly1305::aead::Payload;
+use ed25519_dalek::Signature;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey as X25519PublicKey, ReusableSecret as X25519ReusableSecret};
+use zeroize::Zeroizing;
+
+/*
+    Sealed Sender - See https://signal.org/blog/sealed-sender/
+    Alice sends Bob a message without revealing her identity to the server.
+    E - An ephemeral X25519 key generated for each sealed message.
+    Alice's identity key is encrypted under a key derived from DH(E, IKB), and the contents are
+    encrypted under a key derived from DH(IKA, IKB) so that only Alice could have sealed them.
+    The server still learns the recipient, but Bob has to check that the sender is allowed to send
+    to him. Senders prove they are registered users with a short lived sender certificate that the
+    server signs with its own key.
+*/
+
+const CERTIFICATE_PREFIX: &[u8] = b"BrongnalSenderCertificate";
+const SEALED_SENDER_PREFIX: &[u8] = b"BrongnalSealedSender";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SealedSenderError {
+    #[error("Aead routine failed.")]
+    Aead(#[from] AeadError),
+    #[error("Sealed sender was not a valid identity key.")]
+    InvalidSender,
+    #[error("Sender certificate signature failed to validate.")]
+    CertificateSignature,
+    #[error("Sender certificate expired.")]
+    CertificateExpired,
+    #[error("Sender certificate does not match the sender.")]
+    SenderMismatch,
+}
+
+/// The server's attestation that `sender` is a registered device until `expiration`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderCertificate {
+    pub sender: IdentityPublicKey,
+    /// Seconds since the Unix epoch.
+    pub expiration: u64,
+    pub signature: Signature,
+}
+
+fn certificate_contents(sender: &IdentityPublicKey, expiration: u64) -> Vec<u8> {
+    [
+        CERTIFICATE_PREFIX,
+        &sender.encode(),
+        &expiration.to_be_bytes(),
+    ]
+    .concat()
+}
+
+impl SenderCertificate {
+    pub fn issue(
+        server_key: &IdentityKey,
+        sender: IdentityPublicKey,
+        expiration: u64,
+    ) -> SenderCertificate {
+        let signature = server_key.sign(&certificate_contents(&sender, expiration));
+        SenderCertificate {
+            sender,
+            expiration,
+            signature,
+        }
+    }
+
+    /// Checks that the certificate was signed by `trust_root` and has not expired at `now`.
+    pub fn validate(
+        &self,
+        trust_root: &IdentityPublicKey,
+        now: u64,
+    ) -> Result<(), SealedSenderError> {
+        trust_root
+            .verify(
+                &certificate_contents(&self.sender, self.expiration),
+                &self.signature,
+            )
+            .map_err(|_| SealedSenderError::CertificateSignature)?;
+        if now >= self.expiration {
+            return Err(SealedSenderError::CertificateExpired);
+        }
+        Ok(())
+    }
+}
+
+/// A message whose sender is only known to the recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SealedMessage {
+    pub ek: X25519PublicKey,
+    pub encrypted_static: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+// ephemeral_salt = "BrongnalSealedSender" || Encode(IKB) || E
+// (chain_key, ephemeral_key) = HKDF(ephemeral_salt, DH(E, IKB))
+fn ephemeral_keys(
+    recipient_ik: &IdentityPublicKey,
+    ek: &X25519PublicKey,
+    dh: &[u8; 32],
+) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let salt = [SEALED_SENDER_PREFIX, &recipient_ik.encode(), ek.as_bytes()].concat();
+    let mut okm = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(&salt), dh)
+        .expand(&[], okm.as_mut())
+        .unwrap();
+    (
+        Zeroizing::new(okm[..32].try_into().unwrap()),
+        Zeroizing::new(okm[32..].try_into().unwrap()),
+    )
+}
+
+// static_salt = chain_key || encrypted_static
+// static_key = HKDF(static_salt, DH(IKA, IKB))
+fn static_key(chain_key: &[u8; 32], encrypted_static: &[u8], dh: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    let salt = Zeroizing::new([chain_key.as_slice(), encrypted_static].concat());
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), dh)
+        .expand(&[], okm.as_mut())
+        .unwrap();
+    okm
+}
+
+/// Encrypts `contents` and the sender's identity key to `recipient_ik`.
+pub fn seal(
+    sender_ik: &IdentityKey,
+    recipient_ik: &IdentityPublicKey,
+    contents: &[u8],
+) -> Result<SealedMessage, SealedSenderError> {
+    let e = X25519ReusableSecret::random();
+    let ek = X25519PublicKey::from(&e);
+    let (chain_key, ephemeral_key) = ephemeral_keys(
+        recipient_ik,
+        &ek,
+        e.diffie_hellman(recipient_ik.as_x25519()).as_bytes(),
+    );
+    let encrypted_static = encrypt_data(
+        Payload {
+            msg: &sender_ik.public_key().encode(),
+            aad: &[],
+        },
+        &ephemeral_key,
+    )?;
+
+    let static_key = static_key(
+        &chain_key,
+        &encrypted_static,
+        sender_ik
+            .diffie_hellman(recipient_ik.as_x25519())
+            .as_bytes(),
+    );
+    let ciphertext = encrypt_data(
+        Payload {
+            msg: contents,
+            aad: &[],
+        },
+        &static_key,
+    )?;
+    Ok(SealedMessage {
+        ek,
+        encrypted_static,
+        ciphertext,
+    })
+}
+
+/// Decrypts a sealed message. Returns the sender's identity key and the contents.
+/// Callers must still check the sender certificate in the contents.
+pub fn unseal(
+    recipient_ik: &IdentityKey,
+    message: &SealedMessage,
+) -> Result<(IdentityPublicKey, Vec<u8>), SealedSenderError> {
+    let (chain_key, ephemeral_key) = ephemeral_keys(
+        &recipient_ik.public_key(),
+        &message.ek,
+        recipient_ik.diffie_hellman(&message.ek).as_bytes(),
+    );
+    let sender = decrypt_data(&message.encrypted_static, &[], &ephemeral_key)?;
+    let sender =
+        IdentityPublicKey::decode(&sender).map_err(|_| SealedSenderError::InvalidSender)?;
+
+    let static_key = static_key(
+        &chain_key,
+        &message.encrypted_static,
+        recipient_ik.diffie_hellman(sender.as_x25519()).as_bytes(),
+    );
+    let contents = decrypt_data(&message.ciphertext, &[], &static_key)?;
+    Ok((sender, contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aead::AeadError;
+    use crate::sealed_sender::*;
+    use anyhow::Result;
+
+    #[test]
+    fn seal_unseal() -> Result<()> {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let sealed = seal(&alice, &bob.public_key(), b"Hello Bob!")?;
+
+        let (sender, contents) = unseal(&bob, &sealed)?;
+        assert_eq!(sender, alice.public_key());
+        assert_eq!(contents, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[test]
+    fn unseal_wrong_recipient_fails() -> Result<()> {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let sealed = seal(&alice, &bob.public_key(), b"Hello Bob!")?;
+
+        assert_eq!(
+            unseal(&IdentityKey::generate(), &sealed),
+            Err(SealedSenderError::Aead(AeadError::Decrypt))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_sender_fails() -> Result<()> {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let mut sealed = seal(&alice, &bob.public_key(), b"Hello Bob!")?;
+        let eve = seal(&IdentityKey::generate(), &bob.public_key(), b"Hello Bob!")?;
+        sealed.encrypted_static = eve.encrypted_static;
+
+        assert!(unseal(&bob, &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn certificate_validation() {
+        let server = IdentityKey::generate();
+        let alice = IdentityKey::generate().public_key();
+        let certificate = SenderCertificate::issue(&server, alice, 100);
+
+        assert_eq!(certificate.validate(&server.public_key(), 99), Ok(()));
+        assert_eq!(
+            certificate.validate(&server.public_key(), 100),
+            Err(SealedSenderError::CertificateExpired)
+        );
+        assert_eq!(
+            certificate.validate(&IdentityKey::generate().public_key(), 99),
+            Err(SealedSenderError::CertificateSignature)
+        );
+
+        let forged = SenderCertificate {
+            expiration: 1000,
+            ..certificate
+        };
+        assert_eq!(
+            forged.validate(&server.public_key(), 99),
+            Err(SealedSenderError::CertificateSignature)
+        );
+    }
+}
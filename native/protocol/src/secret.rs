@@ -0,0 +1,46 @@
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// 32 bytes of secret key material, such as the X3DH shared secret SK.
+/// The bytes are wiped when the key is dropped and never printed by `Debug`.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for SecretKey {
+    fn from(bytes: [u8; 32]) -> SecretKey {
+        SecretKey(bytes)
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::*;
+
+    #[test]
+    fn debug_hides_secret() {
+        let key = SecretKey::from([0xAB; 32]);
+        assert_eq!(format!("{key:?}"), "SecretKey(..)");
+        assert_eq!(key, SecretKey::from([0xAB; 32]));
+        assert_ne!(key, SecretKey::from([0xAC; 32]));
+    }
+}
@@ -0,0 +1,605 @@
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError, CipherSuite};
+use crate::secret::SecretKey;
+use crate::xeddsa::{IdentityKey, IdentityPublicKey, KeyEncodingError};
+use blake2::{Blake2b512, Digest};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng, Payload};
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/*
+    Sender Keys - See https://signal.org/blog/private-groups/
+    Every member of a group has a sender key for that group: a symmetric chain key and a signing key.
+    A member shares its sender key with every other member once, in a SenderKeyDistributionMessage
+    that is encrypted with the pairwise session with each member device.
+    Each group message is then encrypted once with the next message key in the sender's chain and
+    signed with the sender's signing key, so one ciphertext can be fanned out to every member.
+    The signature stops other members, who also know the chain key, from forging messages.
+    CK - The sender's chain key. Advanced once per message like the Double Ratchet's symmetric ratchet.
+    Sender keys do not heal after a compromise. Members rotate them by distributing a new chain, e.g.
+    when a member leaves the group.
+    Group messages use the key-committing cipher suite, so every member that accepts a ciphertext
+    decrypts it to the same plaintext.
+*/
+
+/// The maximum number of message keys that can be skipped in a single chain.
+pub const MAX_SKIP: u32 = 1000;
+/// The maximum number of skipped message keys stored for a chain. The oldest are evicted first.
+pub const MAX_SKIPPED_KEYS: usize = 2 * MAX_SKIP as usize;
+
+const GROUP_MESSAGE_PREFIX: &[u8] = b"BrongnalGroupMessage";
+const GROUP_CIPHER_SUITE: CipherSuite = CipherSuite::CommittingChaCha20Poly1305;
+const DISTRIBUTION_MESSAGE_LEN: usize = 16 + 4 + 4 + 32 + 33;
+/// The length of a group message without its ciphertext.
+const GROUP_MESSAGE_HEADER_LEN: usize = 16 + 4 + 4 + 64;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SenderKeyError {
+    #[error("Group message is for a different group.")]
+    GroupMismatch,
+    #[error("No sender key for chain `{0}`.")]
+    UnknownChain(u32),
+    #[error("Group message signature failed to validate.")]
+    Signature,
+    #[error("Group message was already received.")]
+    Duplicate,
+    #[error("Message skipped too many keys.")]
+    TooManySkipped,
+    #[error("Sender key chain has no more message keys.")]
+    ChainExhausted,
+    #[error("Message had an invalid length.")]
+    Length,
+    #[error("Distribution message has an invalid signing key: {0}")]
+    InvalidSigningKey(#[from] KeyEncodingError),
+    #[error("Aead routine failed.")]
+    Aead(#[from] AeadError),
+}
+
+/// Identifies a group. Sender keys are scoped to a single group.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct GroupId(pub [u8; 16]);
+
+impl GroupId {
+    pub fn generate() -> GroupId {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        GroupId(id)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct ChainKey([u8; 32]);
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct MessageKey([u8; 32]);
+
+impl ChainKey {
+    // KDF_CK(ck): Returns a pair (32-byte chain key, 32-byte message key).
+    fn kdf_ck(&self) -> (Self, MessageKey) {
+        let digest: Zeroizing<[u8; 64]> = Zeroizing::new(
+            Blake2b512::new()
+                .chain_update(b"BrongnalSenderKey")
+                .chain_update(self.0)
+                .finalize()
+                .into(),
+        );
+        let (l, r) = digest.split_at(32);
+        (
+            ChainKey(l.try_into().unwrap()),
+            MessageKey(r.try_into().unwrap()),
+        )
+    }
+}
+
+/// Shares a sender key with another member of the group.
+/// Must only be sent inside an encrypted pairwise session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderKeyDistributionMessage {
+    pub group_id: GroupId,
+    pub chain_id: u32,
+    /// The index of the next message in the chain. Earlier messages cannot be decrypted.
+    pub iteration: u32,
+    pub chain_key: SecretKey,
+    pub signing_key: IdentityPublicKey,
+}
+
+/// A message encrypted once for every member of a group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupMessage {
+    pub group_id: GroupId,
+    pub chain_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    /// Signature by the sender's signing key over the rest of the message.
+    pub signature: Signature,
+}
+
+// AD = group_id || chain_id || iteration
+fn associated_data(group_id: &GroupId, chain_id: u32, iteration: u32) -> Vec<u8> {
+    [
+        group_id.0.as_slice(),
+        &chain_id.to_be_bytes(),
+        &iteration.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Decrypts a group message ciphertext, rejecting suites that do not commit to the key.
+fn decrypt_group_data(
+    ciphertext: &[u8],
+    aad: &[u8],
+    mk: &MessageKey,
+) -> Result<Vec<u8>, AeadError> {
+    match ciphertext.first() {
+        Some(&tag) if tag != GROUP_CIPHER_SUITE.version_tag() => Err(AeadError::Tag(tag)),
+        _ => decrypt_data(ciphertext, aad, &mk.0),
+    }
+}
+
+impl GroupMessage {
+    // "BrongnalGroupMessage" || AD || ciphertext
+    fn signed_contents(&self) -> Vec<u8> {
+        [
+            GROUP_MESSAGE_PREFIX,
+            &associated_data(&self.group_id, self.chain_id, self.iteration),
+            &self.ciphertext,
+        ]
+        .concat()
+    }
+}
+
+impl GroupMessage {
+    /// Encodes the message as `group_id || chain_id || iteration || signature || ciphertext`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.group_id.0.as_slice(),
+            &self.chain_id.to_be_bytes(),
+            &self.iteration.to_be_bytes(),
+            &self.signature.to_bytes(),
+            &self.ciphertext,
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<GroupMessage, SenderKeyError> {
+        if bytes.len() < GROUP_MESSAGE_HEADER_LEN {
+            return Err(SenderKeyError::Length);
+        }
+        let (group_id, bytes) = bytes.split_at(16);
+        let (chain_id, bytes) = bytes.split_at(4);
+        let (iteration, bytes) = bytes.split_at(4);
+        let (signature, ciphertext) = bytes.split_at(64);
+        Ok(GroupMessage {
+            group_id: GroupId(group_id.try_into().unwrap()),
+            chain_id: u32::from_be_bytes(chain_id.try_into().unwrap()),
+            iteration: u32::from_be_bytes(iteration.try_into().unwrap()),
+            ciphertext: ciphertext.to_vec(),
+            signature: Signature::from_bytes(signature.try_into().unwrap()),
+        })
+    }
+}
+
+impl SenderKeyDistributionMessage {
+    /// Encodes the message as `group_id || chain_id || iteration || chain_key || signing_key`.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(
+            [
+                self.group_id.0.as_slice(),
+                &self.chain_id.to_be_bytes(),
+                &self.iteration.to_be_bytes(),
+                self.chain_key.as_bytes(),
+                &self.signing_key.encode(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SenderKeyDistributionMessage, SenderKeyError> {
+        if bytes.len() != DISTRIBUTION_MESSAGE_LEN {
+            return Err(SenderKeyError::Length);
+        }
+        let (group_id, bytes) = bytes.split_at(16);
+        let (chain_id, bytes) = bytes.split_at(4);
+        let (iteration, bytes) = bytes.split_at(4);
+        let (chain_key, signing_key) = bytes.split_at(32);
+        Ok(SenderKeyDistributionMessage {
+            group_id: GroupId(group_id.try_into().unwrap()),
+            chain_id: u32::from_be_bytes(chain_id.try_into().unwrap()),
+            iteration: u32::from_be_bytes(iteration.try_into().unwrap()),
+            chain_key: SecretKey::from(<[u8; 32]>::try_from(chain_key).unwrap()),
+            signing_key: IdentityPublicKey::decode(signing_key)?,
+        })
+    }
+}
+
+/// Our sender key for a group.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    group_id: GroupId,
+    chain_id: u32,
+    iteration: u32,
+    chain_key: ChainKey,
+    signing_key: IdentityKey,
+}
+
+impl SenderKey {
+    /// Creates a sender key with a new chain. Members must receive its distribution message
+    /// before they can decrypt messages from it.
+    pub fn generate(group_id: GroupId) -> SenderKey {
+        let mut chain_key = ChainKey([0u8; 32]);
+        OsRng.fill_bytes(&mut chain_key.0);
+        SenderKey {
+            group_id,
+            chain_id: OsRng.next_u32(),
+            iteration: 0,
+            chain_key,
+            signing_key: IdentityKey::generate(),
+        }
+    }
+
+    /// Returns the message that lets another member decrypt our future messages to the group.
+    pub fn distribution_message(&self) -> SenderKeyDistributionMessage {
+        SenderKeyDistributionMessage {
+            group_id: self.group_id,
+            chain_id: self.chain_id,
+            iteration: self.iteration,
+            chain_key: SecretKey::from(self.chain_key.0),
+            signing_key: self.signing_key.public_key(),
+        }
+    }
+
+    /// Encrypts and signs `plaintext` with the next message key in the chain.
+    /// Fails with `ChainExhausted` once the chain has no more iterations, after which a new
+    /// sender key must be generated and distributed.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<GroupMessage, SenderKeyError> {
+        let next_iteration = self
+            .iteration
+            .checked_add(1)
+            .ok_or(SenderKeyError::ChainExhausted)?;
+        let (chain_key, mk) = self.chain_key.kdf_ck();
+        let ciphertext = encrypt_data_with_rng(
+            Payload {
+                msg: plaintext,
+                aad: &associated_data(&self.group_id, self.chain_id, self.iteration),
+            },
+            &mk.0,
+            GROUP_CIPHER_SUITE,
+            &mut OsRng,
+        )?;
+        let mut message = GroupMessage {
+            group_id: self.group_id,
+            chain_id: self.chain_id,
+            iteration: self.iteration,
+            ciphertext,
+            signature: Signature::from_bytes(&[0; 64]),
+        };
+        message.signature = self.signing_key.sign(&message.signed_contents());
+        self.chain_key = chain_key;
+        self.iteration = next_iteration;
+        Ok(message)
+    }
+}
+
+/// Another member's sender key for a group, created from their distribution message.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReceivedSenderKey {
+    group_id: GroupId,
+    chain_id: u32,
+    iteration: u32,
+    chain_key: ChainKey,
+    signing_key: IdentityPublicKey,
+    skipped: BTreeMap<u32, MessageKey>,
+}
+
+impl From<SenderKeyDistributionMessage> for ReceivedSenderKey {
+    fn from(message: SenderKeyDistributionMessage) -> Self {
+        ReceivedSenderKey {
+            group_id: message.group_id,
+            chain_id: message.chain_id,
+            iteration: message.iteration,
+            chain_key: ChainKey(*message.chain_key.as_bytes()),
+            signing_key: message.signing_key,
+            skipped: BTreeMap::new(),
+        }
+    }
+}
+
+impl ReceivedSenderKey {
+    pub fn chain_id(&self) -> u32 {
+        self.chain_id
+    }
+
+    /// Verifies and decrypts a group message from the owner of this sender key.
+    /// If any step fails the sender key is left unmodified.
+    pub fn decrypt(&mut self, message: &GroupMessage) -> Result<Vec<u8>, SenderKeyError> {
+        if message.group_id != self.group_id {
+            return Err(SenderKeyError::GroupMismatch);
+        }
+        if message.chain_id != self.chain_id {
+            return Err(SenderKeyError::UnknownChain(message.chain_id));
+        }
+        self.signing_key
+            .verify(&message.signed_contents(), &message.signature)
+            .map_err(|_| SenderKeyError::Signature)?;
+        let aad = associated_data(&message.group_id, message.chain_id, message.iteration);
+
+        if message.iteration < self.iteration {
+            let mk = self
+                .skipped
+                .get(&message.iteration)
+                .ok_or(SenderKeyError::Duplicate)?;
+            let plaintext = decrypt_group_data(&message.ciphertext, &aad, mk)?;
+            self.skipped.remove(&message.iteration);
+            return Ok(plaintext);
+        }
+
+        if self.iteration.saturating_add(MAX_SKIP) < message.iteration {
+            return Err(SenderKeyError::TooManySkipped);
+        }
+        let next_iteration = message
+            .iteration
+            .checked_add(1)
+            .ok_or(SenderKeyError::ChainExhausted)?;
+        // Changes are made to a copy of the state which is only committed on success.
+        let mut state = self.clone();
+        while state.iteration < message.iteration {
+            let (chain_key, mk) = state.chain_key.kdf_ck();
+            state.skipped.insert(state.iteration, mk);
+            state.chain_key = chain_key;
+            state.iteration += 1;
+        }
+        while state.skipped.len() > MAX_SKIPPED_KEYS {
+            state.skipped.pop_first();
+        }
+        let (chain_key, mk) = state.chain_key.kdf_ck();
+        let plaintext = decrypt_group_data(&message.ciphertext, &aad, &mk)?;
+        state.chain_key = chain_key;
+        state.iteration = next_iteration;
+        *self = state;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ratchet::Session;
+    use crate::sender_keys::*;
+    use anyhow::Result;
+    use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+    fn create_sender_keys() -> (SenderKey, ReceivedSenderKey) {
+        let alice = SenderKey::generate(GroupId::generate());
+        let bob = ReceivedSenderKey::from(alice.distribution_message());
+        (alice, bob)
+    }
+
+    #[test]
+    fn group_message_roundtrip() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let mut charlie = ReceivedSenderKey::from(alice.distribution_message());
+
+        for i in 0..3 {
+            let message = alice.encrypt(&[i])?;
+            assert_eq!(bob.decrypt(&message)?, [i]);
+            assert_eq!(charlie.decrypt(&message)?, [i]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn distribution_message_sent_over_session() -> Result<()> {
+        const AD: &[u8] = b"alice ik || bob ik";
+        let bob_spk = X25519StaticSecret::random_from_rng(OsRng);
+        let sk = SecretKey::from([7u8; 32]);
+        let mut alice_session = Session::new_sender(sk.clone(), X25519PublicKey::from(&bob_spk));
+        let mut bob_session = Session::new_receiver(sk, bob_spk);
+        let mut alice = SenderKey::generate(GroupId::generate());
+
+        let (header, ciphertext) =
+            alice_session.encrypt(&alice.distribution_message().to_bytes(), AD)?;
+        let distribution_message = SenderKeyDistributionMessage::from_bytes(
+            &bob_session.decrypt(&header, &ciphertext, AD)?,
+        )?;
+        assert_eq!(distribution_message, alice.distribution_message());
+
+        let mut bob = ReceivedSenderKey::from(distribution_message);
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"Hello Group!")?)?,
+            b"Hello Group!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn late_member_cannot_decrypt_earlier_messages() -> Result<()> {
+        let (mut alice, _bob) = create_sender_keys();
+        let earlier = alice.encrypt(b"Hello Bob!")?;
+        let mut charlie = ReceivedSenderKey::from(alice.distribution_message());
+
+        assert_eq!(charlie.decrypt(&earlier), Err(SenderKeyError::Duplicate));
+        assert_eq!(
+            charlie.decrypt(&alice.encrypt(b"Hello Charlie!")?)?,
+            b"Hello Charlie!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_order_messages() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let messages = (0..4)
+            .map(|i| alice.encrypt(&[i]))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(bob.decrypt(&messages[2])?, [2]);
+        assert_eq!(bob.decrypt(&messages[0])?, [0]);
+        assert_eq!(bob.decrypt(&messages[3])?, [3]);
+        assert_eq!(bob.decrypt(&messages[1])?, [1]);
+        assert_eq!(bob.decrypt(&messages[1]), Err(SenderKeyError::Duplicate));
+        Ok(())
+    }
+
+    #[test]
+    fn forged_message_rejected() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let mut message = alice.encrypt(b"Hello Bob!")?;
+
+        // Bob knows the chain key, but cannot sign as Alice.
+        let mut mallory = SenderKey {
+            signing_key: IdentityKey::generate(),
+            ..alice.clone()
+        };
+        let forged = GroupMessage {
+            iteration: message.iteration,
+            ..mallory.encrypt(b"Hello Eve!")?
+        };
+        assert_eq!(bob.decrypt(&forged), Err(SenderKeyError::Signature));
+
+        message.ciphertext[20] ^= 0xFF;
+        assert_eq!(bob.decrypt(&message), Err(SenderKeyError::Signature));
+        Ok(())
+    }
+
+    #[test]
+    fn group_messages_commit_to_key() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let message = alice.encrypt(b"Hello Bob!")?;
+        assert_eq!(message.ciphertext[0], GROUP_CIPHER_SUITE.version_tag());
+        assert_eq!(bob.decrypt(&message)?, b"Hello Bob!");
+
+        // Alice signs a ciphertext that does not commit to the message key.
+        let (_, mk) = alice.chain_key.kdf_ck();
+        let mut uncommitted = GroupMessage {
+            ciphertext: encrypt_data_with_rng(
+                Payload {
+                    msg: b"Hello Bob!",
+                    aad: &associated_data(&alice.group_id, alice.chain_id, alice.iteration),
+                },
+                &mk.0,
+                CipherSuite::ChaCha20Poly1305,
+                &mut OsRng,
+            )?,
+            ..message
+        };
+        uncommitted.iteration = alice.iteration;
+        uncommitted.signature = alice.signing_key.sign(&uncommitted.signed_contents());
+        assert_eq!(
+            bob.decrypt(&uncommitted),
+            Err(SenderKeyError::Aead(AeadError::Tag(
+                CipherSuite::ChaCha20Poly1305.version_tag()
+            )))
+        );
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"Hello again!")?)?,
+            b"Hello again!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_group_or_chain_rejected() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let mut other_group = SenderKey::generate(GroupId::generate());
+        assert_eq!(
+            bob.decrypt(&other_group.encrypt(b"Hello Bob!")?),
+            Err(SenderKeyError::GroupMismatch)
+        );
+
+        let mut rotated = SenderKey::generate(alice.group_id);
+        let message = rotated.encrypt(b"Hello Bob!")?;
+        assert_eq!(
+            bob.decrypt(&message),
+            Err(SenderKeyError::UnknownChain(message.chain_id))
+        );
+        assert_eq!(bob.decrypt(&alice.encrypt(b"Hello Bob!")?)?, b"Hello Bob!");
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_skipped_rejected() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        alice.iteration = MAX_SKIP + 1;
+        assert_eq!(
+            bob.decrypt(&alice.encrypt(b"Hello Bob!")?),
+            Err(SenderKeyError::TooManySkipped)
+        );
+        Ok(())
+    }
+
+    /// Advances `sender` by `n` messages without encrypting them.
+    fn skip(sender: &mut SenderKey, n: u32) {
+        for _ in 0..n {
+            let (chain_key, _) = sender.chain_key.kdf_ck();
+            sender.chain_key = chain_key;
+            sender.iteration += 1;
+        }
+    }
+
+    #[test]
+    fn skipped_keys_are_bounded() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let first = alice.encrypt(b"first")?;
+        for _ in 0..3 {
+            skip(&mut alice, MAX_SKIP - 1);
+            assert_eq!(bob.decrypt(&alice.encrypt(b"Hello Bob!")?)?, b"Hello Bob!");
+            assert!(bob.skipped.len() <= MAX_SKIPPED_KEYS);
+        }
+        assert_eq!(bob.skipped.len(), MAX_SKIPPED_KEYS);
+        // The oldest skipped keys were evicted.
+        assert_eq!(bob.decrypt(&first), Err(SenderKeyError::Duplicate));
+        Ok(())
+    }
+
+    #[test]
+    fn exhausted_chain_rejected() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        alice.iteration = u32::MAX - 1;
+        let last = alice.encrypt(b"Hello Bob!")?;
+        assert_eq!(
+            alice.encrypt(b"Hello Bob!"),
+            Err(SenderKeyError::ChainExhausted)
+        );
+        assert_eq!(alice.iteration, u32::MAX);
+
+        // A message at the last iteration would leave the receiver with no next iteration.
+        let mut message = GroupMessage {
+            iteration: u32::MAX,
+            ..last
+        };
+        message.signature = alice.signing_key.sign(&message.signed_contents());
+        bob.iteration = u32::MAX;
+        assert_eq!(bob.decrypt(&message), Err(SenderKeyError::ChainExhausted));
+        Ok(())
+    }
+
+    #[test]
+    fn group_message_encoding() -> Result<()> {
+        let (mut alice, mut bob) = create_sender_keys();
+        let message = alice.encrypt(b"Hello Bob!")?;
+        let bytes = message.to_bytes();
+        let decoded = GroupMessage::from_bytes(&bytes)?;
+        assert_eq!(decoded, message);
+        assert_eq!(bob.decrypt(&decoded)?, b"Hello Bob!");
+        assert_eq!(
+            GroupMessage::from_bytes(&bytes[..GROUP_MESSAGE_HEADER_LEN - 1]),
+            Err(SenderKeyError::Length)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn distribution_message_encoding() -> Result<()> {
+        let alice = SenderKey::generate(GroupId::generate());
+        let bytes = alice.distribution_message().to_bytes();
+        assert_eq!(
+            SenderKeyDistributionMessage::from_bytes(&bytes)?,
+            alice.distribution_message()
+        );
+        assert_eq!(
+            SenderKeyDistributionMessage::from_bytes(&bytes[1..]),
+            Err(SenderKeyError::Length)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/*
+    STREAM - See https://eprint.iacr.org/2015/189.pdf
+    Encrypts a plaintext of any length in fixed size chunks so that neither side has to hold the
+    whole plaintext in memory.
+    stream = VERSION_TAG || nonce_prefix || chunk_0 || ... || chunk_n
+    nonce_i = nonce_prefix || i (big endian u32) || last_flag
+    chunk_i = AEAD(K, nonce_i, plaintext[i * CHUNK_SIZE..(i + 1) * CHUNK_SIZE])
+    Every chunk but the last holds exactly CHUNK_SIZE bytes of plaintext. The last chunk is the
+    only one encrypted with last_flag = 1, so dropping trailing chunks, reordering chunks or
+    appending chunks makes decryption fail.
+*/
+
+const STREAM_VERSION_TAG: u8 = 1;
+const NONCE_PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = 1 + NONCE_PREFIX_LEN;
+const TAG_LEN: usize = 16;
+/// The number of plaintext bytes in every chunk except the last.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum StreamError {
+    #[error("Unexpected tag: `{0}`")]
+    Tag(u8),
+    #[error("Chunk encryption failed.")]
+    Encrypt,
+    #[error("Chunk failed to decrypt. The stream may be truncated or reordered.")]
+    Decrypt,
+    #[error("Stream has too many chunks.")]
+    Overflow,
+}
+
+impl From<StreamError> for io::Error {
+    fn from(e: StreamError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Returns the length of the stream that encrypts `len` bytes of plaintext.
+pub fn encrypted_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_SIZE as u64).max(1);
+    HEADER_LEN as u64 + len + chunks * TAG_LEN as u64
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_PREFIX_LEN + 4] = last as u8;
+    nonce
+}
+
+/// Encrypts everything written to it into `inner`.
+/// `finish` must be called to write the last chunk. A stream that is dropped without being
+/// finished fails to decrypt.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Writes the stream header to `inner`.
+    pub fn new(key: &[u8; 32], mut inner: W) -> io::Result<EncryptWriter<W>> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        inner.write_all(&[STREAM_VERSION_TAG])?;
+        inner.write_all(&nonce_prefix)?;
+        Ok(EncryptWriter {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + 1),
+        })
+    }
+
+    fn write_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, &self.buffer[..len])
+            .map_err(|_| StreamError::Encrypt)?;
+        self.counter = self.counter.checked_add(1).ok_or(StreamError::Overflow)?;
+        self.buffer.drain(..len);
+        self.inner.write_all(&ciphertext)
+    }
+
+    /// Writes the last chunk and returns `inner`.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.buffer.len() > CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE, false)?;
+        }
+        self.write_chunk(self.buffer.len(), true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    // A full chunk is only written once at least one more byte arrives, since the last chunk
+    // has to be encrypted differently.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() > CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE, false)?;
+        }
+        let len = buf.len().min(CHUNK_SIZE + 1 - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptWriter`.
+/// Plaintext is only returned from chunks that authenticated, but callers must read to the end
+/// of the stream before trusting that it was not truncated.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool,
+    failed: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Reads the stream header from `inner`.
+    pub fn new(key: &[u8; 32], mut inner: R) -> io::Result<DecryptReader<R>> {
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        if header[0] != STREAM_VERSION_TAG {
+            return Err(StreamError::Tag(header[0]).into());
+        }
+        Ok(DecryptReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            nonce_prefix: header[1..].try_into().unwrap(),
+            counter: 0,
+            ciphertext: Vec::with_capacity(ENCRYPTED_CHUNK_SIZE + 1),
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false,
+            failed: false,
+        })
+    }
+
+    // Reads one byte past a full chunk to learn whether it is the last one.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut len = self.ciphertext.len();
+        self.ciphertext.resize(ENCRYPTED_CHUNK_SIZE + 1, 0);
+        while len < self.ciphertext.len() {
+            match self.inner.read(&mut self.ciphertext[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.ciphertext.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        self.ciphertext.truncate(len);
+        Ok(())
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        self.fill()?;
+        let last = self.ciphertext.len() <= ENCRYPTED_CHUNK_SIZE;
+        let next = if last { None } else { self.ciphertext.pop() };
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let Ok(plaintext) = self.cipher.decrypt(&nonce, self.ciphertext.as_slice()) else {
+            self.failed = true;
+            return Err(StreamError::Decrypt.into());
+        };
+        self.counter = self.counter.checked_add(1).ok_or(StreamError::Overflow)?;
+        self.ciphertext.clear();
+        self.ciphertext.extend(next);
+        self.plaintext = plaintext;
+        self.position = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.failed {
+                return Err(StreamError::Decrypt.into());
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::*;
+    use anyhow::Result;
+
+    fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut writer = EncryptWriter::new(key, Vec::new())?;
+        writer.write_all(plaintext)?;
+        Ok(writer.finish()?)
+    }
+
+    fn decrypt(key: &[u8; 32], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        DecryptReader::new(key, ciphertext)?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    fn decrypt_error(key: &[u8; 32], ciphertext: &[u8]) -> Option<StreamError> {
+        decrypt(key, ciphertext)
+            .err()?
+            .into_inner()?
+            .downcast::<StreamError>()
+            .ok()
+            .map(|e| *e)
+    }
+
+    #[test]
+    fn stream_roundtrip() -> Result<()> {
+        let key = [7u8; 32];
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ciphertext = encrypt(&key, &plaintext)?;
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(ciphertext.len(), HEADER_LEN + len + chunks * TAG_LEN);
+            assert_eq!(decrypt(&key, &ciphertext)?, plaintext);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn small_writes_and_reads() -> Result<()> {
+        let key = [7u8; 32];
+        let plaintext: Vec<u8> = (0..2 * CHUNK_SIZE + 100).map(|i| i as u8).collect();
+        let mut writer = EncryptWriter::new(&key, Vec::new())?;
+        for piece in plaintext.chunks(1000) {
+            writer.write_all(piece)?;
+        }
+        let ciphertext = writer.finish()?;
+
+        let mut reader = DecryptReader::new(&key, ciphertext.as_slice())?;
+        let mut decrypted = Vec::new();
+        let mut buf = [0u8; 333];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            decrypted.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(decrypted, plaintext);
+        Ok(())
+    }
+
+    #[test]
+    fn truncation_fails() -> Result<()> {
+        let key = [7u8; 32];
+        let ciphertext = encrypt(&key, &[1u8; 2 * CHUNK_SIZE + 10])?;
+
+        // Dropping the last chunk leaves a stream that ends on a full chunk.
+        let at_chunk = &ciphertext[..HEADER_LEN + 2 * ENCRYPTED_CHUNK_SIZE];
+        assert_eq!(decrypt_error(&key, at_chunk), Some(StreamError::Decrypt));
+        let header_only = &ciphertext[..HEADER_LEN];
+        assert_eq!(decrypt_error(&key, header_only), Some(StreamError::Decrypt));
+        let partial = &ciphertext[..ciphertext.len() - 1];
+        assert_eq!(decrypt_error(&key, partial), Some(StreamError::Decrypt));
+        assert!(decrypt(&key, &ciphertext[..HEADER_LEN - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reordering_fails() -> Result<()> {
+        let key = [7u8; 32];
+        let ciphertext = encrypt(&key, &[1u8; 3 * CHUNK_SIZE + 10])?;
+        let (header, chunks) = ciphertext.split_at(HEADER_LEN);
+        let chunks: Vec<&[u8]> = chunks.chunks(ENCRYPTED_CHUNK_SIZE).collect();
+
+        let swapped = [header, chunks[1], chunks[0], chunks[2], chunks[3]].concat();
+        assert_eq!(decrypt_error(&key, &swapped), Some(StreamError::Decrypt));
+        let dropped = [header, chunks[0], chunks[2], chunks[3]].concat();
+        assert_eq!(decrypt_error(&key, &dropped), Some(StreamError::Decrypt));
+        let appended = [&ciphertext, chunks[3]].concat();
+        assert_eq!(decrypt_error(&key, &appended), Some(StreamError::Decrypt));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_key_or_tag_fails() -> Result<()> {
+        let ciphertext = encrypt(&[7u8; 32], b"Hello")?;
+        assert_eq!(
+            decrypt_error(&[8u8; 32], &ciphertext),
+            Some(StreamError::Decrypt)
+        );
+
+        let mut unknown = ciphertext.clone();
+        unknown[0] = 0xFF;
+        assert_eq!(
+            decrypt_error(&[7u8; 32], &unknown),
+            Some(StreamError::Tag(0xFF))
+        );
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
-use crate::aead::{decrypt_data, encrypt_data, AeadError};
+use crate::aead::{decrypt_data, encrypt_data_with_rng, AeadError, CipherSuite};
 use crate::bundle::*;
-use chacha20poly1305::{
-    aead::{KeyInit, Payload},
-    ChaCha20Poly1305,
-};
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use crate::kem::{KemCiphertext, KemPublicKey, KemSecretKey};
+use crate::ratchet::Session;
+use crate::secret::SecretKey;
+use crate::xeddsa::{IdentityKey, IdentityPublicKey};
+use chacha20poly1305::aead::{rand_core::CryptoRngCore, OsRng, Payload};
+use ed25519_dalek::Signature;
 use hkdf::Hkdf;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
@@ -13,6 +14,7 @@ use x25519_dalek::{
     PublicKey as X25519PublicKey, ReusableSecret as X25519ReusableSecret,
     StaticSecret as X25519StaticSecret,
 };
+use zeroize::Zeroizing;
 
 /*
     Glossary - See https://signal.org/docs/specifications/x3dh/
@@ -21,56 +23,95 @@ use x25519_dalek::{
     they can use for Symmetric encryptic encryption and ratcheting.
     EK - Ephemeral Key. This key is created when Alice sends message to Bob. She uses the private
     key to calculate `sk`, forgets `ek`, and puts the `ek` public key in the encoded message.
-    IK - Identity Key. A long-term ED25519 key that identifies as user in the protocol.
+    IK - Identity Key. A long-term X25519 key that identifies as user in the protocol. It signs
+    prekeys with XEdDSA.
     SPK - Signed Pre Key. A medium-term X25519 key signed by the user's IK.
     OPK - One-Time Pre Key. A short-term X25519 key that can only be used once by a client. The
     public key should only be vended by the server once to avoid failures.
     DH - Elliptic-curve Diffie–Hellman (ECDH) is a key agreement protocol that allows two parties,
     each having an elliptic-curve public–private key pair, to establish a shared secret over an insecure channel
     IKA - Alice's Identity Key
+
+    PQXDH - See https://signal.org/docs/specifications/pqxdh/
+    PQXDH extends X3DH with a post-quantum KEM so that SK stays secret from an attacker that
+    records the handshake and later breaks X25519.
+    PQPK - Post-Quantum Pre Key. An ML-KEM public key signed by the user's IK. It is either a
+    one-time key or a last-resort key that is vended once the one-time keys run out.
+    CT - The KEM ciphertext that encapsulates the shared secret SS to PQPK.
 */
 
+/// An X25519 prekey and the id that its owner looks up the private key by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreKey {
+    pub id: u32,
+    pub pre_key: X25519PublicKey,
+}
+
 /// A partipant in the X3DH protocol's prekey and the signature over it using their identity key.
+/// The signature covers the id and creation time as well as the key.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SignedPreKey {
+    pub id: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
     pub pre_key: X25519PublicKey,
     pub signature: Signature,
 }
 
+impl SignedPreKey {
+    pub fn public(&self) -> PreKey {
+        PreKey {
+            id: self.id,
+            pre_key: self.pre_key,
+        }
+    }
+}
+
+/// A participant's ML-KEM prekey and the signature over it using their identity key.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SignedKemPreKey {
+    pub pre_key: KemPublicKey,
+    pub signature: Signature,
+}
+
 /// A signature over multiple signed prekeys. Useful for one-time prekeys.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SignedPreKeys {
-    pub pre_keys: Vec<X25519PublicKey>,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub pre_keys: Vec<PreKey>,
     pub signature: Signature,
 }
 
 /// The X3DH key agreement protocol results in:
 /// * an epheremeral key `ek` that the recipient needs to decrypt encrypted message.
+/// * the KEM ciphertext `kem_ciphertext` that the recipient needs if PQXDH was used.
 /// * the 32-bytes shared secret key SK.
 #[derive(Debug, PartialEq)]
 pub struct X3DHSendKeyAgreement {
     pub ek: X25519PublicKey,
-    pub sk: [u8; 32],
+    pub kem_ciphertext: Option<KemCiphertext>,
+    pub sk: SecretKey,
 }
 
 /// A `Message` in this packages implementation of the X3DH protocol.
 /// This struct is the output of `Alice` sending a message to `Bob`.
 /// * `ik` is the identity key of the sender.
 /// * `ek` is the ephemeral key generated to encrypt the message.
-/// * `pre_key` is the identifier (currently public key) of Bob's prekey that was used.
-/// * `opk` is Bob's one time prekey that (may) have been used.
+/// * `spk_id` is the id of Bob's signed prekey that was used.
+/// * `opk_id` is the id of Bob's one time prekey that (may) have been used.
+/// * `pqpk` is Bob's KEM prekey and the ciphertext encapsulated to it if PQXDH was used.
 /// * `ciphertext` is the encrypted message.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Message {
-    pub ik: VerifyingKey,
+    pub ik: IdentityPublicKey,
     pub ek: X25519PublicKey,
-    pub pre_key: X25519PublicKey,
-    pub opk: Option<X25519PublicKey>,
+    pub spk_id: u32,
+    pub opk_id: Option<u32>,
+    pub pqpk: Option<(KemPublicKey, KemCiphertext)>,
     pub ciphertext: Vec<u8>,
 }
 
-pub struct RatchetMessage {}
-
 #[allow(deprecated)]
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -78,23 +119,27 @@ impl std::fmt::Display for Message {
             f,
             "sender_ik:  {}\n\
              ek:         {}\n\
-             pre_key:    {}\n\
+             spk_id:     {}\n\
+             opk_id:     {}\n\
              Payload:    {}\n",
-            base64::encode(self.ik),
+            base64::encode(self.ik.to_bytes()),
             base64::encode(self.ek),
-            self.opk
-                .map(base64::encode)
+            self.spk_id,
+            self.opk_id
+                .map(|id| id.to_string())
                 .unwrap_or(String::from("(None)")),
             base64::encode(&self.ciphertext)
         )
     }
 }
 
+/// Bundles with a `pqpk` use PQXDH. Bundles without one fall back to X3DH.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PreKeyBundle {
-    pub ik: VerifyingKey,
-    pub opk: Option<X25519PublicKey>,
+    pub ik: IdentityPublicKey,
+    pub opk: Option<PreKey>,
     pub spk: SignedPreKey,
+    pub pqpk: Option<SignedKemPreKey>,
 }
 
 // KDF = Key Derivation Function
@@ -103,25 +148,19 @@ pub struct PreKeyBundle {
 //    HKDF input key material = F || KM, where KM is an input byte sequence containing secret key material, and F is a byte sequence containing 32 0xFF bytes if curve is X25519, and 57 0xFF bytes if curve is X448. F is used for cryptographic domain separation with XEdDSA [2].
 //    HKDF salt = A zero-filled byte sequence with length equal to the hash output length.
 //    HKDF info = An ASCII string identifying the application.
-fn kdf(km: &[u8]) -> [u8; 32] {
+// PQXDH uses an info string that also identifies the curve, hash, and KEM.
+fn kdf(km: &[u8], info: &[u8]) -> SecretKey {
     let salt = [0; 32];
-    let f = [0xFF, 32];
-    let ikm = [&f, km].concat();
+    let f = [0xFF; 32];
+    let ikm = Zeroizing::new([&f, km].concat());
     let hk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
-    let mut okm = [0u8; 32];
-    hk.expand(b"Brongnal", &mut okm).unwrap();
-    okm
-}
-
-/// Convert Ed25519 private key to its birationally equivalent X25519 key.
-fn to_x25519(key: &SigningKey) -> X25519StaticSecret {
-    X25519StaticSecret::from(key.to_scalar_bytes())
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(info, okm.as_mut()).unwrap();
+    SecretKey::from(*okm)
 }
 
-/// Convert Ed25519 public key to its birationally equivalent X25519 key.
-fn to_x25519_pub(key: &VerifyingKey) -> X25519PublicKey {
-    X25519PublicKey::from(key.to_montgomery().to_bytes())
-}
+const X3DH_INFO: &[u8] = b"Brongnal";
+const PQXDH_INFO: &[u8] = b"Brongnal_CURVE25519_SHA-256_ML-KEM-1024";
 
 #[derive(Error, Debug, Serialize, Deserialize, PartialEq)]
 pub enum X3DHError {
@@ -140,41 +179,50 @@ pub enum X3DHError {
 //If the bundle does contain a one-time prekey, the calculation is modified to include an additional DH:
 //    DH4 = DH(EKA, OPKB)
 //    SK = KDF(DH1 || DH2 || DH3 || DH4)
+// PQXDH additionally encapsulates a shared secret to the bundle's KEM prekey:
+//    (CT, SS) = PQKEM-ENC(PQPKB)
+//    SK = KDF(DH1 || DH2 || DH3 || DH4 || SS)
 fn initiate_send_get_sk(
-    recipient_ik: VerifyingKey,
+    recipient_ik: IdentityPublicKey,
     spk: &SignedPreKey,
     opk: Option<X25519PublicKey>,
-    sender_ik: &SigningKey,
+    pqpk: Option<&SignedKemPreKey>,
+    sender_ik: &IdentityKey,
+    rng: &mut impl CryptoRngCore,
 ) -> Result<X3DHSendKeyAgreement, X3DHError> {
     // It might be tempting to observe that mutual authentication and forward secrecy are achieved by the DH calculations, and omit the prekey signature.
     // However, this would allow a "weak forward secrecy" attack:
     // A malicious server could provide Alice a prekey bundle with forged prekeys, and later compromise Bob's IKB to calculate SK.
-    verify_bundle(&recipient_ik, &[spk.pre_key], &spk.signature)
-        .map_err(|_| X3DHError::SignatureValidation)?;
+    verify_pre_key(&recipient_ik, spk).map_err(|_| X3DHError::SignatureValidation)?;
+    if let Some(pqpk) = pqpk {
+        verify_kem_pre_key(&recipient_ik, pqpk).map_err(|_| X3DHError::SignatureValidation)?;
+    }
 
-    let ek = X25519ReusableSecret::random();
-    let dh1 = to_x25519(sender_ik).diffie_hellman(&spk.pre_key);
-    let dh2 = ek.diffie_hellman(&to_x25519_pub(&recipient_ik));
+    let ek = X25519ReusableSecret::random_from_rng(&mut *rng);
+    let dh1 = sender_ik.diffie_hellman(&spk.pre_key);
+    let dh2 = ek.diffie_hellman(recipient_ik.as_x25519());
     let dh3 = ek.diffie_hellman(&spk.pre_key);
 
-    let sk = match opk {
-        Some(one_time_prekey) => {
-            let dh4 = ek.diffie_hellman(&one_time_prekey);
-            kdf(&[
-                dh1.to_bytes(),
-                dh2.to_bytes(),
-                dh3.to_bytes(),
-                dh4.to_bytes(),
-            ]
-            .concat())
+    let mut km =
+        Zeroizing::new([dh1.as_bytes().as_slice(), dh2.as_bytes(), dh3.as_bytes()].concat());
+    if let Some(one_time_prekey) = opk {
+        let dh4 = ek.diffie_hellman(&one_time_prekey);
+        km.extend(dh4.as_bytes());
+    }
+    let (kem_ciphertext, sk) = match pqpk {
+        Some(pqpk) => {
+            let (ct, ss) = pqpk.pre_key.encapsulate_with_rng(rng);
+            km.extend(ss.as_bytes());
+            (Some(ct), kdf(&km, PQXDH_INFO))
         }
-        None => kdf(&[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat()),
+        None => (None, kdf(&km, X3DH_INFO)),
     };
 
     // After a successful protocol run Alice and Bob will share a 32-byte secret key SK.
     // This key may be used within some post-X3DH secure communication protocol.
     Ok(X3DHSendKeyAgreement {
         ek: X25519PublicKey::from(&ek),
+        kem_ciphertext,
         sk,
     })
 }
@@ -187,95 +235,181 @@ fn initiate_send_get_sk(
 //    and using an encryption key which is either SK or the output from some cryptographic PRF keyed by SK.
 pub fn initiate_send(
     prekey_bundle: PreKeyBundle,
-    sender_ik: &SigningKey,
+    sender_ik: &IdentityKey,
+    message: &[u8],
+) -> Result<(SecretKey, Message), X3DHError> {
+    initiate_send_with_rng(prekey_bundle, sender_ik, message, &mut OsRng)
+}
+
+/// `initiate_send` with EK, then the KEM message, then the AEAD nonce drawn from `rng`.
+pub fn initiate_send_with_rng(
+    prekey_bundle: PreKeyBundle,
+    sender_ik: &IdentityKey,
     message: &[u8],
-) -> Result<([u8; 32], Message), X3DHError> {
-    let X3DHSendKeyAgreement { ek, sk } = initiate_send_get_sk(
+    rng: &mut impl CryptoRngCore,
+) -> Result<(SecretKey, Message), X3DHError> {
+    let X3DHSendKeyAgreement {
+        ek,
+        kem_ciphertext,
+        sk,
+    } = initiate_send_get_sk(
         prekey_bundle.ik,
         &prekey_bundle.spk,
-        prekey_bundle.opk,
+        prekey_bundle.opk.map(|opk| opk.pre_key),
+        prekey_bundle.pqpk.as_ref(),
         sender_ik,
+        rng,
     )?;
     // Alice then calculates an "associated data" byte sequence AD that contains identity information for both parties:
     //   AD = Encode(IKA) || Encode(IKB)
     // Alice may optionally append additional information to AD, such as Alice and Bob's usernames, certificates, or other identifying information.
-    let associated_data = [
-        sender_ik.verifying_key().to_bytes(),
-        prekey_bundle.ik.to_bytes(),
-    ]
-    .concat();
+    let associated_data = associated_data(&sender_ik.public_key(), &prekey_bundle.ik);
 
     // The initial ciphertext is typically the first message in some post-X3DH communication protocol.
     // In other words, this ciphertext typically has two roles, serving as the first message within some post-X3DH protocol, and as part of Alice's X3DH initial message.
     // After sending this, Alice may continue using SK or keys derived from SK within the post-X3DH protocol for communication with Bob
-    let ciphertext = encrypt_data(
+    let ciphertext = encrypt_data_with_rng(
         Payload {
             msg: message,
             aad: &associated_data,
         },
-        &ChaCha20Poly1305::new_from_slice(&sk).unwrap(),
+        sk.as_bytes(),
+        CipherSuite::default(),
+        rng,
     )?;
 
     Ok((
         sk,
         Message {
-            ik: sender_ik.verifying_key(),
+            ik: sender_ik.public_key(),
             ek,
-            pre_key: prekey_bundle.spk.pre_key,
-            opk: prekey_bundle.opk,
+            spk_id: prekey_bundle.spk.id,
+            opk_id: prekey_bundle.opk.map(|opk| opk.id),
+            pqpk: prekey_bundle
+                .pqpk
+                .map(|pqpk| pqpk.pre_key)
+                .zip(kem_ciphertext),
             ciphertext,
         },
     ))
 }
 
 fn initiate_recv_get_sk(
-    sender_ik: &VerifyingKey,
+    sender_ik: &IdentityPublicKey,
     ek: X25519PublicKey,
     opk: Option<X25519StaticSecret>,
-    receiver_ik: &SigningKey,
+    pqpk: Option<(&KemSecretKey, &KemCiphertext)>,
+    receiver_ik: &IdentityKey,
     spk: &X25519StaticSecret,
-) -> [u8; 32] {
-    let dh1 = spk.diffie_hellman(&to_x25519_pub(sender_ik));
-    let dh2 = to_x25519(receiver_ik).diffie_hellman(&ek);
+) -> SecretKey {
+    let dh1 = spk.diffie_hellman(sender_ik.as_x25519());
+    let dh2 = receiver_ik.diffie_hellman(&ek);
     let dh3 = spk.diffie_hellman(&ek);
 
+    let mut km =
+        Zeroizing::new([dh1.as_bytes().as_slice(), dh2.as_bytes(), dh3.as_bytes()].concat());
     if let Some(opk) = opk {
         let dh4 = opk.diffie_hellman(&ek);
-        kdf(&[
-            dh1.to_bytes(),
-            dh2.to_bytes(),
-            dh3.to_bytes(),
-            dh4.to_bytes(),
-        ]
-        .concat())
-    } else {
-        kdf(&[dh1.to_bytes(), dh2.to_bytes(), dh3.to_bytes()].concat())
+        km.extend(dh4.as_bytes());
+    }
+    match pqpk {
+        Some((pqpk, ct)) => {
+            // SS = PQKEM-DEC(PQPKB, CT)
+            km.extend(pqpk.decapsulate(ct).as_bytes());
+            kdf(&km, PQXDH_INFO)
+        }
+        None => kdf(&km, X3DH_INFO),
     }
 }
 
 /// Bob deletes any one-time prekey private key that was used, for forward secrecy.
-/// Caller must delete sk on error and the opk must be wiped.
+/// Caller must delete sk on error and the opk and one-time pqpk must be wiped.
 pub fn initiate_recv(
-    receiver_ik: &SigningKey,
+    receiver_ik: &IdentityKey,
     receiver_spk: &X25519StaticSecret,
-    sender_ik: &VerifyingKey,
+    sender_ik: &IdentityPublicKey,
     ek: X25519PublicKey,
     receiver_opk: Option<X25519StaticSecret>,
+    receiver_pqpk: Option<(&KemSecretKey, &KemCiphertext)>,
     ciphertext: &[u8],
-) -> Result<([u8; 32], Vec<u8>), X3DHError> {
+) -> Result<(SecretKey, Vec<u8>), X3DHError> {
     // Upon receiving Alice's initial message, Bob retrieves Alice's identity key and ephemeral key from the message.
     // Bob also loads his identity private key, and the private key(s) corresponding to whichever signed prekey and one-time prekey (if any) Alice used.
     // Using these keys, Bob repeats the DH and KDF calculations from the previous section to derive SK, and then deletes the DH values.
-    let sk = initiate_recv_get_sk(sender_ik, ek, receiver_opk, receiver_ik, receiver_spk);
+    let sk = initiate_recv_get_sk(
+        sender_ik,
+        ek,
+        receiver_opk,
+        receiver_pqpk,
+        receiver_ik,
+        receiver_spk,
+    );
 
     // Bob then constructs the AD byte sequence using IKA and IKB, as described in the previous section.
     // AD = Encode(IKA) || Encode(IKB)
-    let ad = [sender_ik.to_bytes(), receiver_ik.verifying_key().to_bytes()].concat();
+    let ad = associated_data(sender_ik, &receiver_ik.public_key());
 
     // Bob may then continue using SK or keys derived from SK within the post-X3DH protocol for communication with Alice.
     // Finally, Bob attempts to decrypt the initial ciphertext using SK and AD.
-    let cipher = ChaCha20Poly1305::new_from_slice(&sk).unwrap();
-    Ok((sk, decrypt_data(ciphertext, &ad, &cipher)?))
+    let plaintext = decrypt_data(ciphertext, &ad, sk.as_bytes())?;
+    Ok((sk, plaintext))
+}
+
+/// Computes the associated data for messages between the X3DH initiator and recipient.
+///   AD = Encode(IKA) || Encode(IKB)
+/// Encode is the versioned encoding of the identity key.
+pub fn associated_data(sender_ik: &IdentityPublicKey, receiver_ik: &IdentityPublicKey) -> Vec<u8> {
+    [sender_ik.encode(), receiver_ik.encode()].concat()
+}
+
+/// Runs X3DH (or PQXDH if the bundle has a `pqpk`) against `prekey_bundle` and uses SK to start
+/// a Double Ratchet session. The session's first message serves as the initial ciphertext.
+/// Returns the ephemeral key and KEM ciphertext that the recipient needs to repeat the
+/// calculation.
+pub fn initiate_send_session(
+    prekey_bundle: &PreKeyBundle,
+    sender_ik: &IdentityKey,
+) -> Result<(X25519PublicKey, Option<KemCiphertext>, Session), X3DHError> {
+    let X3DHSendKeyAgreement {
+        ek,
+        kem_ciphertext,
+        sk,
+    } = initiate_send_get_sk(
+        prekey_bundle.ik,
+        &prekey_bundle.spk,
+        prekey_bundle.opk.map(|opk| opk.pre_key),
+        prekey_bundle.pqpk.as_ref(),
+        sender_ik,
+        &mut OsRng,
+    )?;
+    // Bob's signed prekey serves as his initial ratchet public key.
+    Ok((
+        ek,
+        kem_ciphertext,
+        Session::new_sender(sk, prekey_bundle.spk.pre_key),
+    ))
+}
+
+/// The recipient's side of `initiate_send_session`.
+/// Caller must wipe the opk and one-time pqpk and discard the session if the first message fails
+/// to decrypt.
+pub fn initiate_recv_session(
+    receiver_ik: &IdentityKey,
+    receiver_spk: &X25519StaticSecret,
+    sender_ik: &IdentityPublicKey,
+    ek: X25519PublicKey,
+    receiver_opk: Option<X25519StaticSecret>,
+    receiver_pqpk: Option<(&KemSecretKey, &KemCiphertext)>,
+) -> Session {
+    let sk = initiate_recv_get_sk(
+        sender_ik,
+        ek,
+        receiver_opk,
+        receiver_pqpk,
+        receiver_ik,
+        receiver_spk,
+    );
+    Session::new_receiver(sk, receiver_spk.clone())
 }
 
 #[cfg(test)]
@@ -285,12 +419,20 @@ mod tests {
 
     use super::PreKeyBundle;
     use super::{
-        create_prekey_bundle, initiate_recv, initiate_recv_get_sk, initiate_send,
-        initiate_send_get_sk, SignedPreKey, X3DHSendKeyAgreement,
+        associated_data, create_kem_pre_key, create_kem_pre_key_with_rng, create_signed_pre_key,
+        create_signed_pre_key_with_rng, initiate_recv, initiate_recv_get_sk, initiate_recv_session,
+        initiate_send, initiate_send_get_sk, initiate_send_session, initiate_send_with_rng, PreKey,
+        SignedKemPreKey, SignedPreKey, X3DHSendKeyAgreement,
     };
-    use anyhow::Result;
-    use chacha20poly1305::aead::OsRng;
-    use ed25519_dalek::SigningKey;
+    use crate::kem::KemSecretKey;
+    use crate::xeddsa::IdentityKey;
+    use anyhow::{anyhow, Result};
+    use chacha20poly1305::aead::{
+        rand_core::{self, CryptoRng, RngCore},
+        OsRng,
+    };
+    use ed25519_dalek::Signature;
+    use serde::{Deserialize, Serialize};
     use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 
     // 1. Bob publishes his identity key and prekeys to a server.
@@ -298,14 +440,9 @@ mod tests {
     // 3. Bob receives and processes Alice's initial message.
     #[test]
     fn x3dh_key_agreement_opk() -> Result<()> {
-        let bob_ik = SigningKey::generate(&mut OsRng);
-        let bob_spk = create_prekey_bundle(&bob_ik, 1);
-        let bob_spk_secret = bob_spk.bundle[0].clone().0;
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
-        let alice_ik = SigningKey::generate(&mut OsRng);
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let alice_ik = IdentityKey::generate();
 
         let opk = X25519StaticSecret::random_from_rng(OsRng);
         let opk_pub = X25519PublicKey::from(&opk);
@@ -313,12 +450,21 @@ mod tests {
         let X3DHSendKeyAgreement {
             ek: ephemeral_key,
             sk: secret_key,
-        } = initiate_send_get_sk(bob_ik.verifying_key(), &bob_spk, Some(opk_pub), &alice_ik)?;
+            ..
+        } = initiate_send_get_sk(
+            bob_ik.public_key(),
+            &bob_spk,
+            Some(opk_pub),
+            None,
+            &alice_ik,
+            &mut OsRng,
+        )?;
 
         let recv_sk = initiate_recv_get_sk(
-            &alice_ik.verifying_key(),
+            &alice_ik.public_key(),
             ephemeral_key,
             Some(opk),
+            None,
             &bob_ik,
             &bob_spk_secret,
         );
@@ -328,22 +474,24 @@ mod tests {
 
     #[test]
     fn x3dh_key_agreement() -> Result<()> {
-        let bob_ik = SigningKey::generate(&mut OsRng);
-        let bob_spk = create_prekey_bundle(&bob_ik, 1);
-        let bob_spk_secret = bob_spk.bundle[0].clone().0;
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
-        let alice_ik = SigningKey::generate(&mut OsRng);
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let alice_ik = IdentityKey::generate();
 
-        let X3DHSendKeyAgreement { ek, sk } =
-            initiate_send_get_sk(bob_ik.verifying_key(), &bob_spk, None, &alice_ik)?;
+        let X3DHSendKeyAgreement { ek, sk, .. } = initiate_send_get_sk(
+            bob_ik.public_key(),
+            &bob_spk,
+            None,
+            None,
+            &alice_ik,
+            &mut OsRng,
+        )?;
 
         let recv_sk = initiate_recv_get_sk(
-            &alice_ik.verifying_key(),
+            &alice_ik.public_key(),
             ek,
             None,
+            None,
             &bob_ik,
             &bob_spk_secret,
         );
@@ -355,26 +503,27 @@ mod tests {
     #[test]
     fn x3dh_send_recv_opk() -> Result<()> {
         // 1. Bob publishes his identity key and prekeys to a server.
-        let bob_ik = SigningKey::generate(&mut OsRng);
-        let bob_spk = create_prekey_bundle(&bob_ik, 1);
-        let bob_spk_secret = bob_spk.bundle[0].clone().0;
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
         let bob_opk_priv = X25519StaticSecret::random_from_rng(OsRng);
         let bob_opk_pub = X25519PublicKey::from(&bob_opk_priv);
 
-        let alice_ik = SigningKey::generate(&mut OsRng);
+        let alice_ik = IdentityKey::generate();
 
         let plaintext = "Hello Bob!";
         // 2. Alice fetches a "prekey bundle" from the server, and uses it to send an initial message to Bob.
         let bundle = PreKeyBundle {
-            ik: bob_ik.verifying_key(),
-            opk: Some(bob_opk_pub),
+            ik: bob_ik.public_key(),
+            opk: Some(PreKey {
+                id: 1,
+                pre_key: bob_opk_pub,
+            }),
             spk: bob_spk.clone(),
+            pqpk: None,
         };
         let (send_sk, message) = initiate_send(bundle, &alice_ik, plaintext.as_bytes())?;
+        assert_eq!(message.spk_id, 1);
+        assert_eq!(message.opk_id, Some(1));
 
         // 3. Bob receives and processes Alice's initial message.
         let (recv_sk, decrypted) = initiate_recv(
@@ -383,6 +532,7 @@ mod tests {
             &message.ik,
             message.ek,
             Some(bob_opk_priv),
+            None,
             &message.ciphertext,
         )?;
         assert_eq!(send_sk, recv_sk);
@@ -394,20 +544,16 @@ mod tests {
     #[test]
     fn x3dh_send_recv() -> Result<()> {
         // 1. Bob publishes his identity key and prekeys to a server.
-        let bob_ik = SigningKey::generate(&mut OsRng);
-        let bob_spk = create_prekey_bundle(&bob_ik, 1);
-        let bob_spk_secret = bob_spk.bundle[0].clone().0;
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
-        let alice_ik = SigningKey::generate(&mut OsRng);
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let alice_ik = IdentityKey::generate();
 
         // 2. Alice fetches a "prekey bundle" from the server, and uses it to send an initial message to Bob.
         let bundle = PreKeyBundle {
-            ik: bob_ik.verifying_key(),
+            ik: bob_ik.public_key(),
             opk: None,
             spk: bob_spk.clone(),
+            pqpk: None,
         };
         let (send_sk, message) = initiate_send(bundle, &alice_ik, b"Hello Bob!")?;
 
@@ -418,6 +564,7 @@ mod tests {
             &message.ik,
             message.ek,
             None,
+            None,
             &message.ciphertext,
         )?;
         assert_eq!(send_sk, recv_sk);
@@ -428,19 +575,16 @@ mod tests {
 
     #[test]
     fn x3dh_invalid_bundle_signature() -> Result<()> {
-        let bob_spk = create_prekey_bundle(&SigningKey::generate(&mut OsRng), 1);
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
+        let (_, bob_spk) = create_signed_pre_key(&IdentityKey::generate(), 1, 1000);
 
         let bundle = PreKeyBundle {
-            ik: SigningKey::generate(&mut OsRng).verifying_key(),
+            ik: IdentityKey::generate().public_key(),
             opk: None,
             spk: bob_spk.clone(),
+            pqpk: None,
         };
         assert_eq!(
-            initiate_send(bundle, &SigningKey::generate(&mut OsRng), b"Hello Bob!"),
+            initiate_send(bundle, &IdentityKey::generate(), b"Hello Bob!"),
             Err(X3DHError::SignatureValidation)
         );
 
@@ -449,19 +593,15 @@ mod tests {
 
     #[test]
     fn x3dh_invalid_ciphertext() -> Result<()> {
-        let bob_ik = SigningKey::generate(&mut OsRng);
-        let bob_spk = create_prekey_bundle(&bob_ik, 1);
-        let bob_spk_secret = bob_spk.bundle[0].clone().0;
-        let bob_spk = SignedPreKey {
-            pre_key: bob_spk.bundle[0].1,
-            signature: bob_spk.signature,
-        };
-        let alice_ik = SigningKey::generate(&mut OsRng);
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let alice_ik = IdentityKey::generate();
 
         let bundle = PreKeyBundle {
-            ik: bob_ik.verifying_key(),
+            ik: bob_ik.public_key(),
             opk: None,
             spk: bob_spk.clone(),
+            pqpk: None,
         };
         let (_, message) = initiate_send(bundle, &alice_ik, b"Hello Bob!")?;
 
@@ -472,6 +612,7 @@ mod tests {
                 &message.ik,
                 message.ek,
                 None,
+                None,
                 b"invalid ciphertext",
             ),
             Err(X3DHError::Aead(AeadError::Tag(b'i')))
@@ -479,4 +620,397 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn x3dh_session() -> Result<()> {
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let bob_opk = X25519StaticSecret::random();
+        let alice_ik = IdentityKey::generate();
+        let ad = associated_data(&alice_ik.public_key(), &bob_ik.public_key());
+
+        let bundle = PreKeyBundle {
+            ik: bob_ik.public_key(),
+            opk: Some(PreKey {
+                id: 1,
+                pre_key: X25519PublicKey::from(&bob_opk),
+            }),
+            spk: bob_spk,
+            pqpk: None,
+        };
+        let (ek, kem_ciphertext, mut alice) = initiate_send_session(&bundle, &alice_ik)?;
+        assert_eq!(kem_ciphertext, None);
+        let (header, ciphertext) = alice.encrypt(b"Hello Bob!", &ad)?;
+
+        let mut bob = initiate_recv_session(
+            &bob_ik,
+            &bob_spk_secret,
+            &alice_ik.public_key(),
+            ek,
+            Some(bob_opk),
+            None,
+        );
+        assert_eq!(bob.decrypt(&header, &ciphertext, &ad)?, b"Hello Bob!");
+
+        let (header, ciphertext) = bob.encrypt(b"Hello Alice!", &ad)?;
+        assert_eq!(alice.decrypt(&header, &ciphertext, &ad)?, b"Hello Alice!");
+        Ok(())
+    }
+
+    #[test]
+    fn pqxdh_key_agreement() -> Result<()> {
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let (bob_pqpk_secret, bob_pqpk) = create_kem_pre_key(&bob_ik);
+        let alice_ik = IdentityKey::generate();
+
+        let X3DHSendKeyAgreement {
+            ek,
+            kem_ciphertext,
+            sk,
+        } = initiate_send_get_sk(
+            bob_ik.public_key(),
+            &bob_spk,
+            None,
+            Some(&bob_pqpk),
+            &alice_ik,
+            &mut OsRng,
+        )?;
+        let kem_ciphertext = kem_ciphertext.ok_or(anyhow!("missing kem ciphertext"))?;
+
+        let recv_sk = initiate_recv_get_sk(
+            &alice_ik.public_key(),
+            ek,
+            None,
+            Some((&bob_pqpk_secret, &kem_ciphertext)),
+            &bob_ik,
+            &bob_spk_secret,
+        );
+        assert_eq!(sk, recv_sk);
+
+        // The KEM shared secret is mixed into SK.
+        let x3dh_sk = initiate_recv_get_sk(
+            &alice_ik.public_key(),
+            ek,
+            None,
+            None,
+            &bob_ik,
+            &bob_spk_secret,
+        );
+        assert_ne!(sk, x3dh_sk);
+        Ok(())
+    }
+
+    #[test]
+    fn pqxdh_send_recv_opk() -> Result<()> {
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let bob_opk_priv = X25519StaticSecret::random_from_rng(OsRng);
+        let (bob_pqpk_secret, bob_pqpk) = create_kem_pre_key(&bob_ik);
+        let alice_ik = IdentityKey::generate();
+
+        let bundle = PreKeyBundle {
+            ik: bob_ik.public_key(),
+            opk: Some(PreKey {
+                id: 1,
+                pre_key: X25519PublicKey::from(&bob_opk_priv),
+            }),
+            spk: bob_spk,
+            pqpk: Some(bob_pqpk.clone()),
+        };
+        let (send_sk, message) = initiate_send(bundle, &alice_ik, b"Hello Bob!")?;
+        let (pqpk, kem_ciphertext) = message.pqpk.ok_or(anyhow!("missing pqpk"))?;
+        assert_eq!(pqpk, bob_pqpk.pre_key);
+
+        let (recv_sk, decrypted) = initiate_recv(
+            &bob_ik,
+            &bob_spk_secret,
+            &message.ik,
+            message.ek,
+            Some(bob_opk_priv),
+            Some((&bob_pqpk_secret, &kem_ciphertext)),
+            &message.ciphertext,
+        )?;
+        assert_eq!(send_sk, recv_sk);
+        assert_eq!(b"Hello Bob!", decrypted.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn pqxdh_invalid_kem_pre_key_signature() -> Result<()> {
+        let bob_ik = IdentityKey::generate();
+        let (_, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let (_, bob_pqpk) = create_kem_pre_key(&IdentityKey::generate());
+
+        let bundle = PreKeyBundle {
+            ik: bob_ik.public_key(),
+            opk: None,
+            spk: bob_spk,
+            pqpk: Some(bob_pqpk),
+        };
+        assert_eq!(
+            initiate_send(bundle, &IdentityKey::generate(), b"Hello Bob!"),
+            Err(X3DHError::SignatureValidation)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pqxdh_session() -> Result<()> {
+        let bob_ik = IdentityKey::generate();
+        let (bob_spk_secret, bob_spk) = create_signed_pre_key(&bob_ik, 1, 1000);
+        let (bob_pqpk_secret, bob_pqpk) = create_kem_pre_key(&bob_ik);
+        let alice_ik = IdentityKey::generate();
+        let ad = associated_data(&alice_ik.public_key(), &bob_ik.public_key());
+
+        let bundle = PreKeyBundle {
+            ik: bob_ik.public_key(),
+            opk: None,
+            spk: bob_spk,
+            pqpk: Some(bob_pqpk),
+        };
+        let (ek, kem_ciphertext, mut alice) = initiate_send_session(&bundle, &alice_ik)?;
+        let kem_ciphertext = kem_ciphertext.ok_or(anyhow!("missing kem ciphertext"))?;
+        let (header, ciphertext) = alice.encrypt(b"Hello Bob!", &ad)?;
+
+        let mut bob = initiate_recv_session(
+            &bob_ik,
+            &bob_spk_secret,
+            &alice_ik.public_key(),
+            ek,
+            None,
+            Some((&bob_pqpk_secret, &kem_ciphertext)),
+        );
+        assert_eq!(bob.decrypt(&header, &ciphertext, &ad)?, b"Hello Bob!");
+        Ok(())
+    }
+
+    const TEST_VECTORS: &str = include_str!("../testdata/x3dh.json");
+
+    /// Returns the bytes it was created with as its random output, in order.
+    struct FixedRng(std::vec::IntoIter<u8>);
+
+    impl FixedRng {
+        fn new(chunks: &[&[u8]]) -> FixedRng {
+            FixedRng(chunks.concat().into_iter())
+        }
+    }
+
+    impl RngCore for FixedRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0.next().expect("FixedRng ran out of bytes");
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for FixedRng {}
+
+    #[derive(Serialize, Deserialize)]
+    struct TestVectors {
+        description: String,
+        vectors: Vec<TestVector>,
+    }
+
+    /// All byte strings are hex. Secret keys are the 32 byte X25519 scalars before clamping.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestVector {
+        name: String,
+        alice_ik: String,
+        bob_ik: String,
+        bob_spk: String,
+        bob_spk_id: u32,
+        bob_spk_created: u64,
+        bob_spk_signature: String,
+        bob_opk: Option<String>,
+        bob_opk_id: Option<u32>,
+        bob_pqpk_seed: Option<String>,
+        bob_pqpk_signature: Option<String>,
+        alice_ek: String,
+        kem_message: Option<String>,
+        nonce: String,
+        plaintext: String,
+        ad: String,
+        sk: String,
+        kem_ciphertext: Option<String>,
+        ciphertext: String,
+    }
+
+    fn unhex(value: &str) -> Vec<u8> {
+        hex::decode(value).unwrap()
+    }
+
+    fn secret(value: &str) -> [u8; 32] {
+        unhex(value).try_into().unwrap()
+    }
+
+    /// Runs X3DH with the inputs of `vector` and returns the vector with its outputs filled in.
+    fn run_vector(vector: &TestVector) -> Result<TestVector> {
+        let alice_ik = IdentityKey::from(secret(&vector.alice_ik));
+        let bob_ik = IdentityKey::from(secret(&vector.bob_ik));
+        let bob_spk = X25519StaticSecret::from(secret(&vector.bob_spk));
+        let bob_opk = vector
+            .bob_opk
+            .as_deref()
+            .map(|opk| X25519StaticSecret::from(secret(opk)));
+        let bob_pqpk = vector
+            .bob_pqpk_seed
+            .as_deref()
+            .map(|seed| KemSecretKey::generate_with_rng(&mut FixedRng::new(&[&unhex(seed)])));
+        let pqpk = match (&bob_pqpk, &vector.bob_pqpk_signature) {
+            (Some(pqpk), Some(signature)) => Some(SignedKemPreKey {
+                pre_key: pqpk.public_key(),
+                signature: Signature::from_slice(&unhex(signature))?,
+            }),
+            _ => None,
+        };
+        let bundle = PreKeyBundle {
+            ik: bob_ik.public_key(),
+            opk: bob_opk
+                .as_ref()
+                .zip(vector.bob_opk_id)
+                .map(|(opk, id)| PreKey {
+                    id,
+                    pre_key: X25519PublicKey::from(opk),
+                }),
+            spk: SignedPreKey {
+                id: vector.bob_spk_id,
+                created: vector.bob_spk_created,
+                pre_key: X25519PublicKey::from(&bob_spk),
+                signature: Signature::from_slice(&unhex(&vector.bob_spk_signature))?,
+            },
+            pqpk,
+        };
+
+        let kem_message = vector.kem_message.as_deref().map(unhex).unwrap_or_default();
+        let mut rng = FixedRng::new(&[
+            &unhex(&vector.alice_ek),
+            &kem_message,
+            &unhex(&vector.nonce),
+        ]);
+        let plaintext = unhex(&vector.plaintext);
+        let (sk, message) = initiate_send_with_rng(bundle, &alice_ik, &plaintext, &mut rng)?;
+        assert_eq!(message.spk_id, vector.bob_spk_id);
+        assert_eq!(message.opk_id, vector.bob_opk_id);
+        assert_eq!(
+            message.ek,
+            X25519PublicKey::from(&X25519StaticSecret::from(secret(&vector.alice_ek)))
+        );
+        let kem_ciphertext = message.pqpk.as_ref().map(|(_, ct)| ct.to_bytes());
+
+        let (recv_sk, recv_plaintext) = initiate_recv(
+            &bob_ik,
+            &bob_spk,
+            &alice_ik.public_key(),
+            message.ek,
+            bob_opk,
+            bob_pqpk
+                .as_ref()
+                .zip(message.pqpk.as_ref().map(|(_, ct)| ct)),
+            &message.ciphertext,
+        )?;
+        assert_eq!(recv_sk, sk);
+        assert_eq!(recv_plaintext, plaintext);
+
+        Ok(TestVector {
+            name: vector.name.clone(),
+            alice_ik: vector.alice_ik.clone(),
+            bob_ik: vector.bob_ik.clone(),
+            bob_spk: vector.bob_spk.clone(),
+            bob_spk_id: vector.bob_spk_id,
+            bob_spk_created: vector.bob_spk_created,
+            bob_spk_signature: vector.bob_spk_signature.clone(),
+            bob_opk: vector.bob_opk.clone(),
+            bob_opk_id: vector.bob_opk_id,
+            bob_pqpk_seed: vector.bob_pqpk_seed.clone(),
+            bob_pqpk_signature: vector.bob_pqpk_signature.clone(),
+            alice_ek: vector.alice_ek.clone(),
+            kem_message: vector.kem_message.clone(),
+            nonce: vector.nonce.clone(),
+            plaintext: vector.plaintext.clone(),
+            ad: hex::encode(associated_data(
+                &alice_ik.public_key(),
+                &bob_ik.public_key(),
+            )),
+            sk: hex::encode(sk.as_bytes()),
+            kem_ciphertext: kem_ciphertext.map(hex::encode),
+            ciphertext: hex::encode(&message.ciphertext),
+        })
+    }
+
+    #[test]
+    fn known_answer_vectors() -> Result<()> {
+        let vectors: TestVectors = serde_json::from_str(TEST_VECTORS)?;
+        assert!(!vectors.vectors.is_empty());
+        for vector in &vectors.vectors {
+            assert_eq!(run_vector(vector)?, *vector, "{}", vector.name);
+        }
+        Ok(())
+    }
+
+    /// Regenerates testdata/x3dh.json after an intentional change to the protocol with
+    /// `cargo test -p protocol -- --ignored write_test_vectors`.
+    #[test]
+    #[ignore]
+    fn write_test_vectors() -> Result<()> {
+        let bob_ik = IdentityKey::from([0x02; 32]);
+        let spk_rng = &mut FixedRng::new(&[&[0x03; 32], &[0x04; 64]]);
+        let (_, bob_spk) = create_signed_pre_key_with_rng(&bob_ik, 1, 1_700_000_000, spk_rng);
+        let pqpk_rng = &mut FixedRng::new(&[&[0x06; 64], &[0x07; 64]]);
+        let (_, bob_pqpk) = create_kem_pre_key_with_rng(&bob_ik, pqpk_rng);
+
+        let vector = |name: &str, opk: bool, pq: bool| TestVector {
+            name: name.to_string(),
+            alice_ik: hex::encode([0x01; 32]),
+            bob_ik: hex::encode([0x02; 32]),
+            bob_spk: hex::encode([0x03; 32]),
+            bob_spk_id: bob_spk.id,
+            bob_spk_created: bob_spk.created,
+            bob_spk_signature: hex::encode(bob_spk.signature.to_bytes()),
+            bob_opk: opk.then(|| hex::encode([0x05; 32])),
+            bob_opk_id: opk.then_some(2),
+            bob_pqpk_seed: pq.then(|| hex::encode([0x06; 64])),
+            bob_pqpk_signature: pq.then(|| hex::encode(bob_pqpk.signature.to_bytes())),
+            alice_ek: hex::encode([0x08; 32]),
+            kem_message: pq.then(|| hex::encode([0x09; 32])),
+            nonce: hex::encode([0x0A; 12]),
+            plaintext: hex::encode(b"Hello Bob!"),
+            ad: String::new(),
+            sk: String::new(),
+            kem_ciphertext: None,
+            ciphertext: String::new(),
+        };
+        let vectors = TestVectors {
+            description: "X3DH and PQXDH known-answer tests. Alice's random output is consumed \
+                          in the order alice_ek, kem_message, nonce. The KEM prekey is the \
+                          ML-KEM-1024 key generated from the 64 byte seed d || z."
+                .to_string(),
+            vectors: [
+                vector("x3dh", false, false),
+                vector("x3dh_opk", true, false),
+                vector("pqxdh_opk", true, true),
+            ]
+            .iter()
+            .map(run_vector)
+            .collect::<Result<_>>()?,
+        };
+        std::fs::write(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/x3dh.json"),
+            serde_json::to_string_pretty(&vectors)? + "\n",
+        )?;
+        Ok(())
+    }
 }
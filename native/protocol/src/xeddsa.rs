@@ -0,0 +1,317 @@
+use chacha20poly1305::aead::{rand_core::CryptoRngCore, OsRng};
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    montgomery::MontgomeryPoint,
+    scalar::{clamp_integer, Scalar},
+};
+use ed25519_dalek::{Signature, SignatureError, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use x25519_dalek::{
+    PublicKey as X25519PublicKey, SharedSecret, StaticSecret as X25519StaticSecret,
+};
+use zeroize::Zeroizing;
+
+/*
+    XEdDSA - See https://signal.org/docs/specifications/xeddsa/
+    XEdDSA lets a single X25519 key pair be used both for Diffie-Hellman and for signatures that
+    are verified as Ed25519 signatures under the key's Edwards form.
+    k - The X25519 private key, clamped, as a scalar.
+    A - The Edwards form of the public key with its sign bit forced to zero.
+    a - The private scalar that corresponds to A. Either k or -k.
+    Z - 64 bytes of secure random data mixed into the nonce.
+*/
+
+/// The version byte that prefixes an encoded X25519 identity key.
+/// Legacy Ed25519 identity keys were encoded as 32 bytes without a version byte.
+pub const KEY_VERSION_X25519: u8 = 1;
+const ENCODED_KEY_LEN: usize = 33;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum KeyEncodingError {
+    #[error("Unknown key version: `{0}`")]
+    Version(u8),
+    #[error("Key had an invalid length.")]
+    Length,
+    #[error("Legacy key was not a valid Ed25519 point.")]
+    InvalidEd25519Key,
+}
+
+/// A long-term X25519 identity key that signs with XEdDSA.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdentityKey(X25519StaticSecret);
+
+/// The public half of an `IdentityKey`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct IdentityPublicKey(X25519PublicKey);
+
+impl IdentityKey {
+    pub fn generate() -> IdentityKey {
+        IdentityKey(X25519StaticSecret::random())
+    }
+
+    /// Converts a legacy Ed25519 identity key to its birationally equivalent X25519 key.
+    /// The public key matches `IdentityPublicKey::from_ed25519` so peers continue to recognize
+    /// the identity.
+    pub fn from_ed25519(key: &SigningKey) -> IdentityKey {
+        IdentityKey(X25519StaticSecret::from(key.to_scalar_bytes()))
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> IdentityPublicKey {
+        IdentityPublicKey(X25519PublicKey::from(&self.0))
+    }
+
+    pub fn diffie_hellman(&self, public_key: &X25519PublicKey) -> SharedSecret {
+        self.0.diffie_hellman(public_key)
+    }
+
+    // calculate_key_pair(k):
+    //     E = kB
+    //     A.y = E.y
+    //     A.s = 0
+    //     if E.s == 1:
+    //         a = -k (mod q)
+    //     else:
+    //         a = k (mod q)
+    //     return A, a
+    fn calculate_key_pair(&self) -> ([u8; 32], Zeroizing<Scalar>) {
+        let k = Zeroizing::new(Scalar::from_bytes_mod_order(clamp_integer(
+            *self.to_bytes(),
+        )));
+        let mut public_key = (&*k * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        let sign = public_key[31] >> 7;
+        public_key[31] &= 0x7F;
+        let a = Zeroizing::new(if sign == 1 { -*k } else { *k });
+        (public_key, a)
+    }
+
+    // xeddsa_sign(k, M, Z):
+    //     A, a = calculate_key_pair(k)
+    //     r = hash1(a || M || Z) (mod q)
+    //     R = rB
+    //     h = hash(R || A || M) (mod q)
+    //     s = r + ha (mod q)
+    //     return R || s
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.sign_with_rng(message, &mut OsRng)
+    }
+
+    /// `sign` with the random nonce Z drawn from `rng`.
+    pub fn sign_with_rng(&self, message: &[u8], rng: &mut impl CryptoRngCore) -> Signature {
+        let (public_key, a) = self.calculate_key_pair();
+        let mut z = [0u8; 64];
+        rng.fill_bytes(&mut z);
+
+        // hash_i(X) = hash(2^b - 1 - i || X)
+        let mut hash1_prefix = [0xFF; 32];
+        hash1_prefix[0] = 0xFE;
+        let r = Zeroizing::new(Scalar::from_bytes_mod_order_wide(
+            &Sha512::new()
+                .chain_update(hash1_prefix)
+                .chain_update(a.as_bytes())
+                .chain_update(message)
+                .chain_update(z)
+                .finalize()
+                .into(),
+        ));
+        let big_r = (&*r * ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        let h = Scalar::from_bytes_mod_order_wide(
+            &Sha512::new()
+                .chain_update(big_r)
+                .chain_update(public_key)
+                .chain_update(message)
+                .finalize()
+                .into(),
+        );
+        let s = *r + h * *a;
+        Signature::from_components(big_r, s.to_bytes())
+    }
+}
+
+impl PartialEq for IdentityKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes().ct_eq(other.0.as_bytes()).into()
+    }
+}
+
+impl std::fmt::Debug for IdentityKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("IdentityKey")
+            .field(&self.public_key())
+            .finish()
+    }
+}
+
+impl From<[u8; 32]> for IdentityKey {
+    fn from(bytes: [u8; 32]) -> IdentityKey {
+        IdentityKey(X25519StaticSecret::from(bytes))
+    }
+}
+
+impl IdentityPublicKey {
+    /// Converts a legacy Ed25519 identity key to its birationally equivalent X25519 key.
+    pub fn from_ed25519(key: &VerifyingKey) -> IdentityPublicKey {
+        IdentityPublicKey(X25519PublicKey::from(key.to_montgomery().to_bytes()))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn as_x25519(&self) -> &X25519PublicKey {
+        &self.0
+    }
+
+    /// Encodes the key as `KEY_VERSION_X25519 || u`.
+    pub fn encode(&self) -> Vec<u8> {
+        [&[KEY_VERSION_X25519], self.0.as_bytes().as_slice()].concat()
+    }
+
+    /// Decodes a versioned key. Unversioned 32 byte keys are decoded as legacy Ed25519 keys.
+    pub fn decode(key: &[u8]) -> Result<IdentityPublicKey, KeyEncodingError> {
+        match key.len() {
+            ENCODED_KEY_LEN if key[0] == KEY_VERSION_X25519 => {
+                let key: [u8; 32] = key[1..].try_into().unwrap();
+                Ok(IdentityPublicKey(X25519PublicKey::from(key)))
+            }
+            ENCODED_KEY_LEN => Err(KeyEncodingError::Version(key[0])),
+            32 => {
+                let key = VerifyingKey::from_bytes(key.try_into().unwrap())
+                    .map_err(|_| KeyEncodingError::InvalidEd25519Key)?;
+                Ok(IdentityPublicKey::from_ed25519(&key))
+            }
+            _ => Err(KeyEncodingError::Length),
+        }
+    }
+
+    // xeddsa_verify(u, M, (R || s)):
+    //     if u >= p or R.y >= 2|p| or s >= 2|q|:
+    //         return false
+    //     A = convert_mont(u)
+    //     if not on_curve(A):
+    //         return false
+    //     h = hash(R || A || M) (mod q)
+    //     Rcheck = sB - hA
+    //     if bytes_equal(R, Rcheck):
+    //         return true
+    //     return false
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<(), SignatureError> {
+        let u = self.0.to_bytes();
+        if !is_canonical_field_element(&u) {
+            return Err(SignatureError::new());
+        }
+        // convert_mont(u) forces the sign bit of the Edwards point to zero.
+        let public_key = MontgomeryPoint(u)
+            .to_edwards(0)
+            .ok_or(SignatureError::new())?
+            .compress();
+        VerifyingKey::from_bytes(public_key.as_bytes())?.verify_strict(message, signature)
+    }
+}
+
+impl AsRef<[u8]> for IdentityPublicKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl From<&IdentityKey> for IdentityPublicKey {
+    fn from(key: &IdentityKey) -> IdentityPublicKey {
+        key.public_key()
+    }
+}
+
+impl From<[u8; 32]> for IdentityPublicKey {
+    fn from(bytes: [u8; 32]) -> IdentityPublicKey {
+        IdentityPublicKey(X25519PublicKey::from(bytes))
+    }
+}
+
+/// Returns true if the little-endian `bytes` are less than p = 2^255 - 19.
+fn is_canonical_field_element(bytes: &[u8; 32]) -> bool {
+    if bytes[31] & 0x80 != 0 {
+        return false;
+    }
+    // The only values in [p, 2^255) are 0x7FFF...FFED through 0x7FFF...FFFF.
+    !(bytes[31] == 0x7F && bytes[1..31].iter().all(|b| *b == 0xFF) && bytes[0] >= 0xED)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xeddsa::*;
+    use anyhow::Result;
+
+    #[test]
+    fn sign_verify() -> Result<()> {
+        let key = IdentityKey::generate();
+        let signature = key.sign(b"Hello Bob!");
+        key.public_key().verify(b"Hello Bob!", &signature)?;
+
+        assert!(key.public_key().verify(b"Hello Eve!", &signature).is_err());
+        assert!(IdentityKey::generate()
+            .public_key()
+            .verify(b"Hello Bob!", &signature)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn signatures_are_randomized() {
+        let key = IdentityKey::generate();
+        assert_ne!(key.sign(b"Hello Bob!"), key.sign(b"Hello Bob!"));
+    }
+
+    #[test]
+    fn non_canonical_public_key_rejected() {
+        let key = IdentityKey::generate();
+        let signature = key.sign(b"Hello Bob!");
+        let mut u = [0xFF; 32];
+        u[31] = 0x7F;
+        assert!(IdentityPublicKey::from(u)
+            .verify(b"Hello Bob!", &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn ed25519_migration_preserves_identity() -> Result<()> {
+        let legacy = SigningKey::generate(&mut OsRng);
+        let key = IdentityKey::from_ed25519(&legacy);
+        assert_eq!(
+            key.public_key(),
+            IdentityPublicKey::from_ed25519(&legacy.verifying_key())
+        );
+        assert_eq!(
+            IdentityPublicKey::decode(legacy.verifying_key().as_bytes())?,
+            key.public_key()
+        );
+        key.public_key()
+            .verify(b"Hello Bob!", &key.sign(b"Hello Bob!"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn encode_decode() -> Result<()> {
+        let key = IdentityKey::generate().public_key();
+        let encoded = key.encode();
+        assert_eq!(encoded[0], KEY_VERSION_X25519);
+        assert_eq!(IdentityPublicKey::decode(&encoded)?, key);
+
+        let mut unknown = encoded.clone();
+        unknown[0] = 0xFF;
+        assert_eq!(
+            IdentityPublicKey::decode(&unknown),
+            Err(KeyEncodingError::Version(0xFF))
+        );
+        assert_eq!(
+            IdentityPublicKey::decode(&encoded[..20]),
+            Err(KeyEncodingError::Length)
+        );
+        Ok(())
+    }
+}
@@ -1,102 +1,247 @@
-use crate::persistence::SqliteStorage;
 use crate::push_notifications::FirebaseCloudMessagingClient;
+use crate::receivers::{Drain, MessageStream, Receivers};
+use crate::store::BrongnalStore;
+use crate::time_now;
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use ed25519_dalek::{Signature, VerifyingKey};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use prost::Message as _;
+use proto::parse_identity_key;
 use proto::service::brongnal_service_server::BrongnalService;
 use proto::service::{
-    Message as MessageProto, PreKeyBundle as PreKeyBundleProto, PreKeyBundleRequest,
-    RegisterPreKeyBundleRequest, RegisterPreKeyBundleResponse, RetrieveMessagesRequest,
-    SendMessageRequest, SendMessageResponse, SignedPreKey as SignedPreKeyProto,
+    AcknowledgeMessagesRequest, AcknowledgeMessagesResponse, ChallengeRequest, ChallengeResponse,
+    DownloadAttachmentRequest, DownloadAttachmentResponse, Message as MessageProto,
+    PreKeyBundle as PreKeyBundleProto, PreKeyBundleRequest, RegisterPreKeyBundleRequest,
+    RegisterPreKeyBundleResponse, RetrieveMessagesRequest, SendMessageRequest, SendMessageResponse,
+    SenderCertificateRequest, SenderCertificateResponse, SignedKemPreKey as SignedKemPreKeyProto,
+    SignedPreKey as SignedPreKeyProto, UploadAttachmentRequest, UploadAttachmentResponse,
 };
-use proto::{parse_verifying_key, parse_x25519_public_key};
-use protocol::bundle::verify_bundle;
+use protocol::attachment::digest;
+use protocol::auth::{
+    acknowledgement_contents, SignedRequest, ACKNOWLEDGE_MESSAGES_PREFIX, PRE_KEY_REQUEST_PREFIX,
+    RETRIEVE_MESSAGES_PREFIX, UPLOAD_ATTACHMENT_PREFIX,
+};
+use protocol::bundle::{verify_bundle, verify_kem_pre_key, verify_pre_key};
+use protocol::sealed_sender::SenderCertificate;
+use protocol::x3dh::{PreKey, SignedKemPreKey, SignedPreKeys};
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::sync::mpsc::{self, Sender};
-use tokio_stream::wrappers::ReceiverStream;
-use tokio_stream::StreamExt;
-use tonic::{Request, Response, Result, Status, Streaming};
-use tracing::{error, info, instrument, warn};
-use x25519_dalek::PublicKey as X25519PublicKey;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Result, Status, Streaming};
+use tracing::{error, info, instrument, warn, Instrument};
+
+/// How long a sender certificate is valid for.
+const SENDER_CERTIFICATE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The largest encrypted attachment that can be uploaded.
+const MAX_ATTACHMENT_SIZE: usize = 32 * 1024 * 1024;
+/// The bytes of attachments that each device can have stored until they expire.
+const ATTACHMENT_QUOTA: u64 = 4 * MAX_ATTACHMENT_SIZE as u64;
+/// Attachments are downloaded in chunks of this size.
+const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+/// Pre key requests are rate limited over windows of this length.
+const PRE_KEY_REQUEST_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// How many pre key bundles a device may request per window.
+const PRE_KEY_REQUESTS_PER_REQUESTER: u32 = 100;
+/// How many one time pre keys of a device may be consumed per window. Further requests are
+/// answered with its last resort pre key.
+const ONE_TIME_PRE_KEYS_PER_TARGET: u32 = 50;
+/// How many messages can be waiting to be sent on a RetrieveMessages stream.
+const MESSAGE_STREAM_BUFFER: usize = 100;
+/// The most messages that are loaded from a mailbox at once.
+const MAILBOX_PAGE_SIZE: u32 = 100;
+/// How long a retrieve messages challenge can be answered for.
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// How many retrieve messages challenges can be outstanding across all devices.
+const MAX_CHALLENGES: usize = 10_000;
+
+/// A challenge issued to a device that wants to retrieve its messages.
+struct Challenge {
+    nonce: [u8; 32],
+    /// Seconds since the Unix epoch.
+    expiry: u64,
+}
+
+/// Outstanding challenges, at most one per device.
+#[derive(Default)]
+struct Challenges {
+    by_ik: HashMap<IdentityPublicKey, Challenge>,
+    /// When expired challenges were last swept, so that a full map is scanned at most once a
+    /// second.
+    last_sweep: u64,
+}
+
+impl Challenges {
+    /// Issues `nonce` to `ik`, replacing its previous challenge.
+    /// Returns false if `MAX_CHALLENGES` other devices have unexpired challenges.
+    fn issue(&mut self, ik: IdentityPublicKey, nonce: [u8; 32], now: u64) -> bool {
+        if self.by_ik.len() >= MAX_CHALLENGES && !self.by_ik.contains_key(&ik) {
+            if self.last_sweep < now {
+                self.by_ik.retain(|_, challenge| challenge.expiry > now);
+                self.last_sweep = now;
+            }
+            if self.by_ik.len() >= MAX_CHALLENGES {
+                return false;
+            }
+        }
+        self.by_ik.insert(
+            ik,
+            Challenge {
+                nonce,
+                expiry: now + CHALLENGE_TTL.as_secs(),
+            },
+        );
+        true
+    }
+
+    /// Consumes the challenge of `ik` if it was issued `nonce` and has not expired.
+    fn take(&mut self, ik: &IdentityPublicKey, nonce: &[u8; 32], now: u64) -> bool {
+        match self.by_ik.get(ik) {
+            Some(challenge) if challenge.nonce == *nonce => self
+                .by_ik
+                .remove(ik)
+                .is_some_and(|challenge| challenge.expiry > now),
+            _ => false,
+        }
+    }
+}
 
 pub struct BrongnalController {
-    storage: SqliteStorage,
-    receivers: Arc<Mutex<HashMap<VerifyingKey, Sender<Result<MessageProto>>>>>,
+    storage: Arc<dyn BrongnalStore>,
+    server_key: IdentityKey,
+    receivers: Receivers,
+    challenges: Arc<Mutex<Challenges>>,
     fcm_client: Option<FirebaseCloudMessagingClient>,
 }
 
 impl BrongnalController {
     pub fn new(
-        storage: SqliteStorage,
+        storage: Arc<dyn BrongnalStore>,
+        server_key: IdentityKey,
         fcm_client: Option<FirebaseCloudMessagingClient>,
     ) -> BrongnalController {
         BrongnalController {
             storage,
-            receivers: Arc::new(Mutex::new(HashMap::new())),
+            server_key,
+            receivers: Receivers::default(),
+            challenges: Arc::new(Mutex::new(Challenges::default())),
             fcm_client,
         }
     }
 
-    #[instrument(name="", skip(self, ik), fields(ik = base64.encode(ik)))]
-    async fn handle_request_pre_keys(&self, ik: VerifyingKey) -> Result<PreKeyBundleProto> {
-        let (spk, opk) = tokio::join!(
-            self.storage.get_current_spk(&ik),
-            // TODO(https://github.com/brongan/brongnal/issues/26) - Prevent one time key pop abuse.
-            self.storage.pop_opk(&ik)
-        );
-        let spk = spk?;
-        let opk = opk?;
+    #[instrument(name="", skip(self, requester, ik), fields(requester = base64.encode(requester), ik = base64.encode(ik)))]
+    async fn handle_request_pre_keys(
+        &self,
+        requester: IdentityPublicKey,
+        ik: IdentityPublicKey,
+    ) -> Result<PreKeyBundleProto> {
+        // Only registered devices may request pre keys.
+        self.storage
+            .get_current_spk(&requester)
+            .await
+            .map_err(|_| Status::unauthenticated("requester is not a registered device"))?;
+        let spk = self.storage.get_current_spk(&ik).await?;
+        let since = time_now() - PRE_KEY_REQUEST_WINDOW.as_secs();
+        let (opk, pqpk, consumed) = match self
+            .storage
+            .request_one_time_pre_keys(
+                &requester,
+                &ik,
+                since,
+                PRE_KEY_REQUESTS_PER_REQUESTER,
+                ONE_TIME_PRE_KEYS_PER_TARGET,
+            )
+            .await
+        {
+            Err(e) if e.code() == Code::ResourceExhausted => {
+                warn!("Requester exceeded the pre key request limit.");
+                return Err(e);
+            }
+            result => result?,
+        };
+        if consumed >= ONE_TIME_PRE_KEYS_PER_TARGET {
+            warn!(consumed, "Anomalous one time pre key consumption.");
+        }
+        let opk = match opk {
+            Some(opk) => Some(opk),
+            None => {
+                info!("Returning last resort pre key.");
+                self.storage.get_last_resort_opk(&ik).await?
+            }
+        };
 
         info!("Returning Pre Keys");
 
         Ok(PreKeyBundleProto {
-            identity_key: Some(ik.as_bytes().into()),
-            one_time_key: opk.map(|opk| opk.as_bytes().into()),
+            identity_key: Some(ik.encode()),
+            one_time_key: opk.map(|opk| opk.pre_key.as_bytes().into()),
+            one_time_key_id: opk.map(|opk| opk.id),
             signed_pre_key: Some(spk),
+            kem_pre_key: pqpk,
         })
     }
 
-    #[instrument(name="", skip(self, ik, spk, pre_keys), fields(ik = base64.encode(ik), pre_keys = pre_keys.len()))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(name="", skip(self, ik, spk, pre_keys, last_resort_pre_key, kem_pre_keys, last_resort_kem_pre_key), fields(ik = base64.encode(ik), pre_keys = pre_keys.len(), kem_pre_keys = kem_pre_keys.len()))]
     async fn handle_register_pre_key_bundle(
         &self,
-        ik: &VerifyingKey,
+        ik: &IdentityPublicKey,
         spk: SignedPreKeyProto,
-        pre_keys: Vec<X25519PublicKey>,
+        pre_keys: Vec<PreKey>,
+        last_resort_pre_key: Option<PreKey>,
+        kem_pre_keys: Vec<SignedKemPreKeyProto>,
+        last_resort_kem_pre_key: Option<SignedKemPreKeyProto>,
         fcm_token: Option<String>,
     ) -> Result<RegisterPreKeyBundleResponse> {
         self.storage.add_user(ik, spk).await?;
         self.storage.add_opks(ik, pre_keys).await?;
+        if let Some(last_resort_pre_key) = last_resort_pre_key {
+            self.storage
+                .set_last_resort_opk(ik, last_resort_pre_key)
+                .await?;
+        }
+        self.storage.add_kem_pre_keys(ik, kem_pre_keys).await?;
+        if let Some(last_resort_kem_pre_key) = last_resort_kem_pre_key {
+            self.storage
+                .set_last_resort_kem_pre_key(ik, last_resort_kem_pre_key)
+                .await?;
+        }
         if let Some(fcm_token) = fcm_token {
             self.storage.set_fcm_token(ik, fcm_token).await?;
         }
         let num_keys = Some(self.storage.get_one_time_prekey_count(ik).await?);
+        let num_kem_keys = Some(self.storage.get_one_time_kem_pre_key_count(ik).await?);
         info!("Registered Device");
-        Ok(RegisterPreKeyBundleResponse { num_keys })
+        Ok(RegisterPreKeyBundleResponse {
+            num_keys,
+            num_kem_keys,
+        })
     }
 
     #[instrument(name="",skip(self, recipient, message), fields(ik = base64.encode(recipient)))]
     async fn handle_send_message(
         &self,
-        recipient: &VerifyingKey,
+        recipient: &IdentityPublicKey,
         message: MessageProto,
     ) -> Result<()> {
         info!("Sending message.");
-        let tx = self.receivers.lock().unwrap().get(recipient).cloned();
-        if let Some(tx) = tx {
-            match tx.send(Ok(message.clone())).await {
-                Ok(_) => {
-                    info!("Delivered message to cached peer.");
-                    return Ok(());
-                }
-                Err(_) => warn!("Failed to deliver message to cached peer."),
-            }
+        // Messages stay in the mailbox until the recipient acknowledges them, even if they are
+        // delivered to an open stream.
+        let message = self.storage.add_message(recipient, message).await?;
+        info!("Put message in mailbox.");
+        // Streams that can't take the message right away are sent it from the mailbox in order,
+        // so a slow recipient never blocks the sender.
+        for drain in self.receivers.deliver(recipient, &message) {
+            self.spawn_drain(drain);
+        }
+        if self.receivers.is_online(recipient) {
+            info!("Delivered message to open streams.");
+            return Ok(());
         }
 
         let two_weeks = Duration::new(2 * 7 * 24 * 60 * 60, 0);
 
-        // TODO: write to database and hit push notification API in parralel?
         match (
             self.storage.get_fcm_token(recipient, two_weeks).await?,
             &self.fcm_client,
@@ -113,35 +258,182 @@ impl BrongnalController {
             (None, _) => info!("Recipient device does not have an active FCM token."),
             (_, None) => info!("Cannot notify: GOOGLE_APPLICATION_CREDENTIALS is unset"),
         }
-
-        self.storage.add_message(recipient, message).await?;
-        info!("Put message in mailbox.");
         Ok(())
     }
 
     #[instrument(name="", skip(self, ik), fields(ik = base64.encode(ik)))]
-    async fn handle_retrieve_messages(
+    async fn handle_get_sender_certificate(
         &self,
-        ik: VerifyingKey,
-    ) -> Result<mpsc::Receiver<Result<MessageProto>>> {
-        let (tx, rx) = mpsc::channel(100);
+        ik: IdentityPublicKey,
+    ) -> Result<SenderCertificateResponse> {
+        // Only registered devices may send messages.
+        // TODO(#14) - GetSenderCertificate requires proof of possession. Until then, a
+        // certificate is only useful to the owner of `ik` because sealing requires its secret key.
+        self.storage.get_current_spk(&ik).await?;
+        let expiration = (SystemTime::now() + SENDER_CERTIFICATE_TTL)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let certificate = SenderCertificate::issue(&self.server_key, ik, expiration);
+        info!("Issued Sender Certificate");
+        Ok(SenderCertificateResponse {
+            certificate: Some(certificate.into()),
+            server_key: Some(self.server_key.public_key().encode()),
+        })
+    }
 
-        // TODO(#14) - RetrieveMessages requires proof of possession
-        for message in self.storage.get_messages(&ik).await.inspect_err(|e| {
-            error!(
-                %e,
-                "Failed to retrieve messages from storage."
-            )
-        })? {
-            // TODO handle result.
-            match tx.send(Ok(message)).await {
-                Ok(_) => info!("Sent message from mailbox."),
-                Err(e) => error!(%e, "Failed to send message from mailbox"),
+    /// Authenticates the uploader with the proof on the first request and collects the chunks of
+    /// the blob, stopping as soon as it exceeds the uploader's quota.
+    async fn receive_attachment(
+        &self,
+        mut stream: impl Stream<Item = Result<UploadAttachmentRequest>> + Unpin,
+    ) -> Result<UploadAttachmentResponse> {
+        let mut next = stream
+            .next()
+            .await
+            .transpose()
+            .inspect_err(|e| error!("UploadAttachmentRequest failed: {e}"))?;
+        let proof: SignedRequest = next
+            .as_mut()
+            .and_then(|request| request.proof.take())
+            .ok_or(Status::unauthenticated("missing proof of possession"))?
+            .try_into()?;
+        proof
+            .verify(UPLOAD_ATTACHMENT_PREFIX, &[], time_now())
+            .map_err(|e| Status::unauthenticated(format!("invalid proof of possession: {e}")))?;
+        let uploader = proof.identity_key;
+        let remaining_quota =
+            ATTACHMENT_QUOTA.saturating_sub(self.storage.get_attachment_usage(&uploader).await?);
+
+        let mut blob = Vec::new();
+        while let Some(request) = next {
+            let chunk = request
+                .chunk
+                .ok_or(Status::invalid_argument("request missing chunk"))?;
+            if blob.len() + chunk.len() > MAX_ATTACHMENT_SIZE {
+                return Err(Status::resource_exhausted(format!(
+                    "attachment exceeds {MAX_ATTACHMENT_SIZE} bytes"
+                )));
+            }
+            if (blob.len() + chunk.len()) as u64 > remaining_quota {
+                return Err(Status::resource_exhausted(format!(
+                    "attachments exceed quota of {ATTACHMENT_QUOTA} bytes"
+                )));
             }
+            blob.extend(chunk);
+            next = stream
+                .next()
+                .await
+                .transpose()
+                .inspect_err(|e| error!("UploadAttachmentRequest failed: {e}"))?;
+        }
+        if blob.is_empty() {
+            return Err(Status::invalid_argument("attachment is empty"));
+        }
+
+        self.handle_upload_attachment(uploader, blob).await
+    }
+
+    #[instrument(name="", skip(self, uploader, blob), fields(uploader = base64.encode(uploader), len = blob.len()))]
+    async fn handle_upload_attachment(
+        &self,
+        uploader: IdentityPublicKey,
+        blob: Vec<u8>,
+    ) -> Result<UploadAttachmentResponse> {
+        let digest = digest(&blob);
+        self.storage
+            .add_attachment(&uploader, digest, blob, ATTACHMENT_QUOTA)
+            .await?;
+        info!("Stored attachment.");
+        Ok(UploadAttachmentResponse {
+            digest: Some(digest.to_vec()),
+        })
+    }
+
+    #[instrument(name="", skip(self, digest), fields(digest = base64.encode(digest)))]
+    async fn handle_download_attachment(
+        &self,
+        digest: [u8; 32],
+    ) -> Result<Vec<DownloadAttachmentResponse>> {
+        let blob = self
+            .storage
+            .get_attachment(digest)
+            .await?
+            .ok_or(Status::not_found("attachment not found"))?;
+        info!("Sending attachment.");
+        let size = blob.len() as u64;
+        Ok(blob
+            .chunks(ATTACHMENT_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| DownloadAttachmentResponse {
+                chunk: Some(chunk.to_vec()),
+                size: (i == 0).then_some(size),
+            })
+            .collect())
+    }
+
+    #[instrument(name="", skip(self, ik), fields(ik = base64.encode(ik)))]
+    async fn handle_get_challenge(&self, ik: IdentityPublicKey) -> Result<ChallengeResponse> {
+        self.storage.get_current_spk(&ik).await?;
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        if !self.challenges.lock().unwrap().issue(ik, nonce, time_now()) {
+            warn!("Too many outstanding challenges.");
+            return Err(Status::resource_exhausted(
+                "too many outstanding challenges",
+            ));
         }
-        self.receivers.lock().unwrap().insert(ik, tx);
-        info!("Message Stream Open");
-        Ok(rx)
+        info!("Issued challenge.");
+        Ok(ChallengeResponse {
+            nonce: Some(nonce.to_vec()),
+        })
+    }
+
+    #[instrument(name="", skip(self, ik), fields(ik = base64.encode(ik)))]
+    async fn handle_retrieve_messages(&self, ik: IdentityPublicKey) -> Result<MessageStream> {
+        let (tx, rx) = mpsc::channel(MESSAGE_STREAM_BUFFER);
+        let (stream, drain) = self.receivers.insert(ik, tx, rx);
+        self.spawn_drain(drain);
+        Ok(stream)
+    }
+
+    /// Sends a stream the messages in the mailbox that it has not been sent yet, a page at a time.
+    fn spawn_drain(&self, drain: Drain) {
+        let storage = self.storage.clone();
+        let receivers = self.receivers.clone();
+        tokio::spawn(
+            async move {
+                let Drain { ik, id, tx, after } = drain;
+                let mut last_sequence = after;
+                loop {
+                    let messages = match storage
+                        .get_messages(&ik, last_sequence, MAILBOX_PAGE_SIZE)
+                        .await
+                    {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            error!(%e, "Failed to retrieve messages from storage.");
+                            let _ = tx.send(Err(e)).await;
+                            return;
+                        }
+                    };
+                    let full_page = messages.len() == MAILBOX_PAGE_SIZE as usize;
+                    for message in messages {
+                        let sequence = message.sequence();
+                        if tx.send(Ok(message)).await.is_err() {
+                            info!("Message stream closed while draining mailbox.");
+                            return;
+                        }
+                        last_sequence = sequence;
+                    }
+                    if !full_page && receivers.finish_drain(&ik, id, last_sequence) {
+                        info!(last_sequence, "Sent messages from mailbox.");
+                        return;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
     }
 }
 
@@ -153,33 +445,59 @@ impl BrongnalService for BrongnalController {
         request: Request<RegisterPreKeyBundleRequest>,
     ) -> Result<Response<RegisterPreKeyBundleResponse>> {
         let request = request.into_inner();
-        let ik = parse_verifying_key(request.identity_key())
+        let ik = parse_identity_key(request.identity_key())
             .map_err(|_| Status::invalid_argument("request has invalid identity_key"))?;
         let spk_proto = request
             .signed_pre_key
             .ok_or(Status::invalid_argument("request is missing signed prekey"))?;
         let spk = protocol::x3dh::SignedPreKey::try_from(spk_proto.clone())?;
-        verify_bundle(&ik, &[spk.pre_key], &spk.signature)
+        verify_pre_key(&ik, &spk)
             .map_err(|_| Status::unauthenticated("failed to validate signed prekey signature"))?;
 
-        let opks = request.one_time_key_bundle.ok_or(Status::invalid_argument(
-            "request missing one_time_prekey_bundle",
-        ))?;
-        let pre_keys: Vec<X25519PublicKey> = opks
-            .pre_keys
-            .iter()
-            .map(|key| parse_x25519_public_key(key))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|_| Status::invalid_argument("invalid prekey bundle"))?;
-        let signature = Signature::from_slice(opks.signature()).map_err(|_e| {
-            Status::invalid_argument("one time prekey bundle signature is invalid")
-        })?;
-        verify_bundle(&ik, &pre_keys, &signature).map_err(|_| {
+        let opks: SignedPreKeys = request
+            .one_time_key_bundle
+            .ok_or(Status::invalid_argument(
+                "request missing one_time_prekey_bundle",
+            ))?
+            .try_into()?;
+        verify_bundle(&ik, &opks).map_err(|_| {
             Status::unauthenticated("failed to validate one time prekey bundle signature")
         })?;
+        let last_resort_pre_key = match request.last_resort_pre_key {
+            Some(last_resort_pre_key) => {
+                let last_resort_pre_key: SignedPreKeys = last_resort_pre_key.try_into()?;
+                verify_bundle(&ik, &last_resort_pre_key).map_err(|_| {
+                    Status::unauthenticated("failed to validate last resort prekey signature")
+                })?;
+                let [pre_key] = last_resort_pre_key.pre_keys[..] else {
+                    return Err(Status::invalid_argument(
+                        "last resort prekey bundle must have exactly one key",
+                    ));
+                };
+                Some(pre_key)
+            }
+            None => None,
+        };
+        for kem_pre_key in request
+            .one_time_kem_pre_keys
+            .iter()
+            .chain(&request.last_resort_kem_pre_key)
+        {
+            let kem_pre_key = SignedKemPreKey::try_from(kem_pre_key.clone())?;
+            verify_kem_pre_key(&ik, &kem_pre_key)
+                .map_err(|_| Status::unauthenticated("failed to validate KEM prekey signature"))?;
+        }
         let fcm_token = request.fcm_token;
         let response = self
-            .handle_register_pre_key_bundle(&ik, spk_proto, pre_keys, fcm_token)
+            .handle_register_pre_key_bundle(
+                &ik,
+                spk_proto,
+                opks.pre_keys,
+                last_resort_pre_key,
+                request.one_time_kem_pre_keys,
+                request.last_resort_kem_pre_key,
+                fcm_token,
+            )
             .await
             .inspect_err(|e| error!(%e, "Failed to register pre key bundle"))?;
         Ok(Response::new(response))
@@ -191,13 +509,21 @@ impl BrongnalService for BrongnalController {
         request: Request<PreKeyBundleRequest>,
     ) -> Result<Response<PreKeyBundleProto>> {
         let request = request.into_inner();
-        let ik = parse_verifying_key(
-            &request
-                .identity_key
-                .ok_or(Status::invalid_argument("missing recipient identity key"))?,
-        )
-        .map_err(|_| Status::invalid_argument("invalid recipient identity key"))?;
-        let reply = self.handle_request_pre_keys(ik).await?;
+        let identity_key = request
+            .identity_key
+            .ok_or(Status::invalid_argument("missing recipient identity key"))?;
+        let ik = parse_identity_key(&identity_key)
+            .map_err(|_| Status::invalid_argument("invalid recipient identity key"))?;
+        let requester: SignedRequest = request
+            .requester
+            .ok_or(Status::unauthenticated("missing requester"))?
+            .try_into()?;
+        requester
+            .verify(PRE_KEY_REQUEST_PREFIX, &identity_key, time_now())
+            .map_err(|e| Status::unauthenticated(format!("invalid requester: {e}")))?;
+        let reply = self
+            .handle_request_pre_keys(requester.identity_key, ik)
+            .await?;
 
         Ok(Response::new(reply))
     }
@@ -214,7 +540,7 @@ impl BrongnalService for BrongnalController {
                 .message
                 .ok_or(Status::invalid_argument("request missing message"))?;
 
-            let recipient = parse_verifying_key(
+            let recipient = parse_identity_key(
                 &request
                     .recipient_identity_key
                     .ok_or(Status::invalid_argument("missing recipient identity key"))?,
@@ -222,8 +548,8 @@ impl BrongnalService for BrongnalController {
             .map_err(|_| Status::invalid_argument("invalid recipient identity key"))?;
 
             // Do some basic validation on the message before persisting it or sending it to the
-            // recipient.
-            let _message = protocol::x3dh::Message::try_from(message_proto.clone())?;
+            // recipient. The sender of a sealed message is only known to the recipient.
+            let _message = proto::Envelope::try_from(message_proto.clone())?;
 
             self.handle_send_message(&recipient, message_proto)
                 .await
@@ -232,22 +558,467 @@ impl BrongnalService for BrongnalController {
         Ok(Response::new(SendMessageResponse {}))
     }
 
-    type RetrieveMessagesStream = ReceiverStream<Result<MessageProto>>;
+    #[instrument(skip(self, request))]
+    async fn get_challenge(
+        &self,
+        request: Request<ChallengeRequest>,
+    ) -> Result<Response<ChallengeResponse>> {
+        let request = request.into_inner();
+        let ik = parse_identity_key(
+            &request
+                .identity_key
+                .ok_or(Status::invalid_argument("missing identity key"))?,
+        )
+        .map_err(|_| Status::invalid_argument("invalid identity key"))?;
+
+        let response = self.handle_get_challenge(ik).await?;
+
+        Ok(Response::new(response))
+    }
+
+    type RetrieveMessagesStream = MessageStream;
     #[instrument(skip(self, request))]
     async fn retrieve_messages(
         &self,
         request: Request<RetrieveMessagesRequest>,
     ) -> Result<Response<Self::RetrieveMessagesStream>> {
         let request = request.into_inner();
-        let ik = parse_verifying_key(
+        let ik = parse_identity_key(
+            &request
+                .identity_key
+                .ok_or(Status::invalid_argument("missing recipient identity key"))?,
+        )
+        .map_err(|_| Status::invalid_argument("invalid recipient identity key"))?;
+        let nonce: [u8; 32] = request
+            .nonce
+            .ok_or(Status::unauthenticated("missing challenge nonce"))?
+            .try_into()
+            .map_err(|_| Status::invalid_argument("invalid challenge nonce"))?;
+        let proof: SignedRequest = request
+            .proof
+            .ok_or(Status::unauthenticated("missing proof of possession"))?
+            .try_into()?;
+
+        // A challenge is consumed by the first attempt to answer it so that it can't be replayed.
+        let now = time_now();
+        if !self.challenges.lock().unwrap().take(&ik, &nonce, now) {
+            return Err(Status::unauthenticated("unknown or expired challenge"));
+        }
+        if proof.identity_key != ik {
+            return Err(Status::unauthenticated("proof is not from the recipient"));
+        }
+        proof
+            .verify(RETRIEVE_MESSAGES_PREFIX, &nonce, now)
+            .map_err(|e| Status::unauthenticated(format!("invalid proof of possession: {e}")))?;
+
+        let stream = self.handle_retrieve_messages(ik).await?;
+
+        Ok(Response::new(stream))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn acknowledge_messages(
+        &self,
+        request: Request<AcknowledgeMessagesRequest>,
+    ) -> Result<Response<AcknowledgeMessagesResponse>> {
+        let request = request.into_inner();
+        let ik = parse_identity_key(
             &request
                 .identity_key
                 .ok_or(Status::invalid_argument("missing recipient identity key"))?,
         )
         .map_err(|_| Status::invalid_argument("invalid recipient identity key"))?;
+        let proof: SignedRequest = request
+            .proof
+            .ok_or(Status::unauthenticated("missing proof of possession"))?
+            .try_into()?;
+        if proof.identity_key != ik {
+            return Err(Status::unauthenticated("proof is not from the recipient"));
+        }
+        proof
+            .verify(
+                ACKNOWLEDGE_MESSAGES_PREFIX,
+                &acknowledgement_contents(&request.message_ids),
+                time_now(),
+            )
+            .map_err(|e| Status::unauthenticated(format!("invalid proof of possession: {e}")))?;
+
+        let deleted = self.storage.ack_messages(&ik, request.message_ids).await?;
+        info!(deleted, "Acknowledged messages.");
+
+        Ok(Response::new(AcknowledgeMessagesResponse {}))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn get_sender_certificate(
+        &self,
+        request: Request<SenderCertificateRequest>,
+    ) -> Result<Response<SenderCertificateResponse>> {
+        let request = request.into_inner();
+        let ik = parse_identity_key(
+            &request
+                .identity_key
+                .ok_or(Status::invalid_argument("missing sender identity key"))?,
+        )
+        .map_err(|_| Status::invalid_argument("invalid sender identity key"))?;
+
+        let response = self.handle_get_sender_certificate(ik).await?;
+
+        Ok(Response::new(response))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn upload_attachment(
+        &self,
+        request: Request<Streaming<UploadAttachmentRequest>>,
+    ) -> Result<Response<UploadAttachmentResponse>> {
+        let response = self.receive_attachment(request.into_inner()).await?;
+
+        Ok(Response::new(response))
+    }
+
+    type DownloadAttachmentStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<DownloadAttachmentResponse>>>;
+    #[instrument(skip(self, request))]
+    async fn download_attachment(
+        &self,
+        request: Request<DownloadAttachmentRequest>,
+    ) -> Result<Response<Self::DownloadAttachmentStream>> {
+        let request = request.into_inner();
+        let digest = request
+            .digest()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("invalid attachment digest"))?;
+
+        let chunks = self.handle_download_attachment(digest).await?;
+
+        Ok(Response::new(tokio_stream::iter(
+            chunks.into_iter().map(Ok).collect::<Vec<_>>(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brongnal::*;
+    use crate::memory::MemoryStorage;
+    use anyhow::Result;
+    use client::X3DHClient;
+    use tokio_rusqlite::Connection;
+    use tonic::Code;
+
+    async fn registered_controller() -> Result<(BrongnalController, IdentityKey)> {
+        let storage = Arc::new(MemoryStorage::default());
+        let bob = X3DHClient::new(Connection::open_in_memory().await?).await?;
+        storage
+            .add_user(&bob.get_ik().public_key(), bob.get_spk().await?.into())
+            .await?;
+        let controller = BrongnalController::new(storage, IdentityKey::generate(), None);
+        Ok((controller, bob.get_ik().clone()))
+    }
+
+    async fn get_nonce(controller: &BrongnalController, ik: &IdentityKey) -> Result<Vec<u8>> {
+        Ok(controller
+            .get_challenge(Request::new(ChallengeRequest {
+                identity_key: Some(ik.public_key().encode()),
+            }))
+            .await?
+            .into_inner()
+            .nonce
+            .unwrap())
+    }
+
+    fn retrieve_request(
+        ik: &IdentityPublicKey,
+        nonce: Vec<u8>,
+        signer: &IdentityKey,
+    ) -> Request<RetrieveMessagesRequest> {
+        let proof = SignedRequest::sign(signer, RETRIEVE_MESSAGES_PREFIX, &nonce, time_now());
+        Request::new(RetrieveMessagesRequest {
+            identity_key: Some(ik.encode()),
+            nonce: Some(nonce),
+            proof: Some(proof.into()),
+        })
+    }
+
+    #[tokio::test]
+    async fn retrieve_messages_requires_proof_of_possession() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let nonce = get_nonce(&controller, &bob).await?;
+        let request = retrieve_request(&bob_ik, nonce, &IdentityKey::generate());
+        assert_eq!(
+            controller
+                .retrieve_messages(request)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+
+        let nonce = get_nonce(&controller, &bob).await?;
+        controller
+            .retrieve_messages(retrieve_request(&bob_ik, nonce, &bob))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrieve_messages_challenge_is_single_use() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let nonce = get_nonce(&controller, &bob).await?;
+        controller
+            .retrieve_messages(retrieve_request(&bob_ik, nonce.clone(), &bob))
+            .await?;
+        assert_eq!(
+            controller
+                .retrieve_messages(retrieve_request(&bob_ik, nonce, &bob))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_challenge_replaces_previous() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let replaced = get_nonce(&controller, &bob).await?;
+        let nonce = get_nonce(&controller, &bob).await?;
+        assert_eq!(
+            controller
+                .retrieve_messages(retrieve_request(&bob_ik, replaced, &bob))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        controller
+            .retrieve_messages(retrieve_request(&bob_ik, nonce, &bob))
+            .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn outstanding_challenges_are_bounded() {
+        let mut challenges = Challenges::default();
+        let ik = |i: usize| {
+            let mut key = [0u8; 32];
+            key[..8].copy_from_slice(&i.to_be_bytes());
+            IdentityPublicKey::from(key)
+        };
+        for i in 0..MAX_CHALLENGES {
+            assert!(challenges.issue(ik(i), [0; 32], 1000));
+        }
+        assert!(!challenges.issue(ik(MAX_CHALLENGES), [0; 32], 1000));
+        // Devices with a challenge can still replace it.
+        assert!(challenges.issue(ik(0), [1; 32], 1000));
+        assert!(!challenges.take(&ik(0), &[0; 32], 1000));
+        assert!(challenges.take(&ik(0), &[1; 32], 1000));
+        assert!(!challenges.take(&ik(0), &[1; 32], 1000));
+
+        // Expired challenges make room.
+        assert!(challenges.issue(ik(0), [0; 32], 1000));
+        let expired = 1000 + CHALLENGE_TTL.as_secs();
+        assert!(challenges.issue(ik(MAX_CHALLENGES), [0; 32], expired));
+        assert_eq!(challenges.by_ik.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn acknowledge_messages_requires_proof_of_possession() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let id = controller
+            .storage
+            .add_message(&bob_ik, MessageProto::default())
+            .await?
+            .id
+            .unwrap();
+        let acknowledge = |signer: &IdentityKey| {
+            let proof = SignedRequest::sign(
+                signer,
+                ACKNOWLEDGE_MESSAGES_PREFIX,
+                &acknowledgement_contents(&[id]),
+                time_now(),
+            );
+            Request::new(AcknowledgeMessagesRequest {
+                identity_key: Some(bob_ik.encode()),
+                message_ids: vec![id],
+                proof: Some(proof.into()),
+            })
+        };
+
+        assert_eq!(
+            controller
+                .acknowledge_messages(acknowledge(&IdentityKey::generate()))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+        assert_eq!(
+            controller
+                .storage
+                .get_messages(&bob_ik, 0, u32::MAX)
+                .await?
+                .len(),
+            1
+        );
+
+        controller.acknowledge_messages(acknowledge(&bob)).await?;
+        assert!(controller
+            .storage
+            .get_messages(&bob_ik, 0, u32::MAX)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn live_messages_delivered_to_every_stream() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let mut first = controller.handle_retrieve_messages(bob_ik).await?;
+        let mut second = controller.handle_retrieve_messages(bob_ik).await?;
+        assert!(controller.receivers.is_online(&bob_ik));
+
+        controller
+            .handle_send_message(&bob_ik, MessageProto::default())
+            .await?;
+        let delivered = first.next().await.transpose()?;
+        assert!(delivered
+            .as_ref()
+            .is_some_and(|message| message.id.is_some()));
+        assert_eq!(second.next().await.transpose()?, delivered);
+
+        drop(first);
+        assert!(controller.receivers.is_online(&bob_ik));
+        drop(second);
+        assert!(!controller.receivers.is_online(&bob_ik));
+        Ok(())
+    }
 
-        let rx = self.handle_retrieve_messages(ik).await?;
+    #[tokio::test]
+    async fn stalled_stream_does_not_block_sender() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let mut stream = controller.handle_retrieve_messages(bob_ik).await?;
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+        let num_messages = 3 * MESSAGE_STREAM_BUFFER as u64;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            for _ in 0..num_messages {
+                controller
+                    .handle_send_message(&bob_ik, MessageProto::default())
+                    .await?;
+            }
+            Ok::<_, Status>(())
+        })
+        .await??;
+
+        for sequence in 1..=num_messages {
+            let message = stream.next().await.transpose()?.unwrap();
+            assert_eq!(message.sequence, Some(sequence));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mailbox_larger_than_stream_buffer_and_page_delivered() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let bob_ik = bob.public_key();
+        let num_messages = 2 * MESSAGE_STREAM_BUFFER.max(MAILBOX_PAGE_SIZE as usize) as u64;
+        for _ in 0..num_messages {
+            controller
+                .handle_send_message(&bob_ik, MessageProto::default())
+                .await?;
+        }
+
+        let mut stream = controller.handle_retrieve_messages(bob_ik).await?;
+        for sequence in 1..=num_messages {
+            let message = stream.next().await.transpose()?.unwrap();
+            assert_eq!(message.sequence, Some(sequence));
+        }
+        Ok(())
+    }
+
+    fn upload_requests(
+        signer: &IdentityKey,
+        chunks: &[&[u8]],
+    ) -> impl Stream<Item = tonic::Result<UploadAttachmentRequest>> + Unpin {
+        let mut requests: Vec<_> = chunks
+            .iter()
+            .map(|chunk| UploadAttachmentRequest {
+                chunk: Some(chunk.to_vec()),
+                proof: None,
+            })
+            .collect();
+        requests[0].proof =
+            Some(SignedRequest::sign(signer, UPLOAD_ATTACHMENT_PREFIX, &[], time_now()).into());
+        tokio_stream::iter(requests.into_iter().map(Ok))
+    }
+
+    #[tokio::test]
+    async fn upload_attachment_requires_proof_of_possession() -> Result<()> {
+        let (controller, bob) = registered_controller().await?;
+        let unsigned = tokio_stream::iter(vec![Ok(UploadAttachmentRequest {
+            chunk: Some(b"blob".to_vec()),
+            proof: None,
+        })]);
+        assert_eq!(
+            controller
+                .receive_attachment(unsigned)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+
+        // A proof for a different kind of request.
+        let proof = SignedRequest::sign(&bob, PRE_KEY_REQUEST_PREFIX, &[], time_now());
+        let forged = tokio_stream::iter(vec![Ok(UploadAttachmentRequest {
+            chunk: Some(b"blob".to_vec()),
+            proof: Some(proof.into()),
+        })]);
+        assert_eq!(
+            controller
+                .receive_attachment(forged)
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::Unauthenticated)
+        );
+
+        // Devices that aren't registered have nowhere to store attachments.
+        assert_eq!(
+            controller
+                .receive_attachment(upload_requests(&IdentityKey::generate(), &[b"blob"]))
+                .await
+                .err()
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+
+        let response = controller
+            .receive_attachment(upload_requests(&bob, &[b"bl", b"ob"]))
+            .await?;
+        assert_eq!(response.digest, Some(digest(b"blob").to_vec()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn challenge_requires_registered_device() -> Result<()> {
+        let (controller, _) = registered_controller().await?;
+        assert_eq!(
+            get_nonce(&controller, &IdentityKey::generate())
+                .await
+                .err()
+                .and_then(|e| e.downcast::<Status>().ok())
+                .map(|e| e.code()),
+            Some(Code::NotFound)
+        );
+        Ok(())
     }
 }
@@ -3,14 +3,16 @@ use crate::push_notifications::FirebaseCloudMessagingClient;
 use brongnal::BrongnalController;
 use gossamer::persistence::GossamerStorage;
 use gossamer::service::Service as GossamerService;
-use persistence::{clean_mailboxes, SqliteStorage};
+use persistence::SqliteStorage;
 use proto::gossamer::gossamer_service_server::GossamerServiceServer as GossamerServer;
 use proto::service::brongnal_service_server::BrongnalServiceServer as BrongnalServer;
 use proto::FILE_DESCRIPTOR_SET;
 use sentry::ClientInitGuard;
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use store::BrongnalStore;
 use tokio_rusqlite::Connection;
 use tonic::transport::Server;
 use tonic_reflection::server::Builder;
@@ -20,17 +22,35 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
 mod brongnal;
+#[cfg(test)]
+mod memory;
 mod persistence;
+#[cfg(feature = "postgres")]
+mod postgres;
 mod push_notifications;
+mod receivers;
+mod store;
 
-pub async fn db_cleanup(connection: tokio_rusqlite::Connection) {
+/// Seconds since the Unix epoch.
+pub fn time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub async fn db_cleanup(storage: Arc<dyn BrongnalStore>) {
     let mut interval = tokio::time::interval(Duration::from_hours(1));
     loop {
         interval.tick().await;
-        match clean_mailboxes(&connection, Duration::from_days(30)).await {
+        match storage.clean_mailboxes(Duration::from_days(30)).await {
             Ok(num) => info!("Cleaned up {num} items from mailboxes."),
             Err(e) => warn!("Failed to clean mailboxes: {e}"),
         }
+        match storage.clean_attachments(Duration::from_days(30)).await {
+            Ok(num) => info!("Cleaned up {num} attachments."),
+            Err(e) => warn!("Failed to clean attachments: {e}"),
+        }
     }
 }
 
@@ -85,10 +105,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     info!("Database Path: {}", db_path.display());
     let connection = Connection::open(db_path).await?;
-    tokio::spawn(db_cleanup(connection.clone()));
 
-    let controller =
-        BrongnalController::new(SqliteStorage::new(connection.clone()).await?, fcm_client);
+    let storage: Arc<dyn BrongnalStore> = match std::env::var("POSTGRES_URL") {
+        #[cfg(feature = "postgres")]
+        Ok(url) => {
+            info!("Storing Brongnal data in PostgreSQL.");
+            Arc::new(postgres::PostgresStorage::connect(&url).await?)
+        }
+        #[cfg(not(feature = "postgres"))]
+        Ok(_) => return Err("POSTGRES_URL is set but the postgres feature is disabled.".into()),
+        Err(_) => Arc::new(SqliteStorage::new(connection.clone()).await?),
+    };
+    tokio::spawn(db_cleanup(storage.clone()));
+    let server_key = storage.get_server_key().await?;
+    let controller = BrongnalController::new(storage, server_key, fcm_client);
     let gossamer = GossamerService::new(GossamerStorage::new(connection).await?);
 
     info!("Brongnal Server listening at: {server_addr}");
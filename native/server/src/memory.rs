@@ -0,0 +1,384 @@
+use crate::store::BrongnalStore;
+use crate::time_now;
+use proto::service::Message as MessageProto;
+use proto::service::SignedKemPreKey as SignedKemPreKeyProto;
+use proto::service::SignedPreKey as SignedPreKeyProto;
+use protocol::x3dh::PreKey;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tonic::{Result, Status};
+
+struct PreKeyRequest {
+    requester: IdentityPublicKey,
+    target: IdentityPublicKey,
+    consumed_opk: bool,
+    time: u64,
+}
+
+struct Attachment {
+    blob: Vec<u8>,
+    uploader: IdentityPublicKey,
+    time: u64,
+}
+
+/// A message with its id and sequence number set.
+struct MailboxEntry {
+    message: MessageProto,
+    time: u64,
+}
+
+#[derive(Default)]
+struct State {
+    devices: HashMap<IdentityPublicKey, SignedPreKeyProto>,
+    opks: HashMap<IdentityPublicKey, VecDeque<PreKey>>,
+    last_resort_opks: HashMap<IdentityPublicKey, PreKey>,
+    pre_key_requests: Vec<PreKeyRequest>,
+    kem_pre_keys: HashMap<IdentityPublicKey, VecDeque<SignedKemPreKeyProto>>,
+    last_resort_kem_pre_keys: HashMap<IdentityPublicKey, SignedKemPreKeyProto>,
+    mailboxes: HashMap<IdentityPublicKey, Vec<MailboxEntry>>,
+    sequences: HashMap<IdentityPublicKey, u64>,
+    last_message_id: u64,
+    attachments: HashMap<[u8; 32], Attachment>,
+    server_key: Option<IdentityKey>,
+    fcm_tokens: HashMap<IdentityPublicKey, (String, u64)>,
+}
+
+impl State {
+    /// Mirrors the foreign keys on the device table of the SQL stores.
+    fn is_registered(&self, ik: &IdentityPublicKey) -> bool {
+        self.devices.contains_key(ik)
+    }
+
+    /// Pops the oldest one time KEM pre key of `ik` if `one_time`, falling back to its last
+    /// resort KEM pre key. Returns the pre key and whether it was a one time pre key.
+    fn pop_kem_pre_key(
+        &mut self,
+        ik: &IdentityPublicKey,
+        one_time: bool,
+    ) -> Option<(SignedKemPreKeyProto, bool)> {
+        if one_time {
+            if let Some(pre_key) = self.kem_pre_keys.get_mut(ik).and_then(VecDeque::pop_front) {
+                return Some((pre_key, true));
+            }
+        }
+        self.last_resort_kem_pre_keys
+            .get(ik)
+            .cloned()
+            .map(|pre_key| (pre_key, false))
+    }
+
+    fn attachment_usage(&self, uploader: &IdentityPublicKey) -> u64 {
+        self.attachments
+            .values()
+            .filter(|attachment| attachment.uploader == *uploader)
+            .map(|attachment| attachment.blob.len() as u64)
+            .sum()
+    }
+}
+
+/// A store that keeps everything in memory so that tests don't need a database.
+#[derive(Default)]
+pub struct MemoryStorage(Mutex<State>);
+
+#[tonic::async_trait]
+impl BrongnalStore for MemoryStorage {
+    async fn insert_device(
+        &self,
+        ik: &IdentityPublicKey,
+        spk: SignedPreKeyProto,
+    ) -> Result<SignedPreKeyProto> {
+        let mut state = self.0.lock().unwrap();
+        Ok(state.devices.entry(*ik).or_insert(spk).clone())
+    }
+
+    async fn replace_spk(
+        &self,
+        ik: &IdentityPublicKey,
+        current: SignedPreKeyProto,
+        spk: SignedPreKeyProto,
+    ) -> Result<bool> {
+        let mut state = self.0.lock().unwrap();
+        match state.devices.get_mut(ik) {
+            Some(persisted) if *persisted == current => {
+                *persisted = spk;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn add_opks(&self, ik: &IdentityPublicKey, opks: Vec<PreKey>) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(ik) {
+            return Err(Status::internal("failed to insert one time key"));
+        }
+        state.opks.entry(*ik).or_default().extend(opks);
+        Ok(())
+    }
+
+    async fn set_last_resort_opk(&self, ik: &IdentityPublicKey, pre_key: PreKey) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(ik) {
+            return Err(Status::internal("failed to set last resort pre key"));
+        }
+        state.last_resort_opks.insert(*ik, pre_key);
+        Ok(())
+    }
+
+    async fn get_last_resort_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>> {
+        Ok(self.0.lock().unwrap().last_resort_opks.get(ik).copied())
+    }
+
+    async fn request_one_time_pre_keys(
+        &self,
+        requester: &IdentityPublicKey,
+        target: &IdentityPublicKey,
+        since: u64,
+        max_requests: u32,
+        max_consumed: u32,
+    ) -> Result<(Option<PreKey>, Option<SignedKemPreKeyProto>, u32)> {
+        let mut state = self.0.lock().unwrap();
+        state
+            .pre_key_requests
+            .retain(|request| request.time >= since);
+        let requests = state
+            .pre_key_requests
+            .iter()
+            .filter(|request| request.requester == *requester)
+            .count() as u32;
+        if requests >= max_requests {
+            return Err(Status::resource_exhausted("too many pre key requests"));
+        }
+        let consumed = state
+            .pre_key_requests
+            .iter()
+            .filter(|request| request.target == *target && request.consumed_opk)
+            .count() as u32;
+        let opk = if consumed < max_consumed {
+            state.opks.get_mut(target).and_then(VecDeque::pop_front)
+        } else {
+            None
+        };
+        let kem_pre_key = state.pop_kem_pre_key(target, consumed < max_consumed);
+        state.pre_key_requests.push(PreKeyRequest {
+            requester: *requester,
+            target: *target,
+            consumed_opk: opk.is_some()
+                || kem_pre_key.as_ref().is_some_and(|(_, one_time)| *one_time),
+            time: time_now(),
+        });
+        Ok((opk, kem_pre_key.map(|(pre_key, _)| pre_key), consumed))
+    }
+
+    async fn add_kem_pre_keys(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_keys: Vec<SignedKemPreKeyProto>,
+    ) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(ik) {
+            return Err(Status::internal("failed to insert one time KEM pre key"));
+        }
+        state.kem_pre_keys.entry(*ik).or_default().extend(pre_keys);
+        Ok(())
+    }
+
+    async fn set_last_resort_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_key: SignedKemPreKeyProto,
+    ) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(ik) {
+            return Err(Status::internal("failed to set last resort KEM pre key"));
+        }
+        state.last_resort_kem_pre_keys.insert(*ik, pre_key);
+        Ok(())
+    }
+
+    async fn get_current_spk(&self, ik: &IdentityPublicKey) -> Result<SignedPreKeyProto> {
+        self.0
+            .lock()
+            .unwrap()
+            .devices
+            .get(ik)
+            .cloned()
+            .ok_or_else(|| Status::not_found("user not found"))
+    }
+
+    async fn pop_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>> {
+        let mut state = self.0.lock().unwrap();
+        Ok(state.opks.get_mut(ik).and_then(VecDeque::pop_front))
+    }
+
+    async fn pop_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+    ) -> Result<Option<SignedKemPreKeyProto>> {
+        let mut state = self.0.lock().unwrap();
+        Ok(state.pop_kem_pre_key(ik, true).map(|(pre_key, _)| pre_key))
+    }
+
+    async fn add_message(
+        &self,
+        recipient: &IdentityPublicKey,
+        mut message: MessageProto,
+    ) -> Result<MessageProto> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(recipient) {
+            return Err(Status::not_found("Cannot enqueue message for unknown user"));
+        }
+        let sequence = state.sequences.entry(*recipient).or_default();
+        *sequence += 1;
+        message.sequence = Some(*sequence);
+        state.last_message_id += 1;
+        message.id = Some(state.last_message_id);
+        state
+            .mailboxes
+            .entry(*recipient)
+            .or_default()
+            .push(MailboxEntry {
+                message: message.clone(),
+                time: time_now(),
+            });
+        Ok(message)
+    }
+
+    async fn get_messages(
+        &self,
+        recipient: &IdentityPublicKey,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<MessageProto>> {
+        let state = self.0.lock().unwrap();
+        Ok(state
+            .mailboxes
+            .get(recipient)
+            .into_iter()
+            .flatten()
+            .map(|entry| &entry.message)
+            .filter(|message| message.sequence() > after)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn ack_messages(&self, recipient: &IdentityPublicKey, ids: Vec<u64>) -> Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        let Some(mailbox) = state.mailboxes.get_mut(recipient) else {
+            return Ok(0);
+        };
+        let len = mailbox.len();
+        mailbox.retain(|entry| !ids.contains(&entry.message.id()));
+        Ok(len - mailbox.len())
+    }
+
+    async fn get_one_time_prekey_count(&self, ik: &IdentityPublicKey) -> Result<u32> {
+        let state = self.0.lock().unwrap();
+        Ok(state.opks.get(ik).map_or(0, VecDeque::len) as u32)
+    }
+
+    async fn get_one_time_kem_pre_key_count(&self, ik: &IdentityPublicKey) -> Result<u32> {
+        let state = self.0.lock().unwrap();
+        Ok(state.kem_pre_keys.get(ik).map_or(0, VecDeque::len) as u32)
+    }
+
+    async fn add_attachment(
+        &self,
+        uploader: &IdentityPublicKey,
+        digest: [u8; 32],
+        blob: Vec<u8>,
+        quota: u64,
+    ) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(attachment) = state.attachments.get_mut(&digest) {
+            attachment.time = time_now();
+            return Ok(());
+        }
+        if !state.is_registered(uploader) {
+            return Err(Status::not_found("device not found"));
+        }
+        if state.attachment_usage(uploader) + blob.len() as u64 > quota {
+            return Err(Status::resource_exhausted(format!(
+                "attachments exceed quota of {quota} bytes"
+            )));
+        }
+        state.attachments.insert(
+            digest,
+            Attachment {
+                blob,
+                uploader: *uploader,
+                time: time_now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_attachment_usage(&self, uploader: &IdentityPublicKey) -> Result<u64> {
+        let state = self.0.lock().unwrap();
+        Ok(state.attachment_usage(uploader))
+    }
+
+    async fn get_attachment(&self, digest: [u8; 32]) -> Result<Option<Vec<u8>>> {
+        let state = self.0.lock().unwrap();
+        Ok(state
+            .attachments
+            .get(&digest)
+            .map(|attachment| attachment.blob.clone()))
+    }
+
+    async fn get_server_key(&self) -> Result<IdentityKey> {
+        let mut state = self.0.lock().unwrap();
+        Ok(state
+            .server_key
+            .get_or_insert_with(IdentityKey::generate)
+            .clone())
+    }
+
+    async fn set_fcm_token(&self, ik: &IdentityPublicKey, token: String) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if !state.is_registered(ik) {
+            return Err(Status::not_found("user not found"));
+        }
+        state.fcm_tokens.insert(*ik, (token, time_now()));
+        Ok(())
+    }
+
+    async fn get_fcm_token(
+        &self,
+        ik: &IdentityPublicKey,
+        max_age: Duration,
+    ) -> Result<Option<String>> {
+        let min_time = time_now().saturating_sub(max_age.as_secs());
+        let state = self.0.lock().unwrap();
+        Ok(state
+            .fcm_tokens
+            .get(ik)
+            .filter(|(_, time)| *time > min_time)
+            .map(|(token, _)| token.clone()))
+    }
+
+    async fn clean_mailboxes(&self, ttl: Duration) -> Result<usize> {
+        let expired = time_now() - ttl.as_secs();
+        let mut state = self.0.lock().unwrap();
+        let mut deleted = 0;
+        for mailbox in state.mailboxes.values_mut() {
+            let len = mailbox.len();
+            mailbox.retain(|entry| entry.time >= expired);
+            deleted += len - mailbox.len();
+        }
+        Ok(deleted)
+    }
+
+    async fn clean_attachments(&self, ttl: Duration) -> Result<usize> {
+        let expired = time_now() - ttl.as_secs();
+        let mut state = self.0.lock().unwrap();
+        let len = state.attachments.len();
+        state
+            .attachments
+            .retain(|_, attachment| attachment.time >= expired);
+        Ok(len - state.attachments.len())
+    }
+}
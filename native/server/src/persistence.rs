@@ -1,61 +1,73 @@
+use crate::store::BrongnalStore;
+use crate::time_now;
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use ed25519_dalek::VerifyingKey;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, VerifyingKey};
+use migrations::{migrate, Migration};
 use prost::Message;
 use proto::service::Message as MessageProto;
+use proto::service::SignedKemPreKey as SignedKemPreKeyProto;
 use proto::service::SignedPreKey as SignedPreKeyProto;
+use protocol::x3dh::PreKey;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
 use rusqlite::params;
 use rusqlite::Error;
 use std::time::Duration;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tonic::Status;
 use tracing::{error, info, instrument};
 use x25519_dalek::PublicKey as X25519PublicKey;
 
+#[derive(Clone)]
 pub struct SqliteStorage(tokio_rusqlite::Connection);
 
-fn time_now() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-}
+/// The name the server's schema is versioned by.
+const SCHEMA: &str = "server";
+
+/// The migrations of the server's schema, in order. The first migration creates the schema from
+/// before it was versioned. Migrations from before the schema was versioned check whether they
+/// are needed because unversioned databases may have any of them applied.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create tables",
+        apply: create_tables,
+    },
+    Migration {
+        description: "Add ids to one time prekeys",
+        apply: migrate_opk_queue,
+    },
+    Migration {
+        description: "Create pre key, attachment and server key tables",
+        apply: create_pre_key_tables,
+    },
+    Migration {
+        description: "Assign ids to mailbox messages",
+        apply: migrate_mailbox,
+    },
+    Migration {
+        description: "Convert legacy Ed25519 identity keys to X25519",
+        apply: migrate_legacy_identity_keys,
+    },
+    Migration {
+        description: "Record who uploaded attachments",
+        apply: |connection| {
+            connection.execute_batch(
+                "ALTER TABLE attachment ADD COLUMN uploader BLOB REFERENCES device(ik);
+                CREATE INDEX attachment_uploader ON attachment(uploader);",
+            )
+        },
+    },
+];
 
 impl SqliteStorage {
     pub async fn new(connection: tokio_rusqlite::Connection) -> tokio_rusqlite::Result<Self> {
-        info!("Creating SQlite Tables.");
+        info!("Migrating SQlite Tables.");
         connection
             .call(|connection| {
                 connection.pragma_update(None, "journal_mode", "WAL")?;
                 connection.pragma_update(None, "synchronous", "normal")?;
                 connection.pragma_update(None, "foreign_keys", "on")?;
-                connection.execute_batch(
-                    "
-                    BEGIN;
-                    CREATE TABLE IF NOT EXISTS device (
-                        ik BLOB PRIMARY KEY,
-                        spk BLOB NOT NULL,
-                        time INTEGER NOT NULL
-                    );
-                    CREATE TABLE IF NOT EXISTS opk_queue (
-                        opk BLOB PRIMARY KEY,
-                        ik BLOB NOT NULL,
-                        time INTEGER NOT NULL,
-                        FOREIGN KEY(ik) REFERENCES device(ik)
-                    );
-                    CREATE TABLE IF NOT EXISTS mailbox (
-                        message BLOB PRIMARY KEY,
-                        ik BLOB NOT NULL,
-                        time integer NOT NULL,
-                        FOREIGN KEY(ik) REFERENCES device(ik)
-                    );
-                    CREATE TABLE IF NOT EXISTS firebasetoken (
-                        ik STRING PRIMARY KEY,
-                        token STRING NOT NULL,
-                        insertion_time integer NOT NULL,
-                        FOREIGN KEY(ik) REFERENCES device(ik)
-                    );
-                    COMMIT;",
-                )?;
+                migrate(connection, SCHEMA, MIGRATIONS)
+                    .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
                 Ok(())
             })
             .await?;
@@ -64,84 +76,285 @@ impl SqliteStorage {
     }
 }
 
-impl SqliteStorage {
-    /// Add a new identity to the storage.
-    /// Attempts to overwrite an identity key returns an error.
-    /// Updates to the user's signed pre key are allowed.
-    #[instrument(skip(self, ik, spk))]
-    pub async fn add_user(&self, ik: &VerifyingKey, spk: SignedPreKeyProto) -> tonic::Result<()> {
-        let spk = spk.encode_to_vec();
-        let ik = ik.to_bytes();
+fn create_tables(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS device (
+            ik BLOB PRIMARY KEY,
+            spk BLOB NOT NULL,
+            time INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS opk_queue (
+            opk BLOB PRIMARY KEY,
+            ik BLOB NOT NULL,
+            time INTEGER NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        CREATE TABLE IF NOT EXISTS mailbox (
+            message BLOB PRIMARY KEY,
+            ik BLOB NOT NULL,
+            time integer NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        CREATE TABLE IF NOT EXISTS firebasetoken (
+            ik STRING PRIMARY KEY,
+            token STRING NOT NULL,
+            insertion_time integer NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );",
+    )
+}
 
-        let persisted_spk = {
-            let serialized_spk = spk.clone();
-            self.0
-                .call(move |connection| {
-                    connection.execute(
-                        "INSERT OR IGNORE INTO device (ik, spk, time) VALUES ($1, $2, ?3)",
-                        params![ik, serialized_spk, time_now()],
-                    )?;
-                    let persisted_spk: Vec<u8> = connection.query_row(
-                        "SELECT spk FROM device where ik = ?1",
-                        params![ik],
-                        |row| row.get(0),
-                    )?;
-                    Ok(persisted_spk)
-                })
-                .await
-                .map_err(|e| Status::internal(format!("Failed to register user: {e}")))?
+fn create_pre_key_tables(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS last_resort_opk (
+            ik BLOB PRIMARY KEY,
+            id INTEGER NOT NULL,
+            opk BLOB NOT NULL,
+            time INTEGER NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        CREATE TABLE IF NOT EXISTS pre_key_request (
+            requester BLOB NOT NULL,
+            target BLOB NOT NULL,
+            consumed_opk INTEGER NOT NULL,
+            time INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS kem_pre_key (
+            pre_key BLOB PRIMARY KEY,
+            ik BLOB NOT NULL,
+            signed_pre_key BLOB NOT NULL,
+            last_resort INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        CREATE TABLE IF NOT EXISTS mailbox_sequence (
+            ik BLOB PRIMARY KEY,
+            sequence INTEGER NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        CREATE TABLE IF NOT EXISTS server_key (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            key BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS attachment (
+            digest BLOB PRIMARY KEY,
+            blob BLOB NOT NULL,
+            time INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Mailboxes from before messages were acknowledged were keyed by the message itself. Their
+/// messages are given ids and sequence numbers in the order they were received.
+fn migrate_mailbox(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let migrated: bool = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('mailbox') WHERE name = 'id'",
+        [],
+        |row| row.get(0),
+    )?;
+    if migrated {
+        return Ok(());
+    }
+    info!("Assigning ids to mailbox messages.");
+    connection.execute_batch(
+        "
+        ALTER TABLE mailbox RENAME TO legacy_mailbox;
+        CREATE TABLE mailbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            ik BLOB NOT NULL,
+            sequence INTEGER NOT NULL,
+            message BLOB NOT NULL,
+            time integer NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );
+        INSERT INTO mailbox (ik, sequence, message, time)
+            SELECT ik, rowid, message, time FROM legacy_mailbox ORDER BY rowid;
+        INSERT INTO mailbox_sequence (ik, sequence)
+            SELECT ik, MAX(sequence) FROM mailbox GROUP BY ik;
+        DROP TABLE legacy_mailbox;",
+    )
+}
+
+/// One time prekeys uploaded before prekeys had ids can't be referenced by prekey messages, so
+/// they are dropped and clients upload new ones.
+fn migrate_opk_queue(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let migrated: bool = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('opk_queue') WHERE name = 'id'",
+        [],
+        |row| row.get(0),
+    )?;
+    if migrated {
+        return Ok(());
+    }
+    info!("Dropping one time prekeys without ids.");
+    connection.execute_batch(
+        "
+        DROP TABLE opk_queue;
+        CREATE TABLE opk_queue (
+            opk BLOB PRIMARY KEY,
+            id INTEGER NOT NULL,
+            ik BLOB NOT NULL,
+            time INTEGER NOT NULL,
+            FOREIGN KEY(ik) REFERENCES device(ik)
+        );",
+    )
+}
+
+/// Pops the oldest one time KEM pre key of `ik` if `one_time`, falling back to its last resort
+/// KEM pre key, which is not consumed.
+/// Returns the pre key and whether it was a one time pre key.
+fn pop_kem_pre_key(
+    connection: &rusqlite::Connection,
+    ik: &[u8; 32],
+    one_time: bool,
+) -> rusqlite::Result<Option<(SignedKemPreKeyProto, bool)>> {
+    let get =
+        |sql: &str| match connection.query_row(sql, params![ik], |row| row.get::<_, Vec<u8>>(0)) {
+            Ok(pre_key) => Ok(Some(
+                SignedKemPreKeyProto::decode(&*pre_key).expect("We don't persist bad keys."),
+            )),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
         };
-        if spk != persisted_spk {
-            return Err(Status::already_exists(
-                "A user already exists with this key.",
-            ));
+    if one_time {
+        if let Some(pre_key) = get("DELETE FROM kem_pre_key WHERE pre_key = (SELECT pre_key FROM kem_pre_key WHERE ik = ?1 AND NOT last_resort ORDER BY time LIMIT 1) RETURNING signed_pre_key")? {
+            return Ok(Some((pre_key, true)));
         }
-        Ok(())
     }
+    Ok(
+        get("SELECT signed_pre_key FROM kem_pre_key WHERE ik = ?1 AND last_resort")?
+            .map(|pre_key| (pre_key, false)),
+    )
+}
+
+/// Returns true if `spk` was signed by the legacy Ed25519 identity key `ik` the way clients did
+/// before identity keys were X25519 keys.
+fn is_legacy_device(ik: &[u8; 32], spk: &SignedPreKeyProto) -> bool {
+    let (Ok(ik), Ok(signature)) = (
+        VerifyingKey::from_bytes(ik),
+        Signature::from_slice(spk.signature()),
+    ) else {
+        return false;
+    };
+    let mut hasher = Blake2b512::new();
+    hasher.update(1usize.to_be_bytes());
+    hasher.update(spk.pre_key());
+    ik.verify_strict(&hasher.finalize(), &signature).is_ok()
+}
 
-    /// Replaces the signed pre key for a given identity.
-    // TODO(https://github.com/brongan/brongnal/issues/27) -  Implement signed pre key rotation.
-    #[allow(dead_code)]
+/// Devices registered before identity keys were X25519 keys are keyed by their Ed25519 key.
+/// Devices whose signed pre key was signed by an Ed25519 key are rekeyed by the birationally
+/// equivalent X25519 key that their clients now use, along with everything that references them.
+fn migrate_legacy_identity_keys(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let mut legacy = Vec::new();
+    {
+        let mut stmt = connection.prepare("SELECT ik, spk FROM device")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let (Ok(ik), Ok(spk)) = (
+                row.get::<_, [u8; 32]>(0),
+                SignedPreKeyProto::decode(&*row.get::<_, Vec<u8>>(1)?),
+            ) else {
+                continue;
+            };
+            if is_legacy_device(&ik, &spk) {
+                let key = VerifyingKey::from_bytes(&ik).expect("legacy keys were verified");
+                legacy.push((ik, IdentityPublicKey::from_ed25519(&key).to_bytes()));
+            }
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Converting {} legacy identity keys to X25519.",
+        legacy.len()
+    );
+    // The device is rekeyed before the rows that reference it.
+    connection.pragma_update(None, "defer_foreign_keys", "on")?;
+    for (ed25519, x25519) in legacy {
+        for (table, column) in [
+            ("device", "ik"),
+            ("opk_queue", "ik"),
+            ("last_resort_opk", "ik"),
+            ("kem_pre_key", "ik"),
+            ("mailbox", "ik"),
+            ("mailbox_sequence", "ik"),
+            ("firebasetoken", "ik"),
+            ("pre_key_request", "requester"),
+            ("pre_key_request", "target"),
+        ] {
+            connection.execute(
+                &format!("UPDATE {table} SET {column} = ?1 WHERE {column} = ?2"),
+                params![x25519, ed25519],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl BrongnalStore for SqliteStorage {
     #[instrument(skip(self, ik, spk))]
-    pub async fn update_spk(&self, ik: &VerifyingKey, spk: SignedPreKeyProto) -> tonic::Result<()> {
-        let ik_bytes = ik.to_bytes();
+    async fn insert_device(
+        &self,
+        ik: &IdentityPublicKey,
+        spk: SignedPreKeyProto,
+    ) -> tonic::Result<SignedPreKeyProto> {
+        let ik = ik.to_bytes();
 
         self.0
             .call(move |connection| {
-                let spk = spk;
-                // Returns the first row updated so that a missing key results in an error.
-                let _: Vec<u8> = connection.query_row(
-                    "UPDATE device SET spk = ?2 WHERE ik = ?1 RETURNING ik",
-                    params![&ik_bytes, spk.encode_to_vec()],
+                connection.execute(
+                    "INSERT OR IGNORE INTO device (ik, spk, time) VALUES (?1, ?2, ?3)",
+                    params![ik, spk.encode_to_vec(), time_now()],
+                )?;
+                let persisted_spk: Vec<u8> = connection.query_row(
+                    "SELECT spk FROM device where ik = ?1",
+                    params![ik],
                     |row| row.get(0),
                 )?;
-                Ok(())
+                Ok(SignedPreKeyProto::decode(&*persisted_spk).expect("We don't persist bad keys."))
             })
             .await
-            .map_err(|e| {
-                Status::not_found(format!(
-                    "pre key for identity_key={} not found: {e}",
-                    base64.encode(ik)
-                ))
+            .map_err(|e| Status::internal(format!("Failed to register user: {e}")))
+    }
+
+    #[instrument(skip(self, ik, current, spk))]
+    async fn replace_spk(
+        &self,
+        ik: &IdentityPublicKey,
+        current: SignedPreKeyProto,
+        spk: SignedPreKeyProto,
+    ) -> tonic::Result<bool> {
+        let ik = ik.to_bytes();
+
+        let updated = self
+            .0
+            .call(move |connection| {
+                Ok(connection.execute(
+                    "UPDATE device SET spk = ?2, time = ?3 WHERE ik = ?1 AND spk = ?4",
+                    params![ik, spk.encode_to_vec(), time_now(), current.encode_to_vec()],
+                )?)
             })
+            .await
+            .map_err(|e| Status::internal(format!("Failed to update signed pre key: {e}")))?;
+        Ok(updated != 0)
     }
 
-    /// Appends new unburnt one time pre keys for others to message a given identity.
     #[instrument(skip(self, ik, opks), fields(opk_count = opks.len()))]
-    pub async fn add_opks(
-        &self,
-        ik: &VerifyingKey,
-        opks: Vec<X25519PublicKey>,
-    ) -> tonic::Result<()> {
+    async fn add_opks(&self, ik: &IdentityPublicKey, opks: Vec<PreKey>) -> tonic::Result<()> {
         let ik = ik.to_bytes();
 
         self.0
             .call(move |connection| {
                 let mut stmt = connection
-                    .prepare("INSERT INTO opk_queue (ik, opk, time) VALUES (?1, ?2, ?3)")
+                    .prepare("INSERT INTO opk_queue (ik, opk, id, time) VALUES (?1, ?2, ?3, ?4)")
                     .unwrap();
                 for opk in opks {
-                    stmt.execute((ik, opk.to_bytes(), time_now()))?;
+                    stmt.execute((ik, opk.pre_key.to_bytes(), opk.id, time_now()))?;
                 }
                 Ok(())
             })
@@ -149,10 +362,159 @@ impl SqliteStorage {
             .map_err(|_| Status::internal("failed to insert one time key"))
     }
 
-    /// Retrieves the identity key and signed pre key for a given identity.
-    /// A client must first invoke this before messaging a peer.
+    #[instrument(skip(self, ik, pre_key))]
+    async fn set_last_resort_opk(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_key: PreKey,
+    ) -> tonic::Result<()> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT OR REPLACE INTO last_resort_opk (ik, id, opk, time) VALUES (?1, ?2, ?3, ?4)",
+                    params![ik, pre_key.id, pre_key.pre_key.to_bytes(), time_now()],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|_| Status::internal("failed to set last resort pre key"))
+    }
+
     #[instrument(skip(self, ik))]
-    pub async fn get_current_spk(&self, ik: &VerifyingKey) -> tonic::Result<SignedPreKeyProto> {
+    async fn get_last_resort_opk(&self, ik: &IdentityPublicKey) -> tonic::Result<Option<PreKey>> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let key: Option<(u32, [u8; 32])> = match connection.query_row(
+                    "SELECT id, opk FROM last_resort_opk WHERE ik = ?1",
+                    params![ik],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                ) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e),
+                }?;
+                Ok(key.map(|(id, key)| PreKey {
+                    id,
+                    pre_key: X25519PublicKey::from(key),
+                }))
+            })
+            .await
+            .map_err(|e| Status::internal(format!("failed to query for last resort pre_key: {e}")))
+    }
+
+    #[instrument(skip(self, requester, target))]
+    async fn request_one_time_pre_keys(
+        &self,
+        requester: &IdentityPublicKey,
+        target: &IdentityPublicKey,
+        since: u64,
+        max_requests: u32,
+        max_consumed: u32,
+    ) -> tonic::Result<(Option<PreKey>, Option<SignedKemPreKeyProto>, u32)> {
+        let requester = requester.to_bytes();
+        let target = target.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                tx.execute("DELETE FROM pre_key_request WHERE time < ?1", [since])?;
+                let requests: u32 = tx.query_row(
+                    "SELECT COUNT(*) FROM pre_key_request WHERE requester = ?1",
+                    params![requester],
+                    |row| row.get(0),
+                )?;
+                if requests >= max_requests {
+                    return Ok(Err(Status::resource_exhausted("too many pre key requests")));
+                }
+                let consumed: u32 = tx.query_row(
+                    "SELECT COUNT(*) FROM pre_key_request WHERE target = ?1 AND consumed_opk",
+                    params![target],
+                    |row| row.get(0),
+                )?;
+                let opk = if consumed < max_consumed {
+                    match tx.query_row(
+                        "DELETE FROM opk_queue WHERE opk = (SELECT opk FROM opk_queue WHERE ik = ?1 ORDER BY time LIMIT 1) RETURNING id, opk",
+                        params![target],
+                        |row| Ok((row.get(0)?, row.get::<_, [u8; 32]>(1)?)),
+                    ) {
+                        Ok((id, key)) => Some(PreKey {
+                            id,
+                            pre_key: X25519PublicKey::from(key),
+                        }),
+                        Err(Error::QueryReturnedNoRows) => None,
+                        Err(e) => return Err(e.into()),
+                    }
+                } else {
+                    None
+                };
+                let kem_pre_key = pop_kem_pre_key(&tx, &target, consumed < max_consumed)?;
+                let consumed_one_time = opk.is_some()
+                    || kem_pre_key.as_ref().is_some_and(|(_, one_time)| *one_time);
+                tx.execute(
+                    "INSERT INTO pre_key_request (requester, target, consumed_opk, time) VALUES (?1, ?2, ?3, ?4)",
+                    params![requester, target, consumed_one_time, time_now()],
+                )?;
+                tx.commit()?;
+                Ok(Ok((opk, kem_pre_key.map(|(pre_key, _)| pre_key), consumed)))
+            })
+            .await
+            .map_err(|e| Status::internal(format!("failed to request one time pre keys: {e}")))?
+    }
+
+    #[instrument(skip(self, ik, pre_keys), fields(pre_key_count = pre_keys.len()))]
+    async fn add_kem_pre_keys(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_keys: Vec<SignedKemPreKeyProto>,
+    ) -> tonic::Result<()> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let mut stmt = connection.prepare(
+                    "INSERT INTO kem_pre_key (pre_key, ik, signed_pre_key, last_resort, time) VALUES (?1, ?2, ?3, FALSE, ?4)",
+                )?;
+                for pre_key in pre_keys {
+                    stmt.execute((pre_key.pre_key(), ik, pre_key.encode_to_vec(), time_now()))?;
+                }
+                Ok(())
+            })
+            .await
+            .map_err(|_| Status::internal("failed to insert one time KEM pre key"))
+    }
+
+    #[instrument(skip(self, ik, pre_key))]
+    async fn set_last_resort_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_key: SignedKemPreKeyProto,
+    ) -> tonic::Result<()> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                tx.execute(
+                    "DELETE FROM kem_pre_key WHERE ik = ?1 AND last_resort",
+                    params![ik],
+                )?;
+                tx.execute(
+                    "INSERT INTO kem_pre_key (pre_key, ik, signed_pre_key, last_resort, time) VALUES (?1, ?2, ?3, TRUE, ?4)",
+                    params![pre_key.pre_key(), ik, pre_key.encode_to_vec(), time_now()],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|_| Status::internal("failed to set last resort KEM pre key"))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_current_spk(&self, ik: &IdentityPublicKey) -> tonic::Result<SignedPreKeyProto> {
         let ik = ik.to_bytes();
 
         self.0
@@ -169,66 +531,102 @@ impl SqliteStorage {
             .map_err(|_| Status::not_found("user not found"))
     }
 
-    /// Retrieve a one time pre key for a identity key.
     #[instrument(skip(self, ik))]
-    pub async fn pop_opk(&self, ik: &VerifyingKey) -> tonic::Result<Option<X25519PublicKey>> {
+    async fn pop_opk(&self, ik: &IdentityPublicKey) -> tonic::Result<Option<PreKey>> {
         let ik = ik.to_bytes();
 
         self.0
             .call(move |connection| {
-                let key: Option<[u8;32]> = match connection.query_row(
-                        "DELETE FROM opk_queue WHERE opk = ( SELECT opk FROM opk_queue WHERE ik = ?1 ORDER BY time LIMIT 1) RETURNING opk", 
+                let key: Option<(u32, [u8;32])> = match connection.query_row(
+                        "DELETE FROM opk_queue WHERE opk = ( SELECT opk FROM opk_queue WHERE ik = ?1 ORDER BY time LIMIT 1) RETURNING id, opk", 
                         params![ik],
-                        |row| row.get(0)) {
+                        |row| Ok((row.get(0)?, row.get(1)?))) {
                         Ok(value) => Ok(Some(value)),
                         Err(Error::QueryReturnedNoRows) => Ok(None),
                         Err(e) => Err(e),
                     }?;
-                Ok(key.map(X25519PublicKey::from))
+                Ok(key.map(|(id, key)| PreKey { id, pre_key: X25519PublicKey::from(key) }))
                 })
             .await
             .map_err(|e| Status::not_found(format!("failed to query for pre_key: {e}")))
     }
 
-    /// Enqueue a message for a given recipient.
+    #[instrument(skip(self, ik))]
+    async fn pop_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+    ) -> tonic::Result<Option<SignedKemPreKeyProto>> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                let pre_key = pop_kem_pre_key(&tx, &ik, true)?;
+                tx.commit()?;
+                Ok(pre_key.map(|(pre_key, _)| pre_key))
+            })
+            .await
+            .map_err(|e| Status::not_found(format!("failed to query for KEM pre_key: {e}")))
+    }
+
     #[instrument(skip(self, recipient, message))]
-    pub async fn add_message(
+    async fn add_message(
         &self,
-        recipient: &VerifyingKey,
-        message: MessageProto,
-    ) -> tonic::Result<()> {
+        recipient: &IdentityPublicKey,
+        mut message: MessageProto,
+    ) -> tonic::Result<MessageProto> {
         let recipient = recipient.to_bytes();
 
         self.0
             .call(move |connection| {
-                connection.execute(
-                    "INSERT INTO mailbox (message, ik, time) VALUES (?1, ?2, ?3)",
-                    (message.encode_to_vec(), recipient, time_now()),
+                let tx = connection.transaction()?;
+                let sequence: u64 = tx.query_row(
+                    "INSERT INTO mailbox_sequence (ik, sequence) VALUES (?1, 1)
+                     ON CONFLICT(ik) DO UPDATE SET sequence = sequence + 1
+                     RETURNING sequence",
+                    params![recipient],
+                    |row| row.get(0),
                 )?;
-                Ok(())
+                message.id = None;
+                message.sequence = None;
+                tx.execute(
+                    "INSERT INTO mailbox (ik, sequence, message, time) VALUES (?1, ?2, ?3, ?4)",
+                    params![recipient, sequence, message.encode_to_vec(), time_now()],
+                )?;
+                message.id = Some(tx.last_insert_rowid() as u64);
+                message.sequence = Some(sequence);
+                tx.commit()?;
+                Ok(message)
             })
             .await
             .map_err(|e| Status::not_found(format!("Cannot enqueue message for unknown user: {e}")))
     }
 
-    /// Retrieve enqueued messages for a given identity.
     #[instrument(skip(self, recipient))]
-    pub async fn get_messages(&self, recipient: &VerifyingKey) -> tonic::Result<Vec<MessageProto>> {
+    async fn get_messages(
+        &self,
+        recipient: &IdentityPublicKey,
+        after: u64,
+        limit: u32,
+    ) -> tonic::Result<Vec<MessageProto>> {
         let recipient = recipient.to_bytes();
 
         self.0
             .call(move |connection| {
-                let mut stmt =
-                    connection.prepare("DELETE FROM mailbox WHERE ik = ?1 RETURNING message")?;
-                let message_iter = stmt
-                    .query_map(params![recipient], |row| row.get(0))
-                    .unwrap();
+                let mut stmt = connection.prepare(
+                    "SELECT id, sequence, message FROM mailbox WHERE ik = ?1 AND sequence > ?2 ORDER BY sequence LIMIT ?3",
+                )?;
+                let message_iter = stmt.query_map(params![recipient, after, limit], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?;
                 let mut ret = Vec::new();
                 for message in message_iter {
-                    let message: Vec<u8> = message?;
-                    ret.push(
-                        MessageProto::decode(&*message).expect("We don't persist bad messages."),
-                    );
+                    let (id, sequence, message): (u64, u64, Vec<u8>) = message?;
+                    let mut message =
+                        MessageProto::decode(&*message).expect("We don't persist bad messages.");
+                    message.id = Some(id);
+                    message.sequence = Some(sequence);
+                    ret.push(message);
                 }
                 Ok(ret)
             })
@@ -236,8 +634,33 @@ impl SqliteStorage {
             .map_err(|e| Status::internal(format!("Failed to query messages: {e}")))
     }
 
+    #[instrument(skip(self, recipient, ids), fields(ids = ids.len()))]
+    async fn ack_messages(
+        &self,
+        recipient: &IdentityPublicKey,
+        ids: Vec<u64>,
+    ) -> tonic::Result<usize> {
+        let recipient = recipient.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                let mut deleted = 0;
+                {
+                    let mut stmt = tx.prepare("DELETE FROM mailbox WHERE ik = ?1 AND id = ?2")?;
+                    for id in ids {
+                        deleted += stmt.execute(params![recipient, id])?;
+                    }
+                }
+                tx.commit()?;
+                Ok(deleted)
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Failed to acknowledge messages: {e}")))
+    }
+
     #[instrument(skip(self, ik))]
-    pub async fn get_one_time_prekey_count(&self, ik: &VerifyingKey) -> tonic::Result<u32> {
+    async fn get_one_time_prekey_count(&self, ik: &IdentityPublicKey) -> tonic::Result<u32> {
         let ik = ik.to_bytes();
 
         self.0
@@ -256,9 +679,129 @@ impl SqliteStorage {
             .map_err(|_| Status::internal("Failed to query opk count."))
     }
 
-    /// Set the current Firebase Cloud Messaging token for a user.
+    #[instrument(skip(self, ik))]
+    async fn get_one_time_kem_pre_key_count(&self, ik: &IdentityPublicKey) -> tonic::Result<u32> {
+        let ik = ik.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                Ok(connection.query_row(
+                    "SELECT COUNT(*) FROM kem_pre_key WHERE ik = ?1 AND NOT last_resort",
+                    [ik],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|_| Status::internal("Failed to query KEM pre key count."))
+    }
+
+    #[instrument(skip(self, uploader, digest, blob), fields(digest = base64.encode(digest), len = blob.len()))]
+    async fn add_attachment(
+        &self,
+        uploader: &IdentityPublicKey,
+        digest: [u8; 32],
+        blob: Vec<u8>,
+        quota: u64,
+    ) -> tonic::Result<()> {
+        let uploader = uploader.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                let tx = connection.transaction()?;
+                if tx.execute(
+                    "UPDATE attachment SET time = ?2 WHERE digest = ?1",
+                    params![digest, time_now()],
+                )? == 1
+                {
+                    tx.commit()?;
+                    return Ok(Ok(()));
+                }
+                let registered: bool = tx.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM device WHERE ik = ?1)",
+                    [uploader],
+                    |row| row.get(0),
+                )?;
+                if !registered {
+                    return Ok(Err(Status::not_found("device not found")));
+                }
+                let usage: u64 = tx.query_row(
+                    "SELECT COALESCE(SUM(length(blob)), 0) FROM attachment WHERE uploader = ?1",
+                    [uploader],
+                    |row| row.get(0),
+                )?;
+                if usage + blob.len() as u64 > quota {
+                    return Ok(Err(Status::resource_exhausted(format!(
+                        "attachments exceed quota of {quota} bytes"
+                    ))));
+                }
+                tx.execute(
+                    "INSERT INTO attachment (digest, blob, time, uploader) VALUES (?1, ?2, ?3, ?4)",
+                    params![digest, blob, time_now(), uploader],
+                )?;
+                tx.commit()?;
+                Ok(Ok(()))
+            })
+            .await
+            .inspect_err(|e| error!("Failed to store attachment: {e}."))
+            .map_err(|_| Status::internal("Failed to store attachment."))?
+    }
+
+    #[instrument(skip(self, uploader))]
+    async fn get_attachment_usage(&self, uploader: &IdentityPublicKey) -> tonic::Result<u64> {
+        let uploader = uploader.to_bytes();
+
+        self.0
+            .call(move |connection| {
+                Ok(connection.query_row(
+                    "SELECT COALESCE(SUM(length(blob)), 0) FROM attachment WHERE uploader = ?1",
+                    [uploader],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|_| Status::internal("Failed to query attachment usage."))
+    }
+
+    #[instrument(skip(self, digest), fields(digest = base64.encode(digest)))]
+    async fn get_attachment(&self, digest: [u8; 32]) -> tonic::Result<Option<Vec<u8>>> {
+        self.0
+            .call(move |connection| {
+                match connection.query_row(
+                    "SELECT blob FROM attachment WHERE digest = ?1",
+                    params![digest],
+                    |row| row.get(0),
+                ) {
+                    Ok(blob) => Ok(Some(blob)),
+                    Err(Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(tokio_rusqlite::Error::Rusqlite(e)),
+                }
+            })
+            .await
+            .inspect_err(|e| error!("Failed to get attachment: {e}."))
+            .map_err(|_| Status::internal("Failed to get attachment."))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_server_key(&self) -> tonic::Result<IdentityKey> {
+        self.0
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT OR IGNORE INTO server_key (id, key) VALUES (0, ?1)",
+                    params![IdentityKey::generate().to_bytes().as_slice()],
+                )?;
+                let key: [u8; 32] =
+                    connection.query_row("SELECT key FROM server_key WHERE id = 0", [], |row| {
+                        row.get(0)
+                    })?;
+                Ok(IdentityKey::from(key))
+            })
+            .await
+            .inspect_err(|e| error!("Failed to get server key: {e}."))
+            .map_err(|_| Status::internal("Failed to get server key."))
+    }
+
     #[instrument(skip(self, ik, token))]
-    pub async fn set_fcm_token(&self, ik: &VerifyingKey, token: String) -> tonic::Result<()> {
+    async fn set_fcm_token(&self, ik: &IdentityPublicKey, token: String) -> tonic::Result<()> {
         let ik = ik.to_bytes();
         self.0
             .call(move |connection| {
@@ -273,11 +816,10 @@ impl SqliteStorage {
             .map_err(|_| Status::not_found("user not found: {e}"))
     }
 
-    /// Returns the current Firebase Cloud Messaging token for a user.
     #[instrument(skip(self, ik))]
-    pub async fn get_fcm_token(
+    async fn get_fcm_token(
         &self,
-        ik: &VerifyingKey,
+        ik: &IdentityPublicKey,
         max_age: Duration,
     ) -> tonic::Result<Option<String>> {
         let ik = ik.to_bytes();
@@ -300,228 +842,353 @@ impl SqliteStorage {
             .inspect_err(|e| error!("Failed to get Firebase Cloud Messaging token: {e}."))
             .map_err(|_| Status::internal("Failed to get Firebase Cloud Messaging token."))
     }
-}
 
-/// Returns the number of messages deleted.
-pub async fn clean_mailboxes(
-    connection: &tokio_rusqlite::Connection,
-    ttl: Duration,
-) -> tokio_rusqlite::Result<usize> {
-    let expired = time_now() - ttl.as_secs();
-    connection
-        .call(move |connection| {
-            Ok(connection.execute("DELETE FROM mailbox WHERE time < $0", params![expired])?)
-        })
-        .await
+    #[instrument(skip(self))]
+    async fn clean_mailboxes(&self, ttl: Duration) -> tonic::Result<usize> {
+        let expired = time_now() - ttl.as_secs();
+        self.0
+            .call(move |connection| {
+                Ok(connection.execute("DELETE FROM mailbox WHERE time < ?1", params![expired])?)
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Failed to clean mailboxes: {e}")))
+    }
+
+    #[instrument(skip(self))]
+    async fn clean_attachments(&self, ttl: Duration) -> tonic::Result<usize> {
+        let expired = time_now() - ttl.as_secs();
+        self.0
+            .call(move |connection| {
+                Ok(connection
+                    .execute("DELETE FROM attachment WHERE time < ?1", params![expired])?)
+            })
+            .await
+            .map_err(|e| Status::internal(format!("Failed to clean attachments: {e}")))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::persistence::*;
     use anyhow::Result;
-    use chacha20poly1305::aead::OsRng;
     use client::X3DHClient;
-    use ed25519_dalek::SigningKey;
     use tokio_rusqlite::Connection;
-    use tonic::Code;
 
-    #[tokio::test]
-    async fn add_user_get_keys_success() -> Result<()> {
-        let conn = Connection::open_in_memory().await?;
-        let storage = SqliteStorage::new(conn.clone()).await?;
-        let alice = X3DHClient::new(conn).await?;
-        let alice_ik = VerifyingKey::from(&alice.get_ik());
-        let alice_spk: SignedPreKeyProto = alice.get_spk().await.unwrap().into();
-        storage.add_user(&alice_ik, alice_spk.clone()).await?;
-        assert_eq!(storage.get_current_spk(&alice_ik).await?, alice_spk);
+    async fn schema_version(conn: &Connection) -> Result<u32> {
+        Ok(conn
+            .call(|connection| Ok(migrations::version(connection, SCHEMA)?))
+            .await?)
+    }
+
+    /// Registers `ik` with `spk` and enqueues `message` the way the server at `version` would have.
+    fn insert_fixture(
+        connection: &rusqlite::Connection,
+        version: usize,
+        ik: [u8; 32],
+        spk: &SignedPreKeyProto,
+        message: &MessageProto,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "INSERT INTO device (ik, spk, time) VALUES (?1, ?2, 0)",
+            params![ik, spk.encode_to_vec()],
+        )?;
+        if version < 4 {
+            connection.execute(
+                "INSERT INTO mailbox (message, ik, time) VALUES (?1, ?2, ?3)",
+                params![message.encode_to_vec(), ik, time_now()],
+            )?;
+        } else {
+            connection.execute(
+                "INSERT INTO mailbox (ik, sequence, message, time) VALUES (?1, 1, ?2, ?3)",
+                params![ik, message.encode_to_vec(), time_now()],
+            )?;
+            connection.execute(
+                "INSERT INTO mailbox_sequence (ik, sequence) VALUES (?1, 1)",
+                params![ik],
+            )?;
+        }
         Ok(())
     }
 
     #[tokio::test]
-    async fn add_user_idempotent() -> Result<()> {
-        let conn = Connection::open_in_memory().await?;
-        let storage = SqliteStorage::new(conn.clone()).await?;
-        let alice = X3DHClient::new(conn).await?;
-        let alice_ik = VerifyingKey::from(&alice.get_ik());
-        let alice_spk: SignedPreKeyProto = alice.get_spk().await.unwrap().into();
-        storage.add_user(&alice_ik, alice_spk.clone()).await?;
-        storage.add_user(&alice_ik, alice_spk.clone()).await?;
+    async fn upgrade_from_every_version() -> Result<()> {
+        for version in 1..=MIGRATIONS.len() {
+            let conn = Connection::open_in_memory().await?;
+            let bob = X3DHClient::new(conn.clone()).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let spk: SignedPreKeyProto = bob.get_spk().await?.into();
+            let message = MessageProto {
+                ciphertext: Some(b"Hello Bob!".to_vec()),
+                ..Default::default()
+            };
+            {
+                let (spk, message) = (spk.clone(), message.clone());
+                conn.call(move |connection| {
+                    migrate(connection, SCHEMA, &MIGRATIONS[..version])
+                        .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
+                    Ok(insert_fixture(
+                        connection,
+                        version,
+                        bob_ik.to_bytes(),
+                        &spk,
+                        &message,
+                    )?)
+                })
+                .await?;
+            }
+            assert_eq!(schema_version(&conn).await?, version as u32);
 
+            let storage = SqliteStorage::new(conn.clone()).await?;
+            assert_eq!(schema_version(&conn).await?, MIGRATIONS.len() as u32);
+            assert_eq!(storage.get_current_spk(&bob_ik).await?, spk);
+            let messages = storage.get_messages(&bob_ik, 0, u32::MAX).await?;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].ciphertext, message.ciphertext);
+            assert_eq!(messages[0].sequence, Some(1));
+            assert_eq!(
+                storage
+                    .add_message(&bob_ik, MessageProto::default())
+                    .await?
+                    .sequence,
+                Some(2)
+            );
+            let keys = bob.create_opks(1).await?.pre_keys;
+            storage.add_opks(&bob_ik, keys.clone()).await?;
+            assert_eq!(storage.pop_opk(&bob_ik).await?, Some(keys[0]));
+        }
         Ok(())
     }
 
     #[tokio::test]
-    async fn add_user_overwrite_fails() -> Result<()> {
-        let conn = Connection::open_in_memory().await?;
-        let storage = SqliteStorage::new(conn.clone()).await?;
-        let alice = X3DHClient::new(conn).await?;
-        let alice_ik = VerifyingKey::from(&alice.get_ik());
-        let alice_spk: SignedPreKeyProto = alice.get_spk().await.unwrap().into();
-        storage.add_user(&alice_ik, alice_spk.clone()).await?;
+    async fn migrate_legacy_identity_keys() -> Result<()> {
+        use chacha20poly1305::aead::OsRng;
+        use ed25519_dalek::{Signer, SigningKey};
+        use protocol::bundle::create_signed_pre_key;
 
-        let alice2 = X3DHClient::new(Connection::open_in_memory().await?).await?;
-        let alice2_spk: SignedPreKeyProto = alice2.get_spk().await.unwrap().into();
-
-        assert_eq!(
-            storage
-                .add_user(&alice_ik, alice2_spk)
-                .await
-                .err()
-                .map(|e| e.code()),
-            Some(Code::AlreadyExists)
-        );
+        let legacy = SigningKey::generate(&mut OsRng);
+        let legacy_ik = legacy.verifying_key().to_bytes();
+        let pre_key = X25519PublicKey::from(&x25519_dalek::StaticSecret::random()).to_bytes();
+        let mut hasher = Blake2b512::new();
+        hasher.update(1usize.to_be_bytes());
+        hasher.update(pre_key);
+        let legacy_spk = SignedPreKeyProto {
+            pre_key: Some(pre_key.to_vec()),
+            signature: Some(legacy.sign(&hasher.finalize()).to_vec()),
+            ..Default::default()
+        };
+        let message = MessageProto {
+            ciphertext: Some(b"Hello Bob!".to_vec()),
+            ..Default::default()
+        };
 
-        Ok(())
-    }
+        let conn = Connection::open_in_memory().await?;
+        {
+            let message = message.clone();
+            conn.call(move |connection| {
+                migrate(connection, SCHEMA, &MIGRATIONS[..1])
+                    .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
+                connection.execute(
+                    "INSERT INTO device (ik, spk, time) VALUES (?1, ?2, 0)",
+                    params![legacy_ik, legacy_spk.encode_to_vec()],
+                )?;
+                connection.execute(
+                    "INSERT INTO mailbox (message, ik, time) VALUES (?1, ?2, ?3)",
+                    params![message.encode_to_vec(), legacy_ik, time_now()],
+                )?;
+                connection.execute(
+                    "INSERT INTO firebasetoken (ik, token, insertion_time) VALUES (?1, 'token', ?2)",
+                    params![legacy_ik, time_now()],
+                )?;
+                Ok(())
+            })
+            .await?;
+        }
 
-    #[tokio::test]
-    async fn get_keys_not_found() -> Result<()> {
-        let identity_key = SigningKey::generate(&mut OsRng);
-        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let storage = SqliteStorage::new(conn).await?;
+        let bob = IdentityKey::from_ed25519(&legacy);
+        let bob_ik = bob.public_key();
+        let messages = storage.get_messages(&bob_ik, 0, u32::MAX).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].ciphertext, message.ciphertext);
         assert_eq!(
             storage
-                .get_current_spk(&VerifyingKey::from(&identity_key))
-                .await
-                .err()
-                .map(|e| e.code()),
-            Some(Code::NotFound)
+                .get_fcm_token(&bob_ik, Duration::from_secs(60))
+                .await?,
+            Some("token".to_owned())
         );
-        Ok(())
-    }
 
-    #[tokio::test]
-    async fn pop_empty_opks_none() -> Result<()> {
-        let identity_key = SigningKey::generate(&mut OsRng);
-        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
-        assert_eq!(
-            storage.pop_opk(&VerifyingKey::from(&identity_key)).await?,
-            None
-        );
+        // The client registers its X25519 signed pre key once it has migrated its identity key.
+        let (_, spk) = create_signed_pre_key(&bob, 1, time_now());
+        storage.add_user(&bob_ik, spk.clone().into()).await?;
+        assert_eq!(storage.get_current_spk(&bob_ik).await?, spk.into());
         Ok(())
     }
 
     #[tokio::test]
-    async fn retrieve_opk() -> Result<()> {
+    async fn migrate_opk_queue_drops_keys_without_ids() -> Result<()> {
         let conn = Connection::open_in_memory().await?;
+        conn.call(|connection| {
+            connection.execute_batch(
+                "CREATE TABLE opk_queue (
+                    opk BLOB PRIMARY KEY,
+                    ik BLOB NOT NULL,
+                    time INTEGER NOT NULL
+                );
+                INSERT INTO opk_queue (opk, ik, time) VALUES (x'01', x'02', 0);",
+            )?;
+            Ok(())
+        })
+        .await?;
         let storage = SqliteStorage::new(conn.clone()).await?;
-        let bob = X3DHClient::new(conn).await?;
-        let bob_ik = VerifyingKey::from(&bob.get_ik());
-        let keys = bob.create_opks(1).await?.pre_keys;
+        assert_eq!(schema_version(&conn).await?, MIGRATIONS.len() as u32);
+        let count: u32 = conn
+            .call(|connection| {
+                Ok(connection.query_row("SELECT COUNT(*) FROM opk_queue", [], |row| row.get(0))?)
+            })
+            .await?;
+        assert_eq!(count, 0);
+
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let bob_ik = bob.get_ik().public_key();
         storage
             .add_user(&bob_ik, bob.get_spk().await?.into())
             .await?;
+        let keys = bob.create_opks(1).await?.pre_keys;
         storage.add_opks(&bob_ik, keys.clone()).await?;
+
+        // Keys with ids survive reopening the database.
+        let storage = SqliteStorage::new(conn).await?;
+        assert_eq!(storage.get_one_time_prekey_count(&bob_ik).await?, 1);
         assert_eq!(storage.pop_opk(&bob_ik).await?, Some(keys[0]));
-        assert_eq!(storage.pop_opk(&bob_ik).await?, None);
         Ok(())
     }
 
     #[tokio::test]
-    async fn updating_spk_user_not_found() -> Result<()> {
-        let identity_key = VerifyingKey::from(&SigningKey::generate(&mut OsRng));
-        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+    async fn migrate_mailbox_assigns_ids() -> Result<()> {
+        let conn = Connection::open_in_memory().await?;
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let bob_ik = bob.get_ik().public_key();
+        let first = MessageProto {
+            ciphertext: Some(b"first".to_vec()),
+            ..Default::default()
+        };
+        let second = MessageProto {
+            ciphertext: Some(b"second".to_vec()),
+            ..Default::default()
+        };
+        let (first_blob, second_blob) = (first.encode_to_vec(), second.encode_to_vec());
+        conn.call(move |connection| {
+            connection.execute_batch(
+                "CREATE TABLE device (
+                    ik BLOB PRIMARY KEY,
+                    spk BLOB NOT NULL,
+                    time INTEGER NOT NULL
+                );
+                CREATE TABLE mailbox (
+                    message BLOB PRIMARY KEY,
+                    ik BLOB NOT NULL,
+                    time integer NOT NULL,
+                    FOREIGN KEY(ik) REFERENCES device(ik)
+                );",
+            )?;
+            connection.execute(
+                "INSERT INTO device (ik, spk, time) VALUES (?1, x'00', 0)",
+                params![bob_ik.to_bytes()],
+            )?;
+            for message in [first_blob, second_blob] {
+                connection.execute(
+                    "INSERT INTO mailbox (message, ik, time) VALUES (?1, ?2, 0)",
+                    params![message, bob_ik.to_bytes()],
+                )?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        let storage = SqliteStorage::new(conn.clone()).await?;
+        assert_eq!(schema_version(&conn).await?, MIGRATIONS.len() as u32);
+        let messages = storage.get_messages(&bob_ik, 0, u32::MAX).await?;
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| (message.ciphertext.clone(), message.sequence))
+                .collect::<Vec<_>>(),
+            vec![(first.ciphertext, Some(1)), (second.ciphertext, Some(2))]
+        );
         assert_eq!(
             storage
-                .update_spk(&identity_key, SignedPreKeyProto::default())
-                .await
-                .err()
-                .map(|e| e.code()),
-            Some(Code::NotFound)
+                .add_message(&bob_ik, MessageProto::default())
+                .await?
+                .sequence,
+            Some(3)
         );
         Ok(())
     }
 
     #[tokio::test]
-    async fn update_spk_success() -> Result<()> {
+    async fn server_key_persists() -> Result<()> {
         let conn = Connection::open_in_memory().await?;
         let storage = SqliteStorage::new(conn.clone()).await?;
-        let bob = X3DHClient::new(conn).await?;
-        let bob_ik = VerifyingKey::from(&bob.get_ik());
-        let bob_spk: SignedPreKeyProto = bob.get_spk().await.unwrap().into();
-        storage.add_user(&bob_ik, bob_spk.clone()).await?;
-
-        // Create a different spk and overwrite it.
-        let new_spk = SignedPreKeyProto {
-            pre_key: Some(bob.create_opks(1).await?.pre_keys[0].to_bytes().to_vec()),
-            signature: bob_spk.signature.clone(),
-        };
-        storage.update_spk(&bob_ik, new_spk.clone()).await?;
-
-        assert_eq!(storage.get_current_spk(&bob_ik).await?, new_spk);
-        Ok(())
-    }
-
-    #[tokio::test]
-    async fn add_message_unknown_user() -> Result<()> {
-        let identity_key = SigningKey::generate(&mut OsRng);
-        let storage = SqliteStorage::new(Connection::open_in_memory().await?).await?;
+        let server_key = storage.get_server_key().await?;
+        assert_eq!(storage.get_server_key().await?, server_key);
         assert_eq!(
-            storage
-                .add_message(&VerifyingKey::from(&identity_key), MessageProto::default())
-                .await
-                .err()
-                .map(|e| e.code()),
-            Some(Code::NotFound)
+            SqliteStorage::new(conn).await?.get_server_key().await?,
+            server_key
         );
         Ok(())
     }
 
     #[tokio::test]
-    async fn add_get_message() -> Result<()> {
+    async fn add_get_clean_attachment() -> Result<()> {
         let conn = Connection::open_in_memory().await?;
         let storage = SqliteStorage::new(conn.clone()).await?;
-        let bob = X3DHClient::new(conn).await?;
-        let bob_ik = VerifyingKey::from(&bob.get_ik());
-        let bob_spk: protocol::x3dh::SignedPreKey = bob.get_spk().await.unwrap();
-        storage.add_user(&bob_ik, bob_spk.clone().into()).await?;
-
-        let message_proto = MessageProto {
-            sender_identity_key: Some(b"alice identity key".to_vec()),
-            ephemeral_key: Some(b"alice ephemeral key".to_vec()),
-            pre_key: Some(b"bob pre key".to_vec()),
-            one_time_key: Some(b"bob one time key".to_vec()),
-            ciphertext: Some(b"ciphertext".to_vec()),
-        };
-        storage.add_message(&bob_ik, message_proto.clone()).await?;
-        assert_eq!(storage.get_messages(&bob_ik).await?, vec![message_proto]);
-
-        Ok(())
-    }
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let bob_ik = bob.get_ik().public_key();
+        storage
+            .add_user(&bob_ik, bob.get_spk().await?.into())
+            .await?;
+        let digest = [1u8; 32];
+        assert_eq!(storage.get_attachment(digest).await?, None);
 
-    #[tokio::test]
-    async fn set_fcm_token_user_not_found() -> Result<()> {
-        let ik = SigningKey::generate(&mut OsRng);
-        let token = String::from("abcd123");
-        let conn = Connection::open_in_memory().await?;
-        let storage = SqliteStorage::new(conn).await?;
+        storage
+            .add_attachment(&bob_ik, digest, b"blob".to_vec(), 4)
+            .await?;
+        storage
+            .add_attachment(&bob_ik, digest, b"blob".to_vec(), 4)
+            .await?;
         assert_eq!(
-            storage
-                .set_fcm_token(&ik.verifying_key(), token.clone())
-                .await
-                .err()
-                .map(|e| e.code()),
-            Some(Code::NotFound)
+            storage.get_attachment(digest).await?,
+            Some(b"blob".to_vec())
         );
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(storage.clean_attachments(ttl).await?, 0);
+        conn.call(|connection| Ok(connection.execute("UPDATE attachment SET time = 0", [])?))
+            .await?;
+        assert_eq!(storage.clean_attachments(ttl).await?, 1);
+        assert_eq!(storage.get_attachment(digest).await?, None);
         Ok(())
     }
 
     #[tokio::test]
-    async fn set_get_fcm_token() -> Result<()> {
+    async fn clean_expired_messages() -> Result<()> {
         let conn = Connection::open_in_memory().await?;
         let storage = SqliteStorage::new(conn.clone()).await?;
-        let bob = X3DHClient::new(conn).await?;
-        let bob_ik = VerifyingKey::from(&bob.get_ik());
-        let bob_spk: protocol::x3dh::SignedPreKey = bob.get_spk().await.unwrap();
-        storage.add_user(&bob_ik, bob_spk.into()).await?;
+        let bob = X3DHClient::new(conn.clone()).await?;
+        let bob_ik = bob.get_ik().public_key();
+        storage
+            .add_user(&bob_ik, bob.get_spk().await?.into())
+            .await?;
+        storage
+            .add_message(&bob_ik, MessageProto::default())
+            .await?;
 
-        let token = String::from("abcd123");
-        storage.set_fcm_token(&bob_ik, token.clone()).await?;
-        assert_eq!(
-            storage
-                .get_fcm_token(&bob_ik, Duration::new(2000, 0))
-                .await?,
-            Some(token)
-        );
+        let ttl = Duration::from_secs(60);
+        assert_eq!(storage.clean_mailboxes(ttl).await?, 0);
+        conn.call(|connection| Ok(connection.execute("UPDATE mailbox SET time = 0", [])?))
+            .await?;
+        assert_eq!(storage.clean_mailboxes(ttl).await?, 1);
+        assert!(storage.get_messages(&bob_ik, 0, u32::MAX).await?.is_empty());
         Ok(())
     }
 }
@@ -0,0 +1,799 @@
+use crate::store::BrongnalStore;
+use crate::time_now;
+use deadpool_postgres::{
+    BuildError, Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod,
+};
+use prost::Message;
+use proto::service::Message as MessageProto;
+use proto::service::SignedKemPreKey as SignedKemPreKeyProto;
+use proto::service::SignedPreKey as SignedPreKeyProto;
+use protocol::x3dh::PreKey;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
+use std::time::Duration;
+use thiserror::Error;
+use tokio_postgres::NoTls;
+use tonic::{Result, Status};
+use tracing::{error, info, instrument};
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+/// Serializes migrations between servers that start at the same time.
+const SCHEMA_LOCK: i64 = 0x6272_6f6e_676e_616c;
+/// The name the server's schema is versioned by, as in `SqliteStorage`.
+const SCHEMA: &str = "server";
+
+/// A change to the schema. The version a migration leads to is its position in `MIGRATIONS` plus
+/// one.
+struct Migration {
+    description: &'static str,
+    /// Runs inside of a transaction, so it must not begin or commit one.
+    sql: &'static str,
+}
+
+/// The migrations of the server's schema, in order. The first migration creates the schema from
+/// before it was versioned, so its statements check whether they are needed.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create tables",
+        sql: "
+            CREATE TABLE IF NOT EXISTS device (
+                ik BYTEA PRIMARY KEY,
+                spk BYTEA NOT NULL,
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS opk_queue (
+                opk BYTEA PRIMARY KEY,
+                id BIGINT NOT NULL,
+                ik BYTEA NOT NULL REFERENCES device(ik),
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS last_resort_opk (
+                ik BYTEA PRIMARY KEY REFERENCES device(ik),
+                id BIGINT NOT NULL,
+                opk BYTEA NOT NULL,
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS pre_key_request (
+                requester BYTEA NOT NULL,
+                target BYTEA NOT NULL,
+                consumed_opk BOOLEAN NOT NULL,
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS kem_pre_key (
+                pre_key BYTEA PRIMARY KEY,
+                ik BYTEA NOT NULL REFERENCES device(ik),
+                signed_pre_key BYTEA NOT NULL,
+                last_resort BOOLEAN NOT NULL,
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS mailbox (
+                id BIGSERIAL PRIMARY KEY,
+                ik BYTEA NOT NULL REFERENCES device(ik),
+                sequence BIGINT NOT NULL,
+                message BYTEA NOT NULL,
+                time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS mailbox_sequence (
+                ik BYTEA PRIMARY KEY REFERENCES device(ik),
+                sequence BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS firebasetoken (
+                ik BYTEA PRIMARY KEY REFERENCES device(ik),
+                token TEXT NOT NULL,
+                insertion_time BIGINT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS server_key (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                key BYTEA NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS attachment (
+                digest BYTEA PRIMARY KEY,
+                blob BYTEA NOT NULL,
+                time BIGINT NOT NULL
+            );",
+    },
+    Migration {
+        description: "Record who uploaded attachments",
+        sql: "ALTER TABLE attachment ADD COLUMN IF NOT EXISTS uploader BYTEA REFERENCES device(ik);
+            CREATE INDEX IF NOT EXISTS attachment_uploader ON attachment(uploader);",
+    },
+];
+
+/// Applies the migrations that haven't been applied yet, each with the version it leads to in one
+/// transaction, like `migrations::migrate`.
+async fn migrate(mut client: Object) -> Result<u32, ConnectError> {
+    let latest = MIGRATIONS.len() as u32;
+    loop {
+        let tx = client.transaction().await?;
+        tx.batch_execute(&format!(
+            "SELECT pg_advisory_xact_lock({SCHEMA_LOCK});
+            CREATE TABLE IF NOT EXISTS schema_version (
+                schema TEXT PRIMARY KEY,
+                version INTEGER NOT NULL
+            );"
+        ))
+        .await?;
+        let version = tx
+            .query_opt(
+                "SELECT version FROM schema_version WHERE schema = $1",
+                &[&SCHEMA],
+            )
+            .await?
+            .map_or(0, |row| row.get::<_, i32>(0) as u32);
+        if version > latest {
+            return Err(ConnectError::UnknownVersion { version, latest });
+        }
+        if version == latest {
+            return Ok(version);
+        }
+        let migration = &MIGRATIONS[version as usize];
+        info!(
+            "Migrating PostgreSQL schema to version {}: {}.",
+            version + 1,
+            migration.description
+        );
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_version (schema, version) VALUES ($1, $2)
+            ON CONFLICT (schema) DO UPDATE SET version = excluded.version",
+            &[&SCHEMA, &(version as i32 + 1)],
+        )
+        .await?;
+        tx.commit().await?;
+    }
+}
+/// The most connections that a server opens to the database.
+const MAX_CONNECTIONS: usize = 16;
+
+/// `time_now` as a BIGINT, since Postgres has no unsigned integers.
+fn pg_time_now() -> i64 {
+    time_now() as i64
+}
+
+fn to_pre_key(id: i64, pre_key: Vec<u8>) -> PreKey {
+    let pre_key: [u8; 32] = pre_key.try_into().expect("We don't persist bad keys.");
+    PreKey {
+        id: id as u32,
+        pre_key: X25519PublicKey::from(pre_key),
+    }
+}
+
+/// Pops the oldest one time KEM pre key of `ik` if `one_time`, falling back to its last resort
+/// KEM pre key, which is not consumed.
+/// Returns the pre key and whether it was a one time pre key.
+async fn pop_kem_pre_key(
+    tx: &tokio_postgres::Transaction<'_>,
+    ik: &[u8],
+    one_time: bool,
+) -> Result<Option<(SignedKemPreKeyProto, bool)>, tokio_postgres::Error> {
+    let decode = |row: tokio_postgres::Row| {
+        SignedKemPreKeyProto::decode(row.get::<_, &[u8]>(0)).expect("We don't persist bad keys.")
+    };
+    if one_time {
+        if let Some(row) = tx
+            .query_opt(
+                "DELETE FROM kem_pre_key WHERE pre_key = (SELECT pre_key FROM kem_pre_key WHERE ik = $1 AND NOT last_resort ORDER BY time LIMIT 1) RETURNING signed_pre_key",
+                &[&ik],
+            )
+            .await?
+        {
+            return Ok(Some((decode(row), true)));
+        }
+    }
+    Ok(tx
+        .query_opt(
+            "SELECT signed_pre_key FROM kem_pre_key WHERE ik = $1 AND last_resort",
+            &[&ik],
+        )
+        .await?
+        .map(|row| (decode(row), false)))
+}
+
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("postgres error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("failed to build connection pool: {0}")]
+    Build(#[from] BuildError),
+    #[error("failed to get connection from pool: {0}")]
+    Pool(#[from] PoolError),
+    #[error("schema is at version {version} but only {latest} migrations are known")]
+    UnknownVersion { version: u32, latest: u32 },
+}
+
+/// A store backed by PostgreSQL, for servers that run on more than one machine.
+/// It has the same tables as `SqliteStorage`.
+pub struct PostgresStorage(Pool);
+
+impl PostgresStorage {
+    /// Connects to the database at `url`, for example `postgres://user@localhost/brongnal`.
+    /// Requests share a pool of up to `MAX_CONNECTIONS` connections.
+    pub async fn connect(url: &str) -> Result<Self, ConnectError> {
+        let manager = Manager::from_config(
+            url.parse()?,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager).max_size(MAX_CONNECTIONS).build()?;
+        migrate(pool.get().await?).await?;
+        Ok(PostgresStorage(pool))
+    }
+
+    async fn client(&self) -> Result<Object> {
+        self.0
+            .get()
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to connect to PostgreSQL: {e}")))
+    }
+}
+
+#[tonic::async_trait]
+impl BrongnalStore for PostgresStorage {
+    #[instrument(skip(self, ik, spk))]
+    async fn insert_device(
+        &self,
+        ik: &IdentityPublicKey,
+        spk: SignedPreKeyProto,
+    ) -> Result<SignedPreKeyProto> {
+        let client = self.client().await?;
+        let ik = ik.as_ref();
+
+        async {
+            client
+                .execute(
+                    "INSERT INTO device (ik, spk, time) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                    &[&ik, &spk.encode_to_vec(), &pg_time_now()],
+                )
+                .await?;
+            client
+                .query_one("SELECT spk FROM device WHERE ik = $1", &[&ik])
+                .await
+        }
+        .await
+        .map(|row| {
+            SignedPreKeyProto::decode(row.get::<_, &[u8]>(0)).expect("We don't persist bad keys.")
+        })
+        .map_err(|e| Status::internal(format!("Failed to register user: {e}")))
+    }
+
+    #[instrument(skip(self, ik, current, spk))]
+    async fn replace_spk(
+        &self,
+        ik: &IdentityPublicKey,
+        current: SignedPreKeyProto,
+        spk: SignedPreKeyProto,
+    ) -> Result<bool> {
+        let client = self.client().await?;
+
+        let updated = client
+            .execute(
+                "UPDATE device SET spk = $2, time = $3 WHERE ik = $1 AND spk = $4",
+                &[
+                    &ik.as_ref(),
+                    &spk.encode_to_vec(),
+                    &pg_time_now(),
+                    &current.encode_to_vec(),
+                ],
+            )
+            .await
+            .map_err(|e| Status::internal(format!("Failed to update signed pre key: {e}")))?;
+        Ok(updated != 0)
+    }
+
+    #[instrument(skip(self, ik, opks), fields(opk_count = opks.len()))]
+    async fn add_opks(&self, ik: &IdentityPublicKey, opks: Vec<PreKey>) -> Result<()> {
+        let mut client = self.client().await?;
+        let ik = ik.as_ref();
+
+        async {
+            let tx = client.transaction().await?;
+            let stmt = tx
+                .prepare("INSERT INTO opk_queue (ik, opk, id, time) VALUES ($1, $2, $3, $4)")
+                .await?;
+            for opk in opks {
+                tx.execute(
+                    &stmt,
+                    &[
+                        &ik,
+                        &opk.pre_key.as_bytes().as_slice(),
+                        &i64::from(opk.id),
+                        &pg_time_now(),
+                    ],
+                )
+                .await?;
+            }
+            tx.commit().await
+        }
+        .await
+        .map_err(|_| Status::internal("failed to insert one time key"))
+    }
+
+    #[instrument(skip(self, ik, pre_key))]
+    async fn set_last_resort_opk(&self, ik: &IdentityPublicKey, pre_key: PreKey) -> Result<()> {
+        let client = self.client().await?;
+
+        client
+            .execute(
+                "INSERT INTO last_resort_opk (ik, id, opk, time) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (ik) DO UPDATE SET id = excluded.id, opk = excluded.opk, time = excluded.time",
+                &[
+                    &ik.as_ref(),
+                    &i64::from(pre_key.id),
+                    &pre_key.pre_key.as_bytes().as_slice(),
+                    &pg_time_now(),
+                ],
+            )
+            .await
+            .map(|_| ())
+            .map_err(|_| Status::internal("failed to set last resort pre key"))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_last_resort_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>> {
+        let client = self.client().await?;
+
+        client
+            .query_opt(
+                "SELECT id, opk FROM last_resort_opk WHERE ik = $1",
+                &[&ik.as_ref()],
+            )
+            .await
+            .map(|row| row.map(|row| to_pre_key(row.get(0), row.get(1))))
+            .map_err(|e| Status::internal(format!("failed to query for last resort pre_key: {e}")))
+    }
+
+    #[instrument(skip(self, requester, target))]
+    async fn request_one_time_pre_keys(
+        &self,
+        requester: &IdentityPublicKey,
+        target: &IdentityPublicKey,
+        since: u64,
+        max_requests: u32,
+        max_consumed: u32,
+    ) -> Result<(Option<PreKey>, Option<SignedKemPreKeyProto>, u32)> {
+        let mut client = self.client().await?;
+        let requester = requester.as_ref();
+        let target = target.as_ref();
+
+        async {
+            let tx = client.transaction().await?;
+            // Locking the devices serializes concurrent requests by the requester or for the
+            // target, so that they can't all pass the limits before any is recorded.
+            tx.execute(
+                "SELECT 1 FROM device WHERE ik = $1 OR ik = $2 ORDER BY ik FOR UPDATE",
+                &[&requester, &target],
+            )
+            .await?;
+            tx.execute(
+                "DELETE FROM pre_key_request WHERE time < $1",
+                &[&(since as i64)],
+            )
+            .await?;
+            let row = tx
+                .query_one(
+                    "SELECT
+                        COUNT(*) FILTER (WHERE requester = $1),
+                        COUNT(*) FILTER (WHERE target = $2 AND consumed_opk)
+                    FROM pre_key_request",
+                    &[&requester, &target],
+                )
+                .await?;
+            let requests = row.get::<_, i64>(0) as u32;
+            let consumed = row.get::<_, i64>(1) as u32;
+            if requests >= max_requests {
+                return Ok(Err(Status::resource_exhausted("too many pre key requests")));
+            }
+            let opk = if consumed < max_consumed {
+                tx.query_opt(
+                    "DELETE FROM opk_queue WHERE opk = (SELECT opk FROM opk_queue WHERE ik = $1 ORDER BY time LIMIT 1) RETURNING id, opk",
+                    &[&target],
+                )
+                .await?
+                .map(|row| to_pre_key(row.get(0), row.get(1)))
+            } else {
+                None
+            };
+            let kem_pre_key = pop_kem_pre_key(&tx, target, consumed < max_consumed).await?;
+            let consumed_one_time =
+                opk.is_some() || kem_pre_key.as_ref().is_some_and(|(_, one_time)| *one_time);
+            tx.execute(
+                "INSERT INTO pre_key_request (requester, target, consumed_opk, time) VALUES ($1, $2, $3, $4)",
+                &[&requester, &target, &consumed_one_time, &pg_time_now()],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(Ok((opk, kem_pre_key.map(|(pre_key, _)| pre_key), consumed)))
+        }
+        .await
+        .map_err(|e: tokio_postgres::Error| {
+            Status::internal(format!("failed to request one time pre keys: {e}"))
+        })?
+    }
+
+    #[instrument(skip(self, ik, pre_keys), fields(pre_key_count = pre_keys.len()))]
+    async fn add_kem_pre_keys(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_keys: Vec<SignedKemPreKeyProto>,
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let ik = ik.as_ref();
+
+        async {
+            let tx = client.transaction().await?;
+            let stmt = tx
+                .prepare(
+                    "INSERT INTO kem_pre_key (pre_key, ik, signed_pre_key, last_resort, time) VALUES ($1, $2, $3, FALSE, $4)",
+                )
+                .await?;
+            for pre_key in pre_keys {
+                tx.execute(
+                    &stmt,
+                    &[&pre_key.pre_key(), &ik, &pre_key.encode_to_vec(), &pg_time_now()],
+                )
+                .await?;
+            }
+            tx.commit().await
+        }
+        .await
+        .map_err(|_| Status::internal("failed to insert one time KEM pre key"))
+    }
+
+    #[instrument(skip(self, ik, pre_key))]
+    async fn set_last_resort_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_key: SignedKemPreKeyProto,
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let ik = ik.as_ref();
+
+        async {
+            let tx = client.transaction().await?;
+            tx.execute(
+                "DELETE FROM kem_pre_key WHERE ik = $1 AND last_resort",
+                &[&ik],
+            )
+            .await?;
+            tx.execute(
+                "INSERT INTO kem_pre_key (pre_key, ik, signed_pre_key, last_resort, time) VALUES ($1, $2, $3, TRUE, $4)",
+                &[&pre_key.pre_key(), &ik, &pre_key.encode_to_vec(), &pg_time_now()],
+            )
+            .await?;
+            tx.commit().await
+        }
+        .await
+        .map_err(|_| Status::internal("failed to set last resort KEM pre key"))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_current_spk(&self, ik: &IdentityPublicKey) -> Result<SignedPreKeyProto> {
+        let client = self.client().await?;
+
+        match client
+            .query_opt("SELECT spk FROM device WHERE ik = $1", &[&ik.as_ref()])
+            .await
+        {
+            Ok(Some(row)) => Ok(SignedPreKeyProto::decode(row.get::<_, &[u8]>(0))
+                .expect("We don't persist bad keys.")),
+            _ => Err(Status::not_found("user not found")),
+        }
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn pop_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>> {
+        let client = self.client().await?;
+
+        client
+            .query_opt(
+                "DELETE FROM opk_queue WHERE opk = (SELECT opk FROM opk_queue WHERE ik = $1 ORDER BY time LIMIT 1) RETURNING id, opk",
+                &[&ik.as_ref()],
+            )
+            .await
+            .map(|row| row.map(|row| to_pre_key(row.get(0), row.get(1))))
+            .map_err(|e| Status::not_found(format!("failed to query for pre_key: {e}")))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn pop_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+    ) -> Result<Option<SignedKemPreKeyProto>> {
+        let mut client = self.client().await?;
+        let ik = ik.as_ref();
+
+        let pre_key = async {
+            let tx = client.transaction().await?;
+            let pre_key = pop_kem_pre_key(&tx, ik, true).await?;
+            tx.commit().await?;
+            Ok::<_, tokio_postgres::Error>(pre_key)
+        }
+        .await
+        .map_err(|e| Status::not_found(format!("failed to query for KEM pre_key: {e}")))?;
+        Ok(pre_key.map(|(pre_key, _)| pre_key))
+    }
+
+    #[instrument(skip(self, recipient, message))]
+    async fn add_message(
+        &self,
+        recipient: &IdentityPublicKey,
+        mut message: MessageProto,
+    ) -> Result<MessageProto> {
+        let mut client = self.client().await?;
+        let recipient = recipient.as_ref();
+        message.id = None;
+        message.sequence = None;
+        let encoded = message.encode_to_vec();
+
+        let (id, sequence) = async {
+            let tx = client.transaction().await?;
+            let sequence: i64 = tx
+                .query_one(
+                    "INSERT INTO mailbox_sequence (ik, sequence) VALUES ($1, 1)
+                    ON CONFLICT (ik) DO UPDATE SET sequence = mailbox_sequence.sequence + 1
+                    RETURNING sequence",
+                    &[&recipient],
+                )
+                .await?
+                .get(0);
+            let id: i64 = tx
+                .query_one(
+                    "INSERT INTO mailbox (ik, sequence, message, time) VALUES ($1, $2, $3, $4) RETURNING id",
+                    &[&recipient, &sequence, &encoded, &pg_time_now()],
+                )
+                .await?
+                .get(0);
+            tx.commit().await?;
+            Ok::<_, tokio_postgres::Error>((id, sequence))
+        }
+        .await
+        .map_err(|e| Status::not_found(format!("Cannot enqueue message for unknown user: {e}")))?;
+        message.id = Some(id as u64);
+        message.sequence = Some(sequence as u64);
+        Ok(message)
+    }
+
+    #[instrument(skip(self, recipient))]
+    async fn get_messages(
+        &self,
+        recipient: &IdentityPublicKey,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<MessageProto>> {
+        let client = self.client().await?;
+
+        let rows = client
+            .query(
+                "SELECT id, sequence, message FROM mailbox WHERE ik = $1 AND sequence > $2 ORDER BY sequence LIMIT $3",
+                &[&recipient.as_ref(), &(after as i64), &i64::from(limit)],
+            )
+            .await
+            .map_err(|e| Status::internal(format!("Failed to query messages: {e}")))?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let mut message = MessageProto::decode(row.get::<_, &[u8]>(2))
+                    .expect("We don't persist bad messages.");
+                message.id = Some(row.get::<_, i64>(0) as u64);
+                message.sequence = Some(row.get::<_, i64>(1) as u64);
+                message
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self, recipient, ids), fields(ids = ids.len()))]
+    async fn ack_messages(&self, recipient: &IdentityPublicKey, ids: Vec<u64>) -> Result<usize> {
+        let client = self.client().await?;
+        let ids: Vec<i64> = ids.into_iter().map(|id| id as i64).collect();
+
+        client
+            .execute(
+                "DELETE FROM mailbox WHERE ik = $1 AND id = ANY($2)",
+                &[&recipient.as_ref(), &ids],
+            )
+            .await
+            .map(|deleted| deleted as usize)
+            .map_err(|e| Status::internal(format!("Failed to acknowledge messages: {e}")))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_one_time_prekey_count(&self, ik: &IdentityPublicKey) -> Result<u32> {
+        let client = self.client().await?;
+
+        client
+            .query_one(
+                "SELECT COUNT(*) FROM opk_queue WHERE ik = $1",
+                &[&ik.as_ref()],
+            )
+            .await
+            .map(|row| row.get::<_, i64>(0) as u32)
+            .map_err(|_| Status::internal("Failed to query opk count."))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_one_time_kem_pre_key_count(&self, ik: &IdentityPublicKey) -> Result<u32> {
+        let client = self.client().await?;
+
+        client
+            .query_one(
+                "SELECT COUNT(*) FROM kem_pre_key WHERE ik = $1 AND NOT last_resort",
+                &[&ik.as_ref()],
+            )
+            .await
+            .map(|row| row.get::<_, i64>(0) as u32)
+            .map_err(|_| Status::internal("Failed to query KEM pre key count."))
+    }
+
+    #[instrument(skip(self, uploader, digest, blob), fields(len = blob.len()))]
+    async fn add_attachment(
+        &self,
+        uploader: &IdentityPublicKey,
+        digest: [u8; 32],
+        blob: Vec<u8>,
+        quota: u64,
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let uploader = uploader.as_ref();
+
+        async {
+            let tx = client.transaction().await?;
+            if tx
+                .execute(
+                    "UPDATE attachment SET time = $2 WHERE digest = $1",
+                    &[&digest.as_slice(), &pg_time_now()],
+                )
+                .await?
+                == 1
+            {
+                tx.commit().await?;
+                return Ok(Ok(()));
+            }
+            // Locking the device serializes concurrent uploads against its quota.
+            if tx
+                .query_opt("SELECT 1 FROM device WHERE ik = $1 FOR UPDATE", &[&uploader])
+                .await?
+                .is_none()
+            {
+                return Ok(Err(Status::not_found("device not found")));
+            }
+            let usage: i64 = tx
+                .query_one(
+                    "SELECT COALESCE(SUM(octet_length(blob)), 0)::BIGINT FROM attachment WHERE uploader = $1",
+                    &[&uploader],
+                )
+                .await?
+                .get(0);
+            if usage as u64 + blob.len() as u64 > quota {
+                return Ok(Err(Status::resource_exhausted(format!(
+                    "attachments exceed quota of {quota} bytes"
+                ))));
+            }
+            tx.execute(
+                "INSERT INTO attachment (digest, blob, time, uploader) VALUES ($1, $2, $3, $4)",
+                &[&digest.as_slice(), &blob, &pg_time_now(), &uploader],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(Ok(()))
+        }
+        .await
+        .inspect_err(|e: &tokio_postgres::Error| error!("Failed to store attachment: {e}."))
+        .map_err(|_| Status::internal("Failed to store attachment."))?
+    }
+
+    #[instrument(skip(self, uploader))]
+    async fn get_attachment_usage(&self, uploader: &IdentityPublicKey) -> Result<u64> {
+        let client = self.client().await?;
+
+        client
+            .query_one(
+                "SELECT COALESCE(SUM(octet_length(blob)), 0)::BIGINT FROM attachment WHERE uploader = $1",
+                &[&uploader.as_ref()],
+            )
+            .await
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .map_err(|_| Status::internal("Failed to query attachment usage."))
+    }
+
+    #[instrument(skip(self, digest))]
+    async fn get_attachment(&self, digest: [u8; 32]) -> Result<Option<Vec<u8>>> {
+        let client = self.client().await?;
+
+        client
+            .query_opt(
+                "SELECT blob FROM attachment WHERE digest = $1",
+                &[&digest.as_slice()],
+            )
+            .await
+            .map(|row| row.map(|row| row.get(0)))
+            .inspect_err(|e| error!("Failed to get attachment: {e}."))
+            .map_err(|_| Status::internal("Failed to get attachment."))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_server_key(&self) -> Result<IdentityKey> {
+        let client = self.client().await?;
+
+        async {
+            client
+                .execute(
+                    "INSERT INTO server_key (id, key) VALUES (0, $1) ON CONFLICT DO NOTHING",
+                    &[&IdentityKey::generate().to_bytes().as_slice()],
+                )
+                .await?;
+            client
+                .query_one("SELECT key FROM server_key WHERE id = 0", &[])
+                .await
+        }
+        .await
+        .map(|row| {
+            let key: [u8; 32] = row
+                .get::<_, &[u8]>(0)
+                .try_into()
+                .expect("We don't persist bad keys.");
+            IdentityKey::from(key)
+        })
+        .inspect_err(|e| error!("Failed to get server key: {e}."))
+        .map_err(|_| Status::internal("Failed to get server key."))
+    }
+
+    #[instrument(skip(self, ik, token))]
+    async fn set_fcm_token(&self, ik: &IdentityPublicKey, token: String) -> Result<()> {
+        let client = self.client().await?;
+
+        client
+            .execute(
+                "INSERT INTO firebasetoken (ik, token, insertion_time) VALUES ($1, $2, $3)",
+                &[&ik.as_ref(), &token, &pg_time_now()],
+            )
+            .await
+            .map(|_| ())
+            .inspect_err(|e| error!("Failed to set Firebase Cloud Messaging token: {e}."))
+            .map_err(|_| Status::not_found("user not found"))
+    }
+
+    #[instrument(skip(self, ik))]
+    async fn get_fcm_token(
+        &self,
+        ik: &IdentityPublicKey,
+        max_age: Duration,
+    ) -> Result<Option<String>> {
+        let client = self.client().await?;
+        let min_time = pg_time_now().saturating_sub(max_age.as_secs() as i64);
+
+        client
+            .query_opt(
+                "SELECT token FROM firebasetoken WHERE ik = $1 AND insertion_time > $2",
+                &[&ik.as_ref(), &min_time],
+            )
+            .await
+            .map(|row| row.map(|row| row.get(0)))
+            .inspect_err(|e| error!("Failed to get Firebase Cloud Messaging token: {e}."))
+            .map_err(|_| Status::internal("Failed to get Firebase Cloud Messaging token."))
+    }
+
+    #[instrument(skip(self))]
+    async fn clean_mailboxes(&self, ttl: Duration) -> Result<usize> {
+        let client = self.client().await?;
+        let expired = pg_time_now() - ttl.as_secs() as i64;
+
+        client
+            .execute("DELETE FROM mailbox WHERE time < $1", &[&expired])
+            .await
+            .map(|deleted| deleted as usize)
+            .map_err(|e| Status::internal(format!("Failed to clean mailboxes: {e}")))
+    }
+
+    #[instrument(skip(self))]
+    async fn clean_attachments(&self, ttl: Duration) -> Result<usize> {
+        let client = self.client().await?;
+        let expired = pg_time_now() - ttl.as_secs() as i64;
+
+        client
+            .execute("DELETE FROM attachment WHERE time < $1", &[&expired])
+            .await
+            .map(|deleted| deleted as usize)
+            .map_err(|e| Status::internal(format!("Failed to clean attachments: {e}")))
+    }
+}
@@ -0,0 +1,288 @@
+use proto::service::Message as MessageProto;
+use protocol::xeddsa::IdentityPublicKey;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::Result;
+use tracing::{info, warn};
+
+type MessageSender = Sender<Result<MessageProto>>;
+
+/*
+    Live Delivery
+    Every message is put in the mailbox before it is delivered, so a stream never has to be
+    handed a message that it can't take right away. A stream is either live or draining.
+    Live - The stream has been sent every message up to `last_sequence`. The next message is sent
+    without waiting if there is room for it in the stream's buffer.
+    Draining - A task is sending the stream the messages in the mailbox after `last_sequence`.
+    A stream starts draining when it is opened, when its buffer is full, or when a message arrives
+    out of order, and goes back to being live once the mailbox has nothing newer for it.
+*/
+
+struct StreamState {
+    tx: MessageSender,
+    last_sequence: u64,
+    draining: bool,
+    /// Set when a message arrives while the stream is draining, so that the drain task checks the
+    /// mailbox again before the stream goes live.
+    pending: bool,
+}
+
+/// A stream that needs to be sent the messages in the mailbox after `after`.
+/// The caller must send them and then call `Receivers::finish_drain`.
+pub struct Drain {
+    pub ik: IdentityPublicKey,
+    pub id: u64,
+    pub tx: MessageSender,
+    pub after: u64,
+}
+
+/// The open RetrieveMessages streams of each device. A device may have several streams open at
+/// once, for example from the app and the CLI.
+#[derive(Clone, Default)]
+pub struct Receivers {
+    next_id: Arc<AtomicU64>,
+    streams: Arc<Mutex<HashMap<IdentityPublicKey, HashMap<u64, StreamState>>>>,
+}
+
+impl Receivers {
+    /// Registers a stream for `ik` that is unregistered when the returned stream is dropped.
+    /// The stream starts out draining the whole mailbox.
+    pub fn insert(
+        &self,
+        ik: IdentityPublicKey,
+        tx: MessageSender,
+        rx: Receiver<Result<MessageProto>>,
+    ) -> (MessageStream, Drain) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut streams = self.streams.lock().unwrap();
+        let device = streams.entry(ik).or_default();
+        device.insert(
+            id,
+            StreamState {
+                tx: tx.clone(),
+                last_sequence: 0,
+                draining: true,
+                pending: false,
+            },
+        );
+        info!(streams = device.len(), "Registered message stream.");
+        let stream = MessageStream {
+            inner: ReceiverStream::new(rx),
+            _guard: StreamGuard {
+                receivers: self.clone(),
+                ik,
+                id,
+            },
+        };
+        let drain = Drain {
+            ik,
+            id,
+            tx,
+            after: 0,
+        };
+        (stream, drain)
+    }
+
+    /// Sends a message that was put in the mailbox of `ik` to each of its live streams without
+    /// waiting. Returns the streams that must start draining the mailbox to receive it.
+    pub fn deliver(&self, ik: &IdentityPublicKey, message: &MessageProto) -> Vec<Drain> {
+        let sequence = message.sequence();
+        let mut drains = Vec::new();
+        let mut streams = self.streams.lock().unwrap();
+        let Some(device) = streams.get_mut(ik) else {
+            return drains;
+        };
+        device.retain(|id, stream| {
+            if stream.draining {
+                stream.pending = true;
+                return true;
+            }
+            if sequence <= stream.last_sequence {
+                // Already sent from the mailbox.
+                return true;
+            }
+            if sequence == stream.last_sequence + 1 {
+                match stream.tx.try_send(Ok(message.clone())) {
+                    Ok(()) => {
+                        stream.last_sequence = sequence;
+                        return true;
+                    }
+                    Err(TrySendError::Full(_)) => warn!("Message stream is full."),
+                    Err(TrySendError::Closed(_)) => {
+                        warn!("Removing closed message stream.");
+                        return false;
+                    }
+                }
+            }
+            stream.draining = true;
+            stream.pending = false;
+            drains.push(Drain {
+                ik: *ik,
+                id: *id,
+                tx: stream.tx.clone(),
+                after: stream.last_sequence,
+            });
+            true
+        });
+        if device.is_empty() {
+            streams.remove(ik);
+        }
+        drains
+    }
+
+    /// Called by a drain task once it has sent every message up to `last_sequence`.
+    /// Returns false if more messages may have arrived, in which case the task must check the
+    /// mailbox again.
+    pub fn finish_drain(&self, ik: &IdentityPublicKey, id: u64, last_sequence: u64) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(ik).and_then(|device| device.get_mut(&id)) else {
+            return true;
+        };
+        stream.last_sequence = last_sequence;
+        if stream.pending {
+            stream.pending = false;
+            return false;
+        }
+        stream.draining = false;
+        true
+    }
+
+    /// Unregisters a stream of `ik`.
+    pub fn remove(&self, ik: &IdentityPublicKey, id: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(device) = streams.get_mut(ik) {
+            if device.remove(&id).is_some() {
+                info!(streams = device.len(), "Unregistered message stream.");
+            }
+            if device.is_empty() {
+                streams.remove(ik);
+            }
+        }
+    }
+
+    /// Returns true if `ik` has at least one open stream.
+    pub fn is_online(&self, ik: &IdentityPublicKey) -> bool {
+        self.streams.lock().unwrap().contains_key(ik)
+    }
+}
+
+struct StreamGuard {
+    receivers: Receivers,
+    ik: IdentityPublicKey,
+    id: u64,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.receivers.remove(&self.ik, self.id);
+    }
+}
+
+/// A RetrieveMessages response stream. Tonic drops it when the client disconnects, which
+/// unregisters it.
+pub struct MessageStream {
+    inner: ReceiverStream<Result<MessageProto>>,
+    _guard: StreamGuard,
+}
+
+impl Stream for MessageStream {
+    type Item = Result<MessageProto>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::receivers::*;
+    use anyhow::Result;
+    use protocol::xeddsa::IdentityKey;
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+
+    fn message(sequence: u64) -> MessageProto {
+        MessageProto {
+            sequence: Some(sequence),
+            ..Default::default()
+        }
+    }
+
+    /// Opens a stream that has drained an empty mailbox.
+    fn open(receivers: &Receivers, ik: IdentityPublicKey, buffer: usize) -> MessageStream {
+        let (tx, rx) = mpsc::channel(buffer);
+        let (stream, drain) = receivers.insert(ik, tx, rx);
+        assert!(receivers.finish_drain(&ik, drain.id, 0));
+        stream
+    }
+
+    #[tokio::test]
+    async fn multiple_streams_per_device() -> Result<()> {
+        let receivers = Receivers::default();
+        let ik = IdentityKey::generate().public_key();
+        let mut first = open(&receivers, ik, 1);
+        let mut second = open(&receivers, ik, 1);
+
+        assert!(receivers.deliver(&ik, &message(1)).is_empty());
+        assert_eq!(first.next().await.transpose()?, Some(message(1)));
+        assert_eq!(second.next().await.transpose()?, Some(message(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_streams_are_removed() {
+        let receivers = Receivers::default();
+        let ik = IdentityKey::generate().public_key();
+        assert!(!receivers.is_online(&ik));
+
+        let first = open(&receivers, ik, 1);
+        let second = open(&receivers, ik, 1);
+        assert!(receivers.is_online(&ik));
+        drop(first);
+        assert!(receivers.is_online(&ik));
+        drop(second);
+        assert!(!receivers.is_online(&ik));
+    }
+
+    #[tokio::test]
+    async fn full_stream_drains_mailbox() -> Result<()> {
+        let receivers = Receivers::default();
+        let ik = IdentityKey::generate().public_key();
+        let mut stream = open(&receivers, ik, 1);
+
+        assert!(receivers.deliver(&ik, &message(1)).is_empty());
+        let drains = receivers.deliver(&ik, &message(2));
+        assert_eq!(drains.len(), 1);
+        assert_eq!(drains[0].after, 1);
+
+        // Messages that arrive while draining are left to the drain task.
+        assert!(receivers.deliver(&ik, &message(3)).is_empty());
+        assert_eq!(stream.next().await.transpose()?, Some(message(1)));
+        assert!(!receivers.finish_drain(&ik, drains[0].id, 2));
+        assert!(receivers.finish_drain(&ik, drains[0].id, 3));
+
+        // Messages already sent by the drain task are not sent again.
+        assert!(receivers.deliver(&ik, &message(3)).is_empty());
+        assert!(receivers.deliver(&ik, &message(4)).is_empty());
+        assert_eq!(stream.next().await.transpose()?, Some(message(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_order_message_drains_mailbox() {
+        let receivers = Receivers::default();
+        let ik = IdentityKey::generate().public_key();
+        let _stream = open(&receivers, ik, 10);
+
+        let drains = receivers.deliver(&ik, &message(2));
+        assert_eq!(drains.len(), 1);
+        assert_eq!(drains[0].after, 0);
+    }
+}
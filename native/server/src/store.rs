@@ -0,0 +1,744 @@
+use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
+use proto::service::Message as MessageProto;
+use proto::service::SignedKemPreKey as SignedKemPreKeyProto;
+use proto::service::SignedPreKey as SignedPreKeyProto;
+use protocol::x3dh::PreKey;
+use protocol::xeddsa::{IdentityKey, IdentityPublicKey};
+use std::time::Duration;
+use tonic::{Result, Status};
+
+/// Everything the server persists: devices and their pre keys, mailboxes, attachments and push
+/// tokens.
+#[tonic::async_trait]
+pub trait BrongnalStore: Send + Sync {
+    /// Registers an identity with a signed pre key unless it is already registered.
+    /// Returns the signed pre key that is stored for the identity.
+    async fn insert_device(
+        &self,
+        ik: &IdentityPublicKey,
+        spk: SignedPreKeyProto,
+    ) -> Result<SignedPreKeyProto>;
+
+    /// Replaces the signed pre key of an identity with `spk` if it is still `current`.
+    /// Returns false if it was not.
+    async fn replace_spk(
+        &self,
+        ik: &IdentityPublicKey,
+        current: SignedPreKeyProto,
+        spk: SignedPreKeyProto,
+    ) -> Result<bool>;
+
+    /// Add a new identity to the storage.
+    /// Registering an existing identity with a different signed pre key rotates it with
+    /// `update_spk`.
+    async fn add_user(&self, ik: &IdentityPublicKey, spk: SignedPreKeyProto) -> Result<()> {
+        let persisted_spk = self.insert_device(ik, spk.clone()).await?;
+        if persisted_spk != spk {
+            return self.update_spk(ik, spk).await;
+        }
+        Ok(())
+    }
+
+    /// Replaces the signed pre key for a given identity.
+    /// Rotated keys can't be older than the current key so that an old signed pre key can't be
+    /// replayed to roll back a rotation. Ids can't be reused for a different key.
+    async fn update_spk(&self, ik: &IdentityPublicKey, spk: SignedPreKeyProto) -> Result<()> {
+        let current = self.get_current_spk(ik).await.map_err(|_| {
+            Status::not_found(format!(
+                "pre key for identity_key={} not found",
+                base64.encode(ik)
+            ))
+        })?;
+        if spk.id() == current.id() {
+            if spk.pre_key() != current.pre_key() {
+                return Err(Status::already_exists(
+                    "A different signed pre key already exists with this id.",
+                ));
+            }
+        } else if spk.created() < current.created() {
+            return Err(Status::failed_precondition(
+                "Signed pre key is older than the current signed pre key.",
+            ));
+        }
+        // Only replaces the key that was checked in case of a concurrent rotation.
+        if !self.replace_spk(ik, current, spk).await? {
+            return Err(Status::aborted("Signed pre key was concurrently updated."));
+        }
+        Ok(())
+    }
+
+    /// Appends new unburnt one time pre keys for others to message a given identity.
+    async fn add_opks(&self, ik: &IdentityPublicKey, opks: Vec<PreKey>) -> Result<()>;
+
+    /// Replaces the one time pre key that is vended once the one time pre keys run out.
+    async fn set_last_resort_opk(&self, ik: &IdentityPublicKey, pre_key: PreKey) -> Result<()>;
+
+    /// Retrieves the last resort pre key for an identity key. It is not consumed.
+    async fn get_last_resort_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>>;
+
+    /// Pops a one time pre key and a one time KEM pre key of `target` for `requester` and
+    /// records the request.
+    /// Fails with `ResourceExhausted` if `requester` made `max_requests` requests since `since`.
+    /// Neither kind of one time pre key is popped once `max_consumed` requests for `target`
+    /// consumed one since `since`; the last resort KEM pre key is returned instead. The limits
+    /// are checked and the request recorded in one transaction, so concurrent requests can't
+    /// exceed them. Records older than `since` are deleted.
+    /// Returns the one time pre key, the KEM pre key and the number of `target`'s requests that
+    /// consumed a one time pre key before this one.
+    async fn request_one_time_pre_keys(
+        &self,
+        requester: &IdentityPublicKey,
+        target: &IdentityPublicKey,
+        since: u64,
+        max_requests: u32,
+        max_consumed: u32,
+    ) -> Result<(Option<PreKey>, Option<SignedKemPreKeyProto>, u32)>;
+
+    /// Appends new unburnt one time KEM pre keys for others to message a given identity.
+    async fn add_kem_pre_keys(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_keys: Vec<SignedKemPreKeyProto>,
+    ) -> Result<()>;
+
+    /// Replaces the last resort KEM pre key for a given identity.
+    async fn set_last_resort_kem_pre_key(
+        &self,
+        ik: &IdentityPublicKey,
+        pre_key: SignedKemPreKeyProto,
+    ) -> Result<()>;
+
+    /// Retrieves the identity key and signed pre key for a given identity.
+    /// A client must first invoke this before messaging a peer.
+    async fn get_current_spk(&self, ik: &IdentityPublicKey) -> Result<SignedPreKeyProto>;
+
+    /// Retrieve a one time pre key for a identity key.
+    async fn pop_opk(&self, ik: &IdentityPublicKey) -> Result<Option<PreKey>>;
+
+    /// Retrieve a one time KEM pre key for an identity key.
+    /// Falls back to the last resort KEM pre key, which is not consumed, once they run out.
+    async fn pop_kem_pre_key(&self, ik: &IdentityPublicKey)
+        -> Result<Option<SignedKemPreKeyProto>>;
+
+    /// Enqueue a message for a given recipient.
+    /// Returns the message with the id and sequence number it was assigned.
+    async fn add_message(
+        &self,
+        recipient: &IdentityPublicKey,
+        message: MessageProto,
+    ) -> Result<MessageProto>;
+
+    /// Retrieve at most `limit` enqueued messages for a given identity with a sequence number
+    /// greater than `after` in the order they were enqueued.
+    /// Messages stay in the mailbox until they are acknowledged.
+    async fn get_messages(
+        &self,
+        recipient: &IdentityPublicKey,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<MessageProto>>;
+
+    /// Deletes acknowledged messages from the mailbox of a given identity.
+    /// Returns the number of messages that were deleted.
+    async fn ack_messages(&self, recipient: &IdentityPublicKey, ids: Vec<u64>) -> Result<usize>;
+
+    async fn get_one_time_prekey_count(&self, ik: &IdentityPublicKey) -> Result<u32>;
+
+    async fn get_one_time_kem_pre_key_count(&self, ik: &IdentityPublicKey) -> Result<u32>;
+
+    /// Stores an encrypted attachment by its digest unless the attachments that `uploader` has
+    /// stored would exceed `quota` bytes.
+    /// Uploading the same blob again restarts its TTL and doesn't count against the quota again.
+    async fn add_attachment(
+        &self,
+        uploader: &IdentityPublicKey,
+        digest: [u8; 32],
+        blob: Vec<u8>,
+        quota: u64,
+    ) -> Result<()>;
+
+    /// Returns the number of bytes of the attachments that `uploader` has stored.
+    async fn get_attachment_usage(&self, uploader: &IdentityPublicKey) -> Result<u64>;
+
+    async fn get_attachment(&self, digest: [u8; 32]) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the key that signs sender certificates, generating it on first use.
+    async fn get_server_key(&self) -> Result<IdentityKey>;
+
+    /// Set the current Firebase Cloud Messaging token for a user.
+    async fn set_fcm_token(&self, ik: &IdentityPublicKey, token: String) -> Result<()>;
+
+    /// Returns the current Firebase Cloud Messaging token for a user.
+    async fn get_fcm_token(
+        &self,
+        ik: &IdentityPublicKey,
+        max_age: Duration,
+    ) -> Result<Option<String>>;
+
+    /// Deletes messages older than `ttl`. Returns the number of messages deleted.
+    async fn clean_mailboxes(&self, ttl: Duration) -> Result<usize>;
+
+    /// Deletes attachments older than `ttl`. Returns the number of attachments deleted.
+    async fn clean_attachments(&self, ttl: Duration) -> Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MemoryStorage;
+    use crate::persistence::SqliteStorage;
+    #[cfg(feature = "postgres")]
+    use crate::postgres::PostgresStorage;
+    use crate::store::*;
+    use anyhow::Result;
+    use client::X3DHClient;
+    use proto::service::MessageType;
+    use protocol::bundle::{create_kem_pre_key, create_signed_pre_key};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio_rusqlite::Connection;
+    use tonic::Code;
+
+    /// Every store, so that the tests check that they behave the same way. PostgreSQL is
+    /// included when the `postgres` feature is enabled and POSTGRES_TEST_URL is set.
+    async fn stores() -> Result<Vec<Box<dyn BrongnalStore>>> {
+        let stores: Vec<Box<dyn BrongnalStore>> = vec![
+            Box::new(SqliteStorage::new(Connection::open_in_memory().await?).await?),
+            Box::new(MemoryStorage::default()),
+        ];
+        #[cfg(feature = "postgres")]
+        let stores = {
+            let mut stores = stores;
+            if let Ok(url) = std::env::var("POSTGRES_TEST_URL") {
+                stores.push(Box::new(PostgresStorage::connect(&url).await?));
+            }
+            stores
+        };
+        Ok(stores)
+    }
+
+    async fn new_client() -> Result<X3DHClient> {
+        Ok(X3DHClient::new(Connection::open_in_memory().await?).await?)
+    }
+
+    async fn registered_client(storage: &dyn BrongnalStore) -> Result<X3DHClient> {
+        let client = new_client().await?;
+        storage
+            .add_user(
+                &client.get_ik().public_key(),
+                client.get_spk().await?.into(),
+            )
+            .await?;
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn add_user_get_keys_success() -> Result<()> {
+        for storage in stores().await? {
+            let alice = new_client().await?;
+            let alice_ik = alice.get_ik().public_key();
+            let alice_spk: SignedPreKeyProto = alice.get_spk().await?.into();
+            storage.add_user(&alice_ik, alice_spk.clone()).await?;
+            assert_eq!(storage.get_current_spk(&alice_ik).await?, alice_spk);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_user_idempotent() -> Result<()> {
+        for storage in stores().await? {
+            let alice = new_client().await?;
+            let alice_ik = alice.get_ik().public_key();
+            let alice_spk: SignedPreKeyProto = alice.get_spk().await?.into();
+            storage.add_user(&alice_ik, alice_spk.clone()).await?;
+            storage.add_user(&alice_ik, alice_spk.clone()).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_user_overwrite_fails() -> Result<()> {
+        for storage in stores().await? {
+            let alice = registered_client(&*storage).await?;
+            let alice_ik = alice.get_ik().public_key();
+            let alice2_spk: SignedPreKeyProto = new_client().await?.get_spk().await?.into();
+
+            assert_eq!(
+                storage
+                    .add_user(&alice_ik, alice2_spk)
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::AlreadyExists)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_keys_not_found() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage.get_current_spk(&ik).await.err().map(|e| e.code()),
+                Some(Code::NotFound)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pop_empty_opks_none() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(storage.pop_opk(&ik).await?, None);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pop_empty_kem_pre_keys_none() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(storage.pop_kem_pre_key(&ik).await?, None);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updating_spk_user_not_found() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage
+                    .update_spk(&ik, SignedPreKeyProto::default())
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::NotFound)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_spk_success() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let bob_spk: SignedPreKeyProto = bob.get_spk().await?.into();
+
+            // Create a newer spk and overwrite it.
+            let (_, new_spk) = create_signed_pre_key(bob.get_ik(), 2, bob_spk.created() + 1);
+            let new_spk: SignedPreKeyProto = new_spk.into();
+            storage.update_spk(&bob_ik, new_spk.clone()).await?;
+
+            assert_eq!(storage.get_current_spk(&bob_ik).await?, new_spk);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_user_rotates_spk() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let bob_spk: SignedPreKeyProto = bob.get_spk().await?.into();
+
+            // The same key signed again is accepted.
+            let resigned: SignedPreKeyProto = bob.get_spk().await?.into();
+            storage.add_user(&bob_ik, resigned.clone()).await?;
+            assert_eq!(storage.get_current_spk(&bob_ik).await?, resigned);
+
+            let (_, rotated) = create_signed_pre_key(bob.get_ik(), 2, bob_spk.created() + 1);
+            let rotated: SignedPreKeyProto = rotated.into();
+            storage.add_user(&bob_ik, rotated.clone()).await?;
+            assert_eq!(storage.get_current_spk(&bob_ik).await?, rotated);
+
+            // Rotating back to the older key is rejected.
+            assert_eq!(
+                storage
+                    .add_user(&bob_ik, bob_spk)
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::FailedPrecondition)
+            );
+            assert_eq!(storage.get_current_spk(&bob_ik).await?, rotated);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrieve_opk() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            assert_eq!(storage.pop_opk(&bob_ik).await?, None);
+
+            let keys = bob.create_opks(2).await?.pre_keys;
+            storage.add_opks(&bob_ik, keys.clone()).await?;
+            assert_eq!(storage.get_one_time_prekey_count(&bob_ik).await?, 2);
+            let first = storage.pop_opk(&bob_ik).await?.unwrap();
+            let second = storage.pop_opk(&bob_ik).await?.unwrap();
+            assert!(keys.contains(&first) && keys.contains(&second) && first != second);
+            assert_eq!(storage.pop_opk(&bob_ik).await?, None);
+            assert_eq!(storage.get_one_time_prekey_count(&bob_ik).await?, 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_resort_opk_is_not_consumed() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            assert_eq!(storage.get_last_resort_opk(&bob_ik).await?, None);
+
+            let key = bob.get_last_resort_pre_key().await?.pre_keys[0];
+            storage.set_last_resort_opk(&bob_ik, key).await?;
+            assert_eq!(storage.get_last_resort_opk(&bob_ik).await?, Some(key));
+            assert_eq!(storage.get_last_resort_opk(&bob_ik).await?, Some(key));
+
+            let replacement = bob.create_opks(1).await?.pre_keys[0];
+            storage.set_last_resort_opk(&bob_ik, replacement).await?;
+            assert_eq!(
+                storage.get_last_resort_opk(&bob_ik).await?,
+                Some(replacement)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_one_time_pre_keys_enforces_limits() -> Result<()> {
+        for storage in stores().await? {
+            let alice = IdentityKey::generate().public_key();
+            let eve = IdentityKey::generate().public_key();
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let keys = bob.create_opks(3).await?.pre_keys;
+            storage.add_opks(&bob_ik, keys.clone()).await?;
+
+            assert_eq!(
+                storage
+                    .request_one_time_pre_keys(&alice, &bob_ik, 0, 2, 2)
+                    .await?,
+                (Some(keys[0]), None, 0)
+            );
+            assert_eq!(
+                storage
+                    .request_one_time_pre_keys(&alice, &bob_ik, 0, 2, 2)
+                    .await?,
+                (Some(keys[1]), None, 1)
+            );
+            assert_eq!(
+                storage
+                    .request_one_time_pre_keys(&alice, &bob_ik, 0, 2, 2)
+                    .await
+                    .map_err(|e| e.code()),
+                Err(Code::ResourceExhausted)
+            );
+            // Bob's one time pre keys are no longer handed out, to anyone.
+            assert_eq!(
+                storage
+                    .request_one_time_pre_keys(&eve, &bob_ik, 0, 2, 2)
+                    .await?,
+                (None, None, 2)
+            );
+
+            // Requests from before the window are not counted.
+            let future = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + 60;
+            assert_eq!(
+                storage
+                    .request_one_time_pre_keys(&alice, &bob_ik, future, 2, 2)
+                    .await?,
+                (Some(keys[2]), None, 0)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kem_pre_keys_survive_request_flood() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let one_time: Vec<SignedKemPreKeyProto> = (0..3)
+                .map(|_| create_kem_pre_key(bob.get_ik()).1.into())
+                .collect();
+            let last_resort: SignedKemPreKeyProto = create_kem_pre_key(bob.get_ik()).1.into();
+            storage.add_kem_pre_keys(&bob_ik, one_time.clone()).await?;
+            storage
+                .set_last_resort_kem_pre_key(&bob_ik, last_resort.clone())
+                .await?;
+
+            // Every request comes from a new requester, so only the per target limit applies.
+            let expected = one_time[..2]
+                .iter()
+                .chain(std::iter::repeat_n(&last_resort, 8));
+            for expected in expected {
+                let requester = IdentityKey::generate().public_key();
+                let (_, kem_pre_key, _) = storage
+                    .request_one_time_pre_keys(&requester, &bob_ik, 0, 2, 2)
+                    .await?;
+                assert_eq!(kem_pre_key.as_ref(), Some(expected));
+            }
+            assert_eq!(storage.get_one_time_kem_pre_key_count(&bob_ik).await?, 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrieve_kem_pre_key_falls_back_to_last_resort() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            assert_eq!(storage.pop_kem_pre_key(&bob_ik).await?, None);
+
+            let one_time: SignedKemPreKeyProto = create_kem_pre_key(bob.get_ik()).1.into();
+            let last_resort: SignedKemPreKeyProto = create_kem_pre_key(bob.get_ik()).1.into();
+            storage
+                .add_kem_pre_keys(&bob_ik, vec![one_time.clone()])
+                .await?;
+            storage
+                .set_last_resort_kem_pre_key(&bob_ik, last_resort.clone())
+                .await?;
+            assert_eq!(storage.get_one_time_kem_pre_key_count(&bob_ik).await?, 1);
+
+            assert_eq!(storage.pop_kem_pre_key(&bob_ik).await?, Some(one_time));
+            assert_eq!(storage.get_one_time_kem_pre_key_count(&bob_ik).await?, 0);
+            assert_eq!(
+                storage.pop_kem_pre_key(&bob_ik).await?,
+                Some(last_resort.clone())
+            );
+            assert_eq!(storage.pop_kem_pre_key(&bob_ik).await?, Some(last_resort));
+
+            // Replacing the last resort key removes the old one.
+            let new_last_resort: SignedKemPreKeyProto = create_kem_pre_key(bob.get_ik()).1.into();
+            storage
+                .set_last_resort_kem_pre_key(&bob_ik, new_last_resort.clone())
+                .await?;
+            assert_eq!(
+                storage.pop_kem_pre_key(&bob_ik).await?,
+                Some(new_last_resort)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_message_unknown_user() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage
+                    .add_message(&ik, MessageProto::default())
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::NotFound)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_get_message() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let message_proto = MessageProto {
+                sender_identity_key: Some(b"alice identity key".to_vec()),
+                ephemeral_key: Some(b"alice ephemeral key".to_vec()),
+                ciphertext: Some(b"ciphertext".to_vec()),
+                r#type: Some(MessageType::PreKey.into()),
+                kem_pre_key: Some(b"bob kem pre key".to_vec()),
+                kem_ciphertext: Some(b"kem ciphertext".to_vec()),
+                sealed_sender: None,
+                pre_key_id: Some(1),
+                one_time_key_id: Some(2),
+                id: None,
+                sequence: None,
+            };
+            let stored = storage.add_message(&bob_ik, message_proto.clone()).await?;
+            assert_eq!(
+                stored,
+                MessageProto {
+                    id: stored.id,
+                    sequence: Some(1),
+                    ..message_proto
+                }
+            );
+            assert_eq!(
+                storage.get_messages(&bob_ik, 0, u32::MAX).await?,
+                vec![stored]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_redelivered_until_acked() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let first = storage
+                .add_message(&bob_ik, MessageProto::default())
+                .await?;
+            let second = storage
+                .add_message(&bob_ik, MessageProto::default())
+                .await?;
+            assert_ne!(first.id, second.id);
+            assert_eq!(second.sequence, Some(2));
+            assert_eq!(
+                storage.get_messages(&bob_ik, 0, u32::MAX).await?,
+                vec![first.clone(), second.clone()]
+            );
+            assert_eq!(
+                storage
+                    .get_messages(&bob_ik, first.sequence.unwrap(), u32::MAX)
+                    .await?,
+                vec![second.clone()]
+            );
+            assert_eq!(
+                storage.get_messages(&bob_ik, 0, 1).await?,
+                vec![first.clone()]
+            );
+
+            // Acknowledgements only apply to the recipient's mailbox.
+            let eve_ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage
+                    .ack_messages(&eve_ik, vec![first.id.unwrap()])
+                    .await?,
+                0
+            );
+            assert_eq!(
+                storage
+                    .ack_messages(&bob_ik, vec![first.id.unwrap()])
+                    .await?,
+                1
+            );
+            assert_eq!(
+                storage.get_messages(&bob_ik, 0, u32::MAX).await?,
+                vec![second.clone()]
+            );
+
+            // Sequence numbers keep increasing after the mailbox is emptied.
+            storage
+                .ack_messages(&bob_ik, vec![second.id.unwrap()])
+                .await?;
+            let third = storage
+                .add_message(&bob_ik, MessageProto::default())
+                .await?;
+            assert_eq!(third.sequence, Some(3));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_get_attachment() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let digest = *IdentityKey::generate().public_key().as_x25519().as_bytes();
+            assert_eq!(storage.get_attachment(digest).await?, None);
+
+            storage
+                .add_attachment(&bob_ik, digest, b"blob".to_vec(), 4)
+                .await?;
+            storage
+                .add_attachment(&bob_ik, digest, b"blob".to_vec(), 4)
+                .await?;
+            assert_eq!(
+                storage.get_attachment(digest).await?,
+                Some(b"blob".to_vec())
+            );
+            assert_eq!(storage.get_attachment_usage(&bob_ik).await?, 4);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attachment_quota_per_uploader() -> Result<()> {
+        for storage in stores().await? {
+            let alice = registered_client(&*storage).await?;
+            let alice_ik = alice.get_ik().public_key();
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+            let digests = [[1; 32], [2; 32], [3; 32]];
+
+            storage
+                .add_attachment(&bob_ik, digests[0], b"blob".to_vec(), 6)
+                .await?;
+            assert_eq!(
+                storage
+                    .add_attachment(&bob_ik, digests[1], b"blob".to_vec(), 6)
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::ResourceExhausted)
+            );
+            assert_eq!(storage.get_attachment(digests[1]).await?, None);
+            assert_eq!(storage.get_attachment_usage(&bob_ik).await?, 4);
+
+            // Other devices have their own quota.
+            storage
+                .add_attachment(&alice_ik, digests[1], b"blob".to_vec(), 6)
+                .await?;
+
+            let eve_ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage
+                    .add_attachment(&eve_ik, digests[2], b"blob".to_vec(), 6)
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::NotFound)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_key_is_stable() -> Result<()> {
+        for storage in stores().await? {
+            let server_key = storage.get_server_key().await?;
+            assert_eq!(storage.get_server_key().await?, server_key);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_fcm_token_user_not_found() -> Result<()> {
+        for storage in stores().await? {
+            let ik = IdentityKey::generate().public_key();
+            assert_eq!(
+                storage
+                    .set_fcm_token(&ik, String::from("abcd123"))
+                    .await
+                    .err()
+                    .map(|e| e.code()),
+                Some(Code::NotFound)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_get_fcm_token() -> Result<()> {
+        for storage in stores().await? {
+            let bob = registered_client(&*storage).await?;
+            let bob_ik = bob.get_ik().public_key();
+
+            let token = String::from("abcd123");
+            storage.set_fcm_token(&bob_ik, token.clone()).await?;
+            assert_eq!(
+                storage
+                    .get_fcm_token(&bob_ik, Duration::new(2000, 0))
+                    .await?,
+                Some(token)
+            );
+        }
+        Ok(())
+    }
+}